@@ -0,0 +1,149 @@
+use crate::instruction::*;
+
+use quote::quote;
+use proc_macro2::{ Span, Ident, TokenStream };
+
+/// Gera o *match pattern* e o encoding de uma instrução do tipo R.
+///
+/// Os campos fixos da instrução (`rs` e `shamt`, quando o YAML os dá, e o
+/// `funct`) são escritos com o valor do YAML, e não com o dos argumentos.
+fn generate_r_encode((name, instr): (&String, &RInstruction)) -> TokenStream {
+    let ename = name.to_uppercase();
+    let ename_ident = Ident::new(&ename, Span::call_site());
+    let opcode = instr.opcode.unwrap_or(0);
+    let funct = instr.funct;
+
+    let rs = match instr.rs {
+        Some(rs) => quote! { #rs },
+        None => quote! { a.rs.0 },
+    };
+    let shamt = match instr.shamt {
+        Some(shamt) => quote! { #shamt },
+        None => quote! { (a.shamt & 31) },
+    };
+    // No mfc0 e no mtc0, o `sel` vai nos 3 bits mais baixos do funct.
+    let funct = if instr.sel.unwrap_or(false) {
+        quote! { (#funct | (a.funct & 7)) }
+    } else {
+        quote! { #funct }
+    };
+
+    let code = quote! {
+        &Instruction:: #ename_ident (ref a) =>
+            #opcode << 26 | #rs << 21 | a.rt.0 << 16 | a.rd.0 << 11 | #shamt << 6 | #funct,
+    };
+
+    code.into()
+}
+
+/// Gera o *match pattern* e o encoding de uma instrução do tipo I.
+fn generate_i_encode((name, instr): (&String, &IInstruction)) -> TokenStream {
+    let ename = name.to_uppercase();
+    let ename_ident = Ident::new(&ename, Span::call_site());
+    let opcode = instr.opcode;
+
+    let rt = match instr.rt {
+        Some(rt) => quote! { #rt },
+        None => quote! { a.rt.0 },
+    };
+
+    let code = quote! {
+        &Instruction:: #ename_ident (ref a) => #opcode << 26 | a.rs.0 << 21 | #rt << 16 | (a.imm & 0xFFFF),
+    };
+
+    code.into()
+}
+
+/// Gera o *match pattern* e o encoding de uma instrução do tipo J.
+fn generate_j_encode((name, instr): (&String, &JInstruction)) -> TokenStream {
+    let ename = name.to_uppercase();
+    let ename_ident = Ident::new(&ename, Span::call_site());
+    let opcode = instr.opcode;
+
+    let code = quote! {
+        &Instruction:: #ename_ident (a) => #opcode << 26 | (a & 0x3FFFFFF),
+    };
+
+    code.into()
+}
+
+/// Gera o *match pattern* e o encoding de uma instrução do tipo FR.
+fn generate_fr_encode((name, instr): (&String, &FRInstruction)) -> TokenStream {
+    let ename = name.to_uppercase().replace(".", "_");
+    let ename_ident = Ident::new(&ename, Span::call_site());
+
+    let opcode = instr.opcode;
+    let fmt = instr.fmt;
+    let funct = instr.funct;
+
+    let code = quote! {
+        &Instruction:: #ename_ident (ref a) =>
+            #opcode << 26 | #fmt << 21 | a.ft.0 << 16 | a.fs.0 << 11 | a.fd.0 << 6 | #funct,
+    };
+
+    code.into()
+}
+
+/// Gera o *match pattern* e o encoding de uma instrução do tipo FI.
+fn generate_fi_encode((name, instr): (&String, &FIInstruction)) -> TokenStream {
+    let ename = name.to_uppercase();
+    let ename_ident = Ident::new(&ename, Span::call_site());
+
+    let opcode = instr.opcode;
+    let fmt = instr.fmt;
+    let ft = instr.ft;
+
+    let code = quote! {
+        &Instruction:: #ename_ident (ref a) => #opcode << 26 | #fmt << 21 | #ft << 16 | (a.imm & 0xFFFF),
+    };
+
+    code.into()
+}
+
+/// Gera `Instruction::encode`, o inverso do `decode`: para toda palavra `w`
+/// que o `decode` aceita, `decode(w)?.encode() == w`.
+pub(crate) fn generate_encode(instrs: &Instructions) -> TokenStream {
+    let r = instrs.r
+        .iter()
+        .map(generate_r_encode)
+        .collect::<Vec<_>>();
+    let i = instrs.i
+        .iter()
+        .map(generate_i_encode)
+        .collect::<Vec<_>>();
+    let j = instrs.j
+        .iter()
+        .map(generate_j_encode)
+        .collect::<Vec<_>>();
+    let fr = instrs.fr
+        .iter()
+        .map(generate_fr_encode)
+        .collect::<Vec<_>>();
+    let fi = instrs.fi
+        .iter()
+        .map(generate_fi_encode)
+        .collect::<Vec<_>>();
+
+    let code = quote! {
+        impl Instruction {
+            /// Codifica a instrução na palavra de 32 bits que a representa.
+            pub fn encode(&self) -> u32 {
+                match self {
+                    &Instruction::NOP => 0,
+                    #(#r)
+                    *
+                    #(#i)
+                    *
+                    #(#j)
+                    *
+                    #(#fr)
+                    *
+                    #(#fi)
+                    *
+                }
+            }
+        }
+    }.into();
+
+    code
+}
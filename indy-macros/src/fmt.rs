@@ -36,6 +36,48 @@ fn generate_r_fmt((name, instr): (&String, &RInstruction)) -> TokenStream {
             write!(f, "{} {}, {}", #name, a.rs, a.rt)
         };
 
+        c.into()
+    } else if instr.variable_shift.unwrap_or(false) {
+        let c = quote! {
+            write!(f, "{} {}, {}, {}", #name, a.rd, a.rt, a.rs)
+        };
+
+        c.into()
+    } else if instr.two_operands_rt.unwrap_or(false) {
+        let c = quote! {
+            write!(f, "{} {}, {}", #name, a.rd, a.rt)
+        };
+
+        c.into()
+    } else if let Some(bitfield) = instr.bitfield.as_deref() {
+        // O campo `rd` guarda o bit mais significativo: na ext ele é
+        // relativo à posição, e na ins é absoluto.
+        let c = if bitfield == "ins" {
+            quote! {
+                write!(f, "{} {}, {}, {}, {}", #name, a.rt, a.rs, a.shamt, (a.rd.0 + 1).wrapping_sub(a.shamt))
+            }
+        } else {
+            quote! {
+                write!(f, "{} {}, {}, {}, {}", #name, a.rt, a.rs, a.shamt, a.rd.0 + 1)
+            }
+        };
+
+        c.into()
+    } else if instr.sel.unwrap_or(false) {
+        // O `sel` só aparece quando não é zero.
+        let c = quote! {
+            match a.funct & 7 {
+                0 => write!(f, "{} {}, ${}", #name, a.rt, a.rd.0),
+                sel => write!(f, "{} {}, ${}, {}", #name, a.rt, a.rd.0, sel),
+            }
+        };
+
+        c.into()
+    } else if instr.cop0.unwrap_or(false) {
+        let c = quote! {
+            write!(f, "{} {}, ${}", #name, a.rt, a.rd.0)
+        };
+
         c.into()
     } else if instr.move_cop.unwrap_or(false) {
         let c = quote! {
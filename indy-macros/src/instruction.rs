@@ -19,12 +19,21 @@ pub(crate) struct RInstruction {
     pub(crate) two_operands: Option<bool>,
     pub(crate) two_operands_alt: Option<bool>,
     pub(crate) move_cop: Option<bool>,
+    pub(crate) rs: Option<u32>,
+    pub(crate) shamt: Option<u32>,
+    pub(crate) cop0: Option<bool>,
+    pub(crate) sel: Option<bool>,
+    pub(crate) variable_shift: Option<bool>,
+    pub(crate) two_operands_rt: Option<bool>,
+    pub(crate) bitfield: Option<String>,
+    pub(crate) rt_is_rd: Option<bool>,
 }
 
 /// Uma instrução do tipo I.
 #[derive(Deserialize)]
 pub(crate) struct IInstruction {
     pub(crate) opcode: u32,
+    pub(crate) rt: Option<u32>,
     pub(crate) sign_ext: Option<bool>,
     pub(crate) load_store: Option<bool>,
     pub(crate) half_word: Option<bool>,
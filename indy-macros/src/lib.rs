@@ -2,13 +2,17 @@
 //! 
 //! Essa subcrate implementa a procedural macro `instr_from_yaml`, responsável
 //! por parsear o arquivo `instructions.yml` e gerar a declaração das
-//! instruções, assim como seu pretty-printing e decoding.
+//! instruções, assim como seu pretty-printing, decoding e encoding, e o
+//! parser do assembly delas.
 //!
 //! Cada submódulo implementa uma parte da macro:
 //! - decl: as declarações das instruções
 //! - instruction: parsing do arquivo yaml
 //! - fmt: pretty-printing/disassembly
 //! - parse: decoding
+//! - encode: encoding, o inverso do decoding
+//! - text: parsing do assembly, o inverso do pretty-printing, e os
+//!   operandos de cada instrução, para o disassembler
 //! - kind: runtime info sobre o tipo da instrucao
 
 use proc_macro::TokenStream;
@@ -20,6 +24,8 @@ mod decl;
 mod instruction;
 mod fmt;
 mod parse;
+mod encode;
+mod text;
 mod kind;
 
 use instruction::Instructions;
@@ -42,6 +48,8 @@ pub fn instr_from_yaml(item: TokenStream) -> TokenStream {
     let decl = decl::generate_decl(&instructions);
     let fmt = fmt::generate_fmt(&instructions);
     let parse = parse::generate_parse(&instructions);
+    let encode = encode::generate_encode(&instructions);
+    let text = text::generate_text(&instructions);
 
     let code = quote! {
         use color_eyre::eyre::{ eyre, Result };
@@ -54,6 +62,7 @@ pub fn instr_from_yaml(item: TokenStream) -> TokenStream {
             pub rt: Register,
             pub rd: Register,
             pub shamt: u32,
+            pub funct: u32,
         }
 
         /// Operandos contidos numa intrução do tipo I.
@@ -92,6 +101,10 @@ pub fn instr_from_yaml(item: TokenStream) -> TokenStream {
         #fmt
 
         #parse
+
+        #encode
+
+        #text
     };
 
     code.into()
@@ -13,8 +13,31 @@ fn generate_r_parse_case((name, instr): (&String, &RInstruction)) -> TokenStream
     let opcode = instr.opcode.as_ref().unwrap_or(&0x0);
     let funct = instr.funct;
 
-    let code = quote! {
-        (#opcode, #funct) => Ok(Instruction::#ename_ident (args))
+    // Algumas instruções (as do cop0, por exemplo) compartilham opcode e
+    // funct, e só são distinguidas pelo campo `rs` ou pelo `shamt`.
+    let mut guards = Vec::new();
+    if let Some(rs) = instr.rs {
+        guards.push(quote! { args.rs.0 == #rs });
+    }
+    if let Some(shamt) = instr.shamt {
+        guards.push(quote! { args.shamt == #shamt });
+    }
+
+    // No mfc0 e no mtc0, os 3 bits mais baixos do funct são o `sel`.
+    let funct = if instr.sel.unwrap_or(false) {
+        quote! { #funct..=7 }
+    } else {
+        quote! { #funct }
+    };
+
+    let code = if !guards.is_empty() {
+        quote! {
+            (#opcode, #funct) if #(#guards)&&* => Ok(Instruction::#ename_ident (args))
+        }
+    } else {
+        quote! {
+            (#opcode, #funct) => Ok(Instruction::#ename_ident (args))
+        }
     };
 
     code.into()
@@ -26,8 +49,15 @@ fn generate_i_parse_case((name, instr): (&String, &IInstruction)) -> TokenStream
     let ename_ident = Ident::new(&ename, Span::call_site());
     let opcode = instr.opcode;
 
-    let code = quote! {
-        #opcode => Ok(Instruction::#ename_ident (args))
+    // As instruções REGIMM compartilham o opcode 1, e são distinguidas
+    // pelo campo `rt`.
+    let code = match instr.rt {
+        Some(rt) => quote! {
+            #opcode if args.rt.0 == #rt => Ok(Instruction::#ename_ident (args))
+        },
+        None => quote! {
+            #opcode => Ok(Instruction::#ename_ident (args))
+        },
     };
 
     code.into()
@@ -95,7 +125,7 @@ fn generate_r_parse(instrs: &HashMap<String, RInstruction>) -> TokenStream {
             let rs = Register((word & (31 << 21)) >> 21);
             let opcode = (word & (63 << 26)) >> 26;
 
-            let args = RArgs { rd, rt, rs, shamt };
+            let args = RArgs { rd, rt, rs, shamt, funct };
 
             match (opcode, funct) {
                 #(#cases),
@@ -237,7 +267,7 @@ pub(crate) fn generate_parse(instrs: &Instructions) -> TokenStream {
                 let opcode = (word & (63 << 26)) >> 26;
 
                 match opcode {
-                    0 | 16 => decode_r_instr(word),
+                    0 | 16 | 28 | 31 => decode_r_instr(word),
                     2 | 3 => decode_j_instr(word),
                     17 => decode_f_instr(word),
                     _ => decode_i_instr(word),
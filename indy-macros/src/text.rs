@@ -0,0 +1,559 @@
+use std::collections::HashMap;
+
+use crate::instruction::*;
+
+use quote::quote;
+use proc_macro2::{ Span, Ident, TokenStream };
+
+/// A sintaxe de uma instrução: o tipo de cada operando, como montar os
+/// argumentos da variante a partir dos valores `v` deles, e como tirar os
+/// operandos dos argumentos `a`.
+struct Syntax {
+    slots: Vec<&'static str>,
+    args: TokenStream,
+    operands: TokenStream,
+    /// Uma conferência extra dos valores, se houver.
+    check: Option<TokenStream>,
+    /// `true` se o primeiro operando pode ser omitido, valendo `$ra`.
+    ra_optional: bool,
+    /// `true` se o último operando, o `sel` do cop0, pode ser omitido,
+    /// valendo 0.
+    sel_optional: bool,
+}
+
+impl Syntax {
+    fn new(slots: &[&'static str], args: TokenStream, operands: TokenStream) -> Syntax {
+        Syntax { slots: slots.to_vec(), args, operands, check: None, ra_optional: false, sel_optional: false }
+    }
+}
+
+/// A lista `&[Slot::...]` com os tipos dos operandos.
+fn slot_list(slots: &[&'static str]) -> TokenStream {
+    let slots = slots
+        .iter()
+        .map(|slot| Ident::new(slot, Span::call_site()))
+        .collect::<Vec<_>>();
+
+    quote! { &[#(Slot:: #slots),*] }
+}
+
+/// Os *match patterns* de uma instrução em cada função gerada.
+struct Cases {
+    syntax: TokenStream,
+    build: TokenStream,
+    mnemonic: TokenStream,
+    operands: TokenStream,
+}
+
+/// Gera os *match patterns* de uma instrução.
+fn generate_cases(name: &str, ename: &str, syntax: Syntax) -> Cases {
+    let ename_ident = Ident::new(ename, Span::call_site());
+    let slots = slot_list(&syntax.slots);
+    let args = syntax.args;
+    let operands = syntax.operands;
+
+    // Sem o primeiro operando, os valores são os do resto, depois do `$ra`.
+    let values = if syntax.ra_optional {
+        let rest = slot_list(&syntax.slots[1..]);
+        quote! {
+            if ops.len() + 1 == (#slots).len() {
+                let mut v = operand_values(#name, #rest, ops)?;
+                v.insert(0, 31);
+                v
+            } else {
+                operand_values(#name, #slots, ops)?
+            }
+        }
+    } else if syntax.sel_optional {
+        // E sem o `sel`, ele vale 0.
+        let rest = slot_list(&syntax.slots[..syntax.slots.len() - 1]);
+        quote! {
+            if ops.len() + 1 == (#slots).len() {
+                let mut v = operand_values(#name, #rest, ops)?;
+                v.push(0);
+                v
+            } else {
+                operand_values(#name, #slots, ops)?
+            }
+        }
+    } else {
+        quote! { operand_values(#name, #slots, ops)? }
+    };
+    let check = syntax.check.unwrap_or_default();
+
+    Cases {
+        syntax: quote! {
+            #name => Some(#slots),
+        },
+        build: quote! {
+            #name => {
+                let v = #values;
+                #check
+                Ok(Instruction:: #ename_ident (#args))
+            }
+        },
+        mnemonic: quote! {
+            &Instruction:: #ename_ident (_) => #name,
+        },
+        operands: quote! {
+            &Instruction:: #ename_ident (ref a) => #operands,
+        },
+    }
+}
+
+/// A sintaxe de uma instrução do tipo R, a mesma que o `Display` usa.
+fn r_syntax(instr: &RInstruction) -> Syntax {
+    let rs = instr.rs.unwrap_or(0);
+    let shamt = instr.shamt.unwrap_or(0);
+    let funct = instr.funct;
+
+    if instr.shift.unwrap_or(false) {
+        Syntax::new(&["Reg", "Reg", "Shamt"], quote! {
+            RArgs { rd: Register(v[0]), rt: Register(v[1]), rs: Register(#rs), shamt: v[2], funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rd), Operand::Reg(a.rt), Operand::Imm(a.shamt as i64)]
+        })
+    } else if instr.one_operand.unwrap_or(false) {
+        Syntax::new(&["Reg"], quote! {
+            RArgs { rs: Register(v[0]), rt: Register(0), rd: Register(0), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rs)]
+        })
+    } else if instr.two_operands.unwrap_or(false) {
+        let rt = if instr.rt_is_rd.unwrap_or(false) {
+            quote! { v[0] }
+        } else {
+            quote! { 0 }
+        };
+        let mut syntax = Syntax::new(&["Reg", "Reg"], quote! {
+            RArgs { rd: Register(v[0]), rs: Register(v[1]), rt: Register(#rt), shamt: #shamt, funct: #funct }
+        }, quote! {
+            if a.rd == Register(31) {
+                vec![Operand::Reg(a.rs)]
+            } else {
+                vec![Operand::Reg(a.rd), Operand::Reg(a.rs)]
+            }
+        });
+        syntax.ra_optional = true;
+        syntax
+    } else if instr.two_operands_alt.unwrap_or(false) {
+        Syntax::new(&["Reg", "Reg"], quote! {
+            RArgs { rs: Register(v[0]), rt: Register(v[1]), rd: Register(0), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rs), Operand::Reg(a.rt)]
+        })
+    } else if instr.variable_shift.unwrap_or(false) {
+        Syntax::new(&["Reg", "Reg", "Reg"], quote! {
+            RArgs { rd: Register(v[0]), rt: Register(v[1]), rs: Register(v[2]), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rd), Operand::Reg(a.rt), Operand::Reg(a.rs)]
+        })
+    } else if instr.two_operands_rt.unwrap_or(false) {
+        Syntax::new(&["Reg", "Reg"], quote! {
+            RArgs { rd: Register(v[0]), rt: Register(v[1]), rs: Register(#rs), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rd), Operand::Reg(a.rt)]
+        })
+    } else if let Some(bitfield) = instr.bitfield.as_deref() {
+        // Os operandos são a posição e o tamanho do campo; o `rd` guarda o
+        // bit mais significativo, como no `Display`.
+        let (msb, size) = if bitfield == "ins" {
+            (quote! { v[2] + v[3] - 1 }, quote! { a.rd.0 as i64 + 1 - a.shamt as i64 })
+        } else {
+            (quote! { v[3] - 1 }, quote! { a.rd.0 as i64 + 1 })
+        };
+        let mut syntax = Syntax::new(&["Reg", "Reg", "Shamt", "Size"], quote! {
+            RArgs { rt: Register(v[0]), rs: Register(v[1]), rd: Register(#msb), shamt: v[2], funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rt), Operand::Reg(a.rs), Operand::Imm(a.shamt as i64), Operand::Imm(#size)]
+        });
+        syntax.check = Some(quote! {
+            if v[2] + v[3] > 32 {
+                return Err(eyre!("the bit field of {} goes past bit 31", name));
+            }
+        });
+        syntax
+    } else if instr.sel.unwrap_or(false) {
+        // O último operando é o `sel`, que é zero se for omitido.
+        let mut syntax = Syntax::new(&["Reg", "Reg", "Shamt"], quote! {
+            RArgs { rt: Register(v[0]), rd: Register(v[1]), rs: Register(#rs), shamt: #shamt, funct: #funct | v[2] }
+        }, quote! {
+            match a.funct & 7 {
+                0 => vec![Operand::Reg(a.rt), Operand::Reg(a.rd)],
+                sel => vec![Operand::Reg(a.rt), Operand::Reg(a.rd), Operand::Imm(sel as i64)],
+            }
+        });
+        syntax.check = Some(quote! {
+            if v[2] > 7 {
+                return Err(eyre!("the sel of {} goes from 0 to 7", name));
+            }
+        });
+        syntax.sel_optional = true;
+        syntax
+    } else if instr.cop0.unwrap_or(false) {
+        Syntax::new(&["Reg", "Reg"], quote! {
+            RArgs { rt: Register(v[0]), rd: Register(v[1]), rs: Register(#rs), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rt), Operand::Reg(a.rd)]
+        })
+    } else if instr.move_cop.unwrap_or(false) {
+        Syntax::new(&["Reg"], quote! {
+            RArgs { rd: Register(v[0]), rs: Register(#rs), rt: Register(0), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rd)]
+        })
+    } else if instr.has_args.unwrap_or(true) {
+        Syntax::new(&["Reg", "Reg", "Reg"], quote! {
+            RArgs { rd: Register(v[0]), rs: Register(v[1]), rt: Register(v[2]), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![Operand::Reg(a.rd), Operand::Reg(a.rs), Operand::Reg(a.rt)]
+        })
+    } else {
+        Syntax::new(&[], quote! {
+            RArgs { rs: Register(#rs), rt: Register(0), rd: Register(0), shamt: #shamt, funct: #funct }
+        }, quote! {
+            vec![]
+        })
+    }
+}
+
+/// A sintaxe de uma instrução do tipo I.
+fn i_syntax(instr: &IInstruction) -> Syntax {
+    let rt = instr.rt.unwrap_or(0);
+
+    if instr.load_store.unwrap_or(false) {
+        let (slot, target) = if instr.target_is_float.unwrap_or(false) {
+            ("FloatReg", quote! { Operand::FloatReg(FloatRegister::from(a.rt)) })
+        } else {
+            ("Reg", quote! { Operand::Reg(a.rt) })
+        };
+        Syntax::new(&[slot, "Mem"], quote! {
+            IArgs { rt: Register(v[0]), imm: v[1], rs: Register(v[2]) }
+        }, quote! {
+            vec![#target, Operand::Mem(sign_extend_cast(a.imm, 16) as i64, a.rs)]
+        })
+    } else if instr.half_word.unwrap_or(false) {
+        Syntax::new(&["Reg", "Imm"], quote! {
+            IArgs { rt: Register(v[0]), imm: v[1], rs: Register(0) }
+        }, quote! {
+            vec![Operand::Reg(a.rt), Operand::Imm(sign_extend_cast(a.imm, 16) as i64)]
+        })
+    } else if instr.invert.unwrap_or(false) {
+        Syntax::new(&["Reg", "Reg", "Offset"], quote! {
+            IArgs { rs: Register(v[0]), rt: Register(v[1]), imm: v[2] }
+        }, quote! {
+            vec![Operand::Reg(a.rs), Operand::Reg(a.rt), Operand::Imm(sign_extend_cast(a.imm, 16) as i64)]
+        })
+    } else if instr.two_operands.unwrap_or(false) {
+        Syntax::new(&["Reg", "Offset"], quote! {
+            IArgs { rs: Register(v[0]), rt: Register(#rt), imm: v[1] }
+        }, quote! {
+            vec![Operand::Reg(a.rs), Operand::Imm(sign_extend_cast(a.imm, 16) as i64)]
+        })
+    } else {
+        let imm = if instr.sign_ext.unwrap_or(true) {
+            quote! { sign_extend_cast(a.imm, 16) as i64 }
+        } else {
+            quote! { a.imm as i64 }
+        };
+        Syntax::new(&["Reg", "Reg", "Imm"], quote! {
+            IArgs { rt: Register(v[0]), rs: Register(v[1]), imm: v[2] }
+        }, quote! {
+            vec![Operand::Reg(a.rt), Operand::Reg(a.rs), Operand::Imm(#imm)]
+        })
+    }
+}
+
+/// A sintaxe de uma instrução do tipo FR.
+fn fr_syntax(instr: &FRInstruction) -> Syntax {
+    let funct = instr.funct;
+
+    if instr.two_operands.unwrap_or(false) {
+        match instr.first_is_float {
+            Some(true) => Syntax::new(&["Reg", "FloatReg"], quote! {
+                FRArgs { ft: FloatRegister(v[0]), fs: FloatRegister(v[1]), fd: FloatRegister(0), funct: #funct }
+            }, quote! {
+                vec![Operand::Reg(Register::from(a.ft)), Operand::FloatReg(a.fs)]
+            }),
+            Some(false) => Syntax::new(&["FloatReg", "Reg"], quote! {
+                FRArgs { ft: FloatRegister(v[0]), fs: FloatRegister(v[1]), fd: FloatRegister(0), funct: #funct }
+            }, quote! {
+                vec![Operand::FloatReg(a.ft), Operand::Reg(Register::from(a.fs))]
+            }),
+            None => Syntax::new(&["FloatReg", "FloatReg"], quote! {
+                FRArgs { fd: FloatRegister(v[0]), fs: FloatRegister(v[1]), ft: FloatRegister(0), funct: #funct }
+            }, quote! {
+                vec![Operand::FloatReg(a.fd), Operand::FloatReg(a.fs)]
+            }),
+        }
+    } else if instr.two_operands_alt.unwrap_or(false) {
+        Syntax::new(&["FloatReg", "FloatReg"], quote! {
+            FRArgs { fs: FloatRegister(v[0]), ft: FloatRegister(v[1]), fd: FloatRegister(0), funct: #funct }
+        }, quote! {
+            vec![Operand::FloatReg(a.fs), Operand::FloatReg(a.ft)]
+        })
+    } else {
+        Syntax::new(&["FloatReg", "FloatReg", "FloatReg"], quote! {
+            FRArgs { fd: FloatRegister(v[0]), fs: FloatRegister(v[1]), ft: FloatRegister(v[2]), funct: #funct }
+        }, quote! {
+            vec![Operand::FloatReg(a.fd), Operand::FloatReg(a.fs), Operand::FloatReg(a.ft)]
+        })
+    }
+}
+
+/// Gera os *match patterns* de todas as instruções de um tipo.
+fn generate_format<T>(
+    instrs: &HashMap<String, T>,
+    syntax: impl Fn(&T) -> Syntax,
+) -> Vec<Cases> {
+    instrs
+        .iter()
+        .map(|(name, instr)| {
+            let ename = name.to_uppercase().replace(".", "_");
+            generate_cases(name, &ename, syntax(instr))
+        })
+        .collect()
+}
+
+/// Gera o parser do assembly das instruções: `Instruction::syntax`, que diz
+/// os operandos de cada uma, `Instruction::from_operands`, que monta uma
+/// instrução a partir deles, e `Instruction::parse`, que lê o texto que o
+/// `Display` escreve. E também o inverso: `Instruction::mnemonic` e
+/// `Instruction::operands`, que desmontam uma instrução nos operandos que o
+/// `Display` mostra.
+pub(crate) fn generate_text(instrs: &Instructions) -> TokenStream {
+    let mut cases = Vec::new();
+    cases.extend(generate_format(&instrs.r, r_syntax));
+    cases.extend(generate_format(&instrs.i, i_syntax));
+    cases.extend(generate_format(&instrs.j, |_| {
+        Syntax::new(&["Target"], quote! { v[0] }, quote! { vec![Operand::Imm(*a as i64)] })
+    }));
+    cases.extend(generate_format(&instrs.fr, fr_syntax));
+    cases.extend(generate_format(&instrs.fi, |instr: &FIInstruction| {
+        let ft = instr.ft;
+        Syntax::new(&["Offset"], quote! {
+            FIArgs { ft: FloatRegister(#ft), imm: v[0] }
+        }, quote! {
+            vec![Operand::Imm(sign_extend_cast(a.imm, 16) as i64)]
+        })
+    }));
+    let syntax_cases = cases.iter().map(|c| &c.syntax).collect::<Vec<_>>();
+    let build_cases = cases.iter().map(|c| &c.build).collect::<Vec<_>>();
+    let mnemonic_cases = cases.iter().map(|c| &c.mnemonic).collect::<Vec<_>>();
+    let operand_cases = cases.iter().map(|c| &c.operands).collect::<Vec<_>>();
+
+    let code = quote! {
+        /// O tipo de um operando de uma instrução no assembly.
+        #[derive(Copy, Clone, Debug, PartialEq, Eq)]
+        pub enum Slot {
+            /// Um registrador.
+            Reg,
+            /// Um registrador de ponto flutuante.
+            FloatReg,
+            /// Um imediato de 16 bits, com ou sem sinal.
+            Imm,
+            /// Um deslocamento ou a posição de um campo de bits, de 0 a 31.
+            Shamt,
+            /// O tamanho de um campo de bits, de 1 a 32.
+            Size,
+            /// O offset de um branch, em instruções.
+            Offset,
+            /// O destino de um jump, em palavras.
+            Target,
+            /// Um endereço, `offset(base)`.
+            Mem,
+        }
+
+        impl Slot {
+            /// O que o operando deve ser, para as mensagens de erro.
+            fn describe(self) -> &'static str {
+                match self {
+                    Slot::Reg => "a register",
+                    Slot::FloatReg => "a floating point register",
+                    Slot::Mem => "an address",
+                    _ => "a number",
+                }
+            }
+        }
+
+        /// Um operando de uma instrução no assembly, já com seu valor.
+        #[derive(Copy, Clone)]
+        pub enum Operand {
+            Reg(Register),
+            FloatReg(FloatRegister),
+            Imm(i64),
+            Mem(i64, Register),
+        }
+
+        impl Operand {
+            /// O que o operando é, para as mensagens de erro.
+            fn describe(&self) -> &'static str {
+                match self {
+                    Operand::Reg(_) => "a register",
+                    Operand::FloatReg(_) => "a floating point register",
+                    Operand::Imm(_) => "a number",
+                    Operand::Mem(..) => "an address",
+                }
+            }
+        }
+
+        /// Confere se os operandos `ops` da instrução `name` são dos tipos
+        /// em `slots`, e retorna os valores de seus campos. Um endereço
+        /// ocupa dois: o offset e a base.
+        fn operand_values(name: &str, slots: &[Slot], ops: &[Operand]) -> Result<Vec<u32>> {
+            if ops.len() != slots.len() {
+                return Err(eyre!("{} takes {} operands, but {} were given", name, slots.len(), ops.len()));
+            }
+
+            let mut values = Vec::new();
+            for (n, (slot, op)) in slots.iter().zip(ops.iter()).enumerate() {
+                let in_range = |val: i64, range: std::ops::RangeInclusive<i64>| {
+                    if range.contains(&val) {
+                        Ok(val as u32)
+                    } else {
+                        Err(eyre!("{} is out of range for operand {} of {}", val, n + 1, name))
+                    }
+                };
+
+                match (slot, op) {
+                    (Slot::Reg, Operand::Reg(reg)) => values.push(reg.0),
+                    (Slot::FloatReg, Operand::FloatReg(reg)) => values.push(reg.0),
+                    (Slot::Imm, Operand::Imm(val)) => values.push(in_range(*val, -0x8000..=0xFFFF)? & 0xFFFF),
+                    (Slot::Shamt, Operand::Imm(val)) => values.push(in_range(*val, 0..=31)?),
+                    (Slot::Size, Operand::Imm(val)) => values.push(in_range(*val, 1..=32)?),
+                    (Slot::Offset, Operand::Imm(val)) => values.push(in_range(*val, -0x8000..=0x7FFF)? & 0xFFFF),
+                    (Slot::Target, Operand::Imm(val)) => values.push(in_range(*val, 0..=0x3FFFFFF)?),
+                    (Slot::Mem, Operand::Mem(offset, base)) => {
+                        values.push(in_range(*offset, -0x8000..=0x7FFF)? & 0xFFFF);
+                        values.push(base.0);
+                    }
+                    _ => {
+                        return Err(eyre!(
+                            "operand {} of {} must be {}, not {}",
+                            n + 1,
+                            name,
+                            slot.describe(),
+                            op.describe()
+                        ))
+                    }
+                }
+            }
+
+            Ok(values)
+        }
+
+        /// Lê um número decimal ou hexadecimal (`0x...`), talvez negativo.
+        fn parse_number(s: &str) -> Result<i64> {
+            let (negative, digits) = match s.strip_prefix('-') {
+                Some(digits) => (true, digits),
+                None => (false, s),
+            };
+            let val = match digits.strip_prefix("0x") {
+                Some(hex) => i64::from_str_radix(hex, 16),
+                None => digits.parse::<i64>(),
+            };
+            let val = val.map_err(|_| eyre!("{} is not a number", s))?;
+
+            Ok(if negative { -val } else { val })
+        }
+
+        /// Lê um operando do tipo `slot`.
+        fn parse_operand(s: &str, slot: Slot) -> Result<Operand> {
+            match slot {
+                Slot::Reg => Ok(Operand::Reg(s.parse()?)),
+                Slot::FloatReg => Ok(Operand::FloatReg(s.parse()?)),
+                Slot::Mem => {
+                    let open = s
+                        .find('(')
+                        .filter(|_| s.ends_with(')'))
+                        .ok_or_else(|| eyre!("{} is not an address", s))?;
+                    let offset = match s[..open].trim() {
+                        "" => 0,
+                        offset => parse_number(offset)?,
+                    };
+                    Ok(Operand::Mem(offset, s[open + 1..s.len() - 1].trim().parse()?))
+                }
+                _ => Ok(Operand::Imm(parse_number(s)?)),
+            }
+        }
+
+        impl Instruction {
+            /// Os tipos dos operandos da instrução `name`, na ordem em que
+            /// são escritos, ou `None` se ela não existe.
+            pub fn syntax(name: &str) -> Option<&'static [Slot]> {
+                match name {
+                    "nop" => Some(&[]),
+                    #(#syntax_cases)
+                    *
+                    _ => None,
+                }
+            }
+
+            /// Monta a instrução `name` com os operandos `ops`, que seguem
+            /// a `syntax` dela.
+            pub fn from_operands(name: &str, ops: &[Operand]) -> Result<Instruction> {
+                match name {
+                    "nop" => {
+                        operand_values(name, &[], ops)?;
+                        Ok(Instruction::NOP)
+                    }
+                    #(#build_cases)
+                    *
+                    _ => Err(eyre!("unknown instruction {}", name)),
+                }
+            }
+
+            /// O nome da instrução.
+            pub fn mnemonic(&self) -> &'static str {
+                match self {
+                    &Instruction::NOP => "nop",
+                    #(#mnemonic_cases)
+                    *
+                }
+            }
+
+            /// Os operandos da instrução, na ordem da `syntax` e com os
+            /// valores que o `Display` mostra.
+            pub fn operands(&self) -> Vec<Operand> {
+                match self {
+                    &Instruction::NOP => vec![],
+                    #(#operand_cases)
+                    *
+                }
+            }
+
+            /// Lê uma instrução escrita como o `Display` a escreve: os
+            /// registradores pelo nome, e os imediatos, offsets e destinos
+            /// com os valores dos campos. Para toda instrução `i` que sai
+            /// daqui, `Instruction::parse(&i.to_string())` lê `i` de novo.
+            pub fn parse(text: &str) -> Result<Instruction> {
+                // O `Display` mostra alguns valores também num comentário.
+                let text = text.split('#').next().unwrap_or("").trim();
+                let (name, rest) = match text.find(char::is_whitespace) {
+                    Some(i) => (&text[..i], text[i..].trim()),
+                    None => (text, ""),
+                };
+
+                let slots = Instruction::syntax(name).ok_or_else(|| eyre!("unknown instruction {}", name))?;
+                let ops = if rest.is_empty() {
+                    Vec::new()
+                } else {
+                    rest.split(',').map(str::trim).collect()
+                };
+                if ops.len() > slots.len() {
+                    return Err(eyre!("{} takes {} operands, but {} were given", name, slots.len(), ops.len()));
+                }
+
+                let ops = ops
+                    .iter()
+                    .zip(slots.iter())
+                    .map(|(s, slot)| parse_operand(s, *slot))
+                    .collect::<Result<Vec<_>>>()?;
+
+                Instruction::from_operands(name, &ops)
+            }
+        }
+    }.into();
+
+    code
+}
@@ -19,9 +19,18 @@ tests=("01.soma"
     "15.pi"
     "16.automato")
 
+# As entradas cujo indy terminou com um código diferente de 0.
+failed=()
+
 for i in ${tests[@]}; do
     echo -e "\e[31m=> Rodando $i...\e[0m"
-    target/release/indy run res/$i
+    target/release/indy run res/$i || failed+=("$i (código $?)")
     echo ""
     sleep 1
 done
+
+if [ ${#failed[@]} -ne 0 ]; then
+    echo -e "\e[31m=> Falharam:\e[0m"
+    printf '%s\n' "${failed[@]}"
+    exit 1
+fi
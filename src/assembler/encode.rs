@@ -0,0 +1,102 @@
+//! Turns a machine instruction, with its operands, into its 32-bit word.
+//!
+//! The instructions, their operands and their encoding all come from
+//! `instructions.yml`, through `Instruction::syntax` and
+//! `Instruction::from_operands`; this module only works out the values of
+//! the operands.
+
+use super::syntax::{Expr, Operand, Part};
+use super::Labels;
+use crate::emulator::{self, FloatRegister, Instruction, Register, Slot};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Returns `true` if `op` is a machine instruction.
+pub fn is_instruction(op: &str) -> bool {
+    Instruction::syntax(op).is_some()
+}
+
+/// Returns `true` if `op` is a load or store, taking an address.
+pub fn takes_address(op: &str) -> bool {
+    Instruction::syntax(op).is_some_and(|slots| slots.contains(&Slot::Mem))
+}
+
+/// The value of `expr`. The parts of a value (`%hi`, `%lo`) are 16-bit
+/// fields, read as signed like the offsets they usually are.
+fn value(expr: &Expr, labels: &Labels) -> Result<i64> {
+    let val = labels.resolve(expr)?;
+    Ok(match expr.part {
+        Part::Whole => val,
+        _ => val as u16 as i16 as i64,
+    })
+}
+
+/// The offset of a branch at `pc` to `expr`: a label, or a number of
+/// instructions.
+fn branch_offset(expr: &Expr, labels: &Labels, pc: u32) -> Result<i64> {
+    if expr.symbol.is_none() {
+        return value(expr, labels);
+    }
+
+    let target = labels.resolve(expr)?;
+    let offset = (target - (pc as i64 + 4)) >> 2;
+    if target % 4 != 0 || !(-0x8000..=0x7fff).contains(&offset) {
+        return Err(eyre!("branch target {:#010x} is out of reach", target));
+    }
+    Ok(offset)
+}
+
+/// The target field of a jump at `pc` to the address `expr`.
+fn jump_target(expr: &Expr, labels: &Labels, pc: u32) -> Result<i64> {
+    let target = labels.resolve(expr)?;
+    if !(0..=u32::MAX as i64).contains(&target)
+        || target % 4 != 0
+        || (target as u32 ^ pc.wrapping_add(4)) & 0xf000_0000 != 0
+    {
+        return Err(eyre!("jump target {:#010x} is out of reach", target));
+    }
+    Ok((target >> 2) & 0x03ff_ffff)
+}
+
+/// The value of an operand of `op` at `pc`, given what it is: branch and
+/// jump targets become the fields that reach them.
+fn resolve(
+    op: &str,
+    operand: &Operand,
+    slot: Option<Slot>,
+    labels: &Labels,
+    pc: u32,
+) -> Result<emulator::Operand> {
+    Ok(match (operand, slot) {
+        (Operand::Reg(n), _) => emulator::Operand::Reg(Register::new(*n)),
+        (Operand::FloatReg(n), _) => emulator::Operand::FloatReg(FloatRegister::new(*n)),
+        // The `pref` hint is a number.
+        (Operand::Imm(expr), Some(Slot::Reg)) if op == "pref" => match value(expr, labels)? {
+            hint @ 0..=31 => emulator::Operand::Reg(Register::new(hint as u32)),
+            hint => return Err(eyre!("{} is out of range", hint)),
+        },
+        (Operand::Imm(expr), Some(Slot::Offset)) => {
+            emulator::Operand::Imm(branch_offset(expr, labels, pc)?)
+        }
+        (Operand::Imm(expr), Some(Slot::Target)) => {
+            emulator::Operand::Imm(jump_target(expr, labels, pc)?)
+        }
+        (Operand::Imm(expr), _) => emulator::Operand::Imm(value(expr, labels)?),
+        (Operand::Mem(expr, base), _) => {
+            emulator::Operand::Mem(value(expr, labels)?, Register::new(*base))
+        }
+    })
+}
+
+/// Encodes the machine instruction `op` at `pc`.
+pub fn encode(op: &str, operands: &[Operand], labels: &Labels, pc: u32) -> Result<u32> {
+    let slots = Instruction::syntax(op).ok_or_else(|| eyre!("unknown instruction {}", op))?;
+
+    let operands = operands
+        .iter()
+        .enumerate()
+        .map(|(i, operand)| resolve(op, operand, slots.get(i).copied(), labels, pc))
+        .collect::<Result<Vec<_>>>()?;
+
+    Ok(Instruction::from_operands(op, &operands)?.encode())
+}
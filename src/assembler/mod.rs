@@ -0,0 +1,660 @@
+//! An assembler for the MIPS assembly MARS reads, so that the `.asm`
+//! programs can be run (or turned into the files MARS would dump) without
+//! MARS.
+//!
+//! It knows the machine instructions the emulator runs, the usual
+//! pseudo-instructions (`li`, `la`, `move`, `b`, `blt`, ...), `%hi` and
+//! `%lo`, and these directives:
+//!
+//! - `.text`, `.data`, `.ktext` and `.kdata`, optionally with an address;
+//! - `.word`, `.half`, `.byte`, `.float` and `.double`, aligned to their
+//!   size;
+//! - `.ascii` and `.asciiz`, whose characters up to U+00FF are stored as a
+//!   single byte, as MARS does, and the others in UTF-8;
+//! - `.space` and `.align`;
+//! - `.globl` (or `.global`), which marks the symbols exported in an ELF;
+//! - `.eqv`, which names a number;
+//! - `.set`, which is ignored.
+//!
+//! The program starts at the beginning of `.text`, like in MARS.
+
+mod encode;
+pub mod output;
+mod pseudo;
+mod syntax;
+
+use syntax::{Expr, Operand, Part};
+
+use std::collections::{HashMap, HashSet};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The segments, and where MARS puts them.
+const SEGMENTS: [(&str, u32); 4] = [
+    (".text", 0x00400000),
+    (".data", 0x10010000),
+    (".ktext", 0x80000180),
+    (".kdata", 0x90000000),
+];
+
+const TEXT: usize = 0;
+const KTEXT: usize = 2;
+
+/// A segment of the assembled program.
+#[derive(Debug)]
+pub struct Segment {
+    pub name: &'static str,
+    pub addr: u32,
+    pub bytes: Vec<u8>,
+}
+
+impl Segment {
+    /// The address right after the last byte.
+    fn end(&self) -> u32 {
+        self.addr.wrapping_add(self.bytes.len() as u32)
+    }
+
+    /// Returns `true` if the segment holds code.
+    pub fn is_code(&self) -> bool {
+        self.name.ends_with("text")
+    }
+}
+
+/// A label of the assembled program.
+#[derive(Debug)]
+pub struct Label {
+    pub name: String,
+    pub addr: u32,
+    /// The index of its segment in `Assembly::segments`.
+    pub segment: usize,
+    /// Was it exported with `.globl`?
+    pub global: bool,
+}
+
+/// An assembled program.
+#[derive(Debug)]
+pub struct Assembly {
+    /// `.text`, `.data`, `.ktext` and `.kdata`, in this order, even if
+    /// empty.
+    pub segments: Vec<Segment>,
+    /// The labels, in the order they were defined.
+    pub labels: Vec<Label>,
+    /// Where execution starts.
+    pub entry: u32,
+    /// The address of each instruction, and the line it came from.
+    pub lines: Vec<(u32, u32)>,
+}
+
+/// The values of the labels, to resolve the operands with.
+pub struct Labels {
+    values: HashMap<String, u32>,
+}
+
+impl Labels {
+    /// The value of `expr`, or the part of it it asks for.
+    pub fn resolve(&self, expr: &Expr) -> Result<i64> {
+        let base = match &expr.symbol {
+            Some(name) => *self
+                .values
+                .get(name)
+                .ok_or_else(|| eyre!("undefined label {}", name))? as i64,
+            None => 0,
+        };
+        let val = base + expr.offset;
+
+        Ok(match expr.part {
+            Part::Whole => val,
+            Part::Hi => ((val + 0x8000) >> 16) & 0xffff,
+            Part::Lo => val & 0xffff,
+            Part::Upper => (val >> 16) & 0xffff,
+        })
+    }
+}
+
+/// What is left to fill in once all labels are known.
+enum Fixup {
+    /// A machine instruction.
+    Instruction(String, Vec<Operand>),
+    /// A value of `size` bytes.
+    Data(Expr, usize),
+}
+
+/// The state of the first pass.
+struct Assembler {
+    segments: Vec<Segment>,
+    /// The segment being assembled.
+    current: usize,
+    labels: Vec<Label>,
+    defined: HashMap<String, u32>,
+    globals: HashSet<String>,
+    /// The numbers named with `.eqv`.
+    constants: HashMap<String, i64>,
+    /// The words to fill in: their segment, offset, contents and line.
+    fixups: Vec<(usize, usize, Fixup, u32)>,
+    lines: Vec<(u32, u32)>,
+}
+
+/// Assembles the program in `source`. Errors name `file` and the line.
+pub fn assemble(source: &str, file: &str) -> Result<Assembly> {
+    let mut asm = Assembler {
+        segments: SEGMENTS
+            .iter()
+            .map(|(name, addr)| Segment {
+                name,
+                addr: *addr,
+                bytes: Vec::new(),
+            })
+            .collect(),
+        current: TEXT,
+        labels: Vec::new(),
+        defined: HashMap::new(),
+        globals: HashSet::new(),
+        constants: HashMap::new(),
+        fixups: Vec::new(),
+        lines: Vec::new(),
+    };
+
+    for (n, line) in source.lines().enumerate() {
+        let n = n as u32 + 1;
+        asm.line(line, n)
+            .map_err(|e| eyre!("{}:{}: {}", file, n, e))?;
+    }
+
+    asm.finish(file)
+}
+
+impl Assembler {
+    /// The segment being assembled.
+    fn segment(&mut self) -> &mut Segment {
+        &mut self.segments[self.current]
+    }
+
+    /// Pads the current segment with zeros up to a multiple of `align`.
+    fn align(&mut self, align: u32) {
+        let segment = self.segment();
+        while !segment.end().is_multiple_of(align) {
+            segment.bytes.push(0);
+        }
+    }
+
+    /// Defines the label `name` at the current address.
+    fn define(&mut self, name: &str) -> Result<()> {
+        if self.defined.contains_key(name) || self.constants.contains_key(name) {
+            return Err(eyre!("{} is already defined", name));
+        }
+
+        let addr = self.segment().end();
+        self.defined.insert(name.to_string(), addr);
+        self.labels.push(Label {
+            name: name.to_string(),
+            addr,
+            segment: self.current,
+            global: false,
+        });
+        Ok(())
+    }
+
+    /// Replaces the names given with `.eqv` by their values.
+    fn fold(&self, expr: &mut Expr) {
+        if let Some(val) = expr.symbol.as_ref().and_then(|s| self.constants.get(s)) {
+            expr.offset += val;
+            expr.symbol = None;
+        }
+    }
+
+    /// Reads an operand.
+    fn operand(&self, s: &str) -> Result<Operand> {
+        let mut operand = syntax::operand(s)?;
+        match &mut operand {
+            Operand::Imm(expr) | Operand::Mem(expr, _) => self.fold(expr),
+            _ => {}
+        }
+        Ok(operand)
+    }
+
+    /// Reads a number that must be known right away.
+    fn constant(&self, s: &str) -> Result<i64> {
+        let mut expr = syntax::expr(s)?;
+        self.fold(&mut expr);
+        expr.constant()
+            .ok_or_else(|| eyre!("expected a number, found {}", s))
+    }
+
+    /// Assembles a line.
+    fn line(&mut self, text: &str, n: u32) -> Result<()> {
+        let line = syntax::split_line(text)?;
+        let op = line.op.as_deref().unwrap_or("");
+        let operands = &line.operands;
+
+        // The labels of a value go after the padding that aligns it.
+        match op {
+            ".half" => self.align(2),
+            ".word" | ".float" => self.align(4),
+            ".double" => self.align(8),
+            _ => {}
+        }
+
+        for label in line.labels.iter() {
+            self.define(label)?;
+        }
+
+        match op {
+            "" => {}
+            ".text" | ".data" | ".ktext" | ".kdata" => {
+                self.current = SEGMENTS.iter().position(|(name, _)| *name == op).unwrap();
+                if let Some(addr) = operands.first() {
+                    let addr = self.constant(addr)? as u32;
+                    self.set_origin(addr)?;
+                }
+            }
+            ".globl" | ".global" => {
+                self.globals.extend(operands.iter().map(|s| s.to_string()));
+            }
+            ".eqv" => {
+                // MARS writes it without the comma.
+                let joined = operands.join(",");
+                let (name, val) = joined
+                    .split_once(|c: char| c == ',' || c.is_whitespace())
+                    .ok_or_else(|| eyre!(".eqv takes a name and a value"))?;
+                let name = name.trim();
+                if !syntax::is_symbol(name) {
+                    return Err(eyre!("bad name {}", name));
+                }
+                let val = self.constant(val)?;
+                self.constants.insert(name.to_string(), val);
+            }
+            ".set" => {}
+            ".align" => {
+                let n = self.constant(one(op, operands)?)?;
+                if !(0..=16).contains(&n) {
+                    return Err(eyre!("bad alignment {}", n));
+                }
+                self.align(1 << n);
+            }
+            ".space" => {
+                let size = self.constant(one(op, operands)?)?;
+                if !(0..=0x1000_0000).contains(&size) {
+                    return Err(eyre!("bad size {}", size));
+                }
+                let segment = self.segment();
+                segment.bytes.resize(segment.bytes.len() + size as usize, 0);
+            }
+            ".word" | ".half" | ".byte" => {
+                let size = match op {
+                    ".word" => 4,
+                    ".half" => 2,
+                    _ => 1,
+                };
+                for s in operands.iter() {
+                    self.value(s, size, n)?;
+                }
+            }
+            ".ascii" | ".asciiz" => {
+                for s in operands.iter() {
+                    let mut bytes = Vec::new();
+                    for c in syntax::string(s)? {
+                        match char::from_u32(c) {
+                            Some(c) if c as u32 > 0xff => bytes.extend(c.to_string().as_bytes()),
+                            _ => bytes.push(c as u8),
+                        }
+                    }
+                    if op == ".asciiz" {
+                        bytes.push(0);
+                    }
+                    self.segment().bytes.extend(bytes);
+                }
+            }
+            ".float" | ".double" => {
+                for s in operands.iter() {
+                    let val: f64 = s
+                        .parse()
+                        .or_else(|_| self.constant(s).map(|v| v as f64))
+                        .map_err(|_| eyre!("bad number {}", s))?;
+                    let bytes = if op == ".float" {
+                        (val as f32).to_le_bytes().to_vec()
+                    } else {
+                        val.to_le_bytes().to_vec()
+                    };
+                    self.segment().bytes.extend(bytes);
+                }
+            }
+            _ if op.starts_with('.') => return Err(eyre!("unknown directive {}", op)),
+            _ => self.instruction(op, operands, n)?,
+        }
+
+        Ok(())
+    }
+
+    /// Moves the current segment to `addr`. A segment that already has
+    /// something can only move forward, and the gap is filled with zeros.
+    fn set_origin(&mut self, addr: u32) -> Result<()> {
+        let segment = self.segment();
+
+        if segment.bytes.is_empty() {
+            segment.addr = addr;
+        } else if addr >= segment.end() {
+            let gap = (addr - segment.end()) as usize;
+            segment.bytes.resize(segment.bytes.len() + gap, 0);
+        } else {
+            return Err(eyre!(
+                "{:#010x} is behind the end of {}, {:#010x}",
+                addr,
+                segment.name,
+                segment.end()
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Adds a value of `size` bytes, to be filled in later if it depends
+    /// on a label.
+    fn value(&mut self, s: &str, size: usize, n: u32) -> Result<()> {
+        let mut expr = syntax::expr(s)?;
+        self.fold(&mut expr);
+
+        let offset = self.segment().bytes.len();
+        self.segment().bytes.resize(offset + size, 0);
+        self.fixups
+            .push((self.current, offset, Fixup::Data(expr, size), n));
+        Ok(())
+    }
+
+    /// Assembles an instruction, or the instructions a pseudo-instruction
+    /// stands for.
+    fn instruction(&mut self, op: &str, operands: &[String], n: u32) -> Result<()> {
+        if self.current != TEXT && self.current != KTEXT {
+            return Err(eyre!("instructions can only go in .text or .ktext"));
+        }
+
+        let operands = operands
+            .iter()
+            .map(|s| self.operand(s))
+            .collect::<Result<Vec<_>>>()?;
+
+        self.align(4);
+        for (op, operands) in pseudo::expand(op, operands)? {
+            let offset = self.segment().bytes.len();
+            let addr = self.segment().end();
+            self.segment().bytes.extend([0; 4]);
+            self.fixups
+                .push((self.current, offset, Fixup::Instruction(op, operands), n));
+            self.lines.push((addr, n));
+        }
+
+        Ok(())
+    }
+
+    /// The second pass: fills in the instructions and values, now that all
+    /// labels are known.
+    fn finish(mut self, file: &str) -> Result<Assembly> {
+        let labels = Labels {
+            values: self.defined,
+        };
+
+        for (segment, offset, fixup, n) in self.fixups.iter() {
+            let segment = &mut self.segments[*segment];
+            let pc = segment.addr.wrapping_add(*offset as u32);
+
+            let bytes = match fixup {
+                Fixup::Instruction(op, operands) => encode::encode(op, operands, &labels, pc)
+                    .map(|word| word.to_le_bytes().to_vec()),
+                Fixup::Data(expr, size) => data(expr, *size, &labels),
+            }
+            .map_err(|e| eyre!("{}:{}: {}", file, n, e))?;
+
+            segment.bytes[*offset..*offset + bytes.len()].copy_from_slice(&bytes);
+        }
+
+        for label in self.labels.iter_mut() {
+            label.global = self.globals.contains(&label.name);
+        }
+
+        Ok(Assembly {
+            entry: self.segments[TEXT].addr,
+            segments: self.segments,
+            labels: self.labels,
+            lines: self.lines,
+        })
+    }
+}
+
+/// The only operand of the directive `op`.
+fn one<'a>(op: &str, operands: &'a [String]) -> Result<&'a str> {
+    match operands {
+        [operand] => Ok(operand),
+        _ => Err(eyre!("{} takes one operand", op)),
+    }
+}
+
+/// The `size` bytes of the value `expr`.
+fn data(expr: &Expr, size: usize, labels: &Labels) -> Result<Vec<u8>> {
+    let val = labels.resolve(expr)?;
+    let bits = 8 * size as u32;
+    if val < -(1 << (bits - 1)) || val >= 1 << bits {
+        return Err(eyre!("{} does not fit in {} bytes", val, size));
+    }
+    Ok((val as u32).to_le_bytes()[..size].to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::Instruction;
+
+    use std::path::Path;
+
+    /// Assembles `source`, as if it were the file `test.asm`.
+    fn assemble_test(source: &str) -> Result<Assembly> {
+        assemble(source, "test.asm")
+    }
+
+    /// The words of `.text` once `source` is assembled.
+    fn text(source: &str) -> Vec<u32> {
+        assemble_test(source).unwrap().segments[TEXT]
+            .bytes
+            .chunks(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect()
+    }
+
+    /// The words of the instructions `lines`, written as `Display` does.
+    fn words(lines: &[&str]) -> Vec<u32> {
+        lines
+            .iter()
+            .map(|line| Instruction::parse(line).unwrap().encode())
+            .collect()
+    }
+
+    /// The message of the error assembling `source` gives.
+    fn error(source: &str) -> String {
+        assemble_test(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn assembles_the_samples_like_mars() {
+        let res = Path::new(env!("CARGO_MANIFEST_DIR")).join("res");
+        let mut count = 0;
+
+        for entry in std::fs::read_dir(&res).unwrap() {
+            let path = entry.unwrap().path();
+            if path.extension().is_none_or(|ext| ext != "asm") {
+                continue;
+            }
+            let source = std::fs::read_to_string(&path).unwrap();
+            let asm = assemble(&source, path.to_str().unwrap()).unwrap();
+
+            // MARS dumps the whole first page of `.data`.
+            for (segment, ext) in [(TEXT, "text"), (1, "data")] {
+                let dump = std::fs::read(path.with_extension(ext)).unwrap();
+                let bytes = &asm.segments[segment].bytes;
+                assert!(bytes.len() <= dump.len(), "{:?}.{}", path, ext);
+                assert_eq!(&dump[..bytes.len()], &bytes[..], "{:?}.{}", path, ext);
+                assert!(
+                    dump[bytes.len()..].iter().all(|&b| b == 0),
+                    "{:?}.{}",
+                    path,
+                    ext
+                );
+            }
+            count += 1;
+        }
+
+        assert!(count > 10);
+    }
+
+    #[test]
+    fn expands_li() {
+        assert_eq!(text("li $t0, 5"), words(&["addiu $t0, $zero, 5"]));
+        assert_eq!(text("li $t0, -1"), words(&["addiu $t0, $zero, -1"]));
+        assert_eq!(text("li $t0, 0xffff"), words(&["ori $t0, $zero, 65535"]));
+        assert_eq!(
+            text("li $t0, 0x12345678"),
+            words(&["lui $at, 4660", "ori $t0, $at, 22136"])
+        );
+        assert!(error("li $t0, 0x100000000").contains("does not fit in 32 bits"));
+    }
+
+    #[test]
+    fn expands_la() {
+        let source = ".data\n.word 0\nx: .word 0\n.text\nla $a0, x";
+        assert_eq!(text(source), words(&["lui $at, 4097", "ori $a0, $at, 4"]));
+        assert_eq!(text("la $a0, 8($sp)"), words(&["addiu $a0, $sp, 8"]));
+    }
+
+    #[test]
+    fn expands_the_branches() {
+        // The branches go to `end`, right after them and their delay slot.
+        let branch = |line: &str| text(&format!("{}\nnop\nend:", line));
+
+        assert_eq!(branch("b end"), words(&["bgez $zero, 1", "nop"]));
+        assert_eq!(
+            branch("beqz $t0, end"),
+            words(&["beq $t0, $zero, 1", "nop"])
+        );
+        assert_eq!(
+            branch("bnez $t0, end"),
+            words(&["bne $t0, $zero, 1", "nop"])
+        );
+        assert_eq!(
+            branch("blt $t0, $t1, end"),
+            words(&["slt $at, $t0, $t1", "bne $at, $zero, 1", "nop"])
+        );
+        assert_eq!(
+            branch("bge $t0, $t1, end"),
+            words(&["slt $at, $t0, $t1", "beq $at, $zero, 1", "nop"])
+        );
+        assert_eq!(
+            branch("bgt $t0, $t1, end"),
+            words(&["slt $at, $t1, $t0", "bne $at, $zero, 1", "nop"])
+        );
+        assert_eq!(
+            branch("bleu $t0, 7, end"),
+            words(&[
+                "addiu $at, $zero, 7",
+                "sltu $at, $at, $t0",
+                "beq $at, $zero, 1",
+                "nop"
+            ])
+        );
+    }
+
+    #[test]
+    fn expands_the_other_pseudo_instructions() {
+        assert_eq!(text("move $t0, $t1"), words(&["addu $t0, $zero, $t1"]));
+        assert_eq!(text("not $t0, $t1"), words(&["nor $t0, $t1, $zero"]));
+        assert_eq!(text("neg $t0, $t1"), words(&["sub $t0, $zero, $t1"]));
+        assert_eq!(
+            text("addi $t0, $t1, 70000"),
+            words(&["lui $at, 1", "ori $at, $at, 4464", "add $t0, $t1, $at"])
+        );
+        assert_eq!(text("subu $t0, $t1, 4"), words(&["addiu $t0, $t1, -4"]));
+        assert_eq!(
+            text("rem $t0, $t1, $t2"),
+            words(&["div $t1, $t2", "mfhi $t0"])
+        );
+    }
+
+    #[test]
+    fn lays_out_the_data_directives() {
+        let source = "
+            .eqv SIZE 3
+            .data
+            a: .byte 1, -1
+            b: .word SIZE
+            c: .half 0x1234
+            d: .asciiz \"hi\"
+            .align 2
+            e: .space SIZE
+            f: .word a
+        ";
+        let asm = assemble_test(source).unwrap();
+
+        let addrs: Vec<_> = asm
+            .labels
+            .iter()
+            .map(|l| (l.name.as_str(), l.addr))
+            .collect();
+        assert_eq!(
+            addrs,
+            [
+                ("a", 0x10010000),
+                ("b", 0x10010004),
+                ("c", 0x10010008),
+                ("d", 0x1001000a),
+                ("e", 0x10010010),
+                ("f", 0x10010014),
+            ]
+        );
+        assert_eq!(
+            asm.segments[1].bytes,
+            [
+                1, 0xff, 0, 0, 3, 0, 0, 0, 0x34, 0x12, b'h', b'i', 0, 0, 0, 0, 0, 0, 0, 0, 0, 0,
+                0x01, 0x10,
+            ]
+        );
+    }
+
+    #[test]
+    fn places_the_segments() {
+        let source = "
+            .globl main
+            .text 0x00400100
+            main: nop
+            .ktext
+            handler: eret
+        ";
+        let asm = assemble_test(source).unwrap();
+
+        assert_eq!(asm.entry, 0x00400100);
+        assert_eq!(asm.segments[KTEXT].addr, 0x80000180);
+        assert_eq!(asm.segments[KTEXT].bytes.len(), 4);
+        assert!(asm.labels[0].global);
+        assert!(!asm.labels[1].global);
+        assert!(error(".data\nnop").contains("instructions can only go in .text or .ktext"));
+        assert!(error(".text\nnop\n.text 0x00400000").contains("is behind the end of .text"));
+    }
+
+    #[test]
+    fn reports_the_errors_with_the_line() {
+        assert_eq!(
+            error("nop\nfoo $t0, $t1"),
+            "test.asm:2: unknown instruction foo"
+        );
+        assert_eq!(error("j nowhere"), "test.asm:1: undefined label nowhere");
+        assert_eq!(
+            error("sll $t0, $t1, 40"),
+            "test.asm:1: 40 is out of range for operand 3 of sll"
+        );
+        assert_eq!(
+            error("beq $t0, $t1, 0x20000000"),
+            "test.asm:1: 536870912 is out of range for operand 3 of beq"
+        );
+        assert_eq!(
+            error(".data\n.byte 256"),
+            "test.asm:2: 256 does not fit in 1 bytes"
+        );
+        assert_eq!(error("a:\na:"), "test.asm:2: a is already defined");
+        assert_eq!(error(".foo"), "test.asm:1: unknown directive .foo");
+    }
+}
@@ -0,0 +1,269 @@
+//! Writes an assembled program: as the files MARS dumps, one per segment,
+//! or as an ELF executable with a symbol table.
+
+use super::{Assembly, SEGMENTS};
+use crate::emulator::cop0::physical_address;
+
+use std::fs;
+
+use goblin::elf::header::{EM_MIPS, ET_EXEC};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD};
+use goblin::elf::section_header::{
+    SHF_ALLOC, SHF_EXECINSTR, SHF_WRITE, SHN_ABS, SHT_PROGBITS, SHT_STRTAB, SHT_SYMTAB,
+};
+use goblin::elf::sym::{STB_GLOBAL, STB_LOCAL, STT_FUNC, STT_OBJECT};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// `e_flags` of an o32 program for MIPS32.
+const EF_MIPS_ARCH_32: u32 = 0x5000_0000;
+const EF_MIPS_ABI_O32: u32 = 0x0000_1000;
+
+const SIZEOF_EHDR: usize = 52;
+const SIZEOF_PHDR: usize = 32;
+const SIZEOF_SHDR: usize = 40;
+const SIZEOF_SYM: usize = 16;
+
+/// The segments go in the file at the same offset within a page as in
+/// memory.
+const PAGE: usize = 0x1000;
+
+/// Writes the program as MARS dumps them in "Binary" format:
+/// `prefix.text`, and `prefix.data`, `prefix.ktext` and `prefix.kdata` if
+/// they are not empty. The segments must be where MARS puts them.
+pub fn write_mars(asm: &Assembly, prefix: &str) -> Result<()> {
+    for (segment, (name, addr)) in asm.segments.iter().zip(SEGMENTS.iter()) {
+        if segment.bytes.is_empty() && *name != ".text" {
+            continue;
+        }
+        if segment.addr != *addr {
+            return Err(eyre!(
+                "{} is at {:#010x}, but the MARS files can only have it at {:#010x}",
+                name,
+                segment.addr,
+                addr
+            ));
+        }
+
+        let mut bytes = segment.bytes.clone();
+        bytes.resize(bytes.len().div_ceil(4) * 4, 0);
+
+        let path = format!("{}{}", prefix, name);
+        fs::write(&path, bytes).map_err(|e| eyre!("{}: {}", path, e))?;
+    }
+
+    Ok(())
+}
+
+/// Builds an ELF executable with a `PT_LOAD` segment and a section for
+/// each segment that is not empty, and the labels in `.symtab`.
+pub fn elf(asm: &Assembly) -> Vec<u8> {
+    let segments: Vec<usize> = (0..asm.segments.len())
+        .filter(|&i| !asm.segments[i].bytes.is_empty())
+        .collect();
+
+    // The section of each segment: 1, 2, ... after the null one.
+    let section_of = |segment: usize| segments.iter().position(|&s| s == segment).map(|i| i + 1);
+
+    // The contents of the segments, each at its offset in the file.
+    let mut offset = SIZEOF_EHDR + segments.len() * SIZEOF_PHDR;
+    let mut offsets = Vec::new();
+    for &i in segments.iter() {
+        let segment = &asm.segments[i];
+        let in_page = segment.addr as usize % PAGE;
+        offset = if offset % PAGE <= in_page {
+            offset - offset % PAGE + in_page
+        } else {
+            offset - offset % PAGE + PAGE + in_page
+        };
+        offsets.push(offset);
+        offset += segment.bytes.len();
+    }
+
+    // The symbols: the local ones first, as ELF wants.
+    let mut strtab = vec![0u8];
+    let mut symtab = vec![0u8; SIZEOF_SYM];
+    let mut labels: Vec<_> = asm.labels.iter().collect();
+    labels.sort_by_key(|label| label.global);
+    let first_global = 1 + labels.iter().filter(|label| !label.global).count();
+
+    for label in labels {
+        let name = strtab.len() as u32;
+        strtab.extend_from_slice(label.name.as_bytes());
+        strtab.push(0);
+
+        let bind = if label.global { STB_GLOBAL } else { STB_LOCAL };
+        let kind = if asm.segments[label.segment].is_code() {
+            STT_FUNC
+        } else {
+            STT_OBJECT
+        };
+        let shndx = section_of(label.segment).unwrap_or(SHN_ABS as usize);
+
+        push_u32(&mut symtab, name);
+        push_u32(&mut symtab, label.addr);
+        push_u32(&mut symtab, 0);
+        symtab.push(bind << 4 | kind);
+        symtab.push(0);
+        push_u16(&mut symtab, shndx as u16);
+    }
+
+    let mut shstrtab = vec![0u8];
+    let mut name = |s: &str| {
+        let at = shstrtab.len() as u32;
+        shstrtab.extend_from_slice(s.as_bytes());
+        shstrtab.push(0);
+        at
+    };
+    let names: Vec<u32> = segments
+        .iter()
+        .map(|&i| name(asm.segments[i].name))
+        .collect();
+    let (symtab_name, strtab_name, shstrtab_name) =
+        (name(".symtab"), name(".strtab"), name(".shstrtab"));
+
+    let symtab_offset = offset.div_ceil(4) * 4;
+    let strtab_offset = symtab_offset + symtab.len();
+    let shstrtab_offset = strtab_offset + strtab.len();
+    let shoff = (shstrtab_offset + shstrtab.len()).div_ceil(4) * 4;
+    let shnum = segments.len() + 4;
+
+    let mut bytes = Vec::new();
+
+    // The ELF header.
+    bytes.extend_from_slice(b"\x7fELF\x01\x01\x01");
+    bytes.resize(16, 0);
+    push_u16(&mut bytes, ET_EXEC);
+    push_u16(&mut bytes, EM_MIPS);
+    push_u32(&mut bytes, 1);
+    push_u32(&mut bytes, asm.entry);
+    push_u32(&mut bytes, SIZEOF_EHDR as u32);
+    push_u32(&mut bytes, shoff as u32);
+    push_u32(&mut bytes, EF_MIPS_ARCH_32 | EF_MIPS_ABI_O32);
+    push_u16(&mut bytes, SIZEOF_EHDR as u16);
+    push_u16(&mut bytes, SIZEOF_PHDR as u16);
+    push_u16(&mut bytes, segments.len() as u16);
+    push_u16(&mut bytes, SIZEOF_SHDR as u16);
+    push_u16(&mut bytes, shnum as u16);
+    push_u16(&mut bytes, (shnum - 1) as u16);
+
+    // The program headers.
+    for (&i, &offset) in segments.iter().zip(offsets.iter()) {
+        let segment = &asm.segments[i];
+        let flags = if segment.is_code() {
+            PF_R | PF_X
+        } else {
+            PF_R | PF_W
+        };
+        for val in [
+            PT_LOAD,
+            offset as u32,
+            segment.addr,
+            physical_address(segment.addr),
+            segment.bytes.len() as u32,
+            segment.bytes.len() as u32,
+            flags,
+            PAGE as u32,
+        ] {
+            push_u32(&mut bytes, val);
+        }
+    }
+
+    // The contents.
+    for (&i, &offset) in segments.iter().zip(offsets.iter()) {
+        bytes.resize(offset, 0);
+        bytes.extend_from_slice(&asm.segments[i].bytes);
+    }
+    bytes.resize(symtab_offset, 0);
+    bytes.extend_from_slice(&symtab);
+    bytes.extend_from_slice(&strtab);
+    bytes.extend_from_slice(&shstrtab);
+    bytes.resize(shoff, 0);
+
+    // The section headers.
+    bytes.extend_from_slice(&[0; SIZEOF_SHDR]);
+    for ((&i, &offset), &name) in segments.iter().zip(offsets.iter()).zip(names.iter()) {
+        let segment = &asm.segments[i];
+        let flags = if segment.is_code() {
+            SHF_ALLOC | SHF_EXECINSTR
+        } else {
+            SHF_ALLOC | SHF_WRITE
+        };
+        let header = [
+            name,
+            SHT_PROGBITS,
+            flags,
+            segment.addr,
+            offset as u32,
+            segment.bytes.len() as u32,
+            0,
+            0,
+            4,
+            0,
+        ];
+        push_shdr(&mut bytes, header);
+    }
+    let strtab_index = segments.len() as u32 + 2;
+    push_shdr(
+        &mut bytes,
+        [
+            symtab_name,
+            SHT_SYMTAB,
+            0,
+            0,
+            symtab_offset as u32,
+            symtab.len() as u32,
+            strtab_index,
+            first_global as u32,
+            4,
+            SIZEOF_SYM as u32,
+        ],
+    );
+    push_shdr(
+        &mut bytes,
+        [
+            strtab_name,
+            SHT_STRTAB,
+            0,
+            0,
+            strtab_offset as u32,
+            strtab.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+    );
+    push_shdr(
+        &mut bytes,
+        [
+            shstrtab_name,
+            SHT_STRTAB,
+            0,
+            0,
+            shstrtab_offset as u32,
+            shstrtab.len() as u32,
+            0,
+            0,
+            1,
+            0,
+        ],
+    );
+
+    bytes
+}
+
+fn push_u16(bytes: &mut Vec<u8>, val: u16) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn push_u32(bytes: &mut Vec<u8>, val: u32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+/// Writes a section header, given its fields in order.
+fn push_shdr(bytes: &mut Vec<u8>, fields: [u32; 10]) {
+    for val in fields {
+        push_u32(bytes, val);
+    }
+}
@@ -0,0 +1,340 @@
+//! The pseudo-instructions, and the forms of the machine instructions that
+//! need more than one instruction (like an immediate that does not fit),
+//! expanded the same way MARS does, with `$at` for the intermediate values.
+
+use super::encode::{is_instruction, takes_address};
+use super::syntax::{Expr, Operand, Part};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The assembler temporary.
+const AT: u32 = 1;
+const ZERO: u32 = 0;
+
+/// A machine instruction, with its operands.
+pub type Instr = (String, Vec<Operand>);
+
+/// The machine instructions that take an immediate in place of the last
+/// register.
+const IMMEDIATE_FORMS: &[(&str, &str)] = &[
+    ("add", "addi"),
+    ("addu", "addiu"),
+    ("and", "andi"),
+    ("or", "ori"),
+    ("xor", "xori"),
+    ("slt", "slti"),
+    ("sltu", "sltiu"),
+];
+
+/// The branches that compare two registers: the `slt` they need, whether
+/// the operands are swapped, and whether they branch if it sets `$at`.
+const COMPARISONS: &[(&str, &str, bool, bool)] = &[
+    ("blt", "slt", false, true),
+    ("bge", "slt", false, false),
+    ("bgt", "slt", true, true),
+    ("ble", "slt", true, false),
+    ("bltu", "sltu", false, true),
+    ("bgeu", "sltu", false, false),
+    ("bgtu", "sltu", true, true),
+    ("bleu", "sltu", true, false),
+];
+
+/// The aliases of the floating point loads and stores.
+const FLOAT_ALIASES: &[(&str, &str)] = &[
+    ("l.s", "lwc1"),
+    ("l.d", "ldc1"),
+    ("s.s", "swc1"),
+    ("s.d", "sdc1"),
+];
+
+fn instr(op: &str, operands: Vec<Operand>) -> Instr {
+    (op.to_string(), operands)
+}
+
+fn reg(n: u32) -> Operand {
+    Operand::Reg(n)
+}
+
+fn num(val: i64) -> Operand {
+    Operand::Imm(Expr::number(val))
+}
+
+fn fits_signed(val: i64) -> bool {
+    (-0x8000..=0x7fff).contains(&val)
+}
+
+fn fits_unsigned(val: i64) -> bool {
+    (0..=0xffff).contains(&val)
+}
+
+/// Loads the number `val` into `rt`, in as few instructions as it takes.
+fn load_constant(rt: u32, val: i64) -> Result<Vec<Instr>> {
+    if !(-0x8000_0000..=0xffff_ffff).contains(&val) {
+        return Err(eyre!("{} does not fit in 32 bits", val));
+    }
+
+    Ok(if fits_signed(val) {
+        vec![instr("addiu", vec![reg(rt), reg(ZERO), num(val)])]
+    } else if fits_unsigned(val) {
+        vec![instr("ori", vec![reg(rt), reg(ZERO), num(val)])]
+    } else {
+        let val = val as u32;
+        vec![
+            instr("lui", vec![reg(AT), num((val >> 16) as i64)]),
+            instr("ori", vec![reg(rt), reg(AT), num((val & 0xffff) as i64)]),
+        ]
+    })
+}
+
+/// Loads the value `expr` into `rt`: a number as `li` does, and an address
+/// with a `lui` and an `ori`.
+fn load_value(rt: u32, expr: &Expr) -> Result<Vec<Instr>> {
+    if expr.part != Part::Whole {
+        return Ok(vec![instr(
+            "ori",
+            vec![reg(rt), reg(ZERO), Operand::Imm(expr.clone())],
+        )]);
+    }
+
+    match expr.constant() {
+        Some(val) => load_constant(rt, val),
+        None => Ok(vec![
+            instr(
+                "lui",
+                vec![reg(AT), Operand::Imm(expr.with_part(Part::Upper))],
+            ),
+            instr(
+                "ori",
+                vec![reg(rt), reg(AT), Operand::Imm(expr.with_part(Part::Lo))],
+            ),
+        ]),
+    }
+}
+
+/// Puts `operand`, a register or a value, in a register, loading the value
+/// into `$at` first.
+fn in_register(operand: &Operand, code: &mut Vec<Instr>) -> Result<u32> {
+    match operand {
+        Operand::Reg(n) => Ok(*n),
+        Operand::Imm(expr) => {
+            code.extend(load_value(AT, expr)?);
+            Ok(AT)
+        }
+        _ => Err(eyre!("expected a register or a value")),
+    }
+}
+
+/// A load or store of `rt` at `expr`, plus `base` if given, when the
+/// offset does not fit in the instruction.
+fn far_access(op: &str, rt: Operand, expr: &Expr, base: Option<u32>) -> Vec<Instr> {
+    let mut code = vec![instr(
+        "lui",
+        vec![reg(AT), Operand::Imm(expr.with_part(Part::Hi))],
+    )];
+    if let Some(base) = base {
+        code.push(instr("addu", vec![reg(AT), reg(AT), reg(base)]));
+    }
+    code.push(instr(
+        op,
+        vec![rt, Operand::Mem(expr.with_part(Part::Lo), AT)],
+    ));
+    code
+}
+
+/// Expands the instruction `op` into machine instructions.
+pub fn expand(op: &str, operands: Vec<Operand>) -> Result<Vec<Instr>> {
+    use Operand::*;
+
+    if let Some((_, real)) = FLOAT_ALIASES.iter().find(|(alias, _)| *alias == op) {
+        return expand(real, operands);
+    }
+
+    let mut code = Vec::new();
+
+    match (op, operands.as_slice()) {
+        ("move", [Reg(rd), Reg(rs)]) => {
+            code.push(instr("addu", vec![reg(*rd), reg(ZERO), reg(*rs)]));
+        }
+        ("li", [Reg(rt), Imm(expr)]) => match expr.constant() {
+            Some(val) => code.extend(load_constant(*rt, val)?),
+            None => return Err(eyre!("li takes a number, use la for addresses")),
+        },
+        ("la", [Reg(rt), Imm(expr)]) => code.extend(load_value(*rt, expr)?),
+        ("la", [Reg(rt), Mem(expr, base)]) => match expr.constant() {
+            Some(val) if fits_signed(val) => {
+                code.push(instr("addiu", vec![reg(*rt), reg(*base), num(val)]));
+            }
+            _ => {
+                code.extend(load_value(AT, expr)?);
+                code.push(instr("addu", vec![reg(*rt), reg(AT), reg(*base)]));
+            }
+        },
+        ("not", [Reg(rd), Reg(rs)]) => {
+            code.push(instr("nor", vec![reg(*rd), reg(*rs), reg(ZERO)]));
+        }
+        ("neg", [Reg(rd), Reg(rs)]) | ("negu", [Reg(rd), Reg(rs)]) => {
+            let sub = if op == "neg" { "sub" } else { "subu" };
+            code.push(instr(sub, vec![reg(*rd), reg(ZERO), reg(*rs)]));
+        }
+        ("abs", [Reg(rd), Reg(rs)]) => {
+            code.push(instr("sra", vec![reg(AT), reg(*rs), num(31)]));
+            code.push(instr("xor", vec![reg(*rd), reg(AT), reg(*rs)]));
+            code.push(instr("subu", vec![reg(*rd), reg(*rd), reg(AT)]));
+        }
+        ("div", [Reg(rd), Reg(rs), rt])
+        | ("divu", [Reg(rd), Reg(rs), rt])
+        | ("rem", [Reg(rd), Reg(rs), rt])
+        | ("remu", [Reg(rd), Reg(rs), rt]) => {
+            let rt = in_register(rt, &mut code)?;
+            let div = if op.ends_with('u') { "divu" } else { "div" };
+            let mv = if op.starts_with("rem") {
+                "mfhi"
+            } else {
+                "mflo"
+            };
+            code.push(instr(div, vec![reg(*rs), reg(rt)]));
+            code.push(instr(mv, vec![reg(*rd)]));
+        }
+        ("b", [target @ Imm(_)]) => {
+            code.push(instr("bgez", vec![reg(ZERO), target.clone()]));
+        }
+        ("bal", [target @ Imm(_)]) => {
+            code.push(instr("bal", vec![reg(ZERO), target.clone()]));
+        }
+        ("bgezal", [Reg(rs), target @ Imm(_)]) => {
+            code.push(instr("bal", vec![reg(*rs), target.clone()]));
+        }
+        ("beqz", [Reg(rs), target @ Imm(_)]) | ("bnez", [Reg(rs), target @ Imm(_)]) => {
+            let branch = if op == "beqz" { "beq" } else { "bne" };
+            code.push(instr(branch, vec![reg(*rs), reg(ZERO), target.clone()]));
+        }
+        ("beq", [Reg(rs), value @ Imm(_), target @ Imm(_)])
+        | ("bne", [Reg(rs), value @ Imm(_), target @ Imm(_)]) => {
+            let rt = in_register(value, &mut code)?;
+            code.push(instr(op, vec![reg(*rs), reg(rt), target.clone()]));
+        }
+        ("mfc1.d", [Reg(rt), FloatReg(fs)]) | ("mtc1.d", [Reg(rt), FloatReg(fs)]) => {
+            if *rt == 31 || *fs == 31 {
+                return Err(eyre!("{} takes the first register of a pair", op));
+            }
+            let mv = &op[..4];
+            code.push(instr(mv, vec![reg(*rt), Operand::FloatReg(*fs)]));
+            code.push(instr(mv, vec![reg(rt + 1), Operand::FloatReg(fs + 1)]));
+        }
+        ("sll", [rd @ Reg(_), rt @ Reg(_), rs @ Reg(_)])
+        | ("srl", [rd @ Reg(_), rt @ Reg(_), rs @ Reg(_)])
+        | ("sra", [rd @ Reg(_), rt @ Reg(_), rs @ Reg(_)]) => {
+            code.push(instr(
+                &format!("{}v", op),
+                vec![rd.clone(), rt.clone(), rs.clone()],
+            ));
+        }
+        _ if COMPARISONS.iter().any(|(name, ..)| *name == op) => {
+            let (_, slt, swap, if_set) = COMPARISONS.iter().find(|(name, ..)| *name == op).unwrap();
+            let (rs, rt, target) = match operands.as_slice() {
+                [Reg(rs), rt, target @ Imm(_)] => (*rs, rt, target),
+                _ => {
+                    return Err(eyre!(
+                        "{} takes a register, a register or value, and a label",
+                        op
+                    ))
+                }
+            };
+            let rt = in_register(rt, &mut code)?;
+            let (a, b) = if *swap { (rt, rs) } else { (rs, rt) };
+            let branch = if *if_set { "bne" } else { "beq" };
+            code.push(instr(slt, vec![reg(AT), reg(a), reg(b)]));
+            code.push(instr(branch, vec![reg(AT), reg(ZERO), target.clone()]));
+        }
+        (_, [rd @ Reg(_), rs @ Reg(_), Imm(expr)]) if is_register_form(op) => {
+            expand_immediate(op, rd, rs, expr, &mut code)?;
+        }
+        (_, [rt, Reg(rs), Imm(expr)]) if is_immediate_form(op) => {
+            let fits = match (expr.constant(), op) {
+                (None, _) => expr.part != Part::Whole,
+                (Some(val), "andi" | "ori" | "xori") => fits_unsigned(val),
+                (Some(val), _) => fits_signed(val),
+            };
+            if fits {
+                code.push(instr(op, operands.clone()));
+            } else {
+                let (register_form, _) =
+                    IMMEDIATE_FORMS.iter().find(|(_, imm)| *imm == op).unwrap();
+                code.extend(load_value(AT, expr)?);
+                code.push(instr(register_form, vec![rt.clone(), reg(*rs), reg(AT)]));
+            }
+        }
+        (_, [rt, Imm(expr)]) if takes_address(op) => match expr.constant() {
+            Some(val) if fits_signed(val) => {
+                code.push(instr(
+                    op,
+                    vec![rt.clone(), Operand::Mem(expr.clone(), ZERO)],
+                ));
+            }
+            _ => code.extend(far_access(op, rt.clone(), expr, None)),
+        },
+        (_, [rt, Mem(expr, base)]) if takes_address(op) && expr.part == Part::Whole => {
+            match expr.constant() {
+                Some(val) if fits_signed(val) => code.push(instr(op, operands.clone())),
+                _ => code.extend(far_access(op, rt.clone(), expr, Some(*base))),
+            }
+        }
+        _ if is_instruction(op) => code.push(instr(op, operands)),
+        _ => return Err(eyre!("unknown instruction {}", op)),
+    }
+
+    Ok(code)
+}
+
+/// Returns `true` if `op` takes three registers, and so needs `$at` (or
+/// its immediate form) when given a value.
+fn is_register_form(op: &str) -> bool {
+    matches!(
+        op,
+        "add" | "addu" | "and" | "or" | "xor" | "slt" | "sltu" | "sub" | "subu" | "nor" | "mul"
+    )
+}
+
+/// Returns `true` if `op` takes a 16-bit immediate after two registers.
+fn is_immediate_form(op: &str) -> bool {
+    IMMEDIATE_FORMS.iter().any(|(_, imm)| *imm == op)
+}
+
+/// Expands `op rd, rs, expr`, where `op` takes three registers.
+fn expand_immediate(
+    op: &str,
+    rd: &Operand,
+    rs: &Operand,
+    expr: &Expr,
+    code: &mut Vec<Instr>,
+) -> Result<()> {
+    // `sub` of a number is the `addi` of its negation.
+    let (op, expr) = match (op, expr.constant()) {
+        ("sub", Some(val)) if fits_signed(-val) => ("add", Expr::number(-val)),
+        ("subu", Some(val)) if fits_signed(-val) => ("addu", Expr::number(-val)),
+        _ => (op, expr.clone()),
+    };
+
+    let immediate = IMMEDIATE_FORMS
+        .iter()
+        .find(|(name, _)| *name == op)
+        .map(|(_, imm)| *imm);
+    let fits = match (immediate, expr.constant()) {
+        (None, _) => false,
+        (Some(_), None) => expr.part != Part::Whole,
+        (Some("andi" | "ori" | "xori"), Some(val)) => fits_unsigned(val),
+        (Some(_), Some(val)) => fits_signed(val),
+    };
+
+    match immediate {
+        Some(imm) if fits => {
+            code.push(instr(imm, vec![rd.clone(), rs.clone(), Operand::Imm(expr)]));
+        }
+        _ => {
+            code.extend(load_value(AT, &expr)?);
+            code.push(instr(op, vec![rd.clone(), rs.clone(), reg(AT)]));
+        }
+    }
+
+    Ok(())
+}
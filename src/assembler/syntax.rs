@@ -0,0 +1,371 @@
+//! The syntax of a MARS source file: how a line splits into labels, a
+//! directive or instruction and its operands, and what each operand is.
+
+use crate::emulator::{FloatRegister, Register};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// A line of source, taken apart.
+#[derive(Debug, Default)]
+pub struct Line {
+    /// The labels defined at the start of the line.
+    pub labels: Vec<String>,
+    /// The directive (with its dot) or the mnemonic, in lowercase.
+    pub op: Option<String>,
+    /// The operands, as written.
+    pub operands: Vec<String>,
+}
+
+/// Splits `line` into its labels, operation and operands, dropping the
+/// comment.
+pub fn split_line(line: &str) -> Result<Line> {
+    let mut rest = strip_comment(line).trim();
+    let mut parsed = Line::default();
+
+    // Any number of `name:` can come before the operation.
+    while let Some(colon) = rest.find(':') {
+        let name = rest[..colon].trim();
+        if !is_symbol(name) {
+            break;
+        }
+        parsed.labels.push(name.to_string());
+        rest = rest[colon + 1..].trim_start();
+    }
+
+    if rest.is_empty() {
+        return Ok(parsed);
+    }
+
+    let (op, operands) = match rest.find(char::is_whitespace) {
+        Some(end) => (&rest[..end], rest[end..].trim()),
+        None => (rest, ""),
+    };
+    parsed.op = Some(op.to_lowercase());
+    parsed.operands = split_operands(operands)?;
+
+    Ok(parsed)
+}
+
+/// Removes the `#` comment at the end of `line`, if any.
+fn strip_comment(line: &str) -> &str {
+    let mut quote = None;
+    let mut escaped = false;
+
+    for (i, c) in line.char_indices() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, '#') => return &line[..i],
+            _ => {}
+        }
+    }
+
+    line
+}
+
+/// Splits the operands at the commas that are not inside quotes.
+fn split_operands(s: &str) -> Result<Vec<String>> {
+    if s.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let mut operands = Vec::new();
+    let mut current = String::new();
+    let mut quote = None;
+    let mut escaped = false;
+
+    for c in s.chars() {
+        match (quote, c) {
+            (Some(_), _) if escaped => escaped = false,
+            (Some(_), '\\') => escaped = true,
+            (Some(q), c) if c == q => quote = None,
+            (None, '"') | (None, '\'') => quote = Some(c),
+            (None, ',') => {
+                operands.push(current.trim().to_string());
+                current.clear();
+                continue;
+            }
+            _ => {}
+        }
+        current.push(c);
+    }
+
+    if quote.is_some() {
+        return Err(eyre!("unterminated quote in {:?}", s));
+    }
+    operands.push(current.trim().to_string());
+
+    if operands.iter().any(String::is_empty) {
+        return Err(eyre!("missing operand in {:?}", s));
+    }
+    Ok(operands)
+}
+
+/// Returns `true` if `s` can be the name of a label.
+pub fn is_symbol(s: &str) -> bool {
+    let mut chars = s.chars();
+    match chars.next() {
+        // GCC names its local labels `$L1`, `$L2`, ...
+        Some(c) if c.is_ascii_alphabetic() || c == '_' || c == '.' || c == '$' => {}
+        _ => return false,
+    }
+    chars.all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '$')
+}
+
+/// The part of a value an operand takes.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Part {
+    /// All of it.
+    Whole,
+    /// `%hi`: the upper 16 bits, plus one if the lower 16 are negative
+    /// once sign extended, so that adding `%lo` gives the value back.
+    Hi,
+    /// `%lo`: the lower 16 bits.
+    Lo,
+    /// The upper 16 bits as they are, for the `lui` that goes with an
+    /// `ori` (which does not sign extend), as in `la`.
+    Upper,
+}
+
+/// A value: a number, or the address of a symbol plus a number.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Expr {
+    pub symbol: Option<String>,
+    pub offset: i64,
+    pub part: Part,
+}
+
+impl Expr {
+    /// The number `val`.
+    pub fn number(val: i64) -> Expr {
+        Expr {
+            symbol: None,
+            offset: val,
+            part: Part::Whole,
+        }
+    }
+
+    /// The same value, but only the `part` of it.
+    pub fn with_part(&self, part: Part) -> Expr {
+        Expr {
+            part,
+            ..self.clone()
+        }
+    }
+
+    /// The number, if the value does not depend on a symbol.
+    pub fn constant(&self) -> Option<i64> {
+        match (&self.symbol, self.part) {
+            (None, Part::Whole) => Some(self.offset),
+            _ => None,
+        }
+    }
+}
+
+/// An operand of an instruction.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Operand {
+    /// A general purpose register.
+    Reg(u32),
+    /// A floating point register.
+    FloatReg(u32),
+    /// A number or address.
+    Imm(Expr),
+    /// A memory address, `offset(base)`.
+    Mem(Expr, u32),
+}
+
+/// Reads a register operand, `$name` or `$n`.
+fn register(s: &str) -> Option<Operand> {
+    if let Ok(reg) = s.parse::<FloatRegister>() {
+        return Some(Operand::FloatReg(reg.number()));
+    }
+    s.parse::<Register>()
+        .ok()
+        .map(|reg| Operand::Reg(reg.number()))
+}
+
+/// Reads an operand.
+pub fn operand(s: &str) -> Result<Operand> {
+    let s = s.trim();
+
+    if s.starts_with('$') {
+        if let Some(reg) = register(s) {
+            return Ok(reg);
+        }
+        if !is_symbol(s) {
+            return Err(eyre!("unknown register {}", s));
+        }
+    }
+
+    // `offset($base)`, where the offset may be missing (and may be a
+    // `%lo(...)`).
+    if let Some(inner) = s.strip_suffix(')') {
+        if let Some(open) = inner.rfind('(') {
+            if let Some(Operand::Reg(base)) = register(inner[open + 1..].trim()) {
+                let offset = s[..open].trim();
+                let offset = if offset.is_empty() {
+                    Expr::number(0)
+                } else {
+                    expr(offset)?
+                };
+                return Ok(Operand::Mem(offset, base));
+            }
+        }
+    }
+
+    expr(s).map(Operand::Imm)
+}
+
+/// Reads a value: a number, a character, a symbol, a sum of those, or
+/// `%hi`/`%lo` of one.
+pub fn expr(s: &str) -> Result<Expr> {
+    let s = s.trim();
+
+    for (prefix, part) in [("%hi(", Part::Hi), ("%lo(", Part::Lo)] {
+        if let Some(inner) = s.strip_prefix(prefix).and_then(|s| s.strip_suffix(')')) {
+            let inner = expr(inner)?;
+            if inner.part != Part::Whole {
+                return Err(eyre!("bad expression {}", s));
+            }
+            return Ok(inner.with_part(part));
+        }
+    }
+
+    let mut value = Expr::number(0);
+    let mut rest = s;
+    let mut negative = false;
+
+    loop {
+        let rest_trimmed = rest.trim_start();
+        let (term, after) = take_term(rest_trimmed).ok_or_else(|| eyre!("bad expression {}", s))?;
+
+        if let Some(n) = number(term) {
+            value.offset += if negative { -n } else { n };
+        } else if is_symbol(term) && !negative && value.symbol.is_none() {
+            value.symbol = Some(term.to_string());
+        } else {
+            return Err(eyre!("bad expression {}", s));
+        }
+
+        let after = after.trim_start();
+        match after.chars().next() {
+            None => break,
+            Some('+') => negative = false,
+            Some('-') => negative = true,
+            Some(_) => return Err(eyre!("bad expression {}", s)),
+        }
+        rest = &after[1..];
+    }
+
+    Ok(value)
+}
+
+/// Takes the first term of an expression: a character literal, or
+/// everything up to the next `+` or `-` that is not a sign.
+fn take_term(s: &str) -> Option<(&str, &str)> {
+    if let Some(inner) = s.strip_prefix('\'') {
+        let end = inner.find('\'').map(|i| i + 1)?;
+        // `'\''` has a quote inside.
+        let end = if &s[1..end] == "\\" {
+            s[end + 1..].find('\'').map(|i| i + end + 1)?
+        } else {
+            end
+        };
+        return Some((&s[..=end], &s[end + 1..]));
+    }
+
+    let start = if s.starts_with('-') || s.starts_with('+') {
+        1
+    } else {
+        0
+    };
+    let end = s[start..]
+        .find(|c| c == '+' || c == '-' || char::is_whitespace(c))
+        .map(|i| i + start)
+        .unwrap_or(s.len());
+
+    if end == 0 {
+        None
+    } else {
+        Some((&s[..end], &s[end..]))
+    }
+}
+
+/// Reads a number: decimal, hexadecimal (`0x`), binary (`0b`) or a
+/// character in single quotes.
+pub fn number(s: &str) -> Option<i64> {
+    let s = s.trim();
+
+    if let Some(inner) = s.strip_prefix('\'').and_then(|s| s.strip_suffix('\'')) {
+        let chars = unescape(inner).ok()?;
+        return match chars.as_slice() {
+            [c] => Some(*c as i64),
+            _ => None,
+        };
+    }
+
+    let (negative, digits) = match s.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, s.strip_prefix('+').unwrap_or(s)),
+    };
+
+    let val = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        i64::from_str_radix(hex, 16).ok()?
+    } else if let Some(bin) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        i64::from_str_radix(bin, 2).ok()?
+    } else if digits.starts_with(|c: char| c.is_ascii_digit()) {
+        digits.parse().ok()?
+    } else {
+        return None;
+    };
+
+    Some(if negative { -val } else { val })
+}
+
+/// Reads a string literal, in double quotes.
+pub fn string(s: &str) -> Result<Vec<u32>> {
+    let inner = s
+        .trim()
+        .strip_prefix('"')
+        .and_then(|s| s.strip_suffix('"'))
+        .ok_or_else(|| eyre!("expected a string in double quotes, found {}", s))?;
+    unescape(inner)
+}
+
+/// Replaces the escapes in `s`, returning its characters.
+fn unescape(s: &str) -> Result<Vec<u32>> {
+    let mut chars = Vec::new();
+    let mut iter = s.chars();
+
+    while let Some(c) = iter.next() {
+        if c != '\\' {
+            chars.push(c as u32);
+            continue;
+        }
+
+        let escaped = match iter.next() {
+            Some('n') => '\n',
+            Some('t') => '\t',
+            Some('r') => '\r',
+            Some('0') => '\0',
+            Some('b') => '\x08',
+            Some('f') => '\x0c',
+            Some('v') => '\x0b',
+            Some(c @ ('\\' | '"' | '\'')) => c,
+            Some(c) => return Err(eyre!("unknown escape \\{}", c)),
+            None => return Err(eyre!("unterminated escape")),
+        };
+        chars.push(escaped as u32);
+    }
+
+    Ok(chars)
+}
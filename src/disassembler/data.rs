@@ -0,0 +1,92 @@
+//! Shows the bytes of a data section as the directives that would have
+//! put them there: strings, words, runs of zeros and loose bytes.
+
+use std::collections::BTreeSet;
+use std::fmt::Write;
+
+/// The shortest string shown as `.asciiz`, without its NUL.
+const MIN_STRING: usize = 2;
+
+/// The shortest run of zeros shown as `.space`.
+const MIN_SPACE: usize = 8;
+
+/// Returns `true` if `b` can be part of a string. Latin-1 letters count,
+/// since MARS reads its sources in that encoding.
+fn is_text(b: u8) -> bool {
+    matches!(b, b' '..=b'~' | b'\n' | b'\t' | b'\r' | 0xa0..=0xff)
+}
+
+/// `bytes` as the inside of a string literal.
+fn escape(bytes: &[u8]) -> String {
+    let mut s = String::new();
+    for &b in bytes {
+        match b {
+            b'\n' => s.push_str("\\n"),
+            b'\t' => s.push_str("\\t"),
+            b'\r' => s.push_str("\\r"),
+            b'"' => s.push_str("\\\""),
+            b'\\' => s.push_str("\\\\"),
+            b => s.push(b as char),
+        }
+    }
+    s
+}
+
+/// The directives for `bytes`, which start at `addr`, each with its
+/// address. No directive goes over one of the `breaks`, where the labels
+/// are; `name` gives the name of the words that are addresses in the
+/// program.
+pub fn directives(
+    bytes: &[u8],
+    addr: u32,
+    breaks: &BTreeSet<u32>,
+    name: impl Fn(u32) -> Option<String>,
+) -> Vec<(u32, String)> {
+    let mut items = Vec::new();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let at = addr.wrapping_add(i as u32);
+        let end = breaks
+            .range(at.wrapping_add(1)..)
+            .next()
+            .map(|b| (b.wrapping_sub(addr) as usize).min(bytes.len()))
+            .unwrap_or(bytes.len());
+        let chunk = &bytes[i..end];
+
+        let text = chunk.iter().take_while(|&&b| is_text(b)).count();
+        let zeros = chunk.iter().take_while(|&&b| b == 0).count();
+
+        let (len, directive) = if text >= MIN_STRING && chunk.get(text) == Some(&0) {
+            (text + 1, format!(".asciiz \"{}\"", escape(&chunk[..text])))
+        } else if zeros >= MIN_SPACE {
+            (zeros, format!(".space {}", zeros))
+        } else if at.is_multiple_of(4) && chunk.len() >= 4 {
+            let word = u32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]);
+            match name(word) {
+                Some(name) => (4, format!(".word {}", name)),
+                None if (-0x10000..0x10000).contains(&(word as i32)) => {
+                    (4, format!(".word {}", word as i32))
+                }
+                None => (4, format!(".word {:#010x}", word)),
+            }
+        } else {
+            // Up to the next word, or the next string.
+            let mut len = ((4 - at % 4) as usize).min(chunk.len());
+            if let Some(start) = (1..len).find(|&j| is_text(chunk[j])) {
+                len = start;
+            }
+            let mut directive = String::from(".byte ");
+            for (j, b) in chunk[..len].iter().enumerate() {
+                let sep = if j == 0 { "" } else { ", " };
+                write!(directive, "{}{:#04x}", sep, b).unwrap();
+            }
+            (len, directive)
+        };
+
+        items.push((at, directive));
+        i += len;
+    }
+
+    items
+}
@@ -0,0 +1,173 @@
+//! Turns decoded instructions into what a person would have written: the
+//! operands with the meaning they have (addresses, hexadecimal masks), and
+//! the pseudo-instructions that assemblers expand, like `move`, `li`, `b`
+//! or a `la` split over a `lui` and an `ori`.
+
+use crate::emulator::instr::branch_target;
+use crate::emulator::{FloatRegister, Instruction, Operand, Register, Slot};
+
+/// An operand of a disassembled instruction.
+#[derive(Copy, Clone)]
+pub enum Arg {
+    Reg(Register),
+    FloatReg(FloatRegister),
+    /// A coprocessor 0 or hardware register, which has no name.
+    CopReg(u32),
+    /// A signed number.
+    Int(i64),
+    /// A number that is a bit pattern, like the immediate of `andi`.
+    Hex(u32),
+    /// A shift amount, or the position or size of a bit field.
+    Count(u32),
+    /// An address, `offset(base)`.
+    Mem(i64, Register),
+    /// An address in the program: where a branch or jump goes, or what a
+    /// `la` loads.
+    Addr(u32),
+}
+
+/// An instruction, or a pseudo-instruction, ready to be printed.
+#[derive(Clone)]
+pub struct Insn {
+    pub mnemonic: &'static str,
+    pub args: Vec<Arg>,
+}
+
+impl Insn {
+    fn new(mnemonic: &'static str, args: Vec<Arg>) -> Insn {
+        Insn { mnemonic, args }
+    }
+}
+
+/// Returns `true` if `arg` is `$zero`.
+fn is_zero(arg: &Arg) -> bool {
+    matches!(arg, Arg::Reg(r) if r.number() == 0)
+}
+
+/// The instruction `instr`, found at `pc`, as it is.
+pub fn plain(instr: &Instruction, pc: u32) -> Insn {
+    let mnemonic = instr.mnemonic();
+    let slots = Instruction::syntax(mnemonic).unwrap_or(&[]);
+    let operands = instr.operands();
+
+    let args = operands
+        .iter()
+        .enumerate()
+        .map(|(i, operand)| match (*operand, slots.get(i)) {
+            // The registers of the coprocessors are shown by number.
+            (Operand::Reg(r), _) if i == 1 && matches!(mnemonic, "mfc0" | "mtc0" | "rdhwr") => {
+                Arg::CopReg(r.number())
+            }
+            // And the `pref` hint is a number.
+            (Operand::Reg(r), _) if mnemonic == "pref" => Arg::Hex(r.number()),
+            (Operand::Reg(r), _) => Arg::Reg(r),
+            (Operand::FloatReg(r), _) => Arg::FloatReg(r),
+            (Operand::Mem(offset, base), _) => Arg::Mem(offset, base),
+            (Operand::Imm(_), Some(Slot::Offset | Slot::Target)) => {
+                Arg::Addr(branch_target(instr, pc).unwrap_or(0))
+            }
+            (Operand::Imm(v), Some(Slot::Shamt | Slot::Size)) => Arg::Count(v as u32),
+            (Operand::Imm(v), _) if matches!(mnemonic, "andi" | "ori" | "xori" | "lui") => {
+                Arg::Hex(v as u32 & 0xffff)
+            }
+            (Operand::Imm(v), _) => Arg::Int(v),
+        })
+        .collect();
+
+    // `bal` is `bgezal $zero`; the other registers get the real name.
+    let mnemonic = match (mnemonic, operands.first()) {
+        ("bal", Some(Operand::Reg(r))) if r.number() != 0 => "bgezal",
+        _ => mnemonic,
+    };
+
+    Insn::new(mnemonic, args)
+}
+
+/// The pseudo-instruction that `insn` is a case of, if any. With `mars`,
+/// only the forms MARS expands its pseudo-instructions to are recognised,
+/// so that the listing assembles back to the same code; otherwise, the
+/// ones binutils shows.
+pub fn alias(insn: &Insn, mars: bool) -> Option<Insn> {
+    use Arg::*;
+
+    let alias = match (insn.mnemonic, insn.args.as_slice()) {
+        ("addu", [d, zero, s]) if mars && is_zero(zero) => Insn::new("move", vec![*d, *s]),
+        ("addu" | "or", [d, s, zero]) if !mars && is_zero(zero) => Insn::new("move", vec![*d, *s]),
+        ("addiu", [t, zero, v]) if is_zero(zero) => Insn::new("li", vec![*t, *v]),
+        // MARS only uses `ori` for what `addiu` cannot load.
+        ("ori", [t, zero, v @ Hex(n)]) if is_zero(zero) && (!mars || *n > 0x7fff) => {
+            Insn::new("li", vec![*t, *v])
+        }
+        ("bgez", [zero, target]) if is_zero(zero) => Insn::new("b", vec![*target]),
+        // Before `beqz`, which would take `$zero` as the register tested.
+        ("beq", [z1, z2, target]) if is_zero(z1) && is_zero(z2) => Insn::new("b", vec![*target]),
+        ("bal", [_, target]) => Insn::new("bal", vec![*target]),
+        ("beq", [s, zero, target]) if is_zero(zero) => Insn::new("beqz", vec![*s, *target]),
+        ("bne", [s, zero, target]) if is_zero(zero) => Insn::new("bnez", vec![*s, *target]),
+        ("nor", [d, s, zero]) if is_zero(zero) => Insn::new("not", vec![*d, *s]),
+        ("subu", [d, zero, t]) if is_zero(zero) => Insn::new("negu", vec![*d, *t]),
+        ("sub", [d, zero, t]) if is_zero(zero) => Insn::new("neg", vec![*d, *t]),
+        _ => return None,
+    };
+
+    Some(alias)
+}
+
+/// The pseudo-instruction that `first` and `second`, one after the other,
+/// are the MARS expansion of, if any: a `lui` of the upper half of a value
+/// into `$at`, followed by what uses the lower half. `known` tells whether
+/// the value is an address in the program, making it a `la` rather than a
+/// `li`.
+pub fn pair(first: &Insn, second: &Insn, known: impl Fn(u32) -> bool) -> Option<Insn> {
+    use Arg::*;
+
+    let hi = match (first.mnemonic, first.args.as_slice()) {
+        ("lui", [Reg(at), Hex(hi)]) if at.number() == 1 => *hi << 16,
+        _ => return None,
+    };
+    let through_at = |base: &Register| base.number() == 1;
+
+    match (second.mnemonic, second.args.as_slice()) {
+        ("ori", [Reg(dest), Reg(base), Hex(lo)]) if through_at(base) => {
+            let value = hi | lo;
+            if known(value) {
+                Some(Insn::new("la", vec![Reg(*dest), Addr(value)]))
+            } else if value > 0xffff {
+                // Smaller values take a single instruction.
+                Some(Insn::new("li", vec![Reg(*dest), Hex(value)]))
+            } else {
+                None
+            }
+        }
+        // `lw $t0, label` and the like.
+        (op, [dest @ (Reg(_) | FloatReg(_)), Mem(offset, base)])
+            if through_at(base) && op != "pref" =>
+        {
+            Some(Insn::new(
+                op,
+                vec![*dest, Addr(hi.wrapping_add(*offset as u32))],
+            ))
+        }
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The mnemonic and operands `alias` gives for `text`, at `0x00400000`.
+    fn alias_of(text: &str, mars: bool) -> Option<(&'static str, usize)> {
+        let instr = Instruction::parse(text).unwrap();
+        alias(&plain(&instr, 0x00400000), mars).map(|insn| (insn.mnemonic, insn.args.len()))
+    }
+
+    #[test]
+    fn beq_zero_zero_is_b() {
+        for &mars in &[true, false] {
+            assert_eq!(alias_of("beq $zero, $zero, 4", mars), Some(("b", 1)));
+            assert_eq!(alias_of("beq $t0, $zero, 4", mars), Some(("beqz", 2)));
+            assert_eq!(alias_of("bgez $zero, 4", mars), Some(("b", 1)));
+        }
+    }
+}
@@ -0,0 +1,385 @@
+//! The disassembler, behind `decode` and `decodeelf`.
+//!
+//! It lists the code as a person would have written it: branches go to
+//! labels (the program's symbols, or `L_00400010`-style names made up for
+//! the targets that have none), pseudo-instructions like `move`, `li`,
+//! `la` and `beqz` are put back together, and the data sections are shown
+//! as `.asciiz`, `.word` and `.space` rather than as instructions.
+//!
+//! The other style mimics `objdump -d` from binutils, line for line, so
+//! that both outputs can be diffed.
+
+mod data;
+mod idioms;
+mod objdump;
+
+use idioms::{Arg, Insn};
+
+use crate::emulator::instr::branch_target;
+use crate::emulator::symbols::Symbols;
+use crate::emulator::Instruction;
+use crate::loader::dwarf::Sources;
+
+use std::collections::{BTreeSet, HashMap};
+use std::io::Write;
+
+use color_eyre::eyre::Result;
+
+/// A section of the program.
+pub struct Section {
+    pub name: String,
+    pub addr: u32,
+    pub bytes: Vec<u8>,
+    /// Does it hold instructions, rather than data?
+    pub code: bool,
+}
+
+/// How the listing looks.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Style {
+    /// Labels, pseudo-instructions and data, in the syntax of MARS.
+    Indy,
+    /// The output of `objdump -d`.
+    Objdump,
+}
+
+/// A program to disassemble.
+pub struct Object {
+    /// The name of the file, for the header of `objdump`.
+    pub file: String,
+    /// Its format, as binutils names it.
+    pub format: &'static str,
+    pub sections: Vec<Section>,
+    pub symbols: Symbols,
+    /// Where execution starts, to be marked.
+    pub entry: Option<u32>,
+    /// The sources of the program, to show each line next to its code.
+    pub sources: Option<Sources>,
+}
+
+impl Object {
+    /// The section that contains `addr`, if any.
+    fn section_at(&self, addr: u32) -> Option<&Section> {
+        self.sections
+            .iter()
+            .find(|s| addr.wrapping_sub(s.addr) < s.bytes.len() as u32)
+    }
+}
+
+/// A line of code: an instruction, a pseudo-instruction that takes several
+/// words, or a word that is not an instruction.
+struct Line {
+    addr: u32,
+    words: Vec<u32>,
+    insn: Option<Insn>,
+    /// Is the word what the instruction assembles to? Some words have
+    /// fields that the assembly does not show, like the code of `break`.
+    canonical: bool,
+    /// Is the next word in the delay slot of this one?
+    branch: bool,
+}
+
+/// The words of a code section. A last incomplete word is padded with
+/// zeros.
+fn words(section: &Section) -> Vec<u32> {
+    section
+        .bytes
+        .chunks(4)
+        .map(|b| {
+            let mut word = [0u8; 4];
+            word[..b.len()].copy_from_slice(b);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+/// Disassembles the code section `section`. In the `Indy` style, the pairs
+/// of instructions that make up a pseudo-instruction become one line, as
+/// long as no label falls between them.
+fn code_lines(
+    object: &Object,
+    section: &Section,
+    style: Style,
+    breaks: impl Fn(u32) -> bool,
+) -> Vec<Line> {
+    let words = words(section);
+    let addr = |i: usize| section.addr.wrapping_add(4 * i as u32);
+    let insns: Vec<Option<(Insn, bool)>> = words
+        .iter()
+        .enumerate()
+        .map(|(i, &word)| {
+            let instr = Instruction::decode(word).ok()?;
+            let branch = branch_target(&instr, addr(i)).is_some()
+                || matches!(instr, Instruction::JR(_) | Instruction::JALR(_));
+            Some((idioms::plain(&instr, addr(i)), branch))
+        })
+        .collect();
+    let canonical = |i: usize| {
+        let written = Instruction::decode(words[i])
+            .and_then(|instr| Instruction::from_operands(instr.mnemonic(), &instr.operands()));
+        written.is_ok_and(|instr| instr.encode() == words[i])
+    };
+
+    let mut lines = Vec::new();
+    let mut i = 0;
+    while i < words.len() {
+        if style == Style::Indy
+            && i + 1 < words.len()
+            && !breaks(addr(i + 1))
+            && canonical(i)
+            && canonical(i + 1)
+        {
+            if let (Some((first, _)), Some((second, branch))) = (&insns[i], &insns[i + 1]) {
+                let known = |value| object.section_at(value).is_some();
+                if let Some(insn) = idioms::pair(first, second, known) {
+                    lines.push(Line {
+                        addr: addr(i),
+                        words: words[i..i + 2].to_vec(),
+                        insn: Some(insn),
+                        canonical: true,
+                        branch: *branch,
+                    });
+                    i += 2;
+                    continue;
+                }
+            }
+        }
+
+        let (insn, branch) = match &insns[i] {
+            Some((insn, branch)) => {
+                let alias = idioms::alias(insn, style == Style::Indy);
+                (Some(alias.unwrap_or_else(|| insn.clone())), *branch)
+            }
+            None => (None, false),
+        };
+        lines.push(Line {
+            addr: addr(i),
+            words: vec![words[i]],
+            insn,
+            canonical: canonical(i),
+            branch,
+        });
+        i += 1;
+    }
+
+    lines
+}
+
+/// Disassembles `object` into `out`.
+pub fn disassemble(object: &mut Object, style: Style, out: &mut dyn Write) -> Result<()> {
+    // The targets of the branches go between the pairs of instructions
+    // too, so they are found before the pairs are.
+    let mut targets = BTreeSet::new();
+    for section in object.sections.iter().filter(|s| s.code) {
+        for (i, word) in words(section).into_iter().enumerate() {
+            let addr = section.addr.wrapping_add(4 * i as u32);
+            if let Some(target) = Instruction::decode(word)
+                .ok()
+                .and_then(|instr| branch_target(&instr, addr))
+            {
+                targets.insert(target);
+            }
+        }
+    }
+
+    let lines: Vec<Option<Vec<Line>>> = object
+        .sections
+        .iter()
+        .map(|section| {
+            let breaks = |addr| targets.contains(&addr) || object.symbols.at(addr).is_some();
+            section
+                .code
+                .then(|| code_lines(object, section, style, breaks))
+        })
+        .collect();
+
+    match style {
+        Style::Indy => {
+            let labels = labels(object, &lines);
+            let sources = object.sources.take();
+            Listing {
+                object,
+                labels,
+                sources,
+                out,
+            }
+            .write(&lines)
+        }
+        Style::Objdump => objdump::write(object, &lines, out),
+    }
+}
+
+/// Names the addresses in the program that the code refers to and that
+/// have no symbol: `L_` and the address for code, `D_` for data.
+fn labels(object: &Object, lines: &[Option<Vec<Line>>]) -> HashMap<u32, String> {
+    let mut labels = HashMap::new();
+    let args = lines
+        .iter()
+        .flatten()
+        .flatten()
+        .filter_map(|line| line.insn.as_ref())
+        .flat_map(|insn| insn.args.iter());
+
+    for arg in args {
+        let addr = match arg {
+            Arg::Addr(addr) => *addr,
+            _ => continue,
+        };
+        if object.symbols.at(addr).is_some() {
+            continue;
+        }
+        if let Some(section) = object.section_at(addr) {
+            let prefix = if section.code { "L" } else { "D" };
+            labels.insert(addr, format!("{}_{:08x}", prefix, addr));
+        }
+    }
+
+    labels
+}
+
+/// The listing in the `Indy` style.
+struct Listing<'a> {
+    object: &'a Object,
+    labels: HashMap<u32, String>,
+    sources: Option<Sources>,
+    out: &'a mut dyn Write,
+}
+
+impl Listing<'_> {
+    /// The name of `addr`: its symbol or label, or the symbol it is in.
+    fn name(&self, addr: u32) -> String {
+        if let Some(symbol) = self.object.symbols.at(addr) {
+            return symbol.name.clone();
+        }
+        if let Some(label) = self.labels.get(&addr) {
+            return label.clone();
+        }
+        match self.object.symbols.locate(addr) {
+            Some(loc) => loc.to_string(),
+            None => format!("{:#010x}", addr),
+        }
+    }
+
+    /// The exact name of `addr`, if it has one.
+    fn exact_name(&self, addr: u32) -> Option<String> {
+        self.object
+            .symbols
+            .at(addr)
+            .map(|s| s.name.clone())
+            .or_else(|| self.labels.get(&addr).cloned())
+    }
+
+    fn arg(&self, arg: &Arg) -> String {
+        match arg {
+            Arg::Reg(r) => r.to_string(),
+            Arg::FloatReg(r) => r.to_string(),
+            Arg::CopReg(n) => format!("${}", n),
+            Arg::Int(v) => v.to_string(),
+            Arg::Hex(v) => format!("{:#x}", v),
+            Arg::Count(v) => v.to_string(),
+            Arg::Mem(offset, base) => format!("{}({})", offset, base),
+            Arg::Addr(addr) => self.name(*addr),
+        }
+    }
+
+    fn insn(&self, insn: &Insn) -> String {
+        let args: Vec<String> = insn.args.iter().map(|a| self.arg(a)).collect();
+        if args.is_empty() {
+            insn.mnemonic.to_string()
+        } else {
+            format!("{} {}", insn.mnemonic, args.join(", "))
+        }
+    }
+
+    /// Writes the header of the label or symbol at `addr`, if any.
+    fn header(&mut self, addr: u32) -> Result<()> {
+        if let Some(name) = self.exact_name(addr) {
+            writeln!(self.out)?;
+            writeln!(self.out, "{:08x} <{}>:", addr, name)?;
+        }
+        Ok(())
+    }
+
+    fn write(mut self, lines: &[Option<Vec<Line>>]) -> Result<()> {
+        for (i, lines) in lines.iter().enumerate() {
+            writeln!(
+                self.out,
+                "Disassembly of section {}:",
+                self.object.sections[i].name
+            )?;
+            match lines {
+                Some(lines) => self.code(lines)?,
+                None => self.data(i)?,
+            }
+            writeln!(self.out)?;
+        }
+        Ok(())
+    }
+
+    fn code(&mut self, lines: &[Line]) -> Result<()> {
+        let object = self.object;
+        let mut last_line = None;
+
+        for line in lines {
+            self.header(line.addr)?;
+
+            // Each line of the source comes before its code.
+            let source = object.symbols.line(line.addr);
+            if let Some(source) = source.filter(|_| source != last_line) {
+                writeln!(self.out, "{}", source)?;
+                if let Some(text) = self.sources.as_mut().and_then(|s| s.text(source)) {
+                    writeln!(self.out, "{}", text)?;
+                }
+            }
+            last_line = source;
+
+            let text = match &line.insn {
+                Some(insn) if line.canonical => self.insn(insn),
+                // Assembling the instruction would lose the other fields.
+                Some(insn) => format!(".word {:#010x} # {}", line.words[0], self.insn(insn)),
+                None => format!(".word {:#010x}", line.words[0]),
+            };
+            write!(
+                self.out,
+                "{:08x}:\t{:08x}\t{}",
+                line.addr, line.words[0], text
+            )?;
+            if Some(line.addr) == self.object.entry {
+                write!(self.out, " # <- entry")?;
+            }
+            writeln!(self.out)?;
+
+            for (j, word) in line.words.iter().enumerate().skip(1) {
+                writeln!(
+                    self.out,
+                    "{:08x}:\t{:08x}",
+                    line.addr.wrapping_add(4 * j as u32),
+                    word
+                )?;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn data(&mut self, index: usize) -> Result<()> {
+        let section = &self.object.sections[index];
+        let end = section.addr.wrapping_add(section.bytes.len() as u32);
+        let breaks: BTreeSet<u32> = (section.addr..end)
+            .filter(|&a| self.exact_name(a).is_some())
+            .collect();
+
+        let name = |word| {
+            self.object.section_at(word)?;
+            self.exact_name(word)
+        };
+        let items = data::directives(&section.bytes, section.addr, &breaks, name);
+
+        for (addr, directive) in items {
+            self.header(addr)?;
+            writeln!(self.out, "{:08x}:\t{}", addr, directive)?;
+        }
+
+        Ok(())
+    }
+}
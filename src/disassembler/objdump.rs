@@ -0,0 +1,121 @@
+//! The `Objdump` style: the output of `objdump -d` from binutils, for the
+//! MIPS32 little-endian targets.
+
+use super::idioms::{Arg, Insn};
+use super::{Line, Object};
+use crate::emulator::Register;
+
+use std::io::Write;
+
+use color_eyre::eyre::Result;
+
+/// The shortest run of zero bytes that `objdump` leaves out, showing `...`
+/// instead.
+const SKIP_ZEROES: usize = 8;
+
+/// The name binutils gives to `reg`: the usual one, without the `$`.
+fn register(reg: Register) -> String {
+    match reg.number() {
+        30 => "s8".to_string(),
+        _ => reg.to_string().trim_start_matches('$').to_string(),
+    }
+}
+
+/// `insn`, as binutils shows it. Some instructions have operands that
+/// `instructions.yml` leaves out, taken from `word`.
+fn binutils(insn: &Insn, word: u32) -> Insn {
+    let mut insn = insn.clone();
+    let code = |shift: u32, bits: u32| Arg::Hex(word >> shift & ((1 << bits) - 1));
+
+    match insn.mnemonic {
+        "div" | "divu" => insn.args.insert(0, Arg::Reg(Register::new(0))),
+        "syscall" if word >> 6 & 0xfffff != 0 => insn.args = vec![code(6, 20)],
+        "break" if word >> 6 & 0x3ff != 0 => insn.args = vec![code(16, 10), code(6, 10)],
+        "break" if word >> 16 & 0x3ff != 0 => insn.args = vec![code(16, 10)],
+        "teq" if word >> 6 & 0x3ff != 0 => insn.args.push(code(6, 10)),
+        _ => {}
+    }
+
+    insn
+}
+
+fn arg(object: &Object, arg: &Arg) -> String {
+    match arg {
+        Arg::Reg(r) => register(*r),
+        Arg::FloatReg(r) => r.to_string(),
+        Arg::CopReg(n) => format!("${}", n),
+        Arg::Int(v) => v.to_string(),
+        Arg::Hex(v) | Arg::Count(v) => format!("{:#x}", v),
+        Arg::Mem(offset, base) => format!("{}({})", offset, register(*base)),
+        Arg::Addr(addr) => match object.symbols.locate(*addr) {
+            Some(loc) => format!("{:x} <{}>", addr, loc),
+            None => format!("{:x}", addr),
+        },
+    }
+}
+
+/// Writes the code sections of `object`, whose lines are `lines`.
+pub fn write(object: &Object, lines: &[Option<Vec<Line>>], out: &mut dyn Write) -> Result<()> {
+    writeln!(out)?;
+    writeln!(out, "{}:     file format {}", object.file, object.format)?;
+    writeln!(out)?;
+
+    for (section, lines) in object.sections.iter().zip(lines) {
+        let lines = match lines {
+            Some(lines) => lines,
+            None => continue,
+        };
+
+        writeln!(out)?;
+        writeln!(out, "Disassembly of section {}:", section.name)?;
+        if object.symbols.at(section.addr).is_none() {
+            writeln!(out)?;
+            writeln!(out, "{:08x} <{}>:", section.addr, section.name)?;
+        }
+
+        let mut i = 0;
+        let mut delay_slot = false;
+        while i < lines.len() {
+            let line = &lines[i];
+            if let Some(symbol) = object.symbols.at(line.addr) {
+                writeln!(out)?;
+                writeln!(out, "{:08x} <{}>:", line.addr, symbol.name)?;
+            }
+
+            // The zeros up to the next symbol, unless they fill a delay
+            // slot.
+            let zeros = lines[i..]
+                .iter()
+                .enumerate()
+                .take_while(|(j, l)| {
+                    l.words[0] == 0 && (*j == 0 || object.symbols.at(l.addr).is_none())
+                })
+                .count();
+            if !delay_slot && 4 * zeros >= SKIP_ZEROES {
+                writeln!(out, "\t...")?;
+                i += zeros;
+                continue;
+            }
+
+            let word = line.words[0];
+            write!(out, "{:8x}:\t{:08x} \t", line.addr, word)?;
+            match &line.insn {
+                Some(insn) => {
+                    let insn = binutils(insn, word);
+                    write!(out, "{}", insn.mnemonic)?;
+                    let args: Vec<String> = insn.args.iter().map(|a| arg(object, a)).collect();
+                    if !args.is_empty() {
+                        write!(out, "\t{}", args.join(","))?;
+                    }
+                }
+                None => write!(out, ".word\t{:#x}", word)?,
+            }
+            writeln!(out)?;
+
+            delay_slot = line.branch;
+            i += 1;
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,488 @@
+//! The ABI profiles: which simulator the guest program was written for.
+//!
+//! Programs written for MARS and for SPIM (or QtSpim) look the same at
+//! the instruction level, but they disagree on a few details that matter
+//! when running them:
+//!
+//! - the syscall table: SPIM only knows services 1 to 17, and its file
+//!   syscalls take the host `open(2)` flags instead of the MARS ones;
+//! - the program arguments: MARS passes them in `$a0` (`argc`) and `$a1`
+//!   (`argv`), without the program name, while SPIM starts with `$sp`
+//!   right below `0x80000000`, pointing at `argc`, `argv` and `envp`;
+//! - the startup code: SPIM does not start at the user code, but at its
+//!   `__start` routine, which calls `main` and then exits.
+//!
+//! The Linux profile is a different beast: it runs user-mode programs
+//! linked against a libc, using the o32 syscalls (see `cpu/linux.rs`).
+//! The program starts with the stack laid out by the kernel's ELF
+//! loader: `argc`, `argv`, `envp` and the auxiliary vector.
+
+use crate::emulator::memory::Memory;
+use crate::emulator::symbols::Symbols;
+
+use std::str::FromStr;
+
+use rand::Rng;
+
+use color_eyre::eyre::{eyre, Report, Result};
+
+/// Initial `$gp`, the same for all profiles, unless the program sets it.
+const GLOBAL_POINTER: u32 = 0x10008000;
+
+/// Initial `$sp` used by MARS.
+const MARS_STACK_POINTER: u32 = 0x7FFFEFFC;
+
+/// The top of the SPIM stack. The initial frame is pushed below it.
+const SPIM_STACK_TOP: u32 = 0x80000000;
+
+/// The address of the SPIM `__start` routine, right before the user text.
+const SPIM_START: u32 = 0x00400000 - 4 * 9;
+
+/// The top of the Linux stack.
+const LINUX_STACK_TOP: u32 = 0x7FFF0000;
+
+/// The most the arguments and the environment can take on the initial
+/// stack, strings and pointers together. It is the `ARG_MAX` of Linux.
+const MAX_FRAME: usize = 2 << 20;
+
+/// The page size reported to Linux programs.
+pub const PAGE_SIZE: u32 = 4096;
+
+/// The auxiliary vector entries we provide.
+const AT_NULL: u32 = 0;
+const AT_PHDR: u32 = 3;
+const AT_PHENT: u32 = 4;
+const AT_PHNUM: u32 = 5;
+const AT_PAGESZ: u32 = 6;
+const AT_ENTRY: u32 = 9;
+const AT_UID: u32 = 11;
+const AT_EUID: u32 = 12;
+const AT_GID: u32 = 13;
+const AT_EGID: u32 = 14;
+const AT_CLKTCK: u32 = 17;
+const AT_RANDOM: u32 = 25;
+const AT_EXECFN: u32 = 31;
+
+/// What the loader knows about the program about to run.
+pub struct Process {
+    /// The address of the first instruction.
+    pub entry: u32,
+    /// The program arguments, including the program name.
+    pub args: Vec<String>,
+    /// The environment, as `NAME=value` strings.
+    pub env: Vec<String>,
+    /// Where the ELF program headers were loaded, if they were.
+    pub phdr: u32,
+    /// The number of ELF program headers.
+    pub phnum: u32,
+    /// The end of the loaded image, where the Linux heap starts.
+    pub brk: Option<u32>,
+    /// The `$gp` the program was linked against (`_gp`), if known.
+    pub gp: Option<u32>,
+    /// The symbol table, empty if the program has none.
+    pub symbols: Symbols,
+}
+
+impl Process {
+    /// Describes a program named `name`, starting at `entry`.
+    pub fn new(entry: u32, name: impl Into<String>) -> Process {
+        Process {
+            entry,
+            args: vec![name.into()],
+            env: Vec::new(),
+            phdr: 0,
+            phnum: 0,
+            brk: None,
+            gp: None,
+            symbols: Symbols::default(),
+        }
+    }
+}
+
+/// The processor state a program starts with.
+pub struct InitialState {
+    pub pc: u32,
+    pub sp: u32,
+    pub gp: u32,
+    /// `argc` and `argv`, if they must be passed in `$a0` and `$a1`.
+    pub main_args: Option<(u32, u32)>,
+    /// The initial program break, if the profile has one.
+    pub brk: Option<u32>,
+}
+
+/// The simulator whose conventions the guest program expects.
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Abi {
+    #[default]
+    Mars,
+    Spim,
+    Linux,
+}
+
+impl FromStr for Abi {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "mars" => Ok(Abi::Mars),
+            "spim" => Ok(Abi::Spim),
+            "linux" => Ok(Abi::Linux),
+            s => Err(eyre!(
+                "abi: unknown profile {}, expected mars, spim or linux",
+                s
+            )),
+        }
+    }
+}
+
+impl Abi {
+    /// Prepares the memory before running `process`. Returns the state
+    /// the processor must start with. Random values given to the program,
+    /// like `AT_RANDOM`, are drawn from `rng`.
+    pub fn setup<M: Memory>(
+        &self,
+        mem: &mut M,
+        process: &Process,
+        rng: &mut impl Rng,
+    ) -> Result<InitialState> {
+        match self {
+            Abi::Mars => {
+                // Like MARS, the arguments are only set up if there are
+                // any, and the program name is not one of them. They live
+                // at the top of the stack, above the initial `$sp`.
+                let args = &process.args[1.min(process.args.len())..];
+                let (sp, main_args) = if args.is_empty() {
+                    (MARS_STACK_POINTER, None)
+                } else {
+                    let frame = push_frame(mem, SPIM_STACK_TOP, args, &[], None, rng)?;
+                    let sp = MARS_STACK_POINTER.min(frame - 4);
+                    (sp, Some((args.len() as u32, frame + 4)))
+                };
+
+                Ok(InitialState {
+                    pc: process.entry,
+                    sp,
+                    gp: process.gp.unwrap_or(GLOBAL_POINTER),
+                    main_args,
+                    brk: None,
+                })
+            }
+            Abi::Spim => {
+                let sp = push_frame(
+                    mem,
+                    SPIM_STACK_TOP,
+                    &process.args,
+                    &process.env,
+                    None,
+                    rng,
+                )?;
+
+                mem.poke_from_slice(SPIM_START, &spim_start(process.entry))?;
+
+                Ok(InitialState {
+                    pc: SPIM_START,
+                    sp,
+                    gp: process.gp.unwrap_or(GLOBAL_POINTER),
+                    main_args: None,
+                    brk: None,
+                })
+            }
+            Abi::Linux => {
+                let sp = push_frame(
+                    mem,
+                    LINUX_STACK_TOP,
+                    &process.args,
+                    &process.env,
+                    Some(process),
+                    rng,
+                )?;
+
+                Ok(InitialState {
+                    pc: process.entry,
+                    sp,
+                    gp: process.gp.unwrap_or(GLOBAL_POINTER),
+                    main_args: None,
+                    brk: process.brk,
+                })
+            }
+        }
+    }
+}
+
+/// Pushes `argc`, `argv` and `envp` below `top`, as the Linux kernel
+/// would. If `auxv` is given, the auxiliary vector describing that
+/// process follows the environment. Returns the address of `argc`.
+///
+/// ```text
+/// sp -> argc
+///       argv[0] ... argv[argc - 1], NULL
+///       envp[0] ... envp[n - 1], NULL
+///       auxv pairs, AT_NULL
+///       ...
+///       the strings and the AT_RANDOM bytes
+/// top
+/// ```
+fn push_frame<M: Memory>(
+    mem: &mut M,
+    top: u32,
+    args: &[String],
+    env: &[String],
+    auxv: Option<&Process>,
+    rng: &mut impl Rng,
+) -> Result<u32> {
+    let strings_len: usize = args.iter().chain(env.iter()).map(|s| s.len() + 1).sum();
+    let frame_len = strings_len + 4 * (args.len() + env.len());
+    if frame_len > MAX_FRAME {
+        return Err(eyre!(
+            "the arguments and the environment take {} bytes, more than the {} allowed",
+            frame_len,
+            MAX_FRAME
+        ));
+    }
+    let too_big = || eyre!("the initial stack does not fit below {:#010x}", top);
+    let strings_base = top
+        .checked_sub(16 + strings_len as u32)
+        .ok_or_else(too_big)?
+        & !15;
+
+    // The strings, followed by the 16 random bytes.
+    let mut strings = Vec::with_capacity(strings_len + 16);
+    let pointers = |list: &[String], strings: &mut Vec<u8>| -> Vec<u32> {
+        list.iter()
+            .map(|s| {
+                let addr = strings_base + strings.len() as u32;
+                strings.extend_from_slice(s.as_bytes());
+                strings.push(0);
+                addr
+            })
+            .collect()
+    };
+    let argv = pointers(args, &mut strings);
+    let envp = pointers(env, &mut strings);
+
+    let mut table = vec![argv.len() as u32];
+    table.extend_from_slice(&argv);
+    table.push(0);
+    table.extend_from_slice(&envp);
+    table.push(0);
+
+    if let Some(process) = auxv {
+        let random = strings_base + strings.len() as u32;
+        strings.extend_from_slice(&rng.gen::<[u8; 16]>());
+
+        let auxv = [
+            (AT_PHDR, process.phdr),
+            (AT_PHENT, 32),
+            (AT_PHNUM, process.phnum),
+            (AT_PAGESZ, PAGE_SIZE),
+            (AT_ENTRY, process.entry),
+            (AT_UID, 0),
+            (AT_EUID, 0),
+            (AT_GID, 0),
+            (AT_EGID, 0),
+            (AT_CLKTCK, 100),
+            (AT_RANDOM, random),
+            (AT_EXECFN, argv.first().copied().unwrap_or(0)),
+            (AT_NULL, 0),
+        ];
+        for (key, val) in auxv.iter() {
+            table.push(*key);
+            table.push(*val);
+        }
+    }
+
+    let sp = strings_base
+        .checked_sub(4 * table.len() as u32)
+        .ok_or_else(too_big)?
+        & !15;
+
+    mem.poke_from_slice(sp, &table)?;
+    mem.poke_from_slice(strings_base, &bytes_to_words(&strings))?;
+
+    Ok(sp)
+}
+
+/// Packs `bytes` into little-endian words, padding the last one with zeros.
+fn bytes_to_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|b| {
+            let mut word = [0u8; 4];
+            word[..b.len()].copy_from_slice(b);
+            u32::from_le_bytes(word)
+        })
+        .collect()
+}
+
+/// The SPIM `__start` routine, calling `main` at `entry`:
+///
+/// ```asm
+/// __start:
+///     lw    $a0, 0($sp)       # argc
+///     addiu $a1, $sp, 4       # argv
+///     addiu $a2, $a1, 4
+///     sll   $v0, $a0, 2
+///     addu  $a2, $a2, $v0     # envp
+///     jal   main
+///     nop
+///     li    $v0, 10
+///     syscall                 # exit
+/// ```
+fn spim_start(entry: u32) -> [u32; 9] {
+    [
+        0x8FA40000,
+        0x27A50004,
+        0x24A60004,
+        0x00041080,
+        0x00C23021,
+        0x0C000000 | ((entry >> 2) & 0x03FFFFFF),
+        0x00000000,
+        0x2402000A,
+        0x0000000C,
+    ]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::Ram;
+
+    use rand::{rngs::StdRng, SeedableRng};
+
+    /// A process named `prog`, with the arguments `a` and `bc` and a
+    /// variable in the environment.
+    fn process() -> Process {
+        let mut process = Process::new(0x00400100, "prog");
+        process.args.extend(["a".to_string(), "bc".to_string()]);
+        process.env.push("HOME=/".to_string());
+        process
+    }
+
+    /// Sets up `process` following `abi`, returning the memory too.
+    fn setup(abi: Abi, process: &Process) -> (Ram, InitialState) {
+        let mut mem = Ram::new(0);
+        let mut rng = StdRng::seed_from_u64(0);
+        let state = abi.setup(&mut mem, process, &mut rng).unwrap();
+        (mem, state)
+    }
+
+    fn word(mem: &Ram, addr: u32) -> u32 {
+        mem.inspect(addr).unwrap()
+    }
+
+    /// The string at `addr`.
+    fn string(mem: &Ram, addr: u32) -> String {
+        let mut bytes = Vec::new();
+        for addr in addr.. {
+            match word(mem, addr & !3).to_le_bytes()[(addr & 3) as usize] {
+                0 => break,
+                byte => bytes.push(byte),
+            }
+        }
+        String::from_utf8(bytes).unwrap()
+    }
+
+    /// The strings of the null-terminated list at `addr`.
+    fn strings(mem: &Ram, addr: u32) -> Vec<String> {
+        (addr..)
+            .step_by(4)
+            .map(|addr| word(mem, addr))
+            .take_while(|&ptr| ptr != 0)
+            .map(|ptr| string(mem, ptr))
+            .collect()
+    }
+
+    #[test]
+    fn mars_passes_the_arguments_in_registers() {
+        let (mem, state) = setup(Abi::Mars, &process());
+        assert_eq!(state.pc, 0x00400100);
+        assert_eq!(state.gp, GLOBAL_POINTER);
+        assert!(state.brk.is_none());
+
+        // Without the program name, and above the stack.
+        let (argc, argv) = state.main_args.unwrap();
+        assert_eq!(argc, 2);
+        assert_eq!(strings(&mem, argv), ["a", "bc"]);
+        assert!(state.sp < argv - 4 && state.sp <= MARS_STACK_POINTER);
+
+        // With no arguments, nothing changes.
+        let (_, state) = setup(Abi::Mars, &Process::new(0x00400000, "prog"));
+        assert_eq!(state.sp, MARS_STACK_POINTER);
+        assert!(state.main_args.is_none());
+    }
+
+    #[test]
+    fn spim_starts_at_its_start_routine() {
+        let (mem, state) = setup(Abi::Spim, &process());
+        assert_eq!(state.pc, SPIM_START);
+        assert_eq!(state.gp, GLOBAL_POINTER);
+        assert!(state.main_args.is_none());
+
+        // `__start` calls `main`.
+        let start: Vec<u32> = (0..9).map(|i| word(&mem, SPIM_START + 4 * i)).collect();
+        assert_eq!(start, spim_start(0x00400100));
+        assert_eq!(start[5], 0x0C000000 | 0x00400100 >> 2);
+
+        assert!(state.sp < SPIM_STACK_TOP);
+        assert_eq!(word(&mem, state.sp), 3);
+        assert_eq!(strings(&mem, state.sp + 4), ["prog", "a", "bc"]);
+        assert_eq!(strings(&mem, state.sp + 20), ["HOME=/"]);
+    }
+
+    #[test]
+    fn linux_lays_out_the_stack_like_the_kernel() {
+        let mut process = process();
+        process.brk = Some(0x10040000);
+        process.gp = Some(0x10018010);
+        process.phdr = 0x00400034;
+        process.phnum = 7;
+        let (mem, state) = setup(Abi::Linux, &process);
+
+        assert_eq!(state.pc, 0x00400100);
+        assert_eq!(state.gp, 0x10018010);
+        assert_eq!(state.brk, Some(0x10040000));
+        assert!(state.main_args.is_none());
+
+        let sp = state.sp;
+        assert_eq!(sp % 16, 0);
+        assert!(sp < LINUX_STACK_TOP);
+        assert_eq!(word(&mem, sp), 3);
+        assert_eq!(strings(&mem, sp + 4), ["prog", "a", "bc"]);
+        assert_eq!(strings(&mem, sp + 20), ["HOME=/"]);
+
+        // The auxiliary vector follows the environment.
+        let mut auxv = Vec::new();
+        for addr in (sp + 28..).step_by(8) {
+            let (key, val) = (word(&mem, addr), word(&mem, addr + 4));
+            if key == AT_NULL {
+                break;
+            }
+            auxv.push((key, val));
+        }
+        let aux = |key| auxv.iter().find(|(k, _)| *k == key).unwrap().1;
+        assert_eq!(aux(AT_PHDR), 0x00400034);
+        assert_eq!(aux(AT_PHNUM), 7);
+        assert_eq!(aux(AT_ENTRY), 0x00400100);
+        assert_eq!(aux(AT_PAGESZ), PAGE_SIZE);
+        assert_eq!(string(&mem, aux(AT_EXECFN)), "prog");
+        assert!(aux(AT_RANDOM) > sp && aux(AT_RANDOM) + 16 <= LINUX_STACK_TOP);
+    }
+
+    #[test]
+    fn push_frame_checks_the_size() {
+        let mut mem = Ram::new(0);
+        let mut rng = StdRng::seed_from_u64(0);
+
+        let huge = vec!["x".repeat(MAX_FRAME)];
+        let err = push_frame(&mut mem, LINUX_STACK_TOP, &huge, &[], None, &mut rng).unwrap_err();
+        assert!(err.to_string().contains("more than the"));
+
+        // Below a low top, the frame would wrap around.
+        let args = vec!["program".to_string(); 4];
+        let err = push_frame(&mut mem, 0x20, &args, &[], None, &mut rng).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "the initial stack does not fit below 0x00000020"
+        );
+    }
+}
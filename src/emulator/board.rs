@@ -0,0 +1,87 @@
+//! Development boards to boot bare-metal programs on.
+//!
+//! A board is a fixed physical memory map: some RAM, a boot ROM at the
+//! reset vector, and the devices firmware expects to find on real
+//! hardware. Accesses outside of the RAM, the ROM and the devices are
+//! bus errors, and so are writes to the ROM.
+//!
+//! The devices interrupt through an interrupt controller, which drives
+//! a single Cop0 interrupt line.
+
+use super::cpu::Cpu;
+use super::devices::{InterruptController, Timer, Uart};
+use super::memory::Memory;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// A range of the physical address space backed by memory.
+#[derive(Copy, Clone, Debug)]
+pub struct Region {
+    pub base: u32,
+    pub size: u32,
+    pub writable: bool,
+}
+
+impl Region {
+    /// Returns `true` if `addr` is inside the region.
+    pub fn contains(&self, addr: u32) -> bool {
+        addr >= self.base && addr - self.base < self.size
+    }
+}
+
+/// A board definition. All addresses are physical.
+#[derive(Debug)]
+pub struct Board {
+    pub name: &'static str,
+    pub ram: Region,
+    pub rom: Region,
+    /// The 16550 UART, on line 0 of the interrupt controller.
+    pub uart: u32,
+    /// The timer, on line 1 of the interrupt controller.
+    pub timer: u32,
+    /// The interrupt controller.
+    pub intc: u32,
+    /// The Cause.IP bit driven by the interrupt controller.
+    pub intc_ip: u32,
+}
+
+/// The boards we know about.
+pub const BOARDS: &[Board] = &[Board {
+    name: "indy-dev",
+    ram: Region {
+        base: 0x0000_0000,
+        size: 64 << 20,
+        writable: true,
+    },
+    rom: Region {
+        base: 0x1FC0_0000,
+        size: 4 << 20,
+        writable: false,
+    },
+    uart: 0x1F00_0000,
+    timer: 0x1F00_0100,
+    intc: 0x1F00_0200,
+    intc_ip: 2,
+}];
+
+impl Board {
+    /// Finds a board by its name.
+    pub fn by_name(name: &str) -> Result<&'static Board> {
+        BOARDS
+            .iter()
+            .find(|b| b.name == name)
+            .ok_or_else(|| eyre!("Unknown board {}", name))
+    }
+
+    /// Attaches the board devices to the CPU and restricts its memory to
+    /// the board memory map.
+    pub fn attach<TD: Memory, TI: Memory>(&self, cpu: &mut Cpu<TD, TI>) {
+        let intc = InterruptController::new(self.intc, self.intc_ip);
+
+        cpu.attach_device(Box::new(Uart::new(self.uart, intc.line(0))));
+        cpu.attach_device(Box::new(Timer::new(self.timer, intc.line(1))));
+        cpu.attach_device(Box::new(intc));
+
+        cpu.set_memory_map(vec![self.ram, self.rom]);
+    }
+}
@@ -0,0 +1,370 @@
+//! The console the guest program talks to through its syscalls and its
+//! terminal devices.
+//!
+//! The syscalls and the devices never touch the host stdin and stdout
+//! directly: they go through a `Console`, which can be the host terminal,
+//! but also a scripted input and a buffer capturing the output, for
+//! tests. The input read by the guest can be recorded to a file, and the
+//! file fed back as the scripted input of a later run to replay the
+//! session.
+
+#[cfg(test)]
+use std::cell::RefCell;
+use std::io::{self, BufRead, Read, Write};
+#[cfg(test)]
+use std::rc::Rc;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, TryRecvError};
+use std::thread;
+use std::time::Instant;
+
+/// Where the guest output goes and where its input comes from.
+pub trait Console {
+    /// Writes to the guest standard output.
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()>;
+
+    /// Writes to the guest standard error. Goes to the output by default.
+    fn write_err(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.write(bytes)
+    }
+
+    /// Reads up to `buf.len()` bytes of input. Returns 0 at the end of it.
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    /// Reads a line of input, including the line terminator. Returns an
+    /// empty string at the end of it.
+    fn read_line(&mut self) -> io::Result<String>;
+
+    /// Reads a byte of input, but only if it is there already: the
+    /// devices poll the keyboard with this, and must not block. Returns
+    /// `None` if there is no input yet, or at the end of it.
+    fn try_read(&mut self) -> io::Result<Option<u8>>;
+
+    /// Makes sure everything written so far reached its destination.
+    fn flush(&mut self) -> io::Result<()>;
+
+    /// Gives up on reads still waiting for input at `deadline`, failing
+    /// them with `TimedOut`.
+    fn set_deadline(&mut self, deadline: Option<Instant>);
+}
+
+/// A console over a pair of streams.
+///
+/// The output is only flushed when input is about to be read, so
+/// prompts show up, and when the program ends.
+pub struct StreamConsole {
+    input: Box<dyn Input>,
+    output: Box<dyn Write>,
+    error: Option<Box<dyn Write>>,
+}
+
+impl Default for StreamConsole {
+    /// The host terminal.
+    fn default() -> Self {
+        StreamConsole::host()
+    }
+}
+
+impl StreamConsole {
+    /// A console reading from `input` and writing both the output and
+    /// the errors to `output`.
+    #[cfg(test)]
+    pub fn new(input: impl BufRead + 'static, output: impl Write + 'static) -> StreamConsole {
+        StreamConsole {
+            input: Box::new(Ready(input)),
+            output: Box::new(output),
+            error: None,
+        }
+    }
+
+    /// The host stdin, stdout and stderr.
+    pub fn host() -> StreamConsole {
+        let mut console = StreamConsole {
+            input: Box::new(HostStdin::default()),
+            output: Box::new(io::stdout()),
+            error: None,
+        };
+        console.set_error(io::stderr());
+        console
+    }
+
+    /// Sends the errors to `error` instead of the output.
+    pub fn set_error(&mut self, error: impl Write + 'static) {
+        self.error = Some(Box::new(error));
+    }
+
+    /// Replaces the input.
+    pub fn set_input(&mut self, input: impl BufRead + 'static) {
+        self.input = Box::new(Ready(input));
+    }
+
+    /// Replaces the output.
+    pub fn set_output(&mut self, output: impl Write + 'static) {
+        self.output = Box::new(output);
+    }
+
+    /// Copies every byte of input consumed by the guest to `log`.
+    pub fn record(&mut self, log: impl Write + 'static) {
+        let input = std::mem::replace(&mut self.input, Box::new(Ready(io::empty())));
+        self.input = Box::new(Recorder {
+            input,
+            log: Box::new(log),
+        });
+    }
+}
+
+impl Console for StreamConsole {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.output.write_all(bytes)
+    }
+
+    fn write_err(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self.error.as_mut() {
+            Some(error) => {
+                // Keeps the output and the errors in order.
+                self.output.flush()?;
+                error.write_all(bytes)
+            }
+            None => self.output.write_all(bytes),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.output.flush()?;
+        self.input.read(buf)
+    }
+
+    fn read_line(&mut self) -> io::Result<String> {
+        self.output.flush()?;
+
+        let mut line = String::new();
+        self.input.read_line(&mut line)?;
+
+        Ok(line)
+    }
+
+    fn try_read(&mut self) -> io::Result<Option<u8>> {
+        if !self.input.ready() {
+            return Ok(None);
+        }
+
+        let byte = self.input.fill_buf()?.first().copied();
+        if byte.is_some() {
+            self.input.consume(1);
+        }
+
+        Ok(byte)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.output.flush()?;
+        if let Some(error) = self.error.as_mut() {
+            error.flush()?;
+        }
+
+        Ok(())
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.input.set_deadline(deadline);
+    }
+}
+
+/// The input of a console.
+trait Input: BufRead {
+    /// Returns `true` if reading would not block.
+    fn ready(&mut self) -> bool;
+
+    /// Sets when to stop waiting for input. Only input that can block
+    /// cares.
+    fn set_deadline(&mut self, _deadline: Option<Instant>) {}
+}
+
+/// Input that can always be read without blocking, like a file.
+struct Ready<R>(R);
+
+impl<R: BufRead> Read for Ready<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.0.read(buf)
+    }
+}
+
+impl<R: BufRead> BufRead for Ready<R> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.0.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.0.consume(amt)
+    }
+}
+
+impl<R: BufRead> Input for Ready<R> {
+    fn ready(&mut self) -> bool {
+        true
+    }
+}
+
+/// The host stdin. It is read on a separate thread, started the first
+/// time there is something to read, so that polling it does not block
+/// the emulator. Note that the terminal stays in cooked mode, so
+/// characters only arrive after the user hits enter.
+#[derive(Default)]
+struct HostStdin {
+    /// What the thread read, in the chunks it read it.
+    chunks: Option<Receiver<Vec<u8>>>,
+    /// The chunk being consumed.
+    buf: Vec<u8>,
+    pos: usize,
+    /// When to stop waiting for the next chunk.
+    deadline: Option<Instant>,
+}
+
+impl HostStdin {
+    fn chunks(&mut self) -> &Receiver<Vec<u8>> {
+        self.chunks.get_or_insert_with(|| {
+            let (tx, rx) = mpsc::channel();
+
+            thread::spawn(move || {
+                let mut buf = [0; 4096];
+                loop {
+                    match io::stdin().read(&mut buf) {
+                        Ok(n) if n > 0 && tx.send(buf[..n].to_vec()).is_ok() => continue,
+                        Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+                        _ => break,
+                    }
+                }
+            });
+
+            rx
+        })
+    }
+}
+
+impl Read for HostStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+
+        Ok(n)
+    }
+}
+
+impl BufRead for HostStdin {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.pos == self.buf.len() {
+            let deadline = self.deadline;
+            let chunk = match deadline {
+                Some(deadline) => {
+                    let timeout = deadline.saturating_duration_since(Instant::now());
+                    self.chunks().recv_timeout(timeout)
+                }
+                None => self.chunks().recv().map_err(RecvTimeoutError::from),
+            };
+
+            // Once the thread is gone, this is the end of the input.
+            self.buf = match chunk {
+                Ok(chunk) => chunk,
+                Err(RecvTimeoutError::Timeout) => return Err(io::ErrorKind::TimedOut.into()),
+                Err(RecvTimeoutError::Disconnected) => Vec::new(),
+            };
+            self.pos = 0;
+        }
+
+        Ok(&self.buf[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos += amt;
+    }
+}
+
+impl Input for HostStdin {
+    fn ready(&mut self) -> bool {
+        if self.pos < self.buf.len() {
+            return true;
+        }
+
+        match self.chunks().try_recv() {
+            Ok(chunk) => {
+                self.buf = chunk;
+                self.pos = 0;
+                true
+            }
+            Err(TryRecvError::Empty) => false,
+            Err(TryRecvError::Disconnected) => true,
+        }
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.deadline = deadline;
+    }
+}
+
+/// Input that copies what is consumed from it to a log.
+struct Recorder {
+    input: Box<dyn Input>,
+    log: Box<dyn Write>,
+}
+
+impl Input for Recorder {
+    fn ready(&mut self) -> bool {
+        self.input.ready()
+    }
+
+    fn set_deadline(&mut self, deadline: Option<Instant>) {
+        self.input.set_deadline(deadline);
+    }
+}
+
+impl io::Read for Recorder {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.input.read(buf)?;
+        self.log.write_all(&buf[..n])?;
+        self.log.flush()?;
+
+        Ok(n)
+    }
+}
+
+impl BufRead for Recorder {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.input.fill_buf()
+    }
+
+    fn consume(&mut self, amt: usize) {
+        // The buffer is the same one returned by the last `fill_buf`.
+        if let Ok(buf) = self.input.fill_buf() {
+            let _ = self.log.write_all(&buf[..amt]);
+            let _ = self.log.flush();
+        }
+        self.input.consume(amt);
+    }
+}
+
+/// A buffer the output can be captured in, which can still be read after
+/// the console is handed to the CPU.
+#[cfg(test)]
+#[derive(Clone, Default)]
+pub struct SharedBuffer(Rc<RefCell<Vec<u8>>>);
+
+#[cfg(test)]
+impl SharedBuffer {
+    /// Returns a copy of everything written so far.
+    pub fn contents(&self) -> Vec<u8> {
+        self.0.borrow().clone()
+    }
+}
+
+#[cfg(test)]
+impl Write for SharedBuffer {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
@@ -11,8 +11,54 @@
 //! also because cop0's registers aren't a simple array of integers,
 //! unlike cop1.
 
+use super::snapshot::{SnapshotReader, SnapshotWriter};
+
+use color_eyre::eyre::Result;
 use log::debug;
 
+/// Status.IE: global interrupt enable.
+pub const STATUS_IE: u32 = 1 << 0;
+/// Status.EXL: exception level.
+pub const STATUS_EXL: u32 = 1 << 1;
+/// Status.ERL: error level.
+pub const STATUS_ERL: u32 = 1 << 2;
+/// Status.BEV: exception vectors at the bootstrap location.
+pub const STATUS_BEV: u32 = 1 << 22;
+
+/// Where execution starts after a reset, in kseg1.
+pub const RESET_VECTOR: u32 = 0xBFC00000;
+
+/// Cause.ExcCode, shifted into place.
+pub const CAUSE_EXC_CODE: u32 = 0x1F << 2;
+/// Cause.BD: the last exception happened in a branch delay slot.
+pub const CAUSE_BD: u32 = 1 << 31;
+/// Cause.TI: the timer interrupt is pending.
+pub const CAUSE_TI: u32 = 1 << 30;
+/// The timer interrupt line, both in Cause.IP and in Status.IM.
+pub const INT_TIMER: u32 = 1 << 15;
+
+/// The exception codes we know how to raise, as written to
+/// Cause.ExcCode.
+#[derive(Copy, Clone, Debug)]
+pub enum ExcCode {
+    /// Interrupt.
+    Int = 0,
+    /// Syscall.
+    Sys = 8,
+}
+
+/// Translates a virtual address to a physical one, without a TLB.
+///
+/// kseg0 and kseg1 are unmapped windows onto the first 512 MiB of the
+/// physical address space, cached and uncached respectively. Every
+/// other segment is mapped to itself.
+pub fn physical_address(addr: u32) -> u32 {
+    match addr {
+        0x8000_0000..=0xBFFF_FFFF => addr & 0x1FFF_FFFF,
+        _ => addr,
+    }
+}
+
 /// Coprocessor 0.
 ///
 /// The register names and descriptions are from the MIPS Vol. 3 manual.
@@ -121,9 +167,90 @@ pub struct Cop0 {
     /// EJTAG debug exception save register.
     /// (n, sel) = (31, 0)
     pub desave: u32,
+    /// Interrupt lines currently asserted by the devices. They are
+    /// not a register by themselves, but show up in Cause.IP when it
+    /// is read, alongside the software interrupt bits.
+    pub ip_lines: u32,
 }
 
 impl Cop0 {
+    /// Puts the registers in their reset state: kernel mode, with the
+    /// exception vectors at the bootstrap location and interrupts
+    /// disabled. The registers the manual leaves undefined are zeroed,
+    /// and the configuration describes a MIPS32r2 core with no TLB.
+    pub fn reset(&mut self) {
+        *self = Cop0::default();
+
+        self.status = STATUS_BEV | STATUS_ERL;
+        // Company MIPS Technologies, processor 24K.
+        self.prid = 0x0001_9300;
+        // M (Config1 follows), AR = release 2, MT = fixed mapping,
+        // K0 = uncached.
+        self.config0 = (1 << 31) | (1 << 10) | (3 << 7) | 2;
+        // FP: there is a floating point unit.
+        self.config1 = 1;
+        self.error_epc = RESET_VECTOR;
+    }
+
+    /// The registers, in the order they are saved in snapshots.
+    fn registers_mut(&mut self) -> Vec<&mut u32> {
+        vec![
+            &mut self.index,
+            &mut self.random,
+            &mut self.entry_lo0,
+            &mut self.entry_lo1,
+            &mut self.context,
+            &mut self.page_mask,
+            &mut self.wired,
+            &mut self.bad_v_addr,
+            &mut self.count,
+            &mut self.entry_hi,
+            &mut self.compare,
+            &mut self.status,
+            &mut self.cause,
+            &mut self.epc,
+            &mut self.prid,
+            &mut self.config0,
+            &mut self.config1,
+            &mut self.config2,
+            &mut self.config3,
+            &mut self.ll_addr,
+            &mut self.watch_lo,
+            &mut self.watch_hi,
+            &mut self.debug,
+            &mut self.depc,
+            &mut self.perf_cnt,
+            &mut self.err_ctl,
+            &mut self.cache_err,
+            &mut self.tag_lo,
+            &mut self.data_lo,
+            &mut self.tag_hi,
+            &mut self.data_hi,
+            &mut self.error_epc,
+            &mut self.desave,
+            &mut self.ip_lines,
+        ]
+    }
+
+    /// Saves every register to `snapshot`.
+    pub fn save(&mut self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("cop0");
+        for reg in self.registers_mut() {
+            snapshot.u32(*reg);
+        }
+    }
+
+    /// Restores the registers saved by `save`.
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<()> {
+        snapshot.section("cop0")?;
+        for reg in self.registers_mut() {
+            *reg = snapshot.u32()?;
+        }
+        Ok(())
+    }
+
+    /// Reads register `n`, select `sel`. The registers we don't have
+    /// read as zero.
     pub fn read_reg(&self, n: u32, sel: u32) -> u32 {
         debug!("read reg {} sel {}", n, sel);
 
@@ -135,15 +262,12 @@ impl Cop0 {
             (4, 0) => self.context,
             (5, 0) => self.page_mask,
             (6, 0) => self.wired,
-            (7, _) => unimplemented!(),
             (8, 0) => self.bad_v_addr,
             (9, 0) => self.count,
-            (9, 6) | (9, 7) => unimplemented!(),
             (10, 0) => self.entry_hi,
             (11, 0) => self.compare,
-            (11, 6) | (11, 7) => unimplemented!(),
             (12, 0) => self.status,
-            (13, 0) => self.cause,
+            (13, 0) => self.cause | ((self.ip_lines & 0xFF) << 8),
             (14, 0) => self.epc,
             (15, 0) => self.prid,
             (16, 0) => self.config0,
@@ -153,9 +277,6 @@ impl Cop0 {
             (17, 0) => self.ll_addr,
             (18, _) => self.watch_lo,
             (19, _) => self.watch_hi,
-            (20, 0) => unimplemented!(),
-            (21, _) => unimplemented!(),
-            (22, _) => unimplemented!(),
             (23, 0) => self.debug,
             (24, 0) => self.depc,
             (25, _) => self.perf_cnt,
@@ -167,10 +288,15 @@ impl Cop0 {
             (29, 1) => self.data_hi,
             (30, 0) => self.error_epc,
             (31, 0) => self.desave,
-            _ => unreachable!()
+            _ => {
+                debug!("reg {} sel {} is not implemented, reading 0", n, sel);
+                0
+            }
         }
     }
 
+    /// Writes `val` to register `n`, select `sel`. Writes to the
+    /// registers we don't have are ignored.
     pub fn write_reg(&mut self, n: u32, sel: u32, val: u32) {
         debug!("write {} to reg {} sel {}", val, n, sel);
 
@@ -182,13 +308,14 @@ impl Cop0 {
             (4, 0) => self.context = val,
             (5, 0) => self.page_mask = val,
             (6, 0) => self.wired = val,
-            (7, _) => unimplemented!(),
             (8, 0) => self.bad_v_addr = val,
             (9, 0) => self.count = val,
-            (9, 6) | (9, 7) => unimplemented!(),
             (10, 0) => self.entry_hi = val,
-            (11, 0) => self.compare = val,
-            (11, 6) | (11, 7) => unimplemented!(),
+            (11, 0) => {
+                // Writing Compare acknowledges the timer interrupt.
+                self.compare = val;
+                self.cause &= !(CAUSE_TI | INT_TIMER);
+            }
             (12, 0) => self.status = val,
             (13, 0) => self.cause = val,
             (14, 0) => self.epc = val,
@@ -200,9 +327,6 @@ impl Cop0 {
             (17, 0) => self.ll_addr = val,
             (18, _) => self.watch_lo = val,
             (19, _) => self.watch_hi = val,
-            (20, 0) => unimplemented!(),
-            (21, _) => unimplemented!(),
-            (22, _) => unimplemented!(),
             (23, 0) => self.debug = val,
             (24, 0) => self.depc = val,
             (25, _) => self.perf_cnt = val,
@@ -214,7 +338,70 @@ impl Cop0 {
             (29, 1) => self.data_hi = val,
             (30, 0) => self.error_epc = val,
             (31, 0) => self.desave = val,
-            _ => unreachable!()
+            _ => debug!("reg {} sel {} is not implemented, ignoring {}", n, sel, val),
+        }
+    }
+
+    /// Advances Count by one. When it reaches Compare, the timer
+    /// interrupt is raised, and stays pending until Compare is written.
+    pub fn tick(&mut self) {
+        self.count = self.count.wrapping_add(1);
+        if self.count == self.compare {
+            self.cause |= CAUSE_TI | INT_TIMER;
+        }
+    }
+
+    /// Returns `true` if there is an interrupt that should be taken
+    /// right now, i.e. some unmasked IP bit is set, interrupts are
+    /// enabled and we are not already handling an exception.
+    pub fn interrupt_pending(&self) -> bool {
+        let ip = ((self.cause >> 8) | self.ip_lines) & 0xFF;
+        let im = (self.status >> 8) & 0xFF;
+
+        self.status & STATUS_IE != 0
+            && self.status & (STATUS_EXL | STATUS_ERL) == 0
+            && ip & im != 0
+    }
+
+    /// Enters exception mode, saving `pc` on EPC. Returns the address
+    /// of the exception handler.
+    ///
+    /// If `in_delay_slot` is `true`, `pc` is the address of the
+    /// instruction in the delay slot, so EPC points to the branch
+    /// instead and Cause.BD is set.
+    pub fn enter_exception(&mut self, code: ExcCode, pc: u32, in_delay_slot: bool) -> u32 {
+        debug!("exception {:?} at {:#010x}", code, pc);
+
+        self.cause = (self.cause & !CAUSE_EXC_CODE) | ((code as u32) << 2);
+
+        if self.status & STATUS_EXL == 0 {
+            if in_delay_slot {
+                self.epc = pc - 4;
+                self.cause |= CAUSE_BD;
+            } else {
+                self.epc = pc;
+                self.cause &= !CAUSE_BD;
+            }
+        }
+
+        self.status |= STATUS_EXL;
+
+        if self.status & STATUS_BEV != 0 {
+            0xBFC00380
+        } else {
+            0x80000180
+        }
+    }
+
+    /// Leaves exception mode. Returns the address where execution
+    /// should resume.
+    pub fn eret(&mut self) -> u32 {
+        if self.status & STATUS_ERL != 0 {
+            self.status &= !STATUS_ERL;
+            self.error_epc
+        } else {
+            self.status &= !STATUS_EXL;
+            self.epc
         }
     }
 }
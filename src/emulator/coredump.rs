@@ -0,0 +1,278 @@
+//! Core dumps of the guest, written when it dies of a fault, so that it
+//! can be inspected offline with `gdb-multiarch prog core`.
+//!
+//! The file is an ELF core file like the ones Linux writes for MIPS o32
+//! programs: a `PT_NOTE` segment with the registers (`NT_PRSTATUS` and
+//! `NT_PRFPREG`) and the program name (`NT_PRPSINFO`), followed by a
+//! `PT_LOAD` segment for each run of memory the program used.
+
+use std::fs;
+
+use super::abi::PAGE_SIZE;
+
+use goblin::elf::header::{EM_MIPS, ET_CORE};
+use goblin::elf::note::{NT_PRPSINFO, NT_PRSTATUS};
+use goblin::elf::program_header::{PF_R, PF_W, PF_X, PT_LOAD, PT_NOTE};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The floating point registers note, missing from goblin.
+const NT_PRFPREG: u32 = 2;
+
+/// `e_flags` of an o32 program for MIPS32.
+const EF_MIPS_ARCH_32: u32 = 0x5000_0000;
+const EF_MIPS_ABI_O32: u32 = 0x0000_1000;
+
+const SIZEOF_EHDR: usize = 52;
+const SIZEOF_PHDR: usize = 32;
+
+/// The sizes of `struct elf_prstatus` and `struct elf_prpsinfo` on o32,
+/// which is how debuggers recognize them.
+const SIZEOF_PRSTATUS: usize = 256;
+const SIZEOF_PRPSINFO: usize = 128;
+
+/// Where `pr_reg` starts in `struct elf_prstatus`.
+const PRSTATUS_REGS: usize = 72;
+
+/// The slots of the o32 `elf_gregset_t`, after the 6 unused ones.
+const EF_REG0: usize = 6;
+const EF_LO: usize = 38;
+const EF_HI: usize = 39;
+const EF_CP0_EPC: usize = 40;
+const EF_CP0_BADVADDR: usize = 41;
+const EF_CP0_STATUS: usize = 42;
+const EF_CP0_CAUSE: usize = 43;
+const ELF_NGREG: usize = 45;
+
+/// kseg0 and kseg1, the windows the bare machine sees the first 512 MiB
+/// of physical memory through.
+const KSEG0: u32 = 0x8000_0000;
+const KSEG1: u32 = 0xA000_0000;
+const KSEG_SIZE: u32 = 0x2000_0000;
+
+/// The state of a guest that died.
+#[derive(Debug, Default)]
+pub struct CoreDump {
+    /// The signal it died of.
+    pub signal: u32,
+    /// The general purpose registers.
+    pub regs: [u32; 32],
+    pub hi: u32,
+    pub lo: u32,
+    /// The address of the instruction that failed.
+    pub pc: u32,
+    /// The Cop0 registers debuggers show.
+    pub bad_vaddr: u32,
+    pub status: u32,
+    pub cause: u32,
+    /// The floating point registers and the FCSR.
+    pub float_regs: [u32; 32],
+    pub fcsr: u32,
+    /// The program name and arguments.
+    pub args: Vec<String>,
+    /// The memory, as runs of bytes at the addresses they have in the
+    /// emulated memory.
+    pub memory: Vec<(u32, Vec<u8>)>,
+    /// The addresses in `memory` are physical, as in a bare machine. Each
+    /// run below 512 MiB is then shown at its kseg0 and kseg1 addresses.
+    pub physical: bool,
+}
+
+impl CoreDump {
+    /// The `PT_LOAD` segments of each run of memory, as (virtual address,
+    /// physical address).
+    fn mappings(&self, addr: u32) -> Vec<(u32, u32)> {
+        if self.physical && addr < KSEG_SIZE {
+            vec![(KSEG0 + addr, addr), (KSEG1 + addr, addr)]
+        } else {
+            vec![(addr, addr)]
+        }
+    }
+
+    /// The `NT_PRSTATUS` note.
+    fn prstatus(&self) -> Vec<u8> {
+        let mut gregs = [0u32; ELF_NGREG];
+        gregs[EF_REG0..EF_REG0 + 32].copy_from_slice(&self.regs);
+        gregs[EF_LO] = self.lo;
+        gregs[EF_HI] = self.hi;
+        gregs[EF_CP0_EPC] = self.pc;
+        gregs[EF_CP0_BADVADDR] = self.bad_vaddr;
+        gregs[EF_CP0_STATUS] = self.status;
+        gregs[EF_CP0_CAUSE] = self.cause;
+
+        let mut desc = vec![0; SIZEOF_PRSTATUS];
+        // pr_info.si_signo and pr_cursig.
+        put_u32(&mut desc, 0, self.signal);
+        desc[12..14].copy_from_slice(&(self.signal as u16).to_le_bytes());
+        // pr_pid.
+        put_u32(&mut desc, 24, 1);
+        for (i, reg) in gregs.iter().enumerate() {
+            put_u32(&mut desc, PRSTATUS_REGS + 4 * i, *reg);
+        }
+        // pr_fpvalid.
+        put_u32(&mut desc, SIZEOF_PRSTATUS - 4, 1);
+
+        desc
+    }
+
+    /// The `NT_PRFPREG` note: each register in the low half of a 64-bit
+    /// slot, followed by the FCSR.
+    fn fpregs(&self) -> Vec<u8> {
+        let mut desc = vec![0; 33 * 8];
+        for (i, reg) in self.float_regs.iter().enumerate() {
+            put_u32(&mut desc, 8 * i, *reg);
+        }
+        put_u32(&mut desc, 32 * 8, self.fcsr);
+        desc
+    }
+
+    /// The `NT_PRPSINFO` note.
+    fn prpsinfo(&self) -> Vec<u8> {
+        let mut desc = vec![0; SIZEOF_PRPSINFO];
+        // pr_sname: the process is a zombie.
+        desc[1] = b'Z';
+        // pr_pid.
+        put_u32(&mut desc, 16, 1);
+
+        let name = self.args.first().map(String::as_str).unwrap_or_default();
+        let name = name.rsplit('/').next().unwrap_or_default().as_bytes();
+        let len = name.len().min(15);
+        desc[32..32 + len].copy_from_slice(&name[..len]);
+
+        let args = self.args.join(" ");
+        let len = args.len().min(79);
+        desc[48..48 + len].copy_from_slice(&args.as_bytes()[..len]);
+
+        desc
+    }
+
+    /// The notes, in the order Linux writes them.
+    fn notes(&self) -> Vec<u8> {
+        let mut notes = Vec::new();
+        for (kind, desc) in [
+            (NT_PRSTATUS, self.prstatus()),
+            (NT_PRPSINFO, self.prpsinfo()),
+            (NT_PRFPREG, self.fpregs()),
+        ] {
+            push_u32(&mut notes, 5);
+            push_u32(&mut notes, desc.len() as u32);
+            push_u32(&mut notes, kind);
+            notes.extend_from_slice(b"CORE\0\0\0\0");
+            notes.extend_from_slice(&desc);
+            pad(&mut notes, 4);
+        }
+        notes
+    }
+
+    /// Builds the core file.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let notes = self.notes();
+        let loads: usize = self
+            .memory
+            .iter()
+            .map(|(addr, _)| self.mappings(*addr).len())
+            .sum();
+        let phnum = 1 + loads;
+
+        let mut bytes = Vec::new();
+
+        // The ELF header.
+        bytes.extend_from_slice(b"\x7fELF\x01\x01\x01");
+        pad(&mut bytes, 16);
+        push_u16(&mut bytes, ET_CORE);
+        push_u16(&mut bytes, EM_MIPS);
+        push_u32(&mut bytes, 1);
+        push_u32(&mut bytes, 0);
+        push_u32(&mut bytes, SIZEOF_EHDR as u32);
+        push_u32(&mut bytes, 0);
+        push_u32(&mut bytes, EF_MIPS_ARCH_32 | EF_MIPS_ABI_O32);
+        push_u16(&mut bytes, SIZEOF_EHDR as u16);
+        push_u16(&mut bytes, SIZEOF_PHDR as u16);
+        push_u16(&mut bytes, phnum as u16);
+        push_u16(&mut bytes, 0);
+        push_u16(&mut bytes, 0);
+        push_u16(&mut bytes, 0);
+
+        // The notes go right after the program headers, and the memory
+        // after them, from the next page on. Each run is written once, even
+        // if it is mapped twice.
+        let notes_offset = SIZEOF_EHDR + phnum * SIZEOF_PHDR;
+        let memory_offset = align_up(notes_offset + notes.len(), PAGE_SIZE as usize);
+        let mut offset = memory_offset;
+
+        push_phdr(&mut bytes, PT_NOTE, notes_offset, 0, 0, notes.len(), 0, 1);
+        for (addr, data) in self.memory.iter() {
+            for (vaddr, paddr) in self.mappings(*addr) {
+                let flags = PF_R | PF_W | PF_X;
+                push_phdr(
+                    &mut bytes,
+                    PT_LOAD,
+                    offset,
+                    vaddr,
+                    paddr,
+                    data.len(),
+                    flags,
+                    PAGE_SIZE,
+                );
+            }
+            offset += data.len();
+        }
+
+        bytes.extend_from_slice(&notes);
+        bytes.resize(memory_offset, 0);
+        for (_, data) in self.memory.iter() {
+            bytes.extend_from_slice(data);
+        }
+
+        bytes
+    }
+
+    /// Writes the core file to `path`.
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, self.to_bytes()).map_err(|e| eyre!("{}: {}", path, e))
+    }
+}
+
+fn put_u32(bytes: &mut [u8], at: usize, val: u32) {
+    bytes[at..at + 4].copy_from_slice(&val.to_le_bytes());
+}
+
+fn push_u16(bytes: &mut Vec<u8>, val: u16) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+fn push_u32(bytes: &mut Vec<u8>, val: u32) {
+    bytes.extend_from_slice(&val.to_le_bytes());
+}
+
+/// Rounds `val` up to a multiple of `align`.
+fn align_up(val: usize, align: usize) -> usize {
+    val.div_ceil(align) * align
+}
+
+/// Pads `bytes` with zeros to a multiple of `align`.
+fn pad(bytes: &mut Vec<u8>, align: usize) {
+    bytes.resize(align_up(bytes.len(), align), 0);
+}
+
+/// Writes a program header, with the same size in the file and in memory.
+#[allow(clippy::too_many_arguments)]
+fn push_phdr(
+    bytes: &mut Vec<u8>,
+    kind: u32,
+    offset: usize,
+    vaddr: u32,
+    paddr: u32,
+    size: usize,
+    flags: u32,
+    align: u32,
+) {
+    push_u32(bytes, kind);
+    push_u32(bytes, offset as u32);
+    push_u32(bytes, vaddr);
+    push_u32(bytes, paddr);
+    push_u32(bytes, size as u32);
+    push_u32(bytes, size as u32);
+    push_u32(bytes, flags);
+    push_u32(bytes, align);
+}
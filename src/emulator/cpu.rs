@@ -7,18 +7,37 @@ use super::memory::Memory;
 use super::FloatRegister;
 use super::Instruction;
 use super::Register;
+use super::Abi;
 
-use super::cop0::Cop0;
+use super::abi::PAGE_SIZE;
+use super::board::Region;
+use super::cop0::{self, Cop0, ExcCode};
+use super::coredump::CoreDump;
+use super::devices::{BitmapDisplay, Device, Devices};
+use super::fault::{Fault, Limit};
 
 use super::instr::{branch_addr, jump_addr, sign_extend, sign_extend_cast};
 
+use super::console::Console;
+use super::snapshot::{SnapshotPlan, SnapshotReader, SnapshotWriter};
 use super::stats::StatsReporter;
+use super::symbols::Symbols;
+use super::vfs::Vfs;
+
+mod linux;
+mod syscall;
+mod uhi;
+
+use syscall::SyscallState;
 
 use std::cell::UnsafeCell;
+use std::collections::BTreeSet;
 use std::convert::TryInto;
-use std::io::Write;
+use std::io;
+use std::time::{Duration, Instant};
 
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::{Report, Result};
+use rand::{rngs::StdRng, SeedableRng};
 
 use log::debug;
 
@@ -62,7 +81,7 @@ fn dword_to_double(lo: u32, hi: u32) -> f64 {
     let arr = [lo, hi];
 
     unsafe {
-        let ptr = (arr.as_ptr() as *const u32) as *const f64;
+        let ptr = arr.as_ptr() as *const f64;
         *ptr
     }
 }
@@ -156,6 +175,21 @@ pub struct Cpu<'a, TD: Memory, TI: Memory> {
     /// A CPU terminou a execução?
     halt: bool,
 
+    /// O status de saída pedido pelo programa.
+    exit_status: i32,
+
+    /// Os limites de execução.
+    limits: Limits,
+    /// Quando o tempo de `limits.timeout` acaba, contado do início de `run`.
+    deadline: Option<Instant>,
+
+    /// A máquina está rodando sem sistema operacional (veja `reset`).
+    bare: bool,
+
+    /// As regiões da memória física que existem. Se estiver vazio, toda
+    /// a memória existe.
+    memory_map: Vec<Region>,
+
     /// Os registradores de aritmetica.
     arith_regs: (u32, u32),
 
@@ -170,6 +204,36 @@ pub struct Cpu<'a, TD: Memory, TI: Memory> {
 
     /// Coprocessor 0.
     cop0: Cop0,
+
+    /// Os dispositivos mapeados em memória.
+    devices: Devices,
+
+    /// O bitmap display, se houver.
+    bitmap: Option<BitmapDisplay>,
+
+    /// Estado mantido pelas syscalls (heap, arquivos abertos, ...).
+    syscalls: SyscallState,
+
+    /// Os símbolos do programa, para mostrar onde ele está.
+    symbols: Symbols,
+
+    /// Quando salvar snapshots da máquina durante a execução.
+    snapshots: SnapshotPlan,
+
+    /// Onde escrever o core dump se o programa falhar, e os argumentos
+    /// dele (com o nome) para o dump.
+    core_file: Option<(String, Vec<String>)>,
+}
+
+/// Os limites de execução da CPU. `None` quer dizer sem limite.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Limits {
+    /// O máximo de instruções executadas.
+    pub instructions: Option<usize>,
+    /// O máximo de ciclos (do modelo monociclo).
+    pub cycles: Option<usize>,
+    /// O tempo real máximo de execução.
+    pub timeout: Option<Duration>,
 }
 
 impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
@@ -190,25 +254,395 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
             in_delay_slot: false,
             branch_to: None,
             halt: false,
+            exit_status: 0,
+            limits: Limits::default(),
+            deadline: None,
+            bare: false,
+            memory_map: Vec::new(),
             arith_regs: (0, 0),
             float_regs: FloatRegisters([0; 32]),
             stats: StatsReporter::new(),
             float_cc: false,
             cop0: std::default::Default::default(),
+            devices: std::default::Default::default(),
+            bitmap: None,
+            syscalls: SyscallState::new(),
+            symbols: Symbols::default(),
+            snapshots: SnapshotPlan::default(),
+            core_file: None,
         };
 
         cpu.regs[Register(28)] = gp;
         cpu.regs[Register(29)] = sp;
 
+        // Como no MARS: user mode e todas as máscaras ligadas. As
+        // interrupções só são habilitadas quando um dispositivo é conectado.
+        cpu.cop0.status = 0x0000FF10;
+
         cpu
     }
 
+    /// Coloca a máquina no estado de reset, sem sistema operacional: a
+    /// execução começa em modo kernel no endereço passado para `new`
+    /// (normalmente o vetor de reset), com o Cop0 no seu estado de reset e
+    /// nenhum registrador preparado.
+    ///
+    /// Nesse modo, kseg0 e kseg1 enxergam a memória física, e `syscall`
+    /// causa uma exceção em vez de ser atendida pelo emulador.
+    pub fn reset(&mut self) {
+        self.regs = Registers([0; 32]);
+        self.arith_regs = (0, 0);
+        self.in_delay_slot = false;
+        self.branch_to = None;
+        self.cop0.reset();
+        self.bare = true;
+    }
+
+    /// Traduz um endereço virtual para o endereço usado na memória.
+    /// Fora do modo sem sistema operacional, os dois são iguais.
+    fn translate(&self, addr: u32) -> u32 {
+        if self.bare {
+            cop0::physical_address(addr)
+        } else {
+            addr
+        }
+    }
+
+    /// Define os símbolos usados para mostrar os endereços do programa
+    /// nos erros e no trace de instruções.
+    pub fn set_symbols(&mut self, symbols: Symbols) {
+        self.symbols = symbols;
+    }
+
+    /// Define quando salvar snapshots da máquina durante a execução.
+    pub fn set_snapshot_plan(&mut self, plan: SnapshotPlan) {
+        self.snapshots = plan;
+    }
+
+    /// Pede um core dump em `path` se o programa, chamado com `args`,
+    /// morrer por causa de uma falha.
+    pub fn set_core_file(&mut self, path: String, args: Vec<String>) {
+        self.core_file = Some((path, args));
+    }
+
+    /// Escreve o core dump do programa, que morreu por causa de `fault`.
+    /// A memória é lida através das caches, então o dump tem os valores
+    /// que o programa vê, mesmo os que ainda não chegaram na RAM.
+    fn write_core(&self, path: &str, args: &[String], fault: &Fault) -> Result<()> {
+        let mut pages = BTreeSet::new();
+        self.mem.pages(&mut pages);
+        if self.imem.get() as *const u32 != self.mem.get() as *const u32 {
+            self.imem.pages(&mut pages);
+        }
+
+        // Páginas seguidas formam um único segmento.
+        let mut memory: Vec<(u32, Vec<u8>)> = Vec::new();
+        for page in pages {
+            let mut data = Vec::with_capacity(PAGE_SIZE as usize);
+            for i in 0..PAGE_SIZE / 4 {
+                data.extend_from_slice(&self.mem.inspect(page + 4 * i)?.to_le_bytes());
+            }
+
+            match memory.last_mut() {
+                Some((start, run)) if *start as usize + run.len() == page as usize => {
+                    run.extend_from_slice(&data)
+                }
+                _ => memory.push((page, data)),
+            }
+        }
+
+        let mut regs = self.regs.0;
+        regs[0] = 0;
+
+        let core = CoreDump {
+            signal: fault.signal().unwrap_or(0),
+            regs,
+            hi: self.arith_regs.1,
+            lo: self.arith_regs.0,
+            pc: self.pc,
+            bad_vaddr: fault.bad_address().unwrap_or(self.cop0.bad_v_addr),
+            status: self.cop0.status,
+            cause: self.cop0.cause,
+            float_regs: self.float_regs.0,
+            // O condition code fica no bit 23 do FCSR.
+            fcsr: (self.float_cc as u32) << 23,
+            args: args.to_vec(),
+            memory,
+            physical: self.bare,
+        };
+
+        core.write(path)
+    }
+
+    /// Salva o estado de toda a máquina no snapshot: os registradores, o
+    /// Cop0, as estatísticas, o estado das syscalls e cada nível de
+    /// memória, na mesma ordem de `print_report`.
+    fn save_snapshot(&mut self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("cpu");
+        snapshot.u32(self.pc);
+        snapshot.bool(self.in_delay_slot);
+        snapshot.bool(self.branch_to.is_some());
+        snapshot.u32(self.branch_to.unwrap_or(0));
+        snapshot.bool(self.bare);
+        for reg in self.regs.0.iter() {
+            snapshot.u32(*reg);
+        }
+        snapshot.u32(self.arith_regs.0);
+        snapshot.u32(self.arith_regs.1);
+        for reg in self.float_regs.0.iter() {
+            snapshot.u32(*reg);
+        }
+        snapshot.bool(self.float_cc);
+
+        self.cop0.save(snapshot);
+        self.stats.save(snapshot);
+        self.syscalls.save(snapshot);
+
+        if self.imem.get() as *const u32 != self.mem.get() as *const u32 {
+            self.imem.save(snapshot, false);
+        }
+        self.mem.save(snapshot, true);
+    }
+
+    /// Restaura a máquina do snapshot em `bytes`, salvo por uma execução
+    /// com a mesma configuração de memória. A execução continua de onde
+    /// o snapshot foi tirado.
+    pub fn restore_snapshot(&mut self, bytes: &[u8]) -> Result<()> {
+        let mut snapshot = SnapshotReader::new(bytes)?;
+
+        snapshot.section("cpu")?;
+        self.pc = snapshot.u32()?;
+        self.in_delay_slot = snapshot.bool()?;
+        let branching = snapshot.bool()?;
+        let branch_to = snapshot.u32()?;
+        self.branch_to = if branching { Some(branch_to) } else { None };
+        self.bare = snapshot.bool()?;
+        for reg in self.regs.0.iter_mut() {
+            *reg = snapshot.u32()?;
+        }
+        self.arith_regs = (snapshot.u32()?, snapshot.u32()?);
+        for reg in self.float_regs.0.iter_mut() {
+            *reg = snapshot.u32()?;
+        }
+        self.float_cc = snapshot.bool()?;
+
+        self.cop0.restore(&mut snapshot)?;
+        self.stats.restore(&mut snapshot)?;
+        self.syscalls.restore(&mut snapshot)?;
+
+        if self.imem.get() as *const u32 != self.mem.get() as *const u32 {
+            self.imem.restore(&mut snapshot, false)?;
+        }
+        self.mem.restore(&mut snapshot, true)?;
+
+        snapshot.finish()
+    }
+
+    /// Restringe a memória física às regiões `regions`. Acessos fora
+    /// delas, ou escritas numa região só de leitura, causam bus error.
+    pub fn set_memory_map(&mut self, regions: Vec<Region>) {
+        self.memory_map = regions;
+    }
+
+    /// Confere se o endereço físico `addr` existe no mapa de memória.
+    fn check_mapped(&self, addr: u32, write: bool) -> Result<()> {
+        if self.memory_map.is_empty() {
+            return Ok(());
+        }
+
+        match self.memory_map.iter().find(|r| r.contains(addr)) {
+            Some(region) if region.writable || !write => Ok(()),
+            _ => Err(Fault::BusError(addr).into()),
+        }
+    }
+
+    /// Define o nome e os argumentos do programa, que ele pede ao host
+    /// pelo UHI quando roda sem sistema operacional.
+    pub fn set_program_args(&mut self, args: Vec<String>) {
+        self.syscalls.args = args;
+    }
+
+    /// Conecta um dispositivo mapeado em memória à CPU. Fora do modo sem
+    /// sistema operacional, isso também habilita as interrupções, como o
+    /// Status inicial do MARS (0x0000FF11).
+    pub fn attach_device(&mut self, device: Box<dyn Device>) {
+        self.devices.attach(device);
+        if !self.bare {
+            self.cop0.status |= cop0::STATUS_IE;
+        }
+    }
+
+    /// Escolhe o perfil de ABI, que define a tabela de syscalls.
+    pub fn set_abi(&mut self, abi: Abi) {
+        self.syscalls.abi = abi;
+    }
+
+    /// Passa os argumentos do programa em $a0 (argc) e $a1 (argv),
+    /// como o MARS faz.
+    pub fn set_main_args(&mut self, argc: u32, argv: u32) {
+        self.regs[Register(4)] = argc;
+        self.regs[Register(5)] = argv;
+    }
+
+    /// Define onde começa o heap (o program break inicial, no Linux).
+    pub fn set_program_break(&mut self, brk: u32) {
+        self.syscalls.heap_start = brk;
+        self.syscalls.heap_end = brk;
+    }
+
+    /// Deixa a execução reproduzível: os valores aleatórios vistos pelo
+    /// programa saem de `seed`, o relógio dele passa a ser o tempo simulado
+    /// e o tempo real de execução não é mais impresso.
+    pub fn set_deterministic(&mut self, seed: u64) {
+        self.syscalls.entropy = StdRng::seed_from_u64(seed);
+        self.syscalls.virtual_time = true;
+        self.stats.hide_wall_clock();
+    }
+
+    /// Define os limites de execução.
+    pub fn set_limits(&mut self, limits: Limits) {
+        self.limits = limits;
+    }
+
+    /// Define o console usado pelas syscalls de entrada e saída.
+    pub fn set_console(&mut self, console: Box<dyn Console>) {
+        self.syscalls.console = console;
+    }
+
+    /// Define o sistema de arquivos visto pelo programa.
+    pub fn set_vfs(&mut self, vfs: Vfs) {
+        self.syscalls.vfs = vfs;
+    }
+
+    /// Retorna o sistema de arquivos visto pelo programa.
+    pub fn vfs(&self) -> &Vfs {
+        &self.syscalls.vfs
+    }
+
+    /// Conecta um bitmap display à CPU.
+    pub fn set_bitmap_display(&mut self, bitmap: BitmapDisplay) {
+        self.bitmap.replace(bitmap);
+    }
+
+    /// Lê uma palavra da memória de dados, ou de um dispositivo caso
+    /// o endereço pertença a um. Retorna o valor e o total de ciclos gasto.
+    fn load(&mut self, addr: u32) -> Result<(u32, usize)> {
+        let addr = self.translate(addr);
+        match self.devices.find(addr) {
+            Some(device) => {
+                let offset = addr - device.base();
+                Ok((device.read(offset, &mut *self.syscalls.console)?, 1))
+            }
+            None => {
+                self.check_mapped(addr, false)?;
+                self.mem.peek(addr)
+            }
+        }
+    }
+
+    /// Escreve uma palavra na memória de dados, ou num dispositivo caso
+    /// o endereço pertença a um. Retorna o total de ciclos gasto.
+    fn store(&mut self, addr: u32, val: u32) -> Result<usize> {
+        let addr = self.translate(addr);
+        match self.devices.find(addr) {
+            Some(device) => {
+                let offset = addr - device.base();
+                device.write(offset, val, &mut *self.syscalls.console)?;
+                Ok(1)
+            }
+            None => {
+                self.check_mapped(addr, true)?;
+                self.mem.poke(addr, val)
+            }
+        }
+    }
+
+    /// Retorna HI e LO concatenados.
+    fn hilo(&self) -> u64 {
+        (self.arith_regs.1 as u64) << 32 | self.arith_regs.0 as u64
+    }
+
+    /// Escreve `val` em HI (parte alta) e LO (parte baixa).
+    fn set_hilo(&mut self, val: u64) {
+        self.arith_regs = (val as u32, (val >> 32) as u32);
+    }
+
+    /// Lê `size` bytes (1 ou 2) da memória de dados, a partir de `addr`.
+    /// O valor é retornado sem extensão de sinal, junto do total de ciclos.
+    fn load_partial(&mut self, addr: u32, size: u32) -> Result<(u32, usize)> {
+        if addr & (size - 1) != 0 {
+            return Err(Fault::AddressError(addr).into());
+        }
+
+        let (word, cycles) = self.load(addr & !3)?;
+        let shift = 8 * (addr & 3);
+        let mask = (1u32 << (8 * size)) - 1;
+
+        Ok(((word >> shift) & mask, cycles))
+    }
+
+    /// Escreve os `size` bytes (1 ou 2) menos significativos de `val` na
+    /// memória de dados, a partir de `addr`. Como a memória só trabalha
+    /// com palavras, a palavra inteira é lida, modificada e escrita de volta.
+    fn store_partial(&mut self, addr: u32, val: u32, size: u32) -> Result<usize> {
+        if addr & (size - 1) != 0 {
+            return Err(Fault::AddressError(addr).into());
+        }
+
+        let shift = 8 * (addr & 3);
+        let mask = ((1u32 << (8 * size)) - 1) << shift;
+
+        // Ler um registrador de dispositivo pode ter efeitos colaterais
+        // (consumir um caractere recebido, por exemplo), então o dispositivo
+        // recebe só os bytes escritos, na posição deles.
+        if self.devices.find(self.translate(addr & !3)).is_some() {
+            return self.store(addr & !3, (val << shift) & mask);
+        }
+
+        let (word, load_cycles) = self.load(addr & !3)?;
+        let word = (word & !mask) | ((val << shift) & mask);
+        let store_cycles = self.store(addr & !3, word)?;
+
+        Ok(load_cycles + store_cycles)
+    }
+
+    /// Avança o Count, atualiza os dispositivos e, se houver uma
+    /// interrupção pendente, desvia a execução para o exception handler.
+    ///
+    /// Interrupções não são atendidas enquanto há um branch pendente,
+    /// assim a instrução no delay slot nunca é separada do seu branch.
+    fn poll_interrupts(&mut self) -> Result<()> {
+        self.cop0.tick();
+        if !self.devices.is_empty() {
+            self.devices.tick(&mut *self.syscalls.console)?;
+            self.cop0.ip_lines = self.devices.interrupts();
+        }
+
+        if self.branch_to.is_none() && self.cop0.interrupt_pending() {
+            self.pc = self.cop0.enter_exception(ExcCode::Int, self.pc, false);
+        }
+
+        Ok(())
+    }
+
+    /// Desvia a execução para o exception handler por causa da instrução
+    /// atual, que não chega a terminar.
+    fn raise(&mut self, code: ExcCode) {
+        self.pc = self
+            .cop0
+            .enter_exception(code, self.pc, self.in_delay_slot);
+        self.in_delay_slot = false;
+        self.branch_to = None;
+    }
+
     /// Executa a instrução apontada pelo program counter atual. Retorna
     /// `Ok(())` se nenhum problema ocorreu.
     ///
     /// Aqui se encontram as implementações das instruções.
     #[allow(unreachable_patterns)]
     pub fn cycle(&mut self) -> Result<()> {
+        self.poll_interrupts()?;
+
         match self.branch_to {
             Some(target) if target != self.pc => {
                 self.in_delay_slot = true;
@@ -217,14 +651,21 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
             _ => {}
         }
 
-        let (word, fetch_latency) = self.imem.peek_instruction(self.pc)?;
+        let fetch_addr = self.translate(self.pc);
+        self.check_mapped(fetch_addr, false)?;
+        let (word, fetch_latency) = self.imem.peek_instruction(fetch_addr)?;
 
-        let instr = Instruction::decode(word)?;
+        let instr = Instruction::decode(word).map_err(|_| Fault::UnknownInstruction {
+            pc: self.pc,
+            word,
+        })?;
         self.stats.add_instr(&instr);
         self.stats.add_cycles(fetch_latency);
         debug!(
-            "{:#010x}: {}; fetch: {} cycles",
-            self.pc, instr, fetch_latency
+            "{}: {}; fetch: {} cycles",
+            self.symbols.describe(self.pc),
+            instr,
+            fetch_latency
         );
         match instr {
             Instruction::NOP => {
@@ -240,102 +681,14 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
                     .0;
                 self.stats.add_cycles(1);
             }
+            Instruction::SYSCALL(_) if self.bare => {
+                // Sem sistema operacional, quem atende é o próprio programa.
+                self.raise(ExcCode::Sys);
+                self.stats.add_cycles(1);
+                return Ok(());
+            }
             Instruction::SYSCALL(_) => {
-                match self.regs[Register(2)] {
-                    1 => {
-                        print!("{}", as_signed(self.regs[Register(4)]));
-                        self.stats.add_cycles(1);
-                    }
-                    2 => {
-                        print!("{}", word_to_single(self.float_regs[FloatRegister(12)]));
-                        self.stats.add_cycles(1);
-                    }
-                    3 => {
-                        print!(
-                            "{}",
-                            dword_to_double(
-                                self.float_regs[FloatRegister(12)],
-                                self.float_regs[FloatRegister(13)]
-                            )
-                        );
-                        self.stats.add_cycles(1);
-                    }
-                    4 => {
-                        let mut addr = self.regs[Register(4)];
-                        let mut byte_offset = addr % 4;
-                        addr -= byte_offset;
-
-                        'outer: loop {
-                            let (val, cycles) = self.mem.peek(addr)?;
-                            let val = val.to_le_bytes();
-
-                            self.stats.add_cycles(cycles);
-
-                            for i in byte_offset..4 {
-                                let c = val[i as usize];
-                                if c != 0 {
-                                    print!("{}", c as char)
-                                } else {
-                                    break 'outer;
-                                }
-                            }
-
-                            addr += 4;
-                            byte_offset = 0;
-                        }
-                    }
-                    5 => {
-                        let mut input = String::new();
-                        std::io::stdin().read_line(&mut input)?;
-
-                        let val = input.trim().parse::<i32>()?;
-
-                        self.regs[Register(2)] = as_unsigned(val);
-
-                        self.stats.add_cycles(1);
-                    }
-                    6 => {
-                        let mut input = String::new();
-                        std::io::stdin().read_line(&mut input)?;
-
-                        let val = input.trim().parse::<f32>()?;
-
-                        self.float_regs[FloatRegister(0)] = single_to_word(val);
-
-                        self.stats.add_cycles(1);
-                    }
-                    7 => {
-                        let mut input = String::new();
-                        std::io::stdin().read_line(&mut input)?;
-
-                        let val = input.trim().parse::<f64>()?;
-
-                        let (lo, hi) = double_to_dword(val);
-
-                        self.float_regs[FloatRegister(0)] = lo;
-                        self.float_regs[FloatRegister(1)] = hi;
-                        self.stats.add_cycles(1);
-                    }
-                    10 => {
-                        self.halt = true;
-                        self.stats.add_cycles(1);
-                        self.stats.finish();
-                    }
-                    11 => {
-                        print!("{}", self.regs[Register(4)] as u8 as char);
-                        self.stats.add_cycles(1);
-                    }
-                    500 => {
-                        self.mem.dump()?;
-                    }
-                    a => println!("syscall: unknown syscall {}", a),
-                };
-                // zsh dentro do term-mode do emacs faz com que o stdout bugue (????)
-                // então temos que flushar o stdout quando termina a syscall.
-                //
-                // ...acho que é uma desculpa pra eu perder tempo configurando
-                // exwm e usar o alacritty direito!
-                std::io::stdout().flush()?;
+                self.syscall()?;
             }
             Instruction::LUI(args) => {
                 let val = args.imm << (32 - 16);
@@ -400,11 +753,12 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
                 self.stats.add_cycles(1);
             }
             Instruction::JR(args) => {
-                self.branch_to = Some(self.regs[args.rs] + 4);
+                self.branch_to = Some(self.regs[args.rs]);
                 self.stats.add_cycles(1);
             }
             Instruction::JAL(addr) => {
-                self.regs[Register(31)] = self.pc + 4;
+                // O retorno é para depois do delay slot.
+                self.regs[Register(31)] = self.pc + 8;
                 let target = jump_addr(self.pc, addr);
                 self.branch_to = Some(target);
                 self.stats.add_cycles(1);
@@ -423,13 +777,13 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
             }
             Instruction::LW(args) => {
                 let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
-                let (val, cycles) = self.mem.peek(addr as u32)?;
+                let (val, cycles) = self.load(addr as u32)?;
                 self.regs[args.rt] = val;
                 self.stats.add_cycles(cycles);
             }
             Instruction::SW(args) => {
                 let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
-                let cycles = self.mem.poke(addr as u32, self.regs[args.rt])?;
+                let cycles = self.store(addr as u32, self.regs[args.rt])?;
                 self.stats.add_cycles(cycles);
             }
             Instruction::OR(args) => {
@@ -448,8 +802,8 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
             Instruction::JALR(args) => {
                 // Note to self:
                 // "(...) is the address of the *second* instruction following the branch (...)"
-                // por isso o + 4
-                self.regs[args.rd] = self.pc + 4;
+                // por isso o + 8
+                self.regs[args.rd] = self.pc + 8;
                 self.branch_to = Some(self.regs[args.rs]);
                 self.stats.add_cycles(1);
             }
@@ -477,18 +831,24 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
                 let a = as_signed(self.regs[args.rs]);
                 let b = as_signed(self.regs[args.rt]);
 
-                self.arith_regs = (as_unsigned(a / b), as_unsigned(a % b));
+                // Divisão por zero é imprevisível no MIPS: deixamos HI/LO como estão.
+                if b != 0 {
+                    self.arith_regs = (
+                        as_unsigned(a.wrapping_div(b)),
+                        as_unsigned(a.wrapping_rem(b)),
+                    );
+                }
                 self.stats.add_cycles(1);
             }
             Instruction::LB(args) => {
                 let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
-                let (val, cycles) = self.mem.peek(addr as u32)?;
+                let (val, cycles) = self.load_partial(addr as u32, 1)?;
                 self.regs[args.rt] = sign_extend(val, 8);
                 self.stats.add_cycles(cycles);
             }
             Instruction::LWC1(args) => {
                 let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
-                let (val, cycles) = self.mem.peek(addr as u32)?;
+                let (val, cycles) = self.load(addr as u32)?;
                 self.float_regs[args.rt.into()] = val;
                 self.stats.add_cycles(cycles);
             }
@@ -501,8 +861,8 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
 
                 let rt: FloatRegister = args.rt.into();
 
-                let (val_lo, cycles_lo) = self.mem.peek(addr as u32)?;
-                let (val_hi, cycles_hi) = self.mem.peek(addr as u32 + 4)?;
+                let (val_lo, cycles_lo) = self.load(addr as u32)?;
+                let (val_hi, cycles_hi) = self.load(addr as u32 + 4)?;
 
                 self.float_regs[rt] = val_lo;
                 self.float_regs[rt + 1] = val_hi;
@@ -621,7 +981,7 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
                 self.stats.add_cycles(1);
             }
             Instruction::SUBU(args) => {
-                self.regs[args.rd] = self.regs[args.rs] - self.regs[args.rt];
+                self.regs[args.rd] = self.regs[args.rs].wrapping_sub(self.regs[args.rt]);
                 self.stats.add_cycles(1);
             }
             Instruction::SRA(args) => {
@@ -636,9 +996,12 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
                 self.stats.add_cycles(1);
             }
             Instruction::BAL(args) => {
-                self.regs[Register(31)] = self.pc + 4;
-                let target = branch_addr(args.imm);
-                self.branch_to = Some((self.pc as i32 + target + 4) as u32);
+                // Também faz o papel da bgezal: o link é incondicional.
+                self.regs[Register(31)] = self.pc + 8;
+                if (self.regs[args.rs] & (1 << 31)) == 0 {
+                    let target = branch_addr(args.imm);
+                    self.branch_to = Some((self.pc as i32 + target + 4) as u32);
+                }
                 self.stats.add_cycles(1);
             }
             Instruction::BGEZ(args) => {
@@ -651,9 +1014,7 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
             }
             Instruction::SWC1(args) => {
                 let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
-                let cycles = self
-                    .mem
-                    .poke(addr as u32, self.float_regs[args.rt.into()])?;
+                let cycles = self.store(addr as u32, self.float_regs[args.rt.into()])?;
                 self.stats.add_cycles(cycles);
             }
             Instruction::C_LT_S(args) => {
@@ -677,17 +1038,344 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
             }
             Instruction::MFC0(args) => {
                 let reg_no = args.rd.0;
-                let sel = word & 0x7; 
+                let sel = args.funct & 0x7;
 
                 self.regs[args.rt] = self.cop0.read_reg(reg_no, sel);
+                self.stats.add_cycles(1);
             }
             Instruction::MTC0(args) => {
                 let reg_no = args.rd.0;
-                let sel = word & 0x7; 
+                let sel = args.funct & 0x7;
+
+                self.cop0.write_reg(reg_no, sel, self.regs[args.rt]);
+                self.stats.add_cycles(1);
+            }
+            Instruction::ERET(_) => {
+                // O eret não tem delay slot: o salto é imediato.
+                self.pc = self.cop0.eret();
+                self.stats.add_cycles(1);
+                return Ok(());
+            }
+            Instruction::WAIT(_) => {
+                // Esperar por uma interrupção que nunca pode chegar trava a
+                // máquina, então a execução para como num limite. Caso
+                // contrário, o wait vira um nop e o programa continua
+                // esperando em loop. Sem dispositivos, só o timer do cop0
+                // pode interromper.
+                let masked = self.cop0.status & cop0::STATUS_IE == 0
+                    || self.cop0.status & (cop0::STATUS_EXL | cop0::STATUS_ERL) != 0;
+                let timer = self.cop0.status & cop0::INT_TIMER != 0;
+                if masked || (self.devices.is_empty() && !timer) {
+                    return Err(Fault::Limit {
+                        pc: self.pc,
+                        limit: Limit::Wait,
+                    }
+                    .into());
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::SUB(args) => {
+                self.regs[args.rd] = self.regs[args.rs].wrapping_sub(self.regs[args.rt]);
+                self.stats.add_cycles(1);
+            }
+            Instruction::NOR(args) => {
+                self.regs[args.rd] = !(self.regs[args.rs] | self.regs[args.rt]);
+                self.stats.add_cycles(1);
+            }
+            Instruction::XORI(args) => {
+                self.regs[args.rt] = self.regs[args.rs] ^ args.imm;
+                self.stats.add_cycles(1);
+            }
+            Instruction::SLTIU(args) => {
+                self.regs[args.rt] = if self.regs[args.rs] < sign_extend(args.imm, 16) {
+                    1
+                } else {
+                    0
+                };
+                self.stats.add_cycles(1);
+            }
+            Instruction::SLLV(args) => {
+                self.regs[args.rd] = self.regs[args.rt] << (self.regs[args.rs] & 31);
+                self.stats.add_cycles(1);
+            }
+            Instruction::SRLV(args) => {
+                self.regs[args.rd] = self.regs[args.rt] >> (self.regs[args.rs] & 31);
+                self.stats.add_cycles(1);
+            }
+            Instruction::SRAV(args) => {
+                let val = as_signed(self.regs[args.rt]) >> (self.regs[args.rs] & 31);
+                self.regs[args.rd] = as_unsigned(val);
+                self.stats.add_cycles(1);
+            }
+            Instruction::MOVZ(args) => {
+                if self.regs[args.rt] == 0 {
+                    self.regs[args.rd] = self.regs[args.rs];
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::MOVN(args) => {
+                if self.regs[args.rt] != 0 {
+                    self.regs[args.rd] = self.regs[args.rs];
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::MTHI(args) => {
+                self.arith_regs.1 = self.regs[args.rs];
+                self.stats.add_cycles(1);
+            }
+            Instruction::MTLO(args) => {
+                self.arith_regs.0 = self.regs[args.rs];
+                self.stats.add_cycles(1);
+            }
+            Instruction::MULTU(args) => {
+                let val = self.regs[args.rs] as u64 * self.regs[args.rt] as u64;
+                self.set_hilo(val);
+                self.stats.add_cycles(1);
+            }
+            Instruction::DIVU(args) => {
+                let a = self.regs[args.rs];
+                let b = self.regs[args.rt];
 
-                 self.cop0.write_reg(reg_no, sel, self.regs[args.rt]);
+                if let (Some(lo), Some(hi)) = (a.checked_div(b), a.checked_rem(b)) {
+                    self.arith_regs = (lo, hi);
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::MUL(args) => {
+                self.regs[args.rd] = self.regs[args.rs].wrapping_mul(self.regs[args.rt]);
+                self.stats.add_cycles(1);
             }
-            a => return Err(eyre!("Instruction {} not implemented yet!", a)),
+            Instruction::MADD(args) => {
+                let prod = as_signed(self.regs[args.rs]) as i64 * as_signed(self.regs[args.rt]) as i64;
+                self.set_hilo(self.hilo().wrapping_add(prod as u64));
+                self.stats.add_cycles(1);
+            }
+            Instruction::MSUB(args) => {
+                let prod = as_signed(self.regs[args.rs]) as i64 * as_signed(self.regs[args.rt]) as i64;
+                self.set_hilo(self.hilo().wrapping_sub(prod as u64));
+                self.stats.add_cycles(1);
+            }
+            Instruction::MADDU(args) => {
+                let prod = self.regs[args.rs] as u64 * self.regs[args.rt] as u64;
+                self.set_hilo(self.hilo().wrapping_add(prod));
+                self.stats.add_cycles(1);
+            }
+            Instruction::MSUBU(args) => {
+                let prod = self.regs[args.rs] as u64 * self.regs[args.rt] as u64;
+                self.set_hilo(self.hilo().wrapping_sub(prod));
+                self.stats.add_cycles(1);
+            }
+            Instruction::CLZ(args) => {
+                self.regs[args.rd] = self.regs[args.rs].leading_zeros();
+                self.stats.add_cycles(1);
+            }
+            Instruction::SEB(args) => {
+                self.regs[args.rd] = sign_extend(self.regs[args.rt] & 0xFF, 8);
+                self.stats.add_cycles(1);
+            }
+            Instruction::SEH(args) => {
+                self.regs[args.rd] = sign_extend(self.regs[args.rt] & 0xFFFF, 16);
+                self.stats.add_cycles(1);
+            }
+            Instruction::WSBH(args) => {
+                let val = self.regs[args.rt];
+                self.regs[args.rd] = ((val & 0x00FF00FF) << 8) | ((val >> 8) & 0x00FF00FF);
+                self.stats.add_cycles(1);
+            }
+            Instruction::EXT(args) => {
+                // O campo precisa caber no registrador: pos + size <= 32.
+                let (pos, size) = (args.shamt, args.rd.0 + 1);
+                if pos + size > 32 {
+                    return Err(Fault::UnknownInstruction { pc: self.pc, word }.into());
+                }
+                let mask = u32::MAX >> (32 - size);
+
+                self.regs[args.rt] = (self.regs[args.rs] >> pos) & mask;
+                self.stats.add_cycles(1);
+            }
+            Instruction::INS(args) => {
+                // Aqui o rd guarda o bit mais significativo (msb) e o
+                // shamt o menos significativo (lsb), com msb >= lsb.
+                let (pos, msb) = (args.shamt, args.rd.0);
+                if msb < pos {
+                    return Err(Fault::UnknownInstruction { pc: self.pc, word }.into());
+                }
+                let mask = (u32::MAX >> (31 - (msb - pos))) << pos;
+
+                self.regs[args.rt] = (self.regs[args.rt] & !mask) | ((self.regs[args.rs] << pos) & mask);
+                self.stats.add_cycles(1);
+            }
+            Instruction::RDHWR(args) => {
+                // Só implementamos os registradores que a libc usa: o
+                // UserLocal ($29), que guarda o ponteiro do TLS, e os
+                // de contador de ciclos.
+                self.regs[args.rt] = match args.rd.0 {
+                    2 => self.stats.cycles() as u32,
+                    3 => 1,
+                    29 => self.syscalls.thread_pointer,
+                    _ => 0,
+                };
+                self.stats.add_cycles(1);
+            }
+            Instruction::BGTZ(args) => {
+                if as_signed(self.regs[args.rs]) > 0 {
+                    let target = branch_addr(args.imm);
+                    self.branch_to = Some((self.pc as i32 + target + 4) as u32);
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::BLTZ(args) => {
+                if as_signed(self.regs[args.rs]) < 0 {
+                    let target = branch_addr(args.imm);
+                    self.branch_to = Some((self.pc as i32 + target + 4) as u32);
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::BLTZAL(args) => {
+                self.regs[Register(31)] = self.pc + 8;
+                if as_signed(self.regs[args.rs]) < 0 {
+                    let target = branch_addr(args.imm);
+                    self.branch_to = Some((self.pc as i32 + target + 4) as u32);
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::LBU(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let (val, cycles) = self.load_partial(addr as u32, 1)?;
+                self.regs[args.rt] = val;
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::LH(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let (val, cycles) = self.load_partial(addr as u32, 2)?;
+                self.regs[args.rt] = sign_extend(val, 16);
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::LHU(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let (val, cycles) = self.load_partial(addr as u32, 2)?;
+                self.regs[args.rt] = val;
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::SB(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let cycles = self.store_partial(addr as u32, self.regs[args.rt], 1)?;
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::SH(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let cycles = self.store_partial(addr as u32, self.regs[args.rt], 2)?;
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::LWL(args) => {
+                // Little-endian: os bytes a partir do endereço vão para a
+                // parte alta do registrador.
+                let addr = (self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16)) as u32;
+                let (word, cycles) = self.load(addr & !3)?;
+                let byte = addr & 3;
+                let keep = u32::MAX.checked_shr(8 * (byte + 1)).unwrap_or(0);
+
+                self.regs[args.rt] = (word << (8 * (3 - byte))) | (self.regs[args.rt] & keep);
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::LWR(args) => {
+                let addr = (self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16)) as u32;
+                let (word, cycles) = self.load(addr & !3)?;
+                let byte = addr & 3;
+                let keep = !(u32::MAX >> (8 * byte));
+
+                self.regs[args.rt] = (word >> (8 * byte)) | (self.regs[args.rt] & keep);
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::SWL(args) => {
+                let addr = (self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16)) as u32;
+                let (word, load_cycles) = self.load(addr & !3)?;
+                let byte = addr & 3;
+                let keep = !(u32::MAX >> (8 * (3 - byte)));
+
+                let word = (word & keep) | (self.regs[args.rt] >> (8 * (3 - byte)));
+                let store_cycles = self.store(addr & !3, word)?;
+                self.stats.add_cycles(load_cycles + store_cycles);
+            }
+            Instruction::SWR(args) => {
+                let addr = (self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16)) as u32;
+                let (word, load_cycles) = self.load(addr & !3)?;
+                let byte = addr & 3;
+                let keep = !(u32::MAX << (8 * byte));
+
+                let word = (word & keep) | (self.regs[args.rt] << (8 * byte));
+                let store_cycles = self.store(addr & !3, word)?;
+                self.stats.add_cycles(load_cycles + store_cycles);
+            }
+            Instruction::LL(args) => {
+                // Não temos múltiplos processadores, então o ll/sc sempre
+                // é bem sucedido.
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let (val, cycles) = self.load(addr as u32)?;
+                self.regs[args.rt] = val;
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::SC(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+                let cycles = self.store(addr as u32, self.regs[args.rt])?;
+                self.regs[args.rt] = 1;
+                self.stats.add_cycles(cycles);
+            }
+            Instruction::SDC1(args) => {
+                let addr = self.regs[args.rs] as i32 + sign_extend_cast(args.imm, 16);
+
+                let rt: FloatRegister = args.rt.into();
+
+                let cycles_lo = self.store(addr as u32, self.float_regs[rt])?;
+                let cycles_hi = self.store(addr as u32 + 4, self.float_regs[rt + 1])?;
+
+                self.stats.add_cycles(cycles_lo);
+                self.stats.add_cycles(cycles_hi);
+            }
+            Instruction::SYNC(_) | Instruction::PREF(_) => {
+                self.stats.add_cycles(1);
+            }
+            Instruction::TEQ(args) => {
+                if self.regs[args.rs] == self.regs[args.rt] {
+                    return Err(Fault::Trap {
+                        pc: self.pc,
+                        instr: "teq",
+                    }
+                    .into());
+                }
+                self.stats.add_cycles(1);
+            }
+            Instruction::SDBBP(_) if self.bare && (word >> 6) & 0xFFFFF == uhi::UHI_CODE => {
+                // Uma chamada de semihosting: o emulador faz o papel do
+                // debugger.
+                self.uhi_call()?;
+                self.stats.add_cycles(1);
+            }
+            Instruction::SDBBP(_) => {
+                return Err(Fault::Trap {
+                    pc: self.pc,
+                    instr: "sdbbp",
+                }
+                .into());
+            }
+            Instruction::BREAK(_) => {
+                return Err(Fault::Trap {
+                    pc: self.pc,
+                    instr: "break",
+                }
+                .into());
+            }
+            a => {
+                return Err(Fault::Unimplemented {
+                    pc: self.pc,
+                    instr: a.to_string(),
+                }
+                .into())
+            }
+        }
+
+        if let Some(bitmap) = self.bitmap.as_mut() {
+            bitmap.tick(&self.mem)?;
         }
 
         // TODO `branch_to.is_some()` é invariante, tirar depois
@@ -702,13 +1390,151 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
         Ok(())
     }
 
-    /// Inicia a execução e continua até que ocorra um erro ou a syscall de
-    /// parada seja chamada.
-    pub fn run(&mut self) -> Result<()> {
+    /// Termina a execução com o status `status`.
+    fn exit(&mut self, status: i32) {
+        self.halt = true;
+        self.exit_status = status;
+        self.stats.finish();
+    }
+
+    /// Retorna o limite de execução atingido, se algum foi.
+    fn limit_reached(&self) -> Option<Limit> {
+        let instructions = self.stats.instructions();
+
+        match self.limits.instructions {
+            Some(max) if instructions >= max => return Some(Limit::Instructions(max)),
+            _ => {}
+        }
+
+        match self.limits.cycles {
+            Some(max) if self.stats.cycles() >= max => return Some(Limit::Cycles(max)),
+            _ => {}
+        }
+
+        // Ler o relógio a cada instrução sairia caro, então o tempo só é
+        // conferido de tempos em tempos.
+        match self.limits.timeout {
+            Some(timeout) if instructions & 0xFFF == 0 && self.timed_out() => {
+                Some(Limit::Timeout(timeout))
+            }
+            _ => None,
+        }
+    }
+
+    /// Retorna `true` se o tempo de execução já acabou.
+    fn timed_out(&self) -> bool {
+        self.deadline
+            .is_some_and(|deadline| Instant::now() >= deadline)
+    }
+
+    /// Retorna `true` se `err` é uma espera pela entrada, ou um `sleep`,
+    /// interrompida pelo fim do tempo de execução.
+    fn cut_by_timeout(&self, err: &Report) -> bool {
+        let timed_out = err.downcast_ref::<io::Error>().map(io::Error::kind);
+        timed_out == Some(io::ErrorKind::TimedOut) && self.timed_out()
+    }
+
+    /// Interrompe a execução por ter atingido `limit`: imprime as
+    /// estatísticas parciais e retorna o erro com o `Fault`.
+    fn stop_at_limit(&mut self, limit: Limit) -> Result<i32> {
+        self.stats.interrupt(limit.to_string());
+        self.syscalls.console.flush()?;
+        self.print_report()?;
+
+        Err(self.locate_fault(Fault::Limit { pc: self.pc, limit }.into()))
+    }
+
+    /// Inicia a execução e continua até que ocorra um erro, um limite de
+    /// execução seja atingido ou a syscall de parada seja chamada. Retorna
+    /// o status de saída pedido pelo programa.
+    ///
+    /// Se o programa fizer algo errado, o erro contém um `Fault`. Quando
+    /// um limite é atingido, as estatísticas parciais são impressas antes.
+    pub fn run(&mut self) -> Result<i32> {
         self.stats.start();
+        self.deadline = self
+            .limits
+            .timeout
+            .and_then(|timeout| Instant::now().checked_add(timeout));
+        self.syscalls.console.set_deadline(self.deadline);
 
         while !self.halt {
-            self.cycle()?;
+            if let Some((mut snapshot, path)) = self.snapshots.due(self.stats.instructions()) {
+                self.save_snapshot(&mut snapshot);
+                snapshot.write(&path)?;
+                eprintln!(
+                    "indy: saved a snapshot after {} instructions to {}",
+                    self.stats.instructions(),
+                    path
+                );
+            }
+
+            if let Some(limit) = self.limit_reached() {
+                return self.stop_at_limit(limit);
+            }
+
+            if let Err(e) = self.cycle() {
+                // Um wait que nunca termina, ou uma syscall que esperava
+                // entrada (ou dormia) quando o tempo acabou.
+                let limit = match e.downcast_ref::<Fault>() {
+                    Some(Fault::Limit { limit, .. }) => Some(*limit),
+                    _ => self
+                        .limits
+                        .timeout
+                        .filter(|_| self.cut_by_timeout(&e))
+                        .map(Limit::Timeout),
+                };
+                if let Some(limit) = limit {
+                    return self.stop_at_limit(limit);
+                }
+
+                // O que o programa escreveu até aqui não pode se perder.
+                self.syscalls.console.flush()?;
+
+                // Um erro ao escrever o core dump não esconde a falha.
+                if let (Some((path, args)), Some(fault)) =
+                    (&self.core_file, e.downcast_ref::<Fault>())
+                {
+                    match self.write_core(path, args, fault) {
+                        Ok(()) => eprintln!("indy: core dumped to {}", path),
+                        Err(err) => eprintln!("indy: could not write the core dump: {}", err),
+                    }
+                }
+
+                return Err(self.locate_fault(e));
+            }
+        }
+
+        self.syscalls.console.flush()?;
+        self.print_report()?;
+
+        Ok(self.exit_status)
+    }
+
+    /// Diz em que função (e linha do código fonte) do programa aconteceu
+    /// uma falha, se os símbolos permitirem. Erros do host passam direto.
+    fn locate_fault(&self, report: Report) -> Report {
+        if !report.is::<Fault>() {
+            return report;
+        }
+
+        let line = self.symbols.line(self.pc);
+        let mut msg = match self.symbols.locate(self.pc) {
+            Some(loc) => format!("in {} at {:#010x}", loc, self.pc),
+            None if line.is_some() => format!("at {:#010x}", self.pc),
+            None => return report,
+        };
+        if let Some(line) = line {
+            msg += &format!(", {}", line);
+        }
+
+        report.wrap_err(msg)
+    }
+
+    /// Imprime as estatísticas de execução e de cada nível de memória.
+    fn print_report(&mut self) -> Result<()> {
+        if let Some(bitmap) = self.bitmap.as_mut() {
+            bitmap.capture_last(&self.mem)?;
         }
 
         self.stats.print_stats()?;
@@ -727,3 +1553,363 @@ impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::abi::Process;
+    use crate::emulator::board::Board;
+    use crate::emulator::memory::Ram;
+
+    /// Executa a instrução `word` com `$t1` = `t1` e `$t0` = `t0`.
+    /// Retorna o valor final de `$t0`.
+    fn execute(word: u32, t0: u32, t1: u32) -> Result<u32> {
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        unsafe { (*imem.get()).poke(0x00400000, word)? };
+
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        cpu.regs[Register(8)] = t0;
+        cpu.regs[Register(9)] = t1;
+        cpu.cycle()?;
+
+        Ok(cpu.regs[Register(8)])
+    }
+
+    /// Monta um `ext`/`ins $t0, $t1` com os campos `rd` e `shamt` dados.
+    fn bitfield(funct: u32, rd: u32, shamt: u32) -> u32 {
+        31 << 26 | 9 << 21 | 8 << 16 | rd << 11 | shamt << 6 | funct
+    }
+
+    /// Memórias com o programa `program` montado a partir de 0x00400000.
+    fn memories(program: &[&str]) -> (UnsafeCell<Ram>, UnsafeCell<Ram>) {
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        for (i, text) in program.iter().enumerate() {
+            let word = Instruction::parse(text).unwrap().encode();
+            unsafe { (*imem.get()).poke(0x00400000 + 4 * i as u32, word).unwrap() };
+        }
+        (mem, imem)
+    }
+
+    /// Uma CPU que roda o programa em `memories`.
+    fn cpu_with(memories: &(UnsafeCell<Ram>, UnsafeCell<Ram>)) -> Cpu<'_, Ram, Ram> {
+        Cpu::new(&memories.0, &memories.1, 0x00400000, 0x7FFFEFFC, 0x10008000)
+    }
+
+    /// Soma 10 + 9 + ... + 1 em `$t0`, guardando cada soma parcial em
+    /// 0($gp), e sai.
+    const SOMA: &[&str] = &[
+        "addiu $t1, $zero, 10",
+        "addu $t0, $t0, $t1",
+        "sw $t0, 0($gp)",
+        "addiu $t1, $t1, -1",
+        "bne $t1, $zero, -4",
+        "nop",
+        "addiu $v0, $zero, 10",
+        "syscall",
+    ];
+
+    fn is_unknown_instruction(result: Result<u32>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<Fault>(),
+            Some(Fault::UnknownInstruction { .. })
+        )
+    }
+
+    #[test]
+    fn ext() {
+        // ext $t0, $t1, 4, 8
+        assert_eq!(execute(bitfield(0, 7, 4), 0, 0x12345678).unwrap(), 0x67);
+        // ext $t0, $t1, 0, 32
+        assert_eq!(execute(bitfield(0, 31, 0), 0, 0x12345678).unwrap(), 0x12345678);
+        // pos + size > 32.
+        assert!(is_unknown_instruction(execute(bitfield(0, 7, 28), 0, 0)));
+    }
+
+    #[test]
+    fn ins() {
+        // ins $t0, $t1, 4, 8
+        assert_eq!(execute(bitfield(4, 11, 4), 0xFFFFFFFF, 0xAB).unwrap(), 0xFFFFFABF);
+        // ins $t0, $t1, 0, 32
+        assert_eq!(execute(bitfield(4, 31, 0), 0, 0x12345678).unwrap(), 0x12345678);
+        // msb < lsb.
+        assert!(is_unknown_instruction(execute(bitfield(4, 3, 4), 0, 0)));
+    }
+
+    /// Escreve `val` no registrador `n`, seleção `sel`, do cop0 com um
+    /// `mtc0`, e retorna o que um `mfc0` logo depois lê dele.
+    fn mtc0_mfc0(n: u32, sel: u32, val: u32) -> Result<u32> {
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        let mtc0 = Instruction::parse(&format!("mtc0 $t1, ${}, {}", n, sel))?;
+        let mfc0 = Instruction::parse(&format!("mfc0 $t0, ${}, {}", n, sel))?;
+        unsafe {
+            (*imem.get()).poke(0x00400000, mtc0.encode())?;
+            (*imem.get()).poke(0x00400004, mfc0.encode())?;
+        }
+
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        cpu.regs[Register(9)] = val;
+        cpu.cycle()?;
+        cpu.cycle()?;
+
+        Ok(cpu.regs[Register(8)])
+    }
+
+    #[test]
+    fn mtc0_e_mfc0_em_todos_os_registradores() {
+        let val = 0x1234_0000;
+
+        for n in 0..32 {
+            for sel in 0..8 {
+                let read =
+                    mtc0_mfc0(n, sel, val).unwrap_or_else(|e| panic!("${}, {}: {}", n, sel, e));
+                let expected = match (n, sel) {
+                    // O Count avança a cada ciclo.
+                    (9, 0) => val + 1,
+                    // Os que não existem são lidos como zero.
+                    (7, _) | (9, 6..=7) | (11, 6..=7) | (20..=22, _) => 0,
+                    (16, 2) | (28, 1) | (29, 1) => val,
+                    _ => continue,
+                };
+                assert_eq!(read, expected, "${}, {}", n, sel);
+            }
+        }
+    }
+
+    #[test]
+    fn o_timer_do_cop0_interrompe_quando_o_count_chega_no_compare() {
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        cpu.reset();
+        cpu.cop0.status = cop0::STATUS_IE | cop0::INT_TIMER;
+        cpu.cop0.compare = 3;
+
+        for _ in 0..3 {
+            cpu.cycle().unwrap();
+        }
+
+        assert_ne!(cpu.cop0.cause & cop0::CAUSE_TI, 0);
+        assert_eq!(cpu.cop0.epc, 0x00400008);
+        assert_eq!(cpu.pc, 0x80000184);
+
+        // Escrever no Compare reconhece a interrupção.
+        cpu.cop0.write_reg(11, 0, 0);
+        assert_eq!(cpu.cop0.cause & (cop0::CAUSE_TI | cop0::INT_TIMER), 0);
+    }
+
+    #[test]
+    fn core_dump_de_um_programa_que_falhou() {
+        let memories = memories(&["sw $t1, 0($t0)", "lw $t2, 2($t0)"]);
+
+        let path = std::env::temp_dir().join(format!("indy-core-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+        let mut cpu = cpu_with(&memories);
+        cpu.set_core_file(path.clone(), vec!["prog".to_string()]);
+        cpu.regs[Register(8)] = 0x10010000;
+        cpu.regs[Register(9)] = 0xCAFEBABE;
+
+        // O lw desalinhado mata o programa.
+        let err = cpu.run().unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Fault>(),
+            Some(Fault::AddressError(0x10010002))
+        ));
+
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let elf = goblin::elf::Elf::parse(&bytes).unwrap();
+        assert_eq!(elf.header.e_type, goblin::elf::header::ET_CORE);
+
+        // O EPC no NT_PRSTATUS é o do lw, e o BadVAddr é o endereço dele.
+        let prstatus = elf
+            .iter_note_headers(&bytes)
+            .unwrap()
+            .map(|note| note.unwrap())
+            .find(|note| note.n_type == goblin::elf::note::NT_PRSTATUS)
+            .unwrap();
+        let greg = |i: usize| {
+            let at = 72 + 4 * i;
+            u32::from_le_bytes(prstatus.desc[at..at + 4].try_into().unwrap())
+        };
+        assert_eq!(greg(6 + 9), 0xCAFEBABE);
+        assert_eq!(greg(40), 0x00400004);
+        assert_eq!(greg(41), 0x10010002);
+
+        // A memória que o sw tocou está em algum PT_LOAD.
+        let load = elf
+            .program_headers
+            .iter()
+            .filter(|ph| ph.p_type == goblin::elf::program_header::PT_LOAD)
+            .find(|ph| ph.vm_range().contains(&0x10010000))
+            .unwrap();
+        let at = (load.p_offset + 0x10010000 - load.p_vaddr) as usize;
+        assert_eq!(bytes[at..at + 4], 0xCAFEBABEu32.to_le_bytes());
+    }
+
+    #[test]
+    fn snapshot_vai_e_volta() {
+        let path = std::env::temp_dir().join(format!("indy-snapshot-{}", std::process::id()));
+        let path = path.to_str().unwrap().to_string();
+
+        // A execução inteira, com um snapshot no meio do laço.
+        let memories_inteira = memories(SOMA);
+        let mut inteira = cpu_with(&memories_inteira);
+        inteira.set_snapshot_plan(SnapshotPlan::new(vec![12], path.clone()));
+        assert_eq!(inteira.run().unwrap(), 0);
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // O snapshot tem o estado de uma execução parada no mesmo ponto.
+        let memories_parada = memories(SOMA);
+        let mut parada = cpu_with(&memories_parada);
+        parada.set_limits(Limits {
+            instructions: Some(12),
+            ..Limits::default()
+        });
+        assert!(parada.run().is_err());
+
+        let memories_retomada = memories(SOMA);
+        let mut retomada = cpu_with(&memories_retomada);
+        retomada.restore_snapshot(&bytes).unwrap();
+        assert_eq!(retomada.pc, parada.pc);
+        assert_eq!(retomada.regs.0, parada.regs.0);
+        assert_eq!(retomada.stats.instructions(), 12);
+        assert_eq!(
+            retomada.mem.inspect(0x10008000).unwrap(),
+            parada.mem.inspect(0x10008000).unwrap()
+        );
+
+        // E continuar dele chega no mesmo fim.
+        assert_eq!(retomada.run().unwrap(), 0);
+        assert_eq!(retomada.pc, inteira.pc);
+        assert_eq!(retomada.regs.0, inteira.regs.0);
+        assert_eq!(retomada.regs[Register(8)], 55);
+        assert_eq!(retomada.stats.instructions(), inteira.stats.instructions());
+        assert_eq!(retomada.mem.inspect(0x10008000).unwrap(), 55);
+    }
+
+    /// Roda `program` com os limites `limits`, que devem interrompê-lo.
+    /// Retorna o limite atingido e quantas instruções foram executadas.
+    fn run_with_limits(program: &[&str], limits: Limits) -> (Limit, usize) {
+        let memories = memories(program);
+        let mut cpu = cpu_with(&memories);
+        cpu.set_limits(limits);
+
+        let err = cpu.run().unwrap_err();
+        let fault = err.downcast_ref::<Fault>().unwrap();
+        // É o que faz o indy sair com 124.
+        assert_eq!(fault.signal(), None);
+        match fault {
+            Fault::Limit { limit, .. } => (*limit, cpu.stats.instructions()),
+            fault => panic!("{}", fault),
+        }
+    }
+
+    #[test]
+    fn limites_de_execucao() {
+        let laco = &["beq $zero, $zero, -1", "nop"];
+
+        let limits = Limits {
+            instructions: Some(100),
+            ..Limits::default()
+        };
+        assert_eq!(
+            run_with_limits(laco, limits),
+            (Limit::Instructions(100), 100)
+        );
+
+        let limits = Limits {
+            cycles: Some(50),
+            ..Limits::default()
+        };
+        assert_eq!(run_with_limits(laco, limits).0, Limit::Cycles(50));
+
+        let timeout = Duration::from_millis(50);
+        let limits = Limits {
+            timeout: Some(timeout),
+            ..Limits::default()
+        };
+        assert_eq!(run_with_limits(laco, limits).0, Limit::Timeout(timeout));
+
+        // Um sleep mais longo que o tempo que resta também é cortado.
+        let dorme = &["addiu $a0, $zero, 30000", "addiu $v0, $zero, 32", "syscall"];
+        let started = Instant::now();
+        assert_eq!(run_with_limits(dorme, limits), (Limit::Timeout(timeout), 3));
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn o_timer_da_placa_interrompe_pelo_ip2() {
+        // Liga a linha do timer no controlador, e o timer para bater em 5.
+        let memories = memories(&[
+            "lui $t0, 48896",
+            "addiu $t1, $zero, 2",
+            "sw $t1, 516($t0)",
+            "addiu $t1, $zero, 5",
+            "sw $t1, 260($t0)",
+            "addiu $t1, $zero, 3",
+            "sw $t1, 264($t0)",
+        ]);
+        let mut cpu = cpu_with(&memories);
+        cpu.reset();
+        Board::by_name("indy-dev").unwrap().attach(&mut cpu);
+        cpu.cop0.status = cop0::STATUS_IE | 1 << 10;
+
+        while cpu.pc < 0x80000000 {
+            cpu.cycle().unwrap();
+        }
+        // O IP2 do Cause vem das linhas dos dispositivos.
+        let cause = cpu.cop0.read_reg(13, 0);
+        assert_eq!(cause & 0xFF00, 1 << 10);
+        assert_eq!(cause & cop0::CAUSE_EXC_CODE, 0);
+        // O ciclo que entra na exceção já executa a primeira instrução do
+        // handler.
+        assert_eq!(cpu.pc, 0x80000184);
+        assert!((0x0040001c..0x00400040).contains(&cpu.cop0.epc));
+
+        // Reconhecer a interrupção no timer baixa a linha.
+        let timer = cpu.devices.find(0x1F000100).unwrap();
+        timer.write(0xc, 1, &mut *cpu.syscalls.console).unwrap();
+        cpu.cycle().unwrap();
+        assert_eq!(cpu.cop0.read_reg(13, 0) & 0xFF00, 0);
+    }
+
+    #[test]
+    fn o_start_do_spim_chama_a_main_com_os_argumentos() {
+        let mut process = Process::new(0x00400100, "prog");
+        process.args.push("a".to_string());
+        process.env.push("HOME=/".to_string());
+
+        // A main que só guarda o argc e retorna.
+        let mut ram = Ram::new(0);
+        let main = ["addu $v1, $a0, $zero", "jr $ra", "nop"];
+        for (i, text) in main.iter().enumerate() {
+            let word = Instruction::parse(text).unwrap().encode();
+            ram.poke(0x00400100 + 4 * i as u32, word).unwrap();
+        }
+        let mut rng = StdRng::seed_from_u64(0);
+        let init = Abi::Spim.setup(&mut ram, &process, &mut rng).unwrap();
+
+        let mem = UnsafeCell::new(ram);
+        let mut cpu = Cpu::new(&mem, &mem, init.pc, init.sp, init.gp);
+        cpu.set_abi(Abi::Spim);
+        assert_eq!(cpu.run().unwrap(), 0);
+
+        // O argv e o envp ficam logo acima do argc.
+        assert_eq!(cpu.regs[Register(3)], 2);
+        assert_eq!(cpu.regs[Register(5)], init.sp + 4);
+        assert_eq!(cpu.regs[Register(6)], init.sp + 16);
+    }
+
+    #[test]
+    fn wait_sem_interrupcoes() {
+        // Sem dispositivos, nada pode acordar o wait.
+        let err = execute(0x42000020, 0, 0).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Fault>(),
+            Some(Fault::Limit {
+                limit: Limit::Wait,
+                ..
+            })
+        ));
+    }
+}
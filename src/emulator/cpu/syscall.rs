@@ -0,0 +1,970 @@
+//! The MARS and SPIM syscall sets.
+//!
+//! The service number comes in `$v0` and the arguments in `$a0`-`$a3`
+//! (or `$f12` for floating point values), as described in the MARS
+//! help. The dialog services (50-59) have no GUI to show here, so they
+//! are degraded to prompts on the console.
+//!
+//! SPIM only implements services 1 to 17, which mostly behave like the
+//! MARS ones. The exception is `open`, which takes the flags and mode
+//! of the host `open(2)`.
+
+use super::{
+    as_signed, as_unsigned, double_to_dword, dword_to_double, single_to_word, word_to_single, Cpu,
+};
+use crate::emulator::console::{Console, StreamConsole};
+use crate::emulator::fault::Fault;
+use crate::emulator::memory::Memory;
+use crate::emulator::snapshot::{SnapshotReader, SnapshotWriter};
+use crate::emulator::vfs::{OpenOptions, Vfs, VfsFile};
+use crate::emulator::{Abi, FloatRegister, Register};
+
+use std::collections::HashMap;
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use color_eyre::eyre::{eyre, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// Where the heap starts, same as in MARS.
+const HEAP_BASE: u32 = 0x10040000;
+
+/// The guest time at the start of a deterministic run: 2021-01-01 UTC.
+const VIRTUAL_EPOCH: Duration = Duration::from_secs(1_609_459_200);
+
+/// Where the Linux `mmap` starts handing out memory.
+const MMAP_BASE: u32 = 0x60000000;
+
+/// The most bytes a single read or write moves. Longer requests are cut
+/// short, which the guest must be ready for anyway.
+pub(super) const MAX_TRANSFER: u32 = 1 << 20;
+
+/// State kept by the emulator on behalf of the guest across syscalls.
+pub(super) struct SyscallState {
+    /// Whose syscall table to follow.
+    pub(super) abi: Abi,
+    /// Where the heap starts.
+    pub(super) heap_start: u32,
+    /// The current end of the heap, moved by `sbrk` (or `brk` on Linux).
+    pub(super) heap_end: u32,
+    /// The end of the region handed out by the Linux `mmap`.
+    pub(super) mmap_end: u32,
+    /// The thread pointer set by the Linux `set_thread_area`, read back
+    /// with `rdhwr $29`.
+    pub(super) thread_pointer: u32,
+    /// Where the console syscalls read and write.
+    pub(super) console: Box<dyn Console>,
+    /// The filesystem the guest opens files in.
+    pub(super) vfs: Vfs,
+    /// The files opened by the guest, indexed by their descriptors.
+    pub(super) files: HashMap<u32, VfsFile>,
+    /// The next file descriptor to be handed out.
+    next_fd: u32,
+    /// The program name and arguments, for programs that ask the host
+    /// for them (UHI).
+    pub(super) args: Vec<String>,
+    /// The random number generators, indexed by their ids.
+    rngs: HashMap<u32, StdRng>,
+    /// Where every other random value the guest sees comes from,
+    /// including the seeds of the generators it does not seed itself.
+    pub(super) entropy: StdRng,
+    /// Whether the guest sees the simulated time instead of the host one.
+    pub(super) virtual_time: bool,
+    /// How long the guest slept, in simulated time.
+    slept: Duration,
+}
+
+impl SyscallState {
+    pub(super) fn new() -> SyscallState {
+        SyscallState {
+            abi: Abi::Mars,
+            heap_start: HEAP_BASE,
+            heap_end: HEAP_BASE,
+            mmap_end: MMAP_BASE,
+            thread_pointer: 0,
+            console: Box::new(StreamConsole::host()),
+            vfs: Vfs::default(),
+            files: HashMap::new(),
+            next_fd: 3,
+            args: Vec::new(),
+            rngs: HashMap::new(),
+            entropy: StdRng::from_entropy(),
+            virtual_time: false,
+            slept: Duration::default(),
+        }
+    }
+
+    /// Saves what the guest sees of this state to `snapshot`: the heap,
+    /// the random generators and the simulated time. The open files, the
+    /// console and the filesystem belong to the host, and are not saved.
+    pub(super) fn save(&mut self, snapshot: &mut SnapshotWriter) {
+        if !self.files.is_empty() {
+            log::warn!(
+                "snapshot: the guest has {} files open, they will be closed when it resumes",
+                self.files.len()
+            );
+        }
+
+        snapshot.section("syscalls");
+
+        snapshot.str(match self.abi {
+            Abi::Mars => "mars",
+            Abi::Spim => "spim",
+            Abi::Linux => "linux",
+        });
+        snapshot.u32(self.heap_start);
+        snapshot.u32(self.heap_end);
+        snapshot.u32(self.mmap_end);
+        snapshot.u32(self.thread_pointer);
+
+        snapshot.usize(self.args.len());
+        for arg in self.args.iter() {
+            snapshot.str(arg);
+        }
+
+        snapshot.rng(&mut self.entropy);
+        let mut ids: Vec<u32> = self.rngs.keys().copied().collect();
+        ids.sort_unstable();
+        snapshot.usize(ids.len());
+        for id in ids {
+            snapshot.u32(id);
+            snapshot.rng(self.rngs.get_mut(&id).unwrap());
+        }
+
+        snapshot.bool(self.virtual_time);
+        snapshot.u64(self.slept.as_nanos() as u64);
+    }
+
+    /// Restores the state saved by `save`. The files the guest had open
+    /// are closed.
+    pub(super) fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<()> {
+        snapshot.section("syscalls")?;
+
+        self.abi = snapshot.str()?.parse()?;
+        self.heap_start = snapshot.u32()?;
+        self.heap_end = snapshot.u32()?;
+        self.mmap_end = snapshot.u32()?;
+        self.thread_pointer = snapshot.u32()?;
+
+        self.args = (0..snapshot.usize()?)
+            .map(|_| snapshot.str())
+            .collect::<Result<_>>()?;
+
+        self.entropy = snapshot.rng()?;
+        self.rngs.clear();
+        for _ in 0..snapshot.usize()? {
+            let id = snapshot.u32()?;
+            self.rngs.insert(id, snapshot.rng()?);
+        }
+
+        self.virtual_time = snapshot.bool()?;
+        self.slept = Duration::from_nanos(snapshot.u64()?);
+
+        self.files.clear();
+        self.next_fd = 3;
+
+        Ok(())
+    }
+
+    /// Returns the generator with the given id, creating it with a random
+    /// seed if it does not exist yet.
+    fn rng(&mut self, id: u32) -> &mut StdRng {
+        let entropy = &mut self.entropy;
+        self.rngs
+            .entry(id)
+            .or_insert_with(|| StdRng::seed_from_u64(entropy.gen()))
+    }
+}
+
+/// The `open(2)` flags taken by the SPIM `open`, as defined by Linux.
+const O_ACCMODE: u32 = 0o3;
+const O_WRONLY: u32 = 0o1;
+const O_RDWR: u32 = 0o2;
+const O_CREAT: u32 = 0o100;
+const O_TRUNC: u32 = 0o1000;
+const O_APPEND: u32 = 0o2000;
+
+/// The MARS input dialogs report their status in `$a1`.
+const DIALOG_OK: i32 = 0;
+const DIALOG_INVALID: i32 = -1;
+const DIALOG_EMPTY: i32 = -3;
+
+impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
+    /// Reads the null-terminated string at `addr` from the data memory.
+    pub(super) fn read_string(&mut self, addr: u32) -> Result<Vec<u8>> {
+        let mut addr = addr;
+        let mut byte_offset = addr % 4;
+        addr -= byte_offset;
+
+        let mut string = Vec::new();
+
+        loop {
+            let (val, cycles) = self.load(addr)?;
+            self.stats.add_cycles(cycles);
+
+            for c in val.to_le_bytes().iter().skip(byte_offset as usize) {
+                if *c == 0 {
+                    return Ok(string);
+                }
+                string.push(*c);
+            }
+
+            addr = addr.checked_add(4).ok_or(Fault::BusError(addr))?;
+            byte_offset = 0;
+        }
+    }
+
+    /// Reads `len` bytes starting at `addr` from the data memory.
+    pub(super) fn read_bytes(&mut self, addr: u32, len: u32) -> Result<Vec<u8>> {
+        // The addresses are computed in 64 bits, so that a buffer ending
+        // at the top of memory does not overflow them.
+        let end = addr as u64 + len as u64;
+        if end > 1 << 32 {
+            return Err(Fault::BusError(addr).into());
+        }
+
+        let mut bytes = Vec::with_capacity(len as usize);
+        let mut word_addr = (addr & !3) as u64;
+
+        while word_addr < end {
+            let (val, cycles) = self.load(word_addr as u32)?;
+            self.stats.add_cycles(cycles);
+
+            for (i, c) in val.to_le_bytes().iter().enumerate() {
+                let byte_addr = word_addr + i as u64;
+                if byte_addr >= addr as u64 && byte_addr < end {
+                    bytes.push(*c);
+                }
+            }
+
+            word_addr += 4;
+        }
+
+        Ok(bytes)
+    }
+
+    /// Writes `bytes` to the data memory, starting at `addr`.
+    pub(super) fn write_bytes(&mut self, addr: u32, bytes: &[u8]) -> Result<()> {
+        // Like in `read_bytes`, the addresses are computed in 64 bits.
+        let (start, end) = (addr as u64, addr as u64 + bytes.len() as u64);
+        if end > 1 << 32 {
+            return Err(Fault::BusError(addr).into());
+        }
+
+        let mut word_addr = start & !3;
+
+        while word_addr < end {
+            let mut val = if word_addr >= start && word_addr + 4 <= end {
+                // The whole word is overwritten, no need to read it.
+                [0; 4]
+            } else {
+                let (val, cycles) = self.load(word_addr as u32)?;
+                self.stats.add_cycles(cycles);
+                val.to_le_bytes()
+            };
+
+            for (i, c) in val.iter_mut().enumerate() {
+                let byte_addr = word_addr + i as u64;
+                if byte_addr >= start && byte_addr < end {
+                    *c = bytes[(byte_addr - start) as usize];
+                }
+            }
+
+            let cycles = self.store(word_addr as u32, u32::from_le_bytes(val))?;
+            self.stats.add_cycles(cycles);
+
+            word_addr += 4;
+        }
+
+        Ok(())
+    }
+
+    /// Writes `val` to `$f0`, as a single.
+    fn return_single(&mut self, val: f32) {
+        self.float_regs[FloatRegister(0)] = single_to_word(val);
+    }
+
+    /// Writes `val` to `$f0` and `$f1`, as a double.
+    fn return_double(&mut self, val: f64) {
+        let (lo, hi) = double_to_dword(val);
+
+        self.float_regs[FloatRegister(0)] = lo;
+        self.float_regs[FloatRegister(1)] = hi;
+    }
+
+    /// Returns `$f12` as a single.
+    fn single_arg(&self) -> f32 {
+        word_to_single(self.float_regs[FloatRegister(12)])
+    }
+
+    /// Returns the time since the epoch seen by the guest. In deterministic
+    /// runs it only depends on the cycles executed and the time slept.
+    pub(super) fn now(&self) -> Result<Duration> {
+        if self.syscalls.virtual_time {
+            Ok(VIRTUAL_EPOCH + self.stats.simulated_time() + self.syscalls.slept)
+        } else {
+            Ok(SystemTime::now().duration_since(UNIX_EPOCH)?)
+        }
+    }
+
+    /// Sleeps for `time`, which only advances the guest clock in
+    /// deterministic runs. Fails with `TimedOut` if the run has a timeout
+    /// and it ends first.
+    fn sleep(&mut self, time: Duration) -> Result<()> {
+        if self.syscalls.virtual_time {
+            self.syscalls.slept += time;
+            return Ok(());
+        }
+
+        // The timeout of the run may come first.
+        match self.deadline {
+            Some(deadline) if Instant::now() + time >= deadline => {
+                std::thread::sleep(deadline.saturating_duration_since(Instant::now()));
+                Err(io::Error::from(io::ErrorKind::TimedOut).into())
+            }
+            _ => {
+                std::thread::sleep(time);
+                Ok(())
+            }
+        }
+    }
+
+    /// Writes `val` to the console.
+    fn print(&mut self, val: impl std::fmt::Display) -> Result<()> {
+        self.syscalls.console.write(val.to_string().as_bytes())?;
+        Ok(())
+    }
+
+    /// Shows `msg` and reads the answer, like the MARS input dialogs.
+    fn prompt(&mut self, msg: &[u8]) -> Result<String> {
+        self.syscalls.console.write(msg)?;
+        Ok(self.syscalls.console.read_line()?)
+    }
+
+    /// Returns `$f12` and `$f13` as a double.
+    fn double_arg(&self) -> f64 {
+        dword_to_double(
+            self.float_regs[FloatRegister(12)],
+            self.float_regs[FloatRegister(13)],
+        )
+    }
+
+    /// Opens `name` with the MARS `flags`: 0 is read-only, 1 is write-only
+    /// (creating or truncating the file) and 9 is write-only appending.
+    /// Returns the new descriptor, or -1 on error.
+    fn open_file(&mut self, name: &[u8], flags: u32) -> i32 {
+        let options = match flags {
+            0 => OpenOptions {
+                read: true,
+                ..Default::default()
+            },
+            1 => OpenOptions {
+                write: true,
+                create: true,
+                truncate: true,
+                ..Default::default()
+            },
+            9 => OpenOptions {
+                append: true,
+                create: true,
+                ..Default::default()
+            },
+            _ => return -1,
+        };
+
+        self.open_with(name, &options).map(|fd| fd as i32).unwrap_or(-1)
+    }
+
+    /// Opens `name` with the `open(2)` `flags` and `mode`, like SPIM does.
+    /// Returns the new descriptor, or -1 on error.
+    fn open_file_spim(&mut self, name: &[u8], flags: u32, mode: u32) -> i32 {
+        let accmode = flags & O_ACCMODE;
+        let options = OpenOptions {
+            read: accmode != O_WRONLY,
+            write: accmode == O_WRONLY || accmode == O_RDWR,
+            append: flags & O_APPEND != 0,
+            create: flags & O_CREAT != 0,
+            truncate: flags & O_TRUNC != 0,
+            mode,
+            ..Default::default()
+        };
+
+        self.open_with(name, &options).map(|fd| fd as i32).unwrap_or(-1)
+    }
+
+    /// Opens `name` in the guest filesystem with `options`, handing out
+    /// a new descriptor.
+    pub(super) fn open_with(&mut self, name: &[u8], options: &OpenOptions) -> io::Result<u32> {
+        match self.syscalls.vfs.open(name, options) {
+            Ok(file) => {
+                let fd = self.syscalls.next_fd;
+                self.syscalls.next_fd += 1;
+                self.syscalls.files.insert(fd, file);
+                Ok(fd)
+            }
+            Err(e) => {
+                log::debug!(
+                    "syscall: failed to open {}: {}",
+                    String::from_utf8_lossy(name),
+                    e
+                );
+                Err(e)
+            }
+        }
+    }
+
+    /// Reads up to `len` bytes from the descriptor `fd` into `buf`. Returns
+    /// the number of bytes read, or -1 on error.
+    fn read_file(&mut self, fd: u32, buf: u32, len: u32) -> Result<i32> {
+        let mut data = vec![0; len.min(MAX_TRANSFER) as usize];
+
+        let read = match fd {
+            0 => self.syscalls.console.read(&mut data),
+            fd => match self.syscalls.files.get_mut(&fd) {
+                Some(file) => file.read(&mut data),
+                None => return Ok(-1),
+            },
+        };
+
+        match read {
+            Ok(n) => {
+                self.write_bytes(buf, &data[..n])?;
+                Ok(n as i32)
+            }
+            Err(_) => Ok(-1),
+        }
+    }
+
+    /// Writes `len` bytes from `buf` to the descriptor `fd`. Returns the
+    /// number of bytes written, or -1 on error.
+    fn write_file(&mut self, fd: u32, buf: u32, len: u32) -> Result<i32> {
+        let len = len.min(MAX_TRANSFER);
+        let data = self.read_bytes(buf, len)?;
+
+        let written = match fd {
+            1 => self.syscalls.console.write(&data),
+            2 => self.syscalls.console.write_err(&data),
+            fd => match self.syscalls.files.get_mut(&fd) {
+                Some(file) => file.write_all(&data),
+                None => return Ok(-1),
+            },
+        };
+
+        Ok(written.map(|_| len as i32).unwrap_or(-1))
+    }
+
+    /// Executes the syscall requested in `$v0`, following the table of
+    /// the current ABI profile.
+    pub(super) fn syscall(&mut self) -> Result<()> {
+        match self.syscalls.abi {
+            Abi::Mars => self.mars_syscall()?,
+            Abi::Spim => self.spim_syscall()?,
+            Abi::Linux => self.linux_syscall()?,
+        }
+
+        self.stats.add_cycles(1);
+
+        Ok(())
+    }
+
+    /// Executes a SPIM syscall. Everything but `open` is shared with MARS.
+    fn spim_syscall(&mut self) -> Result<()> {
+        let a0 = self.regs[Register(4)];
+        let a1 = self.regs[Register(5)];
+        let a2 = self.regs[Register(6)];
+
+        match self.regs[Register(2)] {
+            13 => {
+                let name = self.read_string(a0)?;
+                self.regs[Register(2)] = as_unsigned(self.open_file_spim(&name, a1, a2));
+            }
+            1..=17 => self.mars_syscall()?,
+            a => self.print(format_args!("syscall: unknown syscall {}\n", a))?,
+        }
+
+        Ok(())
+    }
+
+    /// Executes a MARS syscall.
+    fn mars_syscall(&mut self) -> Result<()> {
+        let a0 = self.regs[Register(4)];
+        let a1 = self.regs[Register(5)];
+        let a2 = self.regs[Register(6)];
+
+        match self.regs[Register(2)] {
+            1 => self.print(as_signed(a0))?,
+            2 => self.print(self.single_arg())?,
+            3 => self.print(self.double_arg())?,
+            4 => {
+                let string = self.read_string(a0)?;
+                self.syscalls.console.write(&string)?;
+            }
+            5 => {
+                let val = self.syscalls.console.read_line()?.trim().parse::<i32>()?;
+                self.regs[Register(2)] = as_unsigned(val);
+            }
+            6 => {
+                let val = self.syscalls.console.read_line()?.trim().parse::<f32>()?;
+                self.return_single(val);
+            }
+            7 => {
+                let val = self.syscalls.console.read_line()?.trim().parse::<f64>()?;
+                self.return_double(val);
+            }
+            8 => {
+                // Like MARS: reads at most `a1 - 1` characters, including
+                // the line break, and terminates the string with a null.
+                if a1 >= 1 {
+                    let input = self.syscalls.console.read_line()?;
+                    let mut bytes: Vec<u8> = input
+                        .into_bytes()
+                        .into_iter()
+                        .take(a1 as usize - 1)
+                        .collect();
+                    bytes.push(0);
+                    self.write_bytes(a0, &bytes)?;
+                }
+            }
+            9 => {
+                // Like the C `sbrk`, returns -1 if the heap cannot grow
+                // that much.
+                let end = a0
+                    .checked_add(3)
+                    .and_then(|size| self.syscalls.heap_end.checked_add(size & !3));
+                self.regs[Register(2)] = match end {
+                    Some(end) => std::mem::replace(&mut self.syscalls.heap_end, end),
+                    None => u32::MAX,
+                };
+            }
+            10 => self.exit(0),
+            11 => self.print(a0 as u8 as char)?,
+            12 => {
+                let mut c = [0u8];
+                let n = self.syscalls.console.read(&mut c)?;
+                self.regs[Register(2)] = if n == 0 { 0 } else { c[0] as u32 };
+            }
+            13 => {
+                let name = self.read_string(a0)?;
+                self.regs[Register(2)] = as_unsigned(self.open_file(&name, a1));
+            }
+            14 => {
+                let n = self.read_file(a0, a1, a2)?;
+                self.regs[Register(2)] = as_unsigned(n);
+            }
+            15 => {
+                let n = self.write_file(a0, a1, a2)?;
+                self.regs[Register(2)] = as_unsigned(n);
+            }
+            16 => {
+                self.syscalls.files.remove(&a0);
+            }
+            17 => self.exit(as_signed(a0)),
+            30 => {
+                let now = self.now()?;
+                let millis = now.as_millis() as u64;
+                self.regs[Register(4)] = millis as u32;
+                self.regs[Register(5)] = (millis >> 32) as u32;
+            }
+            32 => {
+                self.sleep(Duration::from_millis(a0 as u64))?;
+            }
+            34 => self.print(format_args!("{:#010x}", a0))?,
+            35 => self.print(format_args!("{:032b}", a0))?,
+            36 => self.print(a0)?,
+            40 => {
+                self.syscalls.rngs.insert(a0, StdRng::seed_from_u64(a1 as u64));
+            }
+            41 => {
+                self.regs[Register(4)] = self.syscalls.rng(a0).gen::<u32>();
+            }
+            42 => {
+                let upper = as_signed(a1);
+                if upper <= 0 {
+                    return Err(eyre!(
+                        "syscall 42: upper bound must be positive, got {}",
+                        upper
+                    ));
+                }
+                self.regs[Register(4)] = self.syscalls.rng(a0).gen_range(0..upper) as u32;
+            }
+            43 => {
+                let val = self.syscalls.rng(a0).gen::<f32>();
+                self.return_single(val);
+            }
+            44 => {
+                let val = self.syscalls.rng(a0).gen::<f64>();
+                self.return_double(val);
+            }
+            50 => {
+                let msg = self.read_string(a0)?;
+                let answer = self.prompt(&[&msg[..], b" [y/n/c] "].concat())?;
+                self.regs[Register(4)] = match answer.trim().chars().next() {
+                    Some('y') | Some('Y') => 0,
+                    Some('n') | Some('N') => 1,
+                    _ => 2,
+                };
+            }
+            51 => {
+                let msg = self.read_string(a0)?;
+                let answer = self.prompt(&msg)?;
+                let (val, status) = match answer.trim() {
+                    "" => (0, DIALOG_EMPTY),
+                    s => match s.parse::<i32>() {
+                        Ok(val) => (val, DIALOG_OK),
+                        Err(_) => (0, DIALOG_INVALID),
+                    },
+                };
+                self.regs[Register(4)] = as_unsigned(val);
+                self.regs[Register(5)] = as_unsigned(status);
+            }
+            52 => {
+                let msg = self.read_string(a0)?;
+                let answer = self.prompt(&msg)?;
+                let (val, status) = match answer.trim() {
+                    "" => (0.0, DIALOG_EMPTY),
+                    s => match s.parse::<f32>() {
+                        Ok(val) => (val, DIALOG_OK),
+                        Err(_) => (0.0, DIALOG_INVALID),
+                    },
+                };
+                self.return_single(val);
+                self.regs[Register(5)] = as_unsigned(status);
+            }
+            53 => {
+                let msg = self.read_string(a0)?;
+                let answer = self.prompt(&msg)?;
+                let (val, status) = match answer.trim() {
+                    "" => (0.0, DIALOG_EMPTY),
+                    s => match s.parse::<f64>() {
+                        Ok(val) => (val, DIALOG_OK),
+                        Err(_) => (0.0, DIALOG_INVALID),
+                    },
+                };
+                self.return_double(val);
+                self.regs[Register(5)] = as_unsigned(status);
+            }
+            54 => {
+                let msg = self.read_string(a0)?;
+                let answer = self.prompt(&msg)?;
+                let answer = answer.trim_end_matches(&['\r', '\n'][..]);
+                let status = if answer.is_empty() {
+                    DIALOG_EMPTY
+                } else {
+                    DIALOG_OK
+                };
+                if a2 >= 1 {
+                    let mut bytes: Vec<u8> = answer.bytes().take(a2 as usize - 1).collect();
+                    bytes.push(0);
+                    self.write_bytes(a1, &bytes)?;
+                }
+                self.regs[Register(5)] = as_unsigned(status);
+            }
+            55 => {
+                let msg = self.read_string(a0)?;
+                let kind = match a1 {
+                    0 => "error: ",
+                    1 => "info: ",
+                    2 => "warning: ",
+                    3 => "question: ",
+                    _ => "",
+                };
+                self.print(format_args!("{}{}\n", kind, String::from_utf8_lossy(&msg)))?;
+            }
+            56 => {
+                let msg = self.read_string(a0)?;
+                self.print(format_args!("{}{}\n", String::from_utf8_lossy(&msg), as_signed(a1)))?;
+            }
+            57 => {
+                let msg = self.read_string(a0)?;
+                let val = self.single_arg();
+                self.print(format_args!("{}{}\n", String::from_utf8_lossy(&msg), val))?;
+            }
+            58 => {
+                let msg = self.read_string(a0)?;
+                let val = self.double_arg();
+                self.print(format_args!("{}{}\n", String::from_utf8_lossy(&msg), val))?;
+            }
+            59 => {
+                let msg = self.read_string(a0)?;
+                let msg2 = self.read_string(a1)?;
+                self.print(format_args!(
+                    "{}{}\n",
+                    String::from_utf8_lossy(&msg),
+                    String::from_utf8_lossy(&msg2)
+                ))?;
+            }
+            500 => {
+                self.mem.dump()?;
+            }
+            501 => {
+                if let Some(bitmap) = self.bitmap.as_mut() {
+                    bitmap.capture_frame(&self.mem)?;
+                }
+            }
+            a => self.print(format_args!("syscall: unknown syscall {}\n", a))?,
+        };
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::SharedBuffer;
+    use crate::emulator::memory::Ram;
+
+    use std::cell::UnsafeCell;
+    use std::io::Cursor;
+
+    /// Runs `f` on a CPU following `abi`, with empty memories.
+    fn with_cpu(abi: Abi, f: impl FnOnce(&mut Cpu<Ram, Ram>)) {
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        cpu.set_abi(abi);
+        f(&mut cpu);
+    }
+
+    /// Calls the syscall `number` with the arguments `args`.
+    fn call(cpu: &mut Cpu<Ram, Ram>, number: u32, args: &[u32]) -> Result<()> {
+        cpu.regs[Register(2)] = number;
+        for (i, arg) in args.iter().enumerate() {
+            cpu.regs[Register(4 + i as u32)] = *arg;
+        }
+        cpu.syscall()
+    }
+
+    /// Gives `cpu` a console that reads `input`, and returns its output.
+    fn console(cpu: &mut Cpu<Ram, Ram>, input: &str) -> SharedBuffer {
+        let output = SharedBuffer::default();
+        let console = StreamConsole::new(Cursor::new(input.as_bytes().to_vec()), output.clone());
+        cpu.set_console(Box::new(console));
+        output
+    }
+
+    fn is_bus_error(result: Result<impl std::fmt::Debug>) -> bool {
+        matches!(
+            result.unwrap_err().downcast_ref::<Fault>(),
+            Some(Fault::BusError(_))
+        )
+    }
+
+    #[test]
+    fn sbrk() {
+        with_cpu(Abi::Mars, |cpu| {
+            call(cpu, 9, &[10]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], HEAP_BASE);
+            call(cpu, 9, &[0]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], HEAP_BASE + 12);
+
+            // Too large to fit, from the rounding or from the addition.
+            call(cpu, 9, &[u32::MAX]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], u32::MAX);
+            call(cpu, 9, &[0xF0000000]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], u32::MAX);
+            assert_eq!(cpu.syscalls.heap_end, HEAP_BASE + 12);
+        });
+    }
+
+    #[test]
+    fn memory_at_the_top_of_the_address_space() {
+        with_cpu(Abi::Mars, |cpu| {
+            cpu.write_bytes(0xFFFFFFFD, b"abc").unwrap();
+            assert_eq!(cpu.read_bytes(0xFFFFFFFD, 3).unwrap(), b"abc");
+            assert_eq!(cpu.read_bytes(0xFFFFFFFE, 0).unwrap(), b"");
+
+            assert!(is_bus_error(cpu.write_bytes(0xFFFFFFFE, b"abc")));
+            assert!(is_bus_error(cpu.read_bytes(0xFFFFFFFE, 3)));
+            // The string has no terminator before the end of memory.
+            assert!(is_bus_error(cpu.read_string(0xFFFFFFFD)));
+        });
+    }
+
+    #[test]
+    fn read_and_write_are_capped() {
+        let root = std::env::temp_dir().join(format!("indy-syscall-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("in.txt"), b"hello").unwrap();
+
+        with_cpu(Abi::Mars, |cpu| {
+            cpu.syscalls.vfs = Vfs::new(&root);
+            cpu.write_bytes(0x10010000, b"in.txt\0").unwrap();
+
+            call(cpu, 13, &[0x10010000, 0]).unwrap();
+            let fd = cpu.regs[Register(2)];
+            assert_eq!(fd, 3);
+
+            call(cpu, 14, &[fd, 0x10010100, u32::MAX]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], 5);
+            assert_eq!(cpu.read_bytes(0x10010100, 5).unwrap(), b"hello");
+
+            // Past the end of memory.
+            call(cpu, 13, &[0x10010000, 0]).unwrap();
+            let fd = cpu.regs[Register(2)];
+            assert!(is_bus_error(call(cpu, 14, &[fd, 0xFFFFFFFE, 16])));
+
+            // Unknown descriptors.
+            call(cpu, 14, &[42, 0x10010100, u32::MAX]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], u32::MAX);
+            call(cpu, 15, &[42, 0x10010100, u32::MAX]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], u32::MAX);
+        });
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn linux_getrandom_and_writev_are_capped() {
+        with_cpu(Abi::Linux, |cpu| {
+            call(cpu, 4353, &[0x10010000, u32::MAX, 0]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], MAX_TRANSFER);
+            assert_eq!(cpu.regs[Register(7)], 0);
+
+            // writev(1, iov, 4096): too many entries.
+            call(cpu, 4146, &[1, 0x10010000, 4096]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], 22);
+            assert_eq!(cpu.regs[Register(7)], 1);
+
+            // An iovec array that wraps around.
+            call(cpu, 4146, &[1, 0xFFFFFFF8, 2]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], 14);
+            assert_eq!(cpu.regs[Register(7)], 1);
+        });
+    }
+
+    #[test]
+    fn linux_stack_arguments_out_of_memory() {
+        with_cpu(Abi::Linux, |cpu| {
+            // mmap takes its fifth argument from the stack, here past the
+            // end of memory or not aligned.
+            for &sp in &[0xFFFFFFF0, 0x7FFFEFFE] {
+                cpu.regs[Register(29)] = sp;
+                let err = call(cpu, 4090, &[0, 4096, 3, 0x22]).unwrap_err();
+                assert!(matches!(
+                    err.downcast_ref::<Fault>(),
+                    Some(Fault::AddressError(addr)) if *addr == sp
+                ));
+            }
+        });
+    }
+
+    #[test]
+    fn linux_kill_with_signal_zero() {
+        with_cpu(Abi::Linux, |cpu| {
+            call(cpu, 4037, &[1, 0]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], 0);
+            assert_eq!(cpu.regs[Register(7)], 0);
+            call(cpu, 4266, &[1, 1, 0]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], 0);
+
+            let err = call(cpu, 4266, &[1, 1, 6]).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Fault>(),
+                Some(Fault::Signal(6))
+            ));
+        });
+    }
+
+    #[test]
+    fn console_output() {
+        with_cpu(Abi::Mars, |cpu| {
+            let output = console(cpu, "");
+            call(cpu, 1, &[as_unsigned(-42)]).unwrap();
+            cpu.float_regs[FloatRegister(12)] = single_to_word(1.5);
+            call(cpu, 2, &[]).unwrap();
+            let (lo, hi) = double_to_dword(-0.25);
+            cpu.float_regs[FloatRegister(12)] = lo;
+            cpu.float_regs[FloatRegister(13)] = hi;
+            call(cpu, 3, &[]).unwrap();
+            cpu.write_bytes(0x10010000, b" text\0").unwrap();
+            call(cpu, 4, &[0x10010000]).unwrap();
+            call(cpu, 11, &[u32::from(b'!')]).unwrap();
+
+            cpu.syscalls.console.flush().unwrap();
+            assert_eq!(output.contents(), b"-421.5-0.25 text!");
+        });
+    }
+
+    #[test]
+    fn console_input() {
+        with_cpu(Abi::Mars, |cpu| {
+            let output = console(cpu, " -7\n2.5\n0.125\nhello\nab");
+            call(cpu, 5, &[]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], as_unsigned(-7));
+            call(cpu, 6, &[]).unwrap();
+            assert_eq!(word_to_single(cpu.float_regs[FloatRegister(0)]), 2.5);
+            call(cpu, 7, &[]).unwrap();
+            let double = dword_to_double(
+                cpu.float_regs[FloatRegister(0)],
+                cpu.float_regs[FloatRegister(1)],
+            );
+            assert_eq!(double, 0.125);
+
+            // At most `a1 - 1` characters, and the terminator.
+            call(cpu, 8, &[0x10010000, 4]).unwrap();
+            assert_eq!(cpu.read_bytes(0x10010000, 4).unwrap(), b"hel\0");
+
+            // The rest of the line is gone; then the input ends.
+            call(cpu, 12, &[]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], u32::from(b'a'));
+            call(cpu, 12, &[]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], u32::from(b'b'));
+            call(cpu, 12, &[]).unwrap();
+            assert_eq!(cpu.regs[Register(2)], 0);
+
+            assert!(call(cpu, 5, &[]).is_err());
+            assert_eq!(output.contents(), b"");
+        });
+    }
+
+    #[test]
+    fn sleep_stops_at_the_deadline() {
+        with_cpu(Abi::Mars, |cpu| {
+            let started = Instant::now();
+            cpu.deadline = Some(started + Duration::from_millis(50));
+            call(cpu, 32, &[1]).unwrap();
+
+            let err = call(cpu, 32, &[60_000]).unwrap_err();
+            assert!(started.elapsed() < Duration::from_secs(10));
+            assert!(cpu.cut_by_timeout(&err));
+        });
+    }
+
+    /// The random numbers and times the guest sees in a deterministic run
+    /// with `seed`.
+    fn deterministic_values(seed: u64) -> Vec<u32> {
+        let mut values = Vec::new();
+        with_cpu(Abi::Mars, |cpu| {
+            cpu.set_deterministic(seed);
+            // An unseeded generator, and one the guest seeded.
+            call(cpu, 41, &[0]).unwrap();
+            values.push(cpu.regs[Register(4)]);
+            call(cpu, 40, &[1, 1234]).unwrap();
+            call(cpu, 42, &[1, 1000]).unwrap();
+            values.push(cpu.regs[Register(4)]);
+
+            // The time, before and after sleeping.
+            call(cpu, 30, &[]).unwrap();
+            values.push(cpu.regs[Register(4)]);
+            call(cpu, 32, &[60_000]).unwrap();
+            call(cpu, 30, &[]).unwrap();
+            values.push(cpu.regs[Register(4)]);
+        });
+        values
+    }
+
+    #[test]
+    fn deterministic_runs_are_reproducible() {
+        let values = deterministic_values(7);
+        assert_eq!(deterministic_values(7), values);
+
+        // The time starts at the virtual epoch, and sleeping is instant.
+        assert_eq!(values[2], VIRTUAL_EPOCH.as_millis() as u32);
+        assert_eq!(values[3], values[2] + 60_000);
+
+        // Only the generators the guest did not seed depend on the seed.
+        let other = deterministic_values(8);
+        assert_ne!(other[0], values[0]);
+        assert_eq!(other[1..], values[1..]);
+    }
+}
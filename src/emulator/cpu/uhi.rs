@@ -0,0 +1,366 @@
+//! The MIPS Unified Hosting Interface (UHI) semihosting calls.
+//!
+//! Bare-metal programs built against newlib with the `mips-mti-elf`
+//! toolchains do their I/O by asking a debugger to do it for them: the
+//! operation number goes in `$t9`, the arguments in `$a0`-`$a3`, and
+//! `sdbbp 1` hands control to the host. The result comes back in `$v0`,
+//! with the errno in `$v1` when it is -1. Here, the emulator plays the
+//! role of the debugger.
+//!
+//! The flags of `open` and the errno values are the newlib ones, and the
+//! standard streams are descriptors 0, 1 and 2, as usual.
+
+use super::{as_signed, as_unsigned, Cpu};
+use crate::emulator::fault::Fault;
+use crate::emulator::memory::Memory;
+use crate::emulator::vfs::{FileMetadata, OpenOptions};
+use crate::emulator::Register;
+
+use std::io;
+use std::os::unix::fs::MetadataExt;
+
+use color_eyre::eyre::Result;
+
+/// The UHI operation numbers we implement.
+mod op {
+    pub const EXIT: u32 = 1;
+    pub const OPEN: u32 = 2;
+    pub const CLOSE: u32 = 3;
+    pub const READ: u32 = 4;
+    pub const WRITE: u32 = 5;
+    pub const LSEEK: u32 = 6;
+    pub const UNLINK: u32 = 7;
+    pub const FSTAT: u32 = 8;
+    pub const ARGC: u32 = 9;
+    pub const ARGLEN: u32 = 10;
+    pub const ARGN: u32 = 11;
+    pub const RAMRANGE: u32 = 12;
+    pub const LOG: u32 = 13;
+    pub const ASSERT: u32 = 14;
+    pub const PREAD: u32 = 19;
+    pub const PWRITE: u32 = 20;
+}
+
+/// The `sdbbp` code that marks a UHI call.
+pub(super) const UHI_CODE: u32 = 1;
+
+/// The newlib errno values we return that the host may not share.
+const EBADF: u32 = 9;
+const EINVAL: u32 = 22;
+const ENOSYS: u32 = 88;
+
+/// The newlib `open` flags.
+const O_ACCMODE: u32 = 0x0003;
+const O_WRONLY: u32 = 0x0001;
+const O_RDWR: u32 = 0x0002;
+const O_APPEND: u32 = 0x0008;
+const O_CREAT: u32 = 0x0200;
+const O_TRUNC: u32 = 0x0400;
+const O_EXCL: u32 = 0x0800;
+
+/// File types, for `st_mode`.
+const S_IFCHR: u32 = 0o020000;
+const S_IFREG: u32 = 0o100000;
+
+/// `abort()` raises this one.
+const SIGABRT: u32 = 6;
+
+/// The result of a call: a value, or an errno.
+type UhiResult = std::result::Result<u32, u32>;
+
+/// Converts a host I/O error to the errno returned to the guest.
+fn errno(e: io::Error) -> u32 {
+    match e.raw_os_error() {
+        // These are the same in newlib and on the hosts we run on.
+        Some(code) if (1..=34).contains(&code) => code as u32,
+        _ => 5,
+    }
+}
+
+/// Translates the newlib `open` flags into open options.
+fn open_options(flags: u32, mode: u32) -> OpenOptions {
+    let accmode = flags & O_ACCMODE;
+    let create = flags & O_CREAT != 0;
+    let exclusive = create && flags & O_EXCL != 0;
+
+    OpenOptions {
+        read: accmode != O_WRONLY,
+        write: accmode == O_WRONLY || accmode == O_RDWR,
+        append: flags & O_APPEND != 0,
+        create: create && !exclusive,
+        create_new: exclusive,
+        truncate: flags & O_TRUNC != 0,
+        mode,
+    }
+}
+
+/// Builds the `struct stat` of UHI, which is the same on every target.
+fn stat(meta: Option<&FileMetadata>) -> Vec<u8> {
+    let mut buf = vec![0u8; 104];
+    let mut put = |offset: usize, bytes: &[u8]| {
+        buf[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+
+    match meta {
+        Some(FileMetadata::Host(meta)) => {
+            put(0, &(meta.dev() as u16).to_le_bytes());
+            put(2, &(meta.ino() as u16).to_le_bytes());
+            put(4, &meta.mode().to_le_bytes());
+            put(8, &(meta.nlink() as u16).to_le_bytes());
+            put(10, &(meta.uid() as u16).to_le_bytes());
+            put(12, &(meta.gid() as u16).to_le_bytes());
+            put(14, &(meta.rdev() as u16).to_le_bytes());
+            put(16, &meta.size().to_le_bytes());
+            put(24, &meta.atime().to_le_bytes());
+            put(40, &meta.mtime().to_le_bytes());
+            put(56, &meta.ctime().to_le_bytes());
+            put(72, &meta.blksize().to_le_bytes());
+            put(80, &meta.blocks().to_le_bytes());
+        }
+        Some(FileMetadata::Memory(size)) => {
+            // A file in the overlay, which only has a size.
+            put(4, &(S_IFREG | 0o644).to_le_bytes());
+            put(8, &1u16.to_le_bytes());
+            put(16, &size.to_le_bytes());
+            put(72, &4096u64.to_le_bytes());
+            put(80, &size.div_ceil(512).to_le_bytes());
+        }
+        None => {
+            // The standard streams are terminals, so newlib buffers them
+            // by line.
+            put(4, &(S_IFCHR | 0o620).to_le_bytes());
+            put(8, &1u16.to_le_bytes());
+        }
+    }
+
+    buf
+}
+
+impl<'a, TD: Memory, TI: Memory> Cpu<'a, TD, TI> {
+    /// Services the UHI call requested by `sdbbp 1`.
+    pub(super) fn uhi_call(&mut self) -> Result<()> {
+        let a0 = self.regs[Register(4)];
+        let a1 = self.regs[Register(5)];
+        let a2 = self.regs[Register(6)];
+        let a3 = self.regs[Register(7)];
+
+        let result: UhiResult = match self.regs[Register(25)] {
+            op::EXIT => {
+                self.exit(as_signed(a0));
+                Ok(0)
+            }
+            op::OPEN => {
+                let name = self.read_string(a0)?;
+                self.open_with(&name, &open_options(a1, a2))
+                    .map_err(errno)
+            }
+            op::CLOSE => match a0 {
+                0..=2 => Ok(0),
+                fd => match self.syscalls.files.remove(&fd) {
+                    Some(_) => Ok(0),
+                    None => Err(EBADF),
+                },
+            },
+            op::READ => self.linux_read(a0, a1, a2)?,
+            op::WRITE => self.linux_write(a0, a1, a2)?,
+            op::PREAD | op::PWRITE => self.uhi_pio(a0, a1, a2, a3)?,
+            op::LSEEK => self
+                .linux_seek(a0, as_signed(a1) as i64, a2)
+                .map(|pos| pos as u32),
+            op::UNLINK => {
+                let name = self.read_string(a0)?;
+                self.syscalls.vfs.remove(&name).map(|_| 0).map_err(errno)
+            }
+            op::FSTAT => match self.linux_metadata(a0) {
+                Ok(meta) => {
+                    self.write_bytes(a1, &stat(meta.as_ref()))?;
+                    Ok(0)
+                }
+                Err(e) => Err(e),
+            },
+            op::ARGC => Ok(self.syscalls.args.len() as u32),
+            op::ARGLEN => match self.syscalls.args.get(a0 as usize) {
+                Some(arg) => Ok(arg.len() as u32),
+                None => Err(EINVAL),
+            },
+            op::ARGN => match self.syscalls.args.get(a0 as usize) {
+                Some(arg) => {
+                    let mut bytes = arg.clone().into_bytes();
+                    bytes.push(0);
+                    self.write_bytes(a1, &bytes)?;
+                    Ok(0)
+                }
+                None => Err(EINVAL),
+            },
+            op::RAMRANGE => {
+                let (low, high) = self.uhi_ram_range();
+                self.regs[Register(2)] = low;
+                self.regs[Register(3)] = high;
+                return Ok(());
+            }
+            op::LOG => {
+                let data = self.read_bytes(a0, a1)?;
+                self.syscalls.console.write(&data).map(|_| a1).map_err(errno)
+            }
+            op::ASSERT => {
+                let msg = self.read_string(a0)?;
+                let file = self.read_string(a1)?;
+                let report = format!(
+                    "Assertion failed: {}, file {}, line {}\n",
+                    String::from_utf8_lossy(&msg),
+                    String::from_utf8_lossy(&file),
+                    as_signed(a2)
+                );
+                self.syscalls.console.write_err(report.as_bytes())?;
+
+                // The program goes on to `abort()` after this.
+                return Err(Fault::Signal(SIGABRT).into());
+            }
+            n => {
+                log::warn!("uhi: unknown operation {}", n);
+                Err(ENOSYS)
+            }
+        };
+
+        self.uhi_return(result);
+        Ok(())
+    }
+
+    /// `pread` and `pwrite`: `read` and `write` at `offset`, leaving the
+    /// file offset alone.
+    fn uhi_pio(&mut self, fd: u32, buf: u32, len: u32, offset: u32) -> Result<UhiResult> {
+        let saved = match self.linux_seek(fd, 0, 1) {
+            Ok(pos) => pos as i64,
+            Err(e) => return Ok(Err(e)),
+        };
+        if let Err(e) = self.linux_seek(fd, offset as i64, 0) {
+            return Ok(Err(e));
+        }
+
+        let result = if self.regs[Register(25)] == op::PREAD {
+            self.linux_read(fd, buf, len)?
+        } else {
+            self.linux_write(fd, buf, len)?
+        };
+
+        Ok(self.linux_seek(fd, saved, 0).and(result))
+    }
+
+    /// The RAM the program may use for its stack and heap: the RAM of the
+    /// board, or else the first 256 MiB of kseg0.
+    fn uhi_ram_range(&self) -> (u32, u32) {
+        match self.memory_map.iter().find(|r| r.writable) {
+            Some(ram) => (0x8000_0000 | ram.base, 0x8000_0000 | (ram.base + ram.size)),
+            None => (0x8000_0000, 0x9000_0000),
+        }
+    }
+
+    /// Writes the result of a call to `$v0`, and the errno to `$v1`.
+    fn uhi_return(&mut self, result: UhiResult) {
+        match result {
+            Ok(val) => self.regs[Register(2)] = val,
+            Err(errno) => {
+                self.regs[Register(2)] = as_unsigned(-1);
+                self.regs[Register(3)] = errno;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::{SharedBuffer, StreamConsole};
+    use crate::emulator::memory::Ram;
+    use crate::emulator::vfs::Vfs;
+    use crate::emulator::Instruction;
+
+    use std::cell::UnsafeCell;
+    use std::io::Cursor;
+
+    /// `sdbbp 1`.
+    const SDBBP_UHI: u32 = 0x7000003F | UHI_CODE << 6;
+
+    /// Makes the call `op` with the arguments `args`, and returns `$v0`
+    /// and `$v1`.
+    fn uhi(cpu: &mut Cpu<Ram, Ram>, op: u32, args: &[u32]) -> (u32, u32) {
+        cpu.regs[Register(25)] = op;
+        for (i, arg) in args.iter().enumerate() {
+            cpu.regs[Register(4 + i as u32)] = *arg;
+        }
+        cpu.regs[Register(3)] = 0;
+        cpu.uhi_call().unwrap();
+        (cpu.regs[Register(2)], cpu.regs[Register(3)])
+    }
+
+    #[test]
+    fn open_read_write_and_close() {
+        let root = std::env::temp_dir().join(format!("indy-uhi-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        cpu.syscalls.vfs = Vfs::new(&root);
+        cpu.write_bytes(0x10010000, b"out.txt\0").unwrap();
+        cpu.write_bytes(0x10010100, b"hello").unwrap();
+
+        let flags = O_WRONLY | O_CREAT | O_TRUNC;
+        let (fd, _) = uhi(&mut cpu, op::OPEN, &[0x10010000, flags, 0o644]);
+        assert_eq!(fd, 3);
+        assert_eq!(uhi(&mut cpu, op::WRITE, &[fd, 0x10010100, 5]).0, 5);
+        assert_eq!(uhi(&mut cpu, op::CLOSE, &[fd]).0, 0);
+        assert_eq!(std::fs::read(root.join("out.txt")).unwrap(), b"hello");
+
+        let (fd, _) = uhi(&mut cpu, op::OPEN, &[0x10010000, 0, 0]);
+        assert_eq!(uhi(&mut cpu, op::READ, &[fd, 0x10010200, 16]).0, 5);
+        assert_eq!(cpu.read_bytes(0x10010200, 5).unwrap(), b"hello");
+        assert_eq!(uhi(&mut cpu, op::READ, &[fd, 0x10010200, 16]).0, 0);
+        assert_eq!(uhi(&mut cpu, op::CLOSE, &[fd]).0, 0);
+
+        // The errors come back in $v1.
+        assert_eq!(uhi(&mut cpu, op::CLOSE, &[fd]), (u32::MAX, EBADF));
+        assert_eq!(
+            uhi(&mut cpu, op::READ, &[fd, 0x10010200, 16]),
+            (u32::MAX, EBADF)
+        );
+        cpu.write_bytes(0x10010000, b"missing\0").unwrap();
+        assert_eq!(uhi(&mut cpu, op::OPEN, &[0x10010000, 0, 0]), (u32::MAX, 2));
+        assert_eq!(uhi(&mut cpu, 99, &[]), (u32::MAX, ENOSYS));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn standard_streams() {
+        let (mem, imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        let output = SharedBuffer::default();
+        let console = StreamConsole::new(Cursor::new(b"in".to_vec()), output.clone());
+        cpu.set_console(Box::new(console));
+
+        cpu.write_bytes(0x10010000, b"out").unwrap();
+        assert_eq!(uhi(&mut cpu, op::WRITE, &[1, 0x10010000, 3]).0, 3);
+        assert_eq!(output.contents(), b"out");
+        assert_eq!(uhi(&mut cpu, op::READ, &[0, 0x10010100, 2]).0, 2);
+        assert_eq!(cpu.read_bytes(0x10010100, 2).unwrap(), b"in");
+    }
+
+    #[test]
+    fn exit_through_sdbbp() {
+        let (mem, mut imem) = (UnsafeCell::new(Ram::new(0)), UnsafeCell::new(Ram::new(0)));
+        let program = [
+            Instruction::parse("addiu $t9, $zero, 1").unwrap().encode(),
+            Instruction::parse("addiu $a0, $zero, 3").unwrap().encode(),
+            SDBBP_UHI,
+        ];
+        for (i, word) in program.iter().enumerate() {
+            imem.get_mut()
+                .poke(0x00400000 + 4 * i as u32, *word)
+                .unwrap();
+        }
+
+        let mut cpu = Cpu::new(&mem, &imem, 0x00400000, 0x7FFFEFFC, 0x10008000);
+        cpu.reset();
+        assert_eq!(cpu.run().unwrap(), 3);
+    }
+}
@@ -0,0 +1,317 @@
+//! A headless version of the MARS "Bitmap Display" tool.
+//!
+//! Like in MARS, the framebuffer is a region of guest memory where each
+//! word is one unit of the display, encoded as `0x00RRGGBB`, row by row.
+//! Instead of showing it in a window, the framebuffer is captured and
+//! written to an image file. The format is chosen by the extension of the
+//! output path: `.png` writes a PNG, anything else writes a binary PPM.
+//!
+//! A frame is captured when the guest calls syscall 501, every N
+//! instructions if so configured, and once more when execution ends.
+//! Intermediate frames are numbered (`out-0000.png`, `out-0001.png`, ...),
+//! while the last one is written to the output path itself.
+//!
+//! Unlike the keyboard and display, this is not a memory-mapped device:
+//! the guest just writes to ordinary memory.
+
+use crate::emulator::memory::Memory;
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::{Path, PathBuf};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The largest image we are willing to capture, in pixels: 4096 x 4096.
+const MAX_PIXELS: u64 = 1 << 24;
+
+/// The bitmap display configuration and capture state.
+pub struct BitmapDisplay {
+    /// Address of the top-left unit.
+    base: u32,
+    /// Width of the display, in units.
+    width: u32,
+    /// Height of the display, in units.
+    height: u32,
+    /// Side of each unit, in pixels.
+    unit: u32,
+    /// Capture a frame every `every` instructions. Zero disables it.
+    every: usize,
+    /// Where to write the images.
+    output: PathBuf,
+    /// Instructions executed since the last periodic capture.
+    counter: usize,
+    /// Number of intermediate frames captured so far.
+    frames: usize,
+}
+
+impl BitmapDisplay {
+    /// Creates a new display with `width` x `height` units of `unit` x
+    /// `unit` pixels, reading the framebuffer at `base`.
+    pub fn new(
+        output: impl AsRef<Path>,
+        base: u32,
+        width: u32,
+        height: u32,
+        unit: u32,
+        every: usize,
+    ) -> Result<BitmapDisplay> {
+        if width == 0 || height == 0 || unit == 0 {
+            return Err(eyre!("bitmap: display size must not be zero"));
+        }
+
+        if base & 3 != 0 {
+            return Err(eyre!(
+                "bitmap: unaligned framebuffer address {:#010x}",
+                base
+            ));
+        }
+
+        // The framebuffer must fit in the address space, after `base`.
+        let units = width as u64 * height as u64;
+        if base as u64 + 4 * units > 1 << 32 {
+            return Err(eyre!(
+                "bitmap: a {}x{} framebuffer at {:#010x} goes past the end of memory",
+                width,
+                height,
+                base
+            ));
+        }
+
+        let pixels = units
+            .checked_mul(unit as u64 * unit as u64)
+            .filter(|&pixels| pixels <= MAX_PIXELS);
+        if pixels.is_none() {
+            return Err(eyre!(
+                "bitmap: a {}x{} display of {}x{} units is too large",
+                width,
+                height,
+                unit,
+                unit
+            ));
+        }
+
+        Ok(BitmapDisplay {
+            base,
+            width,
+            height,
+            unit,
+            every,
+            output: output.as_ref().to_path_buf(),
+            counter: 0,
+            frames: 0,
+        })
+    }
+
+    /// Called once per executed instruction. Captures a numbered frame
+    /// if the configured interval has elapsed.
+    pub fn tick<M: Memory>(&mut self, mem: &M) -> Result<()> {
+        if self.every == 0 {
+            return Ok(());
+        }
+
+        self.counter += 1;
+
+        if self.counter == self.every {
+            self.counter = 0;
+            self.capture_frame(mem)?;
+        }
+
+        Ok(())
+    }
+
+    /// Captures a numbered frame.
+    pub fn capture_frame<M: Memory>(&mut self, mem: &M) -> Result<()> {
+        let path = self.frame_path(self.frames);
+        self.frames += 1;
+
+        self.capture(mem, &path)
+    }
+
+    /// Captures the final frame, written to the output path itself.
+    pub fn capture_last<M: Memory>(&mut self, mem: &M) -> Result<()> {
+        let path = self.output.clone();
+
+        self.capture(mem, &path)
+    }
+
+    /// Returns the path of the `n`-th intermediate frame.
+    fn frame_path(&self, n: usize) -> PathBuf {
+        let stem = self
+            .output
+            .file_stem()
+            .map(|s| s.to_string_lossy().into_owned())
+            .unwrap_or_default();
+
+        let name = match self.output.extension() {
+            Some(ext) => format!("{}-{:04}.{}", stem, n, ext.to_string_lossy()),
+            None => format!("{}-{:04}", stem, n),
+        };
+
+        self.output.with_file_name(name)
+    }
+
+    /// Reads the framebuffer and writes it to `path`.
+    fn capture<M: Memory>(&self, mem: &M, path: &Path) -> Result<()> {
+        // `new` made sure none of this overflows.
+        let (w, h) = (self.width * self.unit, self.height * self.unit);
+        let mut pixels = Vec::with_capacity((w * h * 3) as usize);
+
+        for y in 0..h {
+            for x in 0..w {
+                let unit = (y / self.unit) * self.width + (x / self.unit);
+                let rgb = mem.inspect(self.base + 4 * unit)?.to_be_bytes();
+                pixels.extend_from_slice(&rgb[1..4]);
+            }
+        }
+
+        log::debug!("bitmap: writing {}x{} frame to {:?}", w, h, path);
+
+        let mut file = BufWriter::new(File::create(path)?);
+
+        match path.extension() {
+            Some(ext) if ext.eq_ignore_ascii_case("png") => write_png(&mut file, w, h, &pixels)?,
+            _ => write_ppm(&mut file, w, h, &pixels)?,
+        }
+
+        file.flush()?;
+
+        Ok(())
+    }
+}
+
+/// Writes the RGB `pixels` as a binary PPM.
+fn write_ppm(out: &mut impl Write, w: u32, h: u32, pixels: &[u8]) -> Result<()> {
+    write!(out, "P6\n{} {}\n255\n", w, h)?;
+    out.write_all(pixels)?;
+
+    Ok(())
+}
+
+/// Writes the RGB `pixels` as a PNG.
+///
+/// The image data is not compressed: it goes in stored deflate blocks,
+/// which keeps us from pulling a whole compression library just for this.
+fn write_png(out: &mut impl Write, w: u32, h: u32, pixels: &[u8]) -> Result<()> {
+    out.write_all(b"\x89PNG\r\n\x1a\n")?;
+
+    let mut ihdr = Vec::with_capacity(13);
+    ihdr.extend_from_slice(&w.to_be_bytes());
+    ihdr.extend_from_slice(&h.to_be_bytes());
+    // 8 bits per channel, truecolor, deflate, no filter, no interlace.
+    ihdr.extend_from_slice(&[8, 2, 0, 0, 0]);
+    write_png_chunk(out, b"IHDR", &ihdr)?;
+
+    // Each scanline starts with its filter type (0, none).
+    let stride = (w * 3) as usize;
+    let mut raw = Vec::with_capacity((stride + 1) * h as usize);
+    for row in pixels.chunks(stride) {
+        raw.push(0);
+        raw.extend_from_slice(row);
+    }
+
+    let mut zlib = vec![0x78, 0x01];
+    let mut blocks = raw.chunks(0xFFFF).peekable();
+    while let Some(block) = blocks.next() {
+        let len = block.len() as u16;
+        zlib.push(blocks.peek().is_none() as u8);
+        zlib.extend_from_slice(&len.to_le_bytes());
+        zlib.extend_from_slice(&(!len).to_le_bytes());
+        zlib.extend_from_slice(block);
+    }
+    zlib.extend_from_slice(&adler32(&raw).to_be_bytes());
+    write_png_chunk(out, b"IDAT", &zlib)?;
+
+    write_png_chunk(out, b"IEND", &[])?;
+
+    Ok(())
+}
+
+/// Writes a single PNG chunk, with its length and CRC.
+fn write_png_chunk(out: &mut impl Write, kind: &[u8; 4], data: &[u8]) -> Result<()> {
+    out.write_all(&(data.len() as u32).to_be_bytes())?;
+    out.write_all(kind)?;
+    out.write_all(data)?;
+
+    let crc = crc32(&[&kind[..], data].concat());
+    out.write_all(&crc.to_be_bytes())?;
+
+    Ok(())
+}
+
+/// The CRC-32 used by PNG chunks.
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = 0xFFFFFFFFu32;
+
+    for byte in data {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB88320 & mask);
+        }
+    }
+
+    !crc
+}
+
+/// The Adler-32 checksum that ends a zlib stream.
+fn adler32(data: &[u8]) -> u32 {
+    let (mut a, mut b) = (1u32, 0u32);
+
+    for byte in data {
+        a = (a + *byte as u32) % 65521;
+        b = (b + a) % 65521;
+    }
+
+    (b << 16) | a
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::Ram;
+
+    #[test]
+    fn rejects_bad_geometry() {
+        let new =
+            |base, width, height, unit| BitmapDisplay::new("out.ppm", base, width, height, unit, 0);
+
+        assert!(new(0x10010000, 0, 4, 1).is_err());
+        assert!(new(0x10010002, 4, 4, 1).is_err());
+        assert!(new(0xFFFFFFF0, 4, 4, 1).is_err());
+        assert!(new(0x10010000, 0x10000, 0x10000, 1).is_err());
+        assert!(new(0x10010000, 512, 512, 0x10000).is_err());
+        assert!(new(0xFFFFFFF0, 2, 2, 1).is_ok());
+    }
+
+    #[test]
+    fn captures_ppm() -> Result<()> {
+        let base = 0x10010000;
+        let mut mem = Ram::new(0);
+        let colors = [0x00FF0000, 0x0000FF00, 0x000000FF, 0x00123456];
+        for (i, color) in colors.iter().enumerate() {
+            mem.poke(base + 4 * i as u32, *color)?;
+        }
+
+        let path = std::env::temp_dir().join(format!("indy-bitmap-{}.ppm", std::process::id()));
+        let mut display = BitmapDisplay::new(&path, base, 2, 2, 2, 0)?;
+        display.capture_last(&mem)?;
+        let image = std::fs::read(&path)?;
+        std::fs::remove_file(&path)?;
+
+        // Each unit is 2x2 pixels.
+        let (r, g, b, other) = ([255, 0, 0], [0, 255, 0], [0, 0, 255], [0x12, 0x34, 0x56]);
+        let rows = [
+            [r, r, g, g],
+            [r, r, g, g],
+            [b, b, other, other],
+            [b, b, other, other],
+        ];
+        let mut expected = b"P6\n4 4\n255\n".to_vec();
+        expected.extend(rows.iter().flatten().flatten());
+
+        assert_eq!(image, expected);
+
+        Ok(())
+    }
+}
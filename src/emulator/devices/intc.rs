@@ -0,0 +1,157 @@
+//! A simple interrupt controller, merging the interrupt lines of the
+//! board devices into a single Cop0 interrupt.
+//!
+//! | Offset | Register                                  |
+//! |--------|-------------------------------------------|
+//! | `0x0`  | Pending: lines currently asserted (read)  |
+//! | `0x4`  | Enable: mask of the lines that interrupt  |
+//! | `0x8`  | Status: pending lines that are enabled    |
+//!
+//! Lines are level-triggered: they stay asserted until the guest
+//! acknowledges the interrupt at the device that raised it, so the
+//! controller itself has nothing to acknowledge.
+
+use super::Device;
+use crate::emulator::console::Console;
+use crate::emulator::fault::Fault;
+
+use std::cell::Cell;
+use std::rc::Rc;
+
+use color_eyre::eyre::Result;
+
+/// An interrupt line from a device to the controller.
+#[derive(Clone)]
+pub struct IrqLine {
+    /// The lines of the controller, shared with every device.
+    pending: Rc<Cell<u32>>,
+    /// The bit of this line.
+    mask: u32,
+}
+
+impl IrqLine {
+    /// Asserts or deasserts the line.
+    pub fn set(&self, asserted: bool) {
+        let pending = self.pending.get();
+        if asserted {
+            self.pending.set(pending | self.mask);
+        } else {
+            self.pending.set(pending & !self.mask);
+        }
+    }
+}
+
+/// The interrupt controller.
+pub struct InterruptController {
+    /// Base address of the registers.
+    base: u32,
+    /// The Cop0 interrupt (Cause.IP bit) the controller drives.
+    ip: u32,
+    /// The lines asserted by the devices.
+    pending: Rc<Cell<u32>>,
+    /// The enable register.
+    enable: u32,
+}
+
+impl InterruptController {
+    /// Creates a controller at `base`, driving Cause.IP`ip`.
+    pub fn new(base: u32, ip: u32) -> InterruptController {
+        InterruptController {
+            base,
+            ip,
+            pending: Rc::new(Cell::new(0)),
+            enable: 0,
+        }
+    }
+
+    /// Returns the line `n` of the controller, to be wired to a device.
+    pub fn line(&self, n: u32) -> IrqLine {
+        IrqLine {
+            pending: self.pending.clone(),
+            mask: 1 << n,
+        }
+    }
+}
+
+impl Device for InterruptController {
+    fn name(&self) -> &'static str {
+        "intc"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        12
+    }
+
+    fn read(&mut self, offset: u32, _console: &mut dyn Console) -> Result<u32> {
+        match offset {
+            0x0 => Ok(self.pending.get()),
+            0x4 => Ok(self.enable),
+            0x8 => Ok(self.pending.get() & self.enable),
+            _ => Err(Fault::BusError(self.base.wrapping_add(offset)).into()),
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _console: &mut dyn Console) -> Result<()> {
+        match offset {
+            0x0 | 0x8 => {}
+            0x4 => self.enable = val,
+            _ => return Err(Fault::BusError(self.base.wrapping_add(offset)).into()),
+        }
+
+        Ok(())
+    }
+
+    fn interrupts(&self) -> u32 {
+        if self.pending.get() & self.enable != 0 {
+            1 << self.ip
+        } else {
+            0
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::{SharedBuffer, StreamConsole};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn merges_the_enabled_lines() {
+        let mut console = StreamConsole::new(Cursor::new(Vec::new()), SharedBuffer::default());
+        let mut intc = InterruptController::new(0x1F000200, 2);
+        let (line0, line1) = (intc.line(0), intc.line(1));
+
+        // Nothing interrupts until the line is enabled.
+        line1.set(true);
+        assert_eq!(intc.read(0x0, &mut console).unwrap(), 0b10);
+        assert_eq!(intc.read(0x8, &mut console).unwrap(), 0);
+        assert_eq!(intc.interrupts(), 0);
+
+        intc.write(0x4, 0b11, &mut console).unwrap();
+        assert_eq!(intc.read(0x4, &mut console).unwrap(), 0b11);
+        assert_eq!(intc.read(0x8, &mut console).unwrap(), 0b10);
+        assert_eq!(intc.interrupts(), 1 << 2);
+
+        // The pending register is read only: the lines are cleared at the
+        // devices.
+        intc.write(0x0, 0, &mut console).unwrap();
+        assert_eq!(intc.read(0x0, &mut console).unwrap(), 0b10);
+        line0.set(true);
+        line1.set(false);
+        assert_eq!(intc.read(0x8, &mut console).unwrap(), 0b01);
+        line0.set(false);
+        assert_eq!(intc.interrupts(), 0);
+
+        let err = intc.read(0xc, &mut console).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Fault>(),
+            Some(Fault::BusError(0x1F00020C))
+        ));
+    }
+}
@@ -0,0 +1,199 @@
+//! The MARS "Keyboard and Display MMIO Simulator", bound to the
+//! console.
+//!
+//! The register layout is the same as in MARS:
+//!
+//! | Address      | Register              |
+//! |--------------|-----------------------|
+//! | `0xffff0000` | Receiver control      |
+//! | `0xffff0004` | Receiver data         |
+//! | `0xffff0008` | Transmitter control   |
+//! | `0xffff000c` | Transmitter data      |
+//!
+//! On both control registers, bit 0 is the (read-only) ready bit and
+//! bit 1 is the interrupt enable bit. The keyboard interrupts on
+//! Cause.IP0, and the display on Cause.IP1, just like MARS does.
+//!
+//! The keyboard reads the same input as the syscalls, and the display
+//! writes to the same output, so `--input`, `--output` and `--record`
+//! apply to them as well. The input is polled without blocking, so the
+//! guest can wait for a key while the emulator keeps running.
+
+use super::Device;
+use crate::emulator::console::Console;
+use crate::emulator::fault::Fault;
+
+use color_eyre::eyre::Result;
+
+/// Base address of the device.
+const BASE: u32 = 0xffff0000;
+
+/// Ready bit on the control registers.
+const READY: u32 = 1 << 0;
+/// Interrupt enable bit on the control registers.
+const INT_ENABLE: u32 = 1 << 1;
+
+/// How many instructions the display takes to become ready again
+/// after a character is written. Same default as MARS.
+const DISPLAY_DELAY: usize = 5;
+
+/// The keyboard and display device.
+pub struct KeyboardDisplay {
+    /// The receiver control register.
+    rx_control: u32,
+    /// The receiver data register.
+    rx_data: u32,
+    /// The transmitter control register.
+    tx_control: u32,
+    /// Instructions left until the display is ready again.
+    tx_delay: usize,
+    /// The display became ready with interrupts enabled, and the guest
+    /// did not write to it since.
+    tx_interrupt: bool,
+}
+
+impl KeyboardDisplay {
+    /// Creates the device.
+    pub fn new() -> KeyboardDisplay {
+        KeyboardDisplay {
+            rx_control: 0,
+            rx_data: 0,
+            tx_control: READY,
+            tx_delay: 0,
+            tx_interrupt: false,
+        }
+    }
+}
+
+impl Device for KeyboardDisplay {
+    fn name(&self) -> &'static str {
+        "keyboard/display"
+    }
+
+    fn base(&self) -> u32 {
+        BASE
+    }
+
+    fn size(&self) -> u32 {
+        16
+    }
+
+    fn read(&mut self, offset: u32, _console: &mut dyn Console) -> Result<u32> {
+        match offset {
+            0x0 => Ok(self.rx_control),
+            0x4 => {
+                // Reading the data frees the receiver for the next character.
+                self.rx_control &= !READY;
+                Ok(self.rx_data)
+            }
+            0x8 => Ok(self.tx_control),
+            0xc => Ok(0),
+            _ => Err(Fault::BusError(BASE.wrapping_add(offset)).into()),
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, console: &mut dyn Console) -> Result<()> {
+        match offset {
+            0x0 => {
+                self.rx_control = (self.rx_control & READY) | (val & INT_ENABLE);
+            }
+            0x4 => {}
+            0x8 => {
+                self.tx_control = (self.tx_control & READY) | (val & INT_ENABLE);
+                self.tx_interrupt = false;
+            }
+            0xc => {
+                if self.tx_control & READY != 0 {
+                    console.write(&[val as u8])?;
+                    console.flush()?;
+
+                    self.tx_control &= !READY;
+                    self.tx_delay = DISPLAY_DELAY;
+                    self.tx_interrupt = false;
+                }
+            }
+            _ => return Err(Fault::BusError(BASE.wrapping_add(offset)).into()),
+        }
+
+        Ok(())
+    }
+
+    fn tick(&mut self, console: &mut dyn Console) -> Result<()> {
+        if self.tx_delay > 0 {
+            self.tx_delay -= 1;
+
+            if self.tx_delay == 0 {
+                self.tx_control |= READY;
+                self.tx_interrupt = self.tx_control & INT_ENABLE != 0;
+            }
+        }
+
+        if self.rx_control & READY == 0 {
+            if let Some(byte) = console.try_read()? {
+                self.rx_data = byte as u32;
+                self.rx_control |= READY;
+            }
+        }
+
+        Ok(())
+    }
+
+    fn interrupts(&self) -> u32 {
+        let keyboard = self.rx_control & (READY | INT_ENABLE) == (READY | INT_ENABLE);
+
+        (keyboard as u32) | ((self.tx_interrupt as u32) << 1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::{SharedBuffer, StreamConsole};
+
+    use std::io::Cursor;
+
+    #[test]
+    fn uses_the_console() {
+        let output = SharedBuffer::default();
+        let mut console = StreamConsole::new(Cursor::new(b"hi".to_vec()), output.clone());
+        let mut device = KeyboardDisplay::new();
+
+        // The keyboard holds a character until the guest reads it.
+        device.tick(&mut console).unwrap();
+        device.tick(&mut console).unwrap();
+        assert_eq!(device.read(0x0, &mut console).unwrap(), READY);
+        assert_eq!(device.read(0x4, &mut console).unwrap(), u32::from(b'h'));
+        assert_eq!(device.read(0x0, &mut console).unwrap(), 0);
+        device.tick(&mut console).unwrap();
+        assert_eq!(device.read(0x4, &mut console).unwrap(), u32::from(b'i'));
+        device.tick(&mut console).unwrap();
+        assert_eq!(device.read(0x0, &mut console).unwrap(), 0);
+
+        // The display is busy for a while after each character.
+        device.write(0xc, u32::from(b'o'), &mut console).unwrap();
+        device.write(0xc, u32::from(b'x'), &mut console).unwrap();
+        assert_eq!(device.read(0x8, &mut console).unwrap(), 0);
+        for _ in 0..DISPLAY_DELAY {
+            device.tick(&mut console).unwrap();
+        }
+        device.write(0xc, u32::from(b'k'), &mut console).unwrap();
+        assert_eq!(output.contents(), b"ok");
+    }
+
+    #[test]
+    fn faults_outside_the_registers() {
+        let mut console = StreamConsole::new(Cursor::new(Vec::new()), SharedBuffer::default());
+        let mut device = KeyboardDisplay::new();
+
+        let err = device.read(0x2, &mut console).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Fault>(),
+            Some(Fault::BusError(0xffff0002))
+        ));
+        let err = device.write(0x10, 0, &mut console).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<Fault>(),
+            Some(Fault::BusError(0xffff0010))
+        ));
+    }
+}
@@ -0,0 +1,104 @@
+//! Memory-mapped devices.
+//!
+//! A device claims a range of the address space. Loads and stores the
+//! CPU makes inside that range never reach the memory hierarchy: they
+//! are forwarded to the device instead, so they are never cached.
+//!
+//! Devices may also assert interrupt lines, which end up in the IP
+//! field of the Cop0 Cause register.
+//!
+//! The bitmap display also lives here, even though it is not
+//! memory-mapped: it just watches a region of ordinary memory.
+
+use crate::emulator::console::Console;
+
+use color_eyre::eyre::Result;
+
+pub mod bitmap_display;
+pub mod intc;
+pub mod keyboard_display;
+pub mod timer;
+pub mod uart;
+
+pub use bitmap_display::BitmapDisplay;
+pub use intc::{InterruptController, IrqLine};
+pub use keyboard_display::KeyboardDisplay;
+pub use timer::Timer;
+pub use uart::Uart;
+
+/// Interface of a memory-mapped device.
+pub trait Device {
+    /// The device name, for debugging.
+    fn name(&self) -> &'static str;
+
+    /// The lowest address claimed by the device.
+    fn base(&self) -> u32;
+
+    /// The size, in bytes, of the claimed range.
+    fn size(&self) -> u32;
+
+    /// Reads the word at `offset` bytes from the device base. The
+    /// terminal devices do their I/O on `console`, the same one the
+    /// syscalls use.
+    fn read(&mut self, offset: u32, console: &mut dyn Console) -> Result<u32>;
+
+    /// Writes `val` at `offset` bytes from the device base.
+    fn write(&mut self, offset: u32, val: u32, console: &mut dyn Console) -> Result<()>;
+
+    /// Called once per executed instruction, so the device can update
+    /// its internal state.
+    fn tick(&mut self, _console: &mut dyn Console) -> Result<()> {
+        Ok(())
+    }
+
+    /// Returns the interrupt lines currently asserted by the device,
+    /// as a bitmask where bit `n` corresponds to Cause.IP`n`.
+    fn interrupts(&self) -> u32 {
+        0
+    }
+}
+
+/// The set of devices attached to a CPU.
+#[derive(Default)]
+pub struct Devices {
+    devices: Vec<Box<dyn Device>>,
+}
+
+impl Devices {
+    /// Attaches a new device.
+    pub fn attach(&mut self, device: Box<dyn Device>) {
+        log::debug!(
+            "devices: attaching {} at {:#010x}..{:#010x}",
+            device.name(),
+            device.base(),
+            device.base() as u64 + device.size() as u64
+        );
+        self.devices.push(device);
+    }
+
+    /// Returns `true` if no device is attached.
+    pub fn is_empty(&self) -> bool {
+        self.devices.is_empty()
+    }
+
+    /// Returns the device that claims `addr`, if any.
+    pub fn find(&mut self, addr: u32) -> Option<&mut Box<dyn Device>> {
+        self.devices
+            .iter_mut()
+            .find(|d| addr >= d.base() && (addr - d.base()) < d.size())
+    }
+
+    /// Ticks every device.
+    pub fn tick(&mut self, console: &mut dyn Console) -> Result<()> {
+        for device in self.devices.iter_mut() {
+            device.tick(console)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns the union of the interrupt lines asserted by the devices.
+    pub fn interrupts(&self) -> u32 {
+        self.devices.iter().fold(0, |acc, d| acc | d.interrupts())
+    }
+}
@@ -0,0 +1,180 @@
+//! A programmable timer, counting executed instructions.
+//!
+//! | Offset | Register                                          |
+//! |--------|---------------------------------------------------|
+//! | `0x0`  | Count                                             |
+//! | `0x4`  | Compare                                           |
+//! | `0x8`  | Control: bit 0 enable, bit 1 interrupt enable,    |
+//! |        | bit 2 periodic                                    |
+//! | `0xc`  | Status: bit 0 set on a match, write 1 to clear it |
+//!
+//! While enabled, Count goes up by one every instruction. When it
+//! reaches Compare, the match bit is set, and the interrupt line stays
+//! asserted while both the match bit and the interrupt enable are set.
+//! A periodic timer goes back to zero on a match; otherwise it keeps
+//! counting, wrapping around.
+
+use super::{Device, IrqLine};
+use crate::emulator::console::Console;
+use crate::emulator::fault::Fault;
+
+use color_eyre::eyre::Result;
+
+/// Control: the timer is counting.
+const ENABLE: u32 = 1 << 0;
+/// Control: a match raises the interrupt line.
+const INT_ENABLE: u32 = 1 << 1;
+/// Control: Count goes back to zero on a match.
+const PERIODIC: u32 = 1 << 2;
+
+/// Status: Count reached Compare.
+const MATCH: u32 = 1 << 0;
+
+/// The timer.
+pub struct Timer {
+    /// Base address of the registers.
+    base: u32,
+    /// Where the interrupt goes.
+    irq: IrqLine,
+    count: u32,
+    compare: u32,
+    control: u32,
+    status: u32,
+}
+
+impl Timer {
+    /// Creates a stopped timer at `base`, interrupting on `irq`.
+    pub fn new(base: u32, irq: IrqLine) -> Timer {
+        Timer {
+            base,
+            irq,
+            count: 0,
+            compare: 0,
+            control: 0,
+            status: 0,
+        }
+    }
+
+    /// Updates the interrupt line.
+    fn update_irq(&self) {
+        self.irq
+            .set(self.status & MATCH != 0 && self.control & INT_ENABLE != 0);
+    }
+}
+
+impl Device for Timer {
+    fn name(&self) -> &'static str {
+        "timer"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        16
+    }
+
+    fn read(&mut self, offset: u32, _console: &mut dyn Console) -> Result<u32> {
+        match offset {
+            0x0 => Ok(self.count),
+            0x4 => Ok(self.compare),
+            0x8 => Ok(self.control),
+            0xc => Ok(self.status),
+            _ => Err(Fault::BusError(self.base.wrapping_add(offset)).into()),
+        }
+    }
+
+    fn write(&mut self, offset: u32, val: u32, _console: &mut dyn Console) -> Result<()> {
+        match offset {
+            0x0 => self.count = val,
+            0x4 => self.compare = val,
+            0x8 => self.control = val & (ENABLE | INT_ENABLE | PERIODIC),
+            0xc => self.status &= !val,
+            _ => return Err(Fault::BusError(self.base.wrapping_add(offset)).into()),
+        }
+
+        self.update_irq();
+        Ok(())
+    }
+
+    fn tick(&mut self, _console: &mut dyn Console) -> Result<()> {
+        if self.control & ENABLE != 0 {
+            self.count = self.count.wrapping_add(1);
+
+            if self.count == self.compare {
+                self.status |= MATCH;
+                if self.control & PERIODIC != 0 {
+                    self.count = 0;
+                }
+                self.update_irq();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::{SharedBuffer, StreamConsole};
+    use crate::emulator::devices::InterruptController;
+
+    use std::io::Cursor;
+
+    #[test]
+    fn interrupts_on_a_match() {
+        let mut console = StreamConsole::new(Cursor::new(Vec::new()), SharedBuffer::default());
+        let mut intc = InterruptController::new(0x1F000200, 2);
+        intc.write(0x4, 0b10, &mut console).unwrap();
+        let mut timer = Timer::new(0x1F000100, intc.line(1));
+
+        // Stopped, it does not count.
+        timer.write(0x4, 3, &mut console).unwrap();
+        timer.tick(&mut console).unwrap();
+        assert_eq!(timer.read(0x0, &mut console).unwrap(), 0);
+
+        timer.write(0x8, ENABLE | INT_ENABLE, &mut console).unwrap();
+        for _ in 0..2 {
+            timer.tick(&mut console).unwrap();
+        }
+        assert_eq!(timer.read(0xc, &mut console).unwrap(), 0);
+        assert_eq!(intc.interrupts(), 0);
+
+        timer.tick(&mut console).unwrap();
+        assert_eq!(timer.read(0xc, &mut console).unwrap(), MATCH);
+        assert_eq!(intc.interrupts(), 1 << 2);
+
+        // It keeps counting, and the interrupt stays until it is
+        // acknowledged.
+        timer.tick(&mut console).unwrap();
+        assert_eq!(timer.read(0x0, &mut console).unwrap(), 4);
+        assert_eq!(intc.interrupts(), 1 << 2);
+        timer.write(0xc, MATCH, &mut console).unwrap();
+        assert_eq!(timer.read(0xc, &mut console).unwrap(), 0);
+        assert_eq!(intc.interrupts(), 0);
+    }
+
+    #[test]
+    fn periodic_timers_start_over() {
+        let mut console = StreamConsole::new(Cursor::new(Vec::new()), SharedBuffer::default());
+        let mut intc = InterruptController::new(0x1F000200, 2);
+        let mut timer = Timer::new(0x1F000100, intc.line(1));
+
+        timer.write(0x4, 2, &mut console).unwrap();
+        timer.write(0x8, ENABLE | PERIODIC, &mut console).unwrap();
+        for _ in 0..2 {
+            timer.tick(&mut console).unwrap();
+        }
+        assert_eq!(timer.read(0x0, &mut console).unwrap(), 0);
+        assert_eq!(timer.read(0xc, &mut console).unwrap(), MATCH);
+
+        // Without the interrupt enable, the match does not interrupt.
+        assert_eq!(intc.read(0x0, &mut console).unwrap(), 0);
+
+        // Only the known control bits stick.
+        timer.write(0x8, 0xFF, &mut console).unwrap();
+        assert_eq!(timer.read(0x8, &mut console).unwrap(), 0b111);
+    }
+}
@@ -0,0 +1,321 @@
+//! A 16550-compatible UART, bound to the console: it receives the same
+//! input as the syscalls, and transmits to the same output.
+//!
+//! The registers are the usual ones, each on its own word (a register
+//! shift of 2, in device tree terms):
+//!
+//! | Offset | Read                 | Write             | With LCR.DLAB |
+//! |--------|----------------------|-------------------|---------------|
+//! | `0x00` | Receiver buffer      | Transmit holding  | Divisor low   |
+//! | `0x04` | Interrupt enable     | Interrupt enable  | Divisor high  |
+//! | `0x08` | Interrupt identif.   | FIFO control      |               |
+//! | `0x0c` | Line control         | Line control      |               |
+//! | `0x10` | Modem control        | Modem control     |               |
+//! | `0x14` | Line status          | -                 |               |
+//! | `0x18` | Modem status         | -                 |               |
+//! | `0x1c` | Scratch              | Scratch           |               |
+//!
+//! Characters are sent as soon as they are written, so the transmitter
+//! is always empty. The line settings and the divisor are kept, but have
+//! no effect. The received data available and transmitter empty
+//! interrupts are implemented; line and modem status never interrupt.
+
+use super::{Device, IrqLine};
+use crate::emulator::console::Console;
+use crate::emulator::fault::Fault;
+
+use std::collections::VecDeque;
+
+use color_eyre::eyre::Result;
+
+/// Size of the receiver FIFO.
+const FIFO_SIZE: usize = 16;
+
+/// IER: received data available.
+const IER_RDA: u32 = 1 << 0;
+/// IER: transmitter holding register empty.
+const IER_THRE: u32 = 1 << 1;
+
+/// IIR: no interrupt pending.
+const IIR_NONE: u32 = 0x01;
+/// IIR: transmitter holding register empty.
+const IIR_THRE: u32 = 0x02;
+/// IIR: received data available.
+const IIR_RDA: u32 = 0x04;
+/// IIR: the FIFOs are enabled.
+const IIR_FIFO: u32 = 0xC0;
+
+/// LCR: divisor latch access.
+const LCR_DLAB: u32 = 1 << 7;
+
+/// LSR: data ready.
+const LSR_DR: u32 = 1 << 0;
+/// LSR: transmitter holding register and transmitter empty.
+const LSR_TX_EMPTY: u32 = (1 << 5) | (1 << 6);
+
+/// MSR: carrier detect, data set ready and clear to send.
+const MSR_CONNECTED: u32 = (1 << 7) | (1 << 5) | (1 << 4);
+
+/// The UART.
+pub struct Uart {
+    /// Base address of the registers.
+    base: u32,
+    /// Where the interrupt goes.
+    irq: IrqLine,
+    /// The receiver FIFO.
+    rx: VecDeque<u8>,
+    ier: u32,
+    lcr: u32,
+    mcr: u32,
+    scratch: u32,
+    divisor: u32,
+    /// FCR bit 0: the FIFOs are enabled.
+    fifo_enabled: bool,
+    /// The transmitter empty interrupt is pending.
+    thre_pending: bool,
+}
+
+impl Uart {
+    /// Creates the UART at `base`, interrupting on `irq`.
+    pub fn new(base: u32, irq: IrqLine) -> Uart {
+        Uart {
+            base,
+            irq,
+            rx: VecDeque::with_capacity(FIFO_SIZE),
+            ier: 0,
+            lcr: 0,
+            mcr: 0,
+            scratch: 0,
+            divisor: 0,
+            fifo_enabled: false,
+            thre_pending: false,
+        }
+    }
+
+    /// The highest priority interrupt pending, as reported by the IIR.
+    fn interrupt_id(&self) -> u32 {
+        if self.ier & IER_RDA != 0 && !self.rx.is_empty() {
+            IIR_RDA
+        } else if self.ier & IER_THRE != 0 && self.thre_pending {
+            IIR_THRE
+        } else {
+            IIR_NONE
+        }
+    }
+
+    /// Faults on an access at `offset` where there is no register: past
+    /// the last one, or not at the start of one.
+    fn check(&self, offset: u32) -> Result<()> {
+        if !offset.is_multiple_of(4) || offset >= self.size() {
+            return Err(Fault::BusError(self.base.wrapping_add(offset)).into());
+        }
+        Ok(())
+    }
+
+    /// Updates the interrupt line.
+    fn update_irq(&self) {
+        self.irq.set(self.interrupt_id() != IIR_NONE);
+    }
+}
+
+impl Device for Uart {
+    fn name(&self) -> &'static str {
+        "uart"
+    }
+
+    fn base(&self) -> u32 {
+        self.base
+    }
+
+    fn size(&self) -> u32 {
+        32
+    }
+
+    fn read(&mut self, offset: u32, _console: &mut dyn Console) -> Result<u32> {
+        self.check(offset)?;
+        let dlab = self.lcr & LCR_DLAB != 0;
+
+        let val = match offset / 4 {
+            0 if dlab => self.divisor & 0xFF,
+            0 => self.rx.pop_front().map_or(0, u32::from),
+            1 if dlab => self.divisor >> 8,
+            1 => self.ier,
+            2 => {
+                let id = self.interrupt_id();
+                // Reading the IIR acknowledges the transmitter interrupt.
+                if id == IIR_THRE {
+                    self.thre_pending = false;
+                }
+                let fifo = if self.fifo_enabled { IIR_FIFO } else { 0 };
+                id | fifo
+            }
+            3 => self.lcr,
+            4 => self.mcr,
+            5 => LSR_TX_EMPTY | if self.rx.is_empty() { 0 } else { LSR_DR },
+            6 => MSR_CONNECTED,
+            _ => self.scratch,
+        };
+
+        self.update_irq();
+        Ok(val)
+    }
+
+    fn write(&mut self, offset: u32, val: u32, console: &mut dyn Console) -> Result<()> {
+        self.check(offset)?;
+        let dlab = self.lcr & LCR_DLAB != 0;
+        let val = val & 0xFF;
+
+        match offset / 4 {
+            0 if dlab => self.divisor = (self.divisor & 0xFF00) | val,
+            0 => {
+                console.write(&[val as u8])?;
+                console.flush()?;
+
+                // The character is gone already, so the holding register
+                // is empty again.
+                self.thre_pending = true;
+            }
+            1 if dlab => self.divisor = (self.divisor & 0xFF) | (val << 8),
+            1 => {
+                // Enabling the interrupt with the transmitter empty raises
+                // it right away.
+                if self.ier & IER_THRE == 0 && val & IER_THRE != 0 {
+                    self.thre_pending = true;
+                }
+                self.ier = val & 0x0F;
+            }
+            2 => {
+                self.fifo_enabled = val & 1 != 0;
+                // Bit 1 clears the receiver FIFO.
+                if val & 2 != 0 {
+                    self.rx.clear();
+                }
+            }
+            3 => self.lcr = val,
+            4 => self.mcr = val,
+            5 | 6 => {}
+            _ => self.scratch = val,
+        }
+
+        self.update_irq();
+        Ok(())
+    }
+
+    fn tick(&mut self, console: &mut dyn Console) -> Result<()> {
+        if self.rx.len() < FIFO_SIZE {
+            if let Some(byte) = console.try_read()? {
+                self.rx.push_back(byte);
+                self.update_irq();
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::console::{SharedBuffer, StreamConsole};
+    use crate::emulator::devices::InterruptController;
+
+    use std::io::Cursor;
+
+    /// A UART on line 0 of an interrupt controller with it enabled, and a
+    /// console that reads `input`.
+    fn uart(input: &[u8]) -> (Uart, InterruptController, StreamConsole, SharedBuffer) {
+        let output = SharedBuffer::default();
+        let mut console = StreamConsole::new(Cursor::new(input.to_vec()), output.clone());
+        let mut intc = InterruptController::new(0x1F000200, 2);
+        intc.write(0x4, 1, &mut console).unwrap();
+        let uart = Uart::new(0x1F000000, intc.line(0));
+        (uart, intc, console, output)
+    }
+
+    #[test]
+    fn transmits_and_receives() {
+        let (mut uart, _, mut console, output) = uart(b"hi");
+
+        uart.write(0x00, u32::from(b'o'), &mut console).unwrap();
+        uart.write(0x00, u32::from(b'k'), &mut console).unwrap();
+        assert_eq!(output.contents(), b"ok");
+        assert_eq!(uart.read(0x14, &mut console).unwrap(), LSR_TX_EMPTY);
+
+        uart.tick(&mut console).unwrap();
+        uart.tick(&mut console).unwrap();
+        assert_eq!(
+            uart.read(0x14, &mut console).unwrap(),
+            LSR_TX_EMPTY | LSR_DR
+        );
+        assert_eq!(uart.read(0x00, &mut console).unwrap(), u32::from(b'h'));
+        assert_eq!(uart.read(0x00, &mut console).unwrap(), u32::from(b'i'));
+        assert_eq!(uart.read(0x14, &mut console).unwrap(), LSR_TX_EMPTY);
+    }
+
+    #[test]
+    fn divisor_latch() {
+        let (mut uart, _, mut console, output) = uart(b"");
+
+        uart.write(0x0c, LCR_DLAB | 3, &mut console).unwrap();
+        uart.write(0x00, 0x34, &mut console).unwrap();
+        uart.write(0x04, 0x12, &mut console).unwrap();
+        assert_eq!(uart.read(0x00, &mut console).unwrap(), 0x34);
+        assert_eq!(uart.read(0x04, &mut console).unwrap(), 0x12);
+
+        // With the latch closed, the same offsets are the data and the IER.
+        uart.write(0x0c, 3, &mut console).unwrap();
+        assert_eq!(uart.read(0x04, &mut console).unwrap(), 0);
+        assert_eq!(output.contents(), b"");
+        assert_eq!(uart.read(0x0c, &mut console).unwrap(), 3);
+    }
+
+    #[test]
+    fn received_data_interrupt() {
+        let (mut uart, intc, mut console, _) = uart(b"x");
+
+        uart.write(0x04, IER_RDA, &mut console).unwrap();
+        assert_eq!(uart.read(0x08, &mut console).unwrap(), IIR_NONE);
+        assert_eq!(intc.interrupts(), 0);
+
+        uart.tick(&mut console).unwrap();
+        assert_eq!(uart.read(0x08, &mut console).unwrap(), IIR_RDA);
+        assert_eq!(intc.interrupts(), 1 << 2);
+
+        // Reading the character clears it.
+        assert_eq!(uart.read(0x00, &mut console).unwrap(), u32::from(b'x'));
+        assert_eq!(uart.read(0x08, &mut console).unwrap(), IIR_NONE);
+        assert_eq!(intc.interrupts(), 0);
+    }
+
+    #[test]
+    fn transmitter_empty_interrupt() {
+        let (mut uart, intc, mut console, _) = uart(b"");
+
+        // Enabling it with the transmitter empty raises it right away, and
+        // reading the IIR acknowledges it.
+        uart.write(0x08, 1, &mut console).unwrap();
+        uart.write(0x04, IER_THRE, &mut console).unwrap();
+        assert_eq!(intc.interrupts(), 1 << 2);
+        assert_eq!(uart.read(0x08, &mut console).unwrap(), IIR_THRE | IIR_FIFO);
+        assert_eq!(intc.interrupts(), 0);
+
+        // Each character sent raises it again.
+        uart.write(0x00, u32::from(b'a'), &mut console).unwrap();
+        assert_eq!(intc.interrupts(), 1 << 2);
+        uart.write(0x04, 0, &mut console).unwrap();
+        assert_eq!(intc.interrupts(), 0);
+    }
+
+    #[test]
+    fn faults_outside_the_registers() {
+        let (mut uart, _, mut console, _) = uart(b"");
+
+        for offset in [0x02, 0x20] {
+            let err = uart.read(offset, &mut console).unwrap_err();
+            assert!(matches!(
+                err.downcast_ref::<Fault>(),
+                Some(Fault::BusError(addr)) if *addr == 0x1F000000 + offset
+            ));
+        }
+    }
+}
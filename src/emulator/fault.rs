@@ -0,0 +1,101 @@
+//! The ways a guest program can end abnormally.
+//!
+//! These are returned as errors from `Cpu::run`, wrapped in an eyre
+//! report, so the caller can tell a misbehaving program apart from a
+//! problem on the host side by downcasting the report.
+
+use std::fmt;
+use std::time::Duration;
+
+/// The signals a Unix kernel would kill the program with for each fault.
+pub const SIGILL: u32 = 4;
+pub const SIGTRAP: u32 = 5;
+pub const SIGBUS: u32 = 7;
+pub const SIGSEGV: u32 = 11;
+
+/// Something the guest program did that stopped the emulation.
+#[derive(Debug)]
+pub enum Fault {
+    /// A memory access that is not aligned to its size.
+    AddressError(u32),
+    /// A memory access to an address where nothing is mapped, like a
+    /// device offset with no register, or a write to read-only memory.
+    BusError(u32),
+    /// A word that does not decode to any known instruction.
+    UnknownInstruction { pc: u32, word: u32 },
+    /// An instruction that is decoded, but not emulated.
+    Unimplemented { pc: u32, instr: String },
+    /// A trap or breakpoint instruction, like `teq` and `break`.
+    Trap { pc: u32, instr: &'static str },
+    /// The program killed itself with a signal, like `abort()` does.
+    Signal(u32),
+    /// The program ran into one of the execution limits.
+    Limit { pc: u32, limit: Limit },
+}
+
+/// An execution limit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Limit {
+    Instructions(usize),
+    Cycles(usize),
+    Timeout(Duration),
+    /// A `wait` that no interrupt can end: the program would hang
+    /// forever.
+    Wait,
+}
+
+impl Fault {
+    /// The signal the program dies of, or `None` for the limits, which
+    /// are not its fault.
+    pub fn signal(&self) -> Option<u32> {
+        match self {
+            Fault::AddressError(_) => Some(SIGSEGV),
+            Fault::BusError(_) => Some(SIGBUS),
+            Fault::UnknownInstruction { .. } | Fault::Unimplemented { .. } => Some(SIGILL),
+            Fault::Trap { .. } => Some(SIGTRAP),
+            Fault::Signal(signal) => Some(*signal),
+            Fault::Limit { .. } => None,
+        }
+    }
+
+    /// The address the program tried to access, for the memory faults.
+    pub fn bad_address(&self) -> Option<u32> {
+        match self {
+            Fault::AddressError(addr) | Fault::BusError(addr) => Some(*addr),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for Limit {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Limit::Instructions(n) => write!(f, "limit of {} instructions reached", n),
+            Limit::Cycles(n) => write!(f, "limit of {} cycles reached", n),
+            Limit::Timeout(t) => write!(f, "timeout of {:.2} sec reached", t.as_secs_f32()),
+            Limit::Wait => write!(f, "waiting for an interrupt that cannot happen"),
+        }
+    }
+}
+
+impl fmt::Display for Fault {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Fault::AddressError(addr) => write!(f, "Address error accessing {:#010x}", addr),
+            Fault::BusError(addr) => write!(f, "Bus error accessing {:#010x}", addr),
+            Fault::UnknownInstruction { pc, word } => {
+                write!(f, "Unknown instruction {:#010x} at {:#010x}", word, pc)
+            }
+            Fault::Unimplemented { pc, instr } => {
+                write!(f, "Instruction {} at {:#010x} not implemented yet!", instr, pc)
+            }
+            Fault::Trap { pc, instr } => write!(f, "Trap ({}) at {:#010x}", instr, pc),
+            Fault::Signal(signal) => {
+                write!(f, "The program killed itself with signal {}", signal)
+            }
+            Fault::Limit { pc, limit } => write!(f, "Stopped at {:#010x}: {}", pc, limit),
+        }
+    }
+}
+
+impl std::error::Error for Fault {}
@@ -1,6 +1,8 @@
 //! Algumas operações úteis para trabalhar com instruções. Antes do grande
 //! overhaul com a macro, o decode das instruções ficava aqui.
 
+use super::Instruction;
+
 /// Faz extensão de sinal em `val`, supondo que o tamanho anterior do inteiro
 /// era `init_size` bits.
 pub fn sign_extend(val: u32, init_size: u32) -> u32 {
@@ -40,3 +42,20 @@ pub fn jump_addr(pc: u32, val: u32) -> u32 {
     let high_pc = (pc + 4) & (0xF0000000);
     (high_pc) | (val << 2)
 }
+
+/// Calcula o destino de um branch ou jump com endereço fixo que está no
+/// endereço `pc`. Retorna `None` para as outras instruções (inclusive os
+/// jumps para registradores, como a `jr`).
+pub fn branch_target(instr: &Instruction, pc: u32) -> Option<u32> {
+    let offset = |imm| Some((pc as i32 + branch_addr(imm) + 4) as u32);
+
+    match instr {
+        Instruction::BEQ(args) | Instruction::BNE(args) => offset(args.imm),
+        Instruction::BLEZ(args) | Instruction::BGTZ(args) => offset(args.imm),
+        Instruction::BLTZ(args) | Instruction::BGEZ(args) => offset(args.imm),
+        Instruction::BLTZAL(args) | Instruction::BAL(args) => offset(args.imm),
+        Instruction::BC1T(args) | Instruction::BC1F(args) => offset(args.imm),
+        Instruction::J(addr) | Instruction::JAL(addr) => Some(jump_addr(pc, *addr)),
+        _ => None,
+    }
+}
@@ -2,15 +2,18 @@
 
 use super::reporter::MemoryEvent;
 use super::Memory;
+use crate::emulator::abi::PAGE_SIZE;
+use crate::emulator::snapshot::{SnapshotReader, SnapshotWriter};
 
 use std::cell::UnsafeCell;
+use std::collections::BTreeSet;
 use std::sync::mpsc::SyncSender;
 
 use log::debug;
 use rand::{
     distributions::{Distribution, Uniform},
-    rngs::ThreadRng,
-    thread_rng,
+    rngs::StdRng,
+    SeedableRng,
 };
 
 use color_eyre::eyre::Result;
@@ -110,7 +113,7 @@ struct LineIndex {
 
 impl LineIndex {
     /// Retorna o endereço mais baixo que tem o número de linha do índice atual.
-    pub fn to_addr<const L: usize>(&self) -> u32 {
+    pub fn to_addr<const L: usize>(self) -> u32 {
         (self.line_number * L * 4) as u32
     }
 }
@@ -144,7 +147,7 @@ pub struct Cache<'a, T: Memory, const L: usize, const N: usize, const A: usize>
     /// A quantidade de misses.
     misses: usize,
     /// Um gerador aleatorio para o line replacing.
-    rng: ThreadRng,
+    rng: StdRng,
     /// O write-end de um Memory Reporter.
     reporter: Option<SyncSender<MemoryEvent>>,
     /// A cache irmã, se existente.
@@ -194,13 +197,24 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Cache<'a, T,
             latency,
             accesses: 0,
             misses: 0,
-            rng: thread_rng(),
+            rng: StdRng::from_entropy(),
             reporter,
             sister: None,
             fetch_from_sister: false,
         }
     }
 
+    /// Semeia o gerador aleatório, deixando as substituições reproduzíveis.
+    pub fn set_seed(&mut self, seed: u64) {
+        self.rng = StdRng::seed_from_u64(seed);
+    }
+
+    /// O nome da seção do snapshot com o estado da cache, que inclui a
+    /// geometria para que uma cache diferente não a aceite.
+    fn snapshot_section(&self) -> String {
+        format!("cache {} ({} lines of {} words, {}-way)", self.name, N, L, A)
+    }
+
     /// Define `sister` como a cache irmã.
     pub fn set_sister(&mut self, sister: &'a UnsafeCell<Cache<'a, T, L, N, A>>, fetch: bool) {
         self.sister.replace(sister);
@@ -295,7 +309,6 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Cache<'a, T,
                 // a linha com a menor idade (que no caso é a mais velha). Se várias linhas
                 // tiverem a mesma idade minimal, então escolhe a de menor índice.
                 let (_, idx, way) = (0..set_size)
-                    .into_iter()
                     .map(|i| {
                         let line_idx = set_idx * set_size + i;
 
@@ -478,7 +491,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Cache<'a, T,
                         "cache {}: line {:#010x} found in sister, copying...",
                         self.name, idx.line_number
                     );
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
                     Ok((idx, line.data[idx.offset], self.latency))
                 } else {
@@ -494,7 +507,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Cache<'a, T,
                     cycles += self.flush_line(&idx)?;
                     cycles += self.load_into_line(&idx, base)?;
 
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
 
                     Ok((idx, line.data[idx.offset], cycles + self.latency))
@@ -513,7 +526,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Cache<'a, T,
                         "cache {}: line {:#010x} found in sister, copying...",
                         self.name, idx.line_number
                     );
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
                     Ok((idx, line.data[idx.offset], self.latency))
                 } else {
@@ -529,7 +542,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Cache<'a, T,
                     cycles += self.flush_line(&idx)?;
                     cycles += self.load_into_line(&idx, base)?;
 
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
 
                     Ok((idx, line.data[idx.offset], cycles + self.latency))
@@ -645,7 +658,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
                         "cache {}: line {:#010x} found in sister, copying...",
                         self.name, idx.line_number
                     );
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
 
                     let range = (idx.offset)..(idx.offset + target.len());
@@ -665,7 +678,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
                     cycles += self.flush_line(&idx)?;
                     cycles += self.load_into_line(&idx, addr)?; // HACK HACK HACK
 
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
 
                     let range = (idx.offset)..(idx.offset + target.len());
@@ -687,7 +700,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
                         "cache {}: line {:#010x} found in sister, copying...",
                         self.name, idx.line_number
                     );
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
 
                     let range = (idx.offset)..(idx.offset + target.len());
@@ -707,7 +720,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
                     cycles += self.flush_line(&idx)?;
                     cycles += self.load_into_line(&idx, addr)?;
 
-                    let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                    let line = self.lines[idx.line_idx].as_mut().unwrap();
                     line.last_access = self.accesses;
 
                     let range = (idx.offset)..(idx.offset + target.len());
@@ -736,7 +749,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
                     self.name, addr, idx.line_number, idx.line_idx, idx.offset
                 );
 
-                let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                let line = self.lines[idx.line_idx].as_mut().unwrap();
                 line.data[idx.offset] = val;
                 line.dirty = true;
                 line.last_access = self.accesses;
@@ -769,7 +782,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
                 cycles += self.flush_line(&idx)?;
                 cycles += self.load_into_line(&idx, base)?;
 
-                let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                let line = self.lines[idx.line_idx].as_mut().unwrap();
                 line.data[idx.offset] = val;
                 line.dirty = true;
                 line.last_access = self.accesses;
@@ -805,7 +818,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
 
                 let range = (idx.offset)..(idx.offset + data.len());
 
-                let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                let line = self.lines[idx.line_idx].as_mut().unwrap();
                 line.data[range].copy_from_slice(data);
                 line.dirty = true;
                 line.last_access = self.accesses;
@@ -840,7 +853,7 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
 
                 let range = (idx.offset)..(idx.offset + data.len());
 
-                let mut line = self.lines[idx.line_idx].as_mut().unwrap();
+                let line = self.lines[idx.line_idx].as_mut().unwrap();
                 line.data[range].copy_from_slice(data);
                 line.dirty = true;
                 line.last_access = self.accesses;
@@ -867,6 +880,112 @@ impl<'a, T: Memory, const L: usize, const N: usize, const A: usize> Memory
         Ok(())
     }
 
+    /// Salva as linhas (com as idades usadas pelo LRU), as estatísticas e
+    /// o gerador aleatório da política de substituição.
+    fn save(&mut self, snapshot: &mut SnapshotWriter, recurse: bool) {
+        snapshot.section(&self.snapshot_section());
+        snapshot.usize(self.accesses);
+        snapshot.usize(self.misses);
+        snapshot.rng(&mut self.rng);
+
+        for line in self.lines.iter() {
+            snapshot.bool(line.is_some());
+            if let Some(line) = line {
+                snapshot.usize(line.tag);
+                snapshot.bool(line.dirty);
+                snapshot.bool(line.valid);
+                snapshot.usize(line.last_access);
+                for word in line.data.iter() {
+                    snapshot.u32(*word);
+                }
+            }
+        }
+
+        if recurse {
+            let mut next = self.next;
+            next.save(snapshot, true);
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader, recurse: bool) -> Result<()> {
+        snapshot.section(&self.snapshot_section())?;
+        self.accesses = snapshot.usize()?;
+        self.misses = snapshot.usize()?;
+        self.rng = snapshot.rng()?;
+
+        for line in self.lines.iter_mut() {
+            *line = None;
+            if !snapshot.bool()? {
+                continue;
+            }
+
+            let tag = snapshot.usize()?;
+            let dirty = snapshot.bool()?;
+            let valid = snapshot.bool()?;
+            let last_access = snapshot.usize()?;
+            let mut data = [0; L];
+            for word in data.iter_mut() {
+                *word = snapshot.u32()?;
+            }
+
+            *line = Some(Line {
+                tag,
+                dirty,
+                valid,
+                last_access,
+                data,
+            });
+        }
+
+        if recurse {
+            let mut next = self.next;
+            next.restore(snapshot, true)?;
+        }
+
+        Ok(())
+    }
+
+    /// Inclui as páginas das linhas válidas, que podem ter dados que o
+    /// próximo nível ainda não tem.
+    fn pages(&self, pages: &mut BTreeSet<u32>) {
+        let set_size = A;
+        let n_sets_bits = log2_lut(N / set_size);
+
+        for (i, line) in self.lines.iter().enumerate() {
+            if let Some(line) = line.as_ref().filter(|line| line.valid) {
+                let line_no = (line.tag << n_sets_bits) | (i / set_size);
+                let addr = (line_no * L * 4) as u32;
+                pages.insert(addr & !(PAGE_SIZE - 1));
+            }
+        }
+
+        self.next.pages(pages);
+    }
+
+    fn inspect(&self, addr: u32) -> Result<u32> {
+        let set_size = A;
+        let n_sets = N / set_size;
+        let n_sets_bits = log2_lut(n_sets);
+
+        let line_number = addr as usize / (L * 4);
+        let set_idx = line_number & ((n_sets).next_power_of_two() - 1);
+        let tag = line_number >> n_sets_bits;
+        let offset = (addr as usize / 4) % L;
+
+        // Se o endereço está numa linha válida, ela tem o valor mais recente
+        // (possivelmente ainda não escrito no próximo nível).
+        for i in 0..set_size {
+            match &self.lines[set_idx * set_size + i] {
+                Some(ref line) if line.tag == tag && line.valid => {
+                    return Ok(line.data[offset]);
+                }
+                _ => continue,
+            }
+        }
+
+        self.next.inspect(addr)
+    }
+
     fn print_stats(&self, recurse: bool) {
         let hits = self.accesses - self.misses;
         let miss_rate = (self.misses as f32) / (self.accesses as f32);
@@ -11,6 +11,8 @@
 
 use color_eyre::eyre::Result;
 
+use super::snapshot::{SnapshotReader, SnapshotWriter};
+
 pub mod cache;
 pub mod ram;
 pub mod reporter;
@@ -19,6 +21,7 @@ pub use cache::{Cache, RepPolicy};
 pub use ram::Ram;
 
 use std::cell::UnsafeCell;
+use std::collections::BTreeSet;
 
 /// Interface geral de um dispositivo de memória.
 pub trait Memory {
@@ -49,11 +52,29 @@ pub trait Memory {
     /// Mostra o conteúdo desse nível de memória. Apenas para debugging.
     fn dump(&self) -> Result<()>;
 
+    /// Salva o estado desse nível de memória (conteúdo, linhas e
+    /// estatísticas) no snapshot. Se `recurse` é `true` e a memória tem
+    /// outros níveis abaixo, então também salva o estado desses.
+    fn save(&mut self, snapshot: &mut SnapshotWriter, recurse: bool);
+
+    /// Restaura o estado salvo por `save`.
+    fn restore(&mut self, snapshot: &mut SnapshotReader, recurse: bool) -> Result<()>;
+
+    /// Acrescenta a `pages` o endereço de cada página (de `PAGE_SIZE` bytes)
+    /// com dados nesse nível de memória ou nos abaixo dele.
+    fn pages(&self, pages: &mut BTreeSet<u32>);
+
+    /// Lê o valor armazenado no endereço `addr` sem efeitos colaterais: não
+    /// conta como acesso, não altera as linhas da cache e não gasta ciclos.
+    /// Útil para quem precisa observar a memória de fora da CPU.
+    fn inspect(&self, addr: u32) -> Result<u32>;
+
     /// Faz uma leitura não alinhada na memória. Isto é, retorna apenas um byte
     /// de uma word.
     ///
     /// Método deprecado: faça a coisa certa e leia palavra-a-palavra1
     #[deprecated]
+    #[allow(dead_code)]
     fn peek_unaligned(&mut self, addr: u32) -> Result<u8> {
         let base = addr & 0xFFFFFFFC; // alinha pro lowest multiplo de 4
         let offset = addr - base; // offset agora armazena qual é o byte desejado
@@ -66,7 +87,7 @@ pub trait Memory {
 
 // LOL, eu não sabia que podia fazer isso!
 // Type system lindo!
-impl<'a, T: Memory> Memory for &'a UnsafeCell<T> {
+impl<T: Memory> Memory for &UnsafeCell<T> {
     fn peek(&mut self, addr: u32) -> Result<(u32, usize)> {
         unsafe { (&mut *self.get()).peek(addr) }
     }
@@ -91,6 +112,22 @@ impl<'a, T: Memory> Memory for &'a UnsafeCell<T> {
         unsafe { (&*self.get()).dump() }
     }
 
+    fn save(&mut self, snapshot: &mut SnapshotWriter, recurse: bool) {
+        unsafe { (&mut *self.get()).save(snapshot, recurse) }
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader, recurse: bool) -> Result<()> {
+        unsafe { (&mut *self.get()).restore(snapshot, recurse) }
+    }
+
+    fn pages(&self, pages: &mut BTreeSet<u32>) {
+        unsafe { (&*self.get()).pages(pages) }
+    }
+
+    fn inspect(&self, addr: u32) -> Result<u32> {
+        unsafe { (&*self.get()).inspect(addr) }
+    }
+
     fn print_stats(&self, recurse: bool) {
         unsafe { (&*self.get()).print_stats(recurse) }
     }
@@ -3,18 +3,21 @@
 //! de ciclos para acessos de leitura e escrita.
 
 use super::Memory;
+use crate::emulator::abi::PAGE_SIZE;
+use crate::emulator::fault::Fault;
+use crate::emulator::snapshot::{SnapshotReader, SnapshotWriter};
 
-use color_eyre::eyre::{eyre, Result};
+use color_eyre::eyre::Result;
 use log::debug;
 
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 
 /// Checa o alinhamento de um endereço. Causa erro caso não seja alinhado a
 /// palavras.
 macro_rules! check_alignment {
     ($addr:ident) => {
         if $addr % 4 != 0 {
-            return Err(eyre!("Acesso a endereço não alinhado! {:#x}", $addr));
+            return Err(Fault::AddressError($addr).into());
         }
     };
 }
@@ -119,6 +122,45 @@ impl Memory for Ram {
         unimplemented!()
     }
 
+    /// Salva as palavras em ordem de endereço, para que o mesmo estado dê
+    /// sempre o mesmo arquivo.
+    fn save(&mut self, snapshot: &mut SnapshotWriter, _: bool) {
+        snapshot.section("ram");
+        snapshot.usize(self.accesses);
+
+        let mut words: Vec<(&u32, &u32)> = self.memory.iter().collect();
+        words.sort_unstable();
+
+        snapshot.usize(words.len());
+        for (addr, val) in words {
+            snapshot.u32(*addr);
+            snapshot.u32(*val);
+        }
+    }
+
+    fn restore(&mut self, snapshot: &mut SnapshotReader, _: bool) -> Result<()> {
+        snapshot.section("ram")?;
+        self.accesses = snapshot.usize()?;
+
+        self.memory.clear();
+        for _ in 0..snapshot.usize()? {
+            let addr = snapshot.u32()?;
+            self.memory.insert(addr, snapshot.u32()?);
+        }
+
+        Ok(())
+    }
+
+    fn pages(&self, pages: &mut BTreeSet<u32>) {
+        pages.extend(self.memory.keys().map(|addr| addr & !(PAGE_SIZE - 1)));
+    }
+
+    fn inspect(&self, addr: u32) -> Result<u32> {
+        check_alignment!(addr);
+
+        Ok(*self.memory.get(&addr).unwrap_or(&0))
+    }
+
     fn print_stats(&self, _: bool) {
         println!(
             "{:>5}  {:>12}  {:>12}  {:>12}   {:>8.2}%",
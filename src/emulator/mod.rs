@@ -1,14 +1,27 @@
 //! Módulo que engloba a emulação propriamente dita.
 
+pub(crate) mod abi;
+pub(crate) mod board;
+pub(crate) mod console;
 pub(crate) mod cpu;
 pub(crate) mod cop0;
+pub(crate) mod coredump;
+pub(crate) mod devices;
+pub(crate) mod fault;
 pub(crate) mod instr;
 pub(crate) mod memory;
+pub(crate) mod snapshot;
 pub(crate) mod stats;
+pub(crate) mod symbols;
+pub(crate) mod vfs;
 
 // Re-exports pra ficar melhor de usar ao longo do código
+pub use abi::Abi;
 pub use cpu::Cpu;
-pub use memory::Ram as Memory;
+
+use std::str::FromStr;
+
+use color_eyre::eyre::{eyre, Report, Result};
 
 /// Mais uma vez usamos o `newtype`. Essa struct não é um registrador em si
 /// (vide `cpu.rs`), mas um *índice* para um registrador.
@@ -18,6 +31,19 @@ pub use memory::Ram as Memory;
 #[derive(Copy, Clone, PartialEq, Eq)]
 pub struct Register(u32);
 
+impl Register {
+    /// O registrador de número `n`, que vai de 0 a 31.
+    pub fn new(n: u32) -> Register {
+        assert!(n < 32, "não existe o registrador ${}", n);
+        Register(n)
+    }
+
+    /// O número do registrador.
+    pub fn number(self) -> u32 {
+        self.0
+    }
+}
+
 impl std::ops::Add<u32> for Register {
     type Output = Register;
 
@@ -36,6 +62,7 @@ impl std::fmt::Display for Register {
             8..=15 => write!(f, "$t{}", self.0 - 8),
             16..=23 => write!(f, "$s{}", self.0 - 16),
             24..=25 => write!(f, "$t{}", self.0 - 24 + 8),
+            26..=27 => write!(f, "$k{}", self.0 - 26),
             28 => write!(f, "$gp"),
             29 => write!(f, "$sp"),
             30 => write!(f, "$fp"),
@@ -45,10 +72,83 @@ impl std::fmt::Display for Register {
     }
 }
 
+/// Lê um registrador pelo nome que o `Display` mostra (`$t0`, `$sp`...),
+/// pelos outros nomes que o MARS aceita (`$s8`) ou pelo número (`$8`).
+impl FromStr for Register {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let name = s
+            .strip_prefix('$')
+            .ok_or_else(|| eyre!("{} não é um registrador", s))?;
+
+        // Os registradores numerados dentro de um grupo, como `$t3`.
+        let indexed = |prefix: &str, first: u32, count: u32| {
+            let n: u32 = name.strip_prefix(prefix)?.parse().ok()?;
+            if n < count {
+                Some(first + n)
+            } else {
+                None
+            }
+        };
+
+        let n = match name {
+            "zero" => Some(0),
+            "at" => Some(1),
+            "t8" => Some(24),
+            "t9" => Some(25),
+            "gp" => Some(28),
+            "sp" => Some(29),
+            "fp" | "s8" => Some(30),
+            "ra" => Some(31),
+            _ => name
+                .parse::<u32>()
+                .ok()
+                .filter(|&n| n < 32)
+                .or_else(|| indexed("v", 2, 2))
+                .or_else(|| indexed("a", 4, 4))
+                .or_else(|| indexed("t", 8, 8))
+                .or_else(|| indexed("s", 16, 8))
+                .or_else(|| indexed("k", 26, 2)),
+        };
+
+        n.map(Register)
+            .ok_or_else(|| eyre!("{} não é um registrador", s))
+    }
+}
+
 /// Um índice para um registrador de ponto flutuante.
 #[derive(Copy, Clone)]
 pub struct FloatRegister(u32);
 
+impl FloatRegister {
+    /// O registrador de ponto flutuante `$fn`, com `n` de 0 a 31.
+    pub fn new(n: u32) -> FloatRegister {
+        assert!(n < 32, "não existe o registrador $f{}", n);
+        FloatRegister(n)
+    }
+
+    /// O número do registrador.
+    pub fn number(self) -> u32 {
+        self.0
+    }
+}
+
+/// Lê um registrador de ponto flutuante, de `$f0` a `$f31`.
+impl FromStr for FloatRegister {
+    type Err = Report;
+
+    fn from_str(s: &str) -> Result<Self> {
+        s.strip_prefix("$f")
+            .and_then(|n| n.parse::<u32>().ok())
+            .filter(|&n| n < 32)
+            .map(FloatRegister)
+            .ok_or_else(|| {
+                eyre!("{} não é um registrador de ponto flutuante", s)
+            })
+    }
+}
+
 impl std::convert::From<Register> for FloatRegister {
     fn from(reg: Register) -> Self {
         FloatRegister(reg.0)
@@ -88,3 +188,78 @@ mod autogen {
 }
 
 pub use autogen::*;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Os nomes das instruções de `instructions.yml`: as chaves indentadas
+    /// uma vez, dentro de cada tipo.
+    fn mnemonics() -> Vec<&'static str> {
+        let yaml = include_str!("../../instructions.yml");
+        let mut names: Vec<&str> = yaml
+            .lines()
+            .filter_map(|line| line.strip_prefix("    "))
+            .filter(|line| !line.starts_with(' ') && !line.starts_with('#'))
+            .filter_map(|line| line.trim_end().strip_suffix(':'))
+            .collect();
+        names.push("nop");
+        names
+    }
+
+    /// Operandos de exemplo para `slots`. Com `negative`, os imediatos e
+    /// offsets são negativos, e os registradores são outros.
+    fn sample_operands(slots: &[Slot], negative: bool) -> Vec<Operand> {
+        // Os campos de bits vão de um extremo ao outro.
+        let (first_reg, imm, offset, pos, size) = if negative {
+            (16, -0x1234, -3, 0, 32)
+        } else {
+            (8, 0x7abc, 0x100, 4, 12)
+        };
+
+        slots
+            .iter()
+            .enumerate()
+            .map(|(i, slot)| match slot {
+                Slot::Reg => Operand::Reg(Register::new(first_reg + i as u32)),
+                Slot::FloatReg => {
+                    Operand::FloatReg(FloatRegister::new(2 * (first_reg + i as u32) % 32))
+                }
+                Slot::Imm => Operand::Imm(imm),
+                Slot::Shamt => Operand::Imm(pos),
+                Slot::Size => Operand::Imm(size),
+                Slot::Offset => Operand::Imm(offset),
+                Slot::Target => Operand::Imm(0x0100040),
+                Slot::Mem => Operand::Mem(offset, Register::new(29)),
+            })
+            .collect()
+    }
+
+    #[test]
+    fn todas_as_instrucoes_vao_e_voltam() {
+        let names = mnemonics();
+        assert!(names.len() > 100);
+
+        for name in names {
+            let slots =
+                Instruction::syntax(name).unwrap_or_else(|| panic!("{} não tem sintaxe", name));
+
+            for &negative in &[false, true] {
+                let instr = Instruction::from_operands(name, &sample_operands(slots, negative))
+                    .unwrap_or_else(|e| panic!("{}: {}", name, e));
+                let (word, text) = (instr.encode(), instr.to_string());
+
+                let decoded = Instruction::decode(word)
+                    .unwrap_or_else(|e| panic!("{} ({:#010x}): {}", text, word, e));
+                assert_eq!(decoded.mnemonic(), name, "{:#010x}", word);
+                assert_eq!(decoded.encode(), word, "{}", text);
+                assert_eq!(decoded.to_string(), text, "{:#010x}", word);
+
+                let parsed =
+                    Instruction::parse(&text).unwrap_or_else(|e| panic!("{}: {}", text, e));
+                assert_eq!(parsed.encode(), word, "{}", text);
+                assert_eq!(parsed.to_string(), text);
+            }
+        }
+    }
+}
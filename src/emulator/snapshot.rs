@@ -0,0 +1,233 @@
+//! Snapshots of the whole machine: the registers, Cop0, the caches and the
+//! memory, saved to a file so that the run can be resumed later exactly
+//! where it was.
+//!
+//! The file starts with a header, the memory configuration the machine was
+//! built with, and is followed by a section for each part of the machine,
+//! written by the part itself and starting with its name. Restoring into a
+//! machine built differently (with another cache, say) stops at the first
+//! section that does not match, instead of loading garbage. Numbers are
+//! little-endian.
+//!
+//! What lives outside of the machine is not saved: open files, the devices
+//! and the console. A resumed run gets them from its own options.
+
+use std::fs;
+
+use color_eyre::eyre::{eyre, Result};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+/// The first bytes of a snapshot file.
+const MAGIC: &[u8; 8] = b"INDYSNAP";
+
+/// The version of the format, bumped whenever it changes.
+const VERSION: u32 = 1;
+
+/// Builds a snapshot.
+pub struct SnapshotWriter {
+    bytes: Vec<u8>,
+}
+
+impl SnapshotWriter {
+    /// Starts a snapshot of a machine with the memory configuration
+    /// `config`.
+    pub fn new(config: &str) -> SnapshotWriter {
+        let mut writer = SnapshotWriter {
+            bytes: MAGIC.to_vec(),
+        };
+        writer.u32(VERSION);
+        writer.str(config);
+        writer
+    }
+
+    /// Starts the section of the part of the machine called `name`.
+    pub fn section(&mut self, name: &str) {
+        self.str(name);
+    }
+
+    pub fn bool(&mut self, val: bool) {
+        self.bytes.push(val as u8);
+    }
+
+    pub fn u32(&mut self, val: u32) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn u64(&mut self, val: u64) {
+        self.bytes.extend_from_slice(&val.to_le_bytes());
+    }
+
+    pub fn usize(&mut self, val: usize) {
+        self.u64(val as u64);
+    }
+
+    pub fn str(&mut self, val: &str) {
+        self.usize(val.len());
+        self.bytes.extend_from_slice(val.as_bytes());
+    }
+
+    /// Saves a random generator. Its state cannot be read, so it is
+    /// reseeded with a seed drawn from itself, and the seed is saved: the
+    /// run goes on the same way, whether it continues or is resumed from
+    /// the snapshot.
+    pub fn rng(&mut self, rng: &mut StdRng) {
+        let seed = rng.gen();
+        *rng = StdRng::seed_from_u64(seed);
+        self.u64(seed);
+    }
+
+    /// Writes the snapshot to `path`.
+    pub fn write(&self, path: &str) -> Result<()> {
+        fs::write(path, &self.bytes).map_err(|e| eyre!("{}: {}", path, e))
+    }
+}
+
+/// Reads a snapshot back, in the same order it was written.
+pub struct SnapshotReader<'a> {
+    bytes: &'a [u8],
+    pos: usize,
+    config: String,
+}
+
+impl<'a> SnapshotReader<'a> {
+    /// Reads the header of the snapshot in `bytes`.
+    pub fn new(bytes: &'a [u8]) -> Result<SnapshotReader<'a>> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(eyre!("not an indy snapshot"));
+        }
+
+        let mut reader = SnapshotReader {
+            bytes,
+            pos: MAGIC.len(),
+            config: String::new(),
+        };
+
+        let version = reader.u32()?;
+        if version != VERSION {
+            return Err(eyre!(
+                "the snapshot is in version {} of the format, this indy reads version {}",
+                version,
+                VERSION
+            ));
+        }
+        reader.config = reader.str()?;
+
+        Ok(reader)
+    }
+
+    /// The memory configuration of the machine that was saved.
+    pub fn config(&self) -> &str {
+        &self.config
+    }
+
+    /// Checks that the next section is the one of `name`.
+    pub fn section(&mut self, name: &str) -> Result<()> {
+        let found = self.str()?;
+        if found != name {
+            return Err(eyre!(
+                "expected the state of {}, found {}: the snapshot is of another machine",
+                name,
+                found
+            ));
+        }
+        Ok(())
+    }
+
+    /// Takes the next `len` bytes.
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        let bytes = self
+            .pos
+            .checked_add(len)
+            .and_then(|end| self.bytes.get(self.pos..end))
+            .ok_or_else(|| eyre!("the snapshot is truncated"))?;
+        self.pos += len;
+        Ok(bytes)
+    }
+
+    pub fn bool(&mut self) -> Result<bool> {
+        Ok(self.take(1)?[0] != 0)
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        let b = self.take(4)?;
+        Ok(u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+    }
+
+    pub fn u64(&mut self) -> Result<u64> {
+        let mut b = [0; 8];
+        b.copy_from_slice(self.take(8)?);
+        Ok(u64::from_le_bytes(b))
+    }
+
+    pub fn usize(&mut self) -> Result<usize> {
+        Ok(self.u64()? as usize)
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        let len = self.usize()?;
+        String::from_utf8(self.take(len)?.to_vec())
+            .map_err(|_| eyre!("the snapshot is corrupted"))
+    }
+
+    /// Reads a random generator saved by `SnapshotWriter::rng`.
+    pub fn rng(&mut self) -> Result<StdRng> {
+        Ok(StdRng::seed_from_u64(self.u64()?))
+    }
+
+    /// Checks that the whole snapshot was read.
+    pub fn finish(&self) -> Result<()> {
+        if self.pos != self.bytes.len() {
+            return Err(eyre!("the snapshot has more state than this machine"));
+        }
+        Ok(())
+    }
+}
+
+/// When to take snapshots during a run, and where to save them.
+#[derive(Debug, Default)]
+pub struct SnapshotPlan {
+    /// The instruction counts to take them at, from the last to the
+    /// first.
+    at: Vec<usize>,
+    /// The file name, with `{}` replaced by the instruction count.
+    path: String,
+    /// The memory configuration, saved in the header.
+    config: String,
+}
+
+impl SnapshotPlan {
+    /// Plans snapshots after each of the instruction counts in `at`, saved
+    /// to `path`.
+    pub fn new(mut at: Vec<usize>, path: impl Into<String>) -> SnapshotPlan {
+        at.sort_unstable_by(|a, b| b.cmp(a));
+        at.dedup();
+
+        SnapshotPlan {
+            at,
+            path: path.into(),
+            config: String::new(),
+        }
+    }
+
+    /// Records the memory configuration of the machine.
+    pub fn set_config(&mut self, config: &str) {
+        self.config = config.into();
+    }
+
+    /// If a snapshot is due after `instructions` instructions, returns a
+    /// writer for it and the file to save it to.
+    pub fn due(&mut self, instructions: usize) -> Option<(SnapshotWriter, String)> {
+        // Counts already behind (like when resuming) are skipped.
+        while self.at.last().is_some_and(|&at| at < instructions) {
+            self.at.pop();
+        }
+
+        if self.at.last() != Some(&instructions) {
+            return None;
+        }
+        self.at.pop();
+
+        let path = self.path.replace("{}", &instructions.to_string());
+        Some((SnapshotWriter::new(&self.config), path))
+    }
+}
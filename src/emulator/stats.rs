@@ -1,11 +1,15 @@
-///! Reportador de estatísticas de execução.
+//! Reportador de estatísticas de execução.
 
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
+use crate::emulator::snapshot::{SnapshotReader, SnapshotWriter};
 use crate::emulator::Instruction;
 
 use color_eyre::eyre::{eyre, Result};
 
+/// A frequência do modelo monociclo, em MHz.
+const MONO_FREQ: f32 = 33.8688 / 4.0;
+
 /// Um reportador de estatísticas de execução.
 /// Armazena o total de ciclos gasto, bem como o tempo gasto
 /// durante a execução.
@@ -13,12 +17,20 @@ pub struct StatsReporter {
     /// O total de instruções executadas, indexadas pelo tipo da 
     /// instrução.
     n_instructions: [usize; 5],
+    /// A soma de `n_instructions`, mantida à parte porque os limites de
+    /// execução a consultam a cada instrução.
+    total_instructions: usize,
     /// O número de (mono)ciclos executados até agora.
     n_cycles: usize,
     /// O instante em que a execução começou.
     start: Option<Instant>,
     /// O instante em que a execução terminou.
     finish: Option<Instant>,
+    /// Por que a execução foi interrompida, se foi.
+    interrupted: Option<String>,
+    /// Se o tempo real de execução deve ser impresso. Ele muda a cada
+    /// execução, então é omitido no modo determinístico.
+    wall_clock: bool,
 }
 
 impl StatsReporter {
@@ -26,9 +38,12 @@ impl StatsReporter {
     pub fn new() -> StatsReporter {
         StatsReporter {
             n_instructions: [0; 5],
+            total_instructions: 0,
             n_cycles: 0,
             start: None,
             finish: None,
+            interrupted: None,
+            wall_clock: true,
         }
     }
 
@@ -42,9 +57,21 @@ impl StatsReporter {
         self.finish = Some(Instant::now());
     }
 
+    /// Deixa de imprimir o tempo real de execução.
+    pub fn hide_wall_clock(&mut self) {
+        self.wall_clock = false;
+    }
+
+    /// Termina a contagem antes do programa terminar, guardando o motivo.
+    pub fn interrupt(&mut self, why: String) {
+        self.finish();
+        self.interrupted = Some(why);
+    }
+
     /// Adiciona uma instrução na contagem de instruções.
     pub fn add_instr(&mut self, instr: &Instruction) {
         self.n_instructions[instr.kind() as usize] += 1;
+        self.total_instructions += 1;
     }
 
     /// Adiciona o número de ciclos à contagem.
@@ -52,6 +79,42 @@ impl StatsReporter {
         self.n_cycles += cycles;
     }
 
+    /// Retorna o número de instruções executadas até agora.
+    pub fn instructions(&self) -> usize {
+        self.total_instructions
+    }
+
+    /// Retorna o número de ciclos executados até agora.
+    pub fn cycles(&self) -> usize {
+        self.n_cycles
+    }
+
+    /// Retorna o tempo de execução simulado até agora, no modelo monociclo.
+    pub fn simulated_time(&self) -> Duration {
+        Duration::from_secs_f64(self.n_cycles as f64 / (MONO_FREQ as f64 * 1_000_000.0))
+    }
+
+    /// Salva as contagens de instruções e de ciclos no snapshot. Os tempos
+    /// reais não são salvos: eles são da execução, não da máquina.
+    pub fn save(&self, snapshot: &mut SnapshotWriter) {
+        snapshot.section("stats");
+        for n in self.n_instructions.iter() {
+            snapshot.usize(*n);
+        }
+        snapshot.usize(self.n_cycles);
+    }
+
+    /// Restaura as contagens salvas por `save`.
+    pub fn restore(&mut self, snapshot: &mut SnapshotReader) -> Result<()> {
+        snapshot.section("stats")?;
+        for n in self.n_instructions.iter_mut() {
+            *n = snapshot.usize()?;
+        }
+        self.total_instructions = self.n_instructions.iter().sum();
+        self.n_cycles = snapshot.usize()?;
+        Ok(())
+    }
+
     /// Analisa as estatísticas e imprime os resultados na saída padrão.
     pub fn print_stats(&self) -> Result<()> {
         let start = *self
@@ -65,12 +128,15 @@ impl StatsReporter {
 
         let delta = finish - start;
 
-        let total: usize = self.n_instructions.iter().sum();
+        let total = self.total_instructions;
 
         let ips = total as f64 / delta.as_secs_f64();
 
         println!();
-        println!("Execution finished successfully!");
+        match &self.interrupted {
+            Some(why) => println!("Execution interrupted: {}", why),
+            None => println!("Execution finished successfully!"),
+        }
         println!("--------------------------");
         println!(
             "Instruction count: {} (R: {} I: {} J: {} FR: {} FI: {})",
@@ -81,8 +147,10 @@ impl StatsReporter {
             self.n_instructions[3],
             self.n_instructions[4],
         );
-        println!("Simulation time: {:.2} sec", delta.as_secs_f32());
-        println!("Average IPS: {:.2}", ips);
+        if self.wall_clock {
+            println!("Simulation time: {:.2} sec", delta.as_secs_f32());
+            println!("Average IPS: {:.2}", ips);
+        }
 
         println!();
         println!();
@@ -93,7 +161,7 @@ impl StatsReporter {
         println!("Monocycle");
         let mono_cycles = self.n_cycles;
         println!("\tCycles: {}", mono_cycles);
-        let mono_freq = 33.8688 / 4.0;
+        let mono_freq = MONO_FREQ;
         println!("\tFrequency: {:.4} MHz", mono_freq);
         let mono_time = (mono_cycles as f32) / mono_freq / 1_000_000.0;
         println!("\tEstimated execution time: {:.4} sec", mono_time);
@@ -0,0 +1,253 @@
+//! The symbol table of the guest program.
+//!
+//! It turns addresses back into names, like `main+0x1c`, for the
+//! disassembly, the instruction trace and the fault messages. The symbols
+//! come from the loader; programs without them (like the split MARS
+//! files) just get bare addresses everywhere.
+//!
+//! Programs built with `-g` also have a line table, which turns addresses
+//! into source lines, like `pi.c:42`.
+
+use std::fmt;
+
+/// A named address of the program.
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub addr: u32,
+    /// The size in bytes, or 0 if unknown.
+    pub size: u32,
+    /// Is it a function, as opposed to data or a plain label?
+    pub func: bool,
+}
+
+/// An address, relative to the symbol that contains it.
+#[derive(Copy, Clone, Debug)]
+pub struct Location<'a> {
+    pub symbol: &'a Symbol,
+    pub offset: u32,
+}
+
+impl fmt::Display for Location<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.offset == 0 {
+            write!(f, "{}", self.symbol.name)
+        } else {
+            write!(f, "{}+{:#x}", self.symbol.name, self.offset)
+        }
+    }
+}
+
+/// A line of a source file.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SourceLine<'a> {
+    pub file: &'a SourceFile,
+    pub line: u32,
+}
+
+impl fmt::Display for SourceLine<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}", self.file.name, self.line)
+    }
+}
+
+/// A source file mentioned by the line table.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct SourceFile {
+    /// The name given to the compiler, like `pi.c` or `src/pi.c`.
+    pub name: String,
+    /// The directory the compiler ran in, if known.
+    pub dir: Option<String>,
+}
+
+/// One row of the line table: the code from `addr` up to the next row
+/// comes from `line` of `files[file]`. Rows with no file end a sequence
+/// of code.
+#[derive(Copy, Clone, Debug)]
+pub struct LineRow {
+    pub addr: u32,
+    pub file: Option<usize>,
+    pub line: u32,
+}
+
+/// Maps addresses to source lines.
+#[derive(Clone, Debug, Default)]
+pub struct LineTable {
+    files: Vec<SourceFile>,
+    rows: Vec<LineRow>,
+}
+
+impl LineTable {
+    /// Builds the table from its rows, in any order.
+    pub fn new(files: Vec<SourceFile>, mut rows: Vec<LineRow>) -> LineTable {
+        // A sequence may end right where the next one starts, so the ends
+        // go first.
+        rows.sort_by_key(|r| (r.addr, r.file.is_some()));
+        LineTable { files, rows }
+    }
+
+    /// The source line the code at `addr` comes from.
+    pub fn lookup(&self, addr: u32) -> Option<SourceLine<'_>> {
+        let i = self.rows.partition_point(|r| r.addr <= addr);
+        let row = self.rows[..i].last()?;
+
+        Some(SourceLine {
+            file: self.files.get(row.file?)?,
+            line: row.line,
+        })
+    }
+}
+
+/// The symbols of a program, sorted by address, and its line table.
+#[derive(Clone, Debug, Default)]
+pub struct Symbols {
+    symbols: Vec<Symbol>,
+    lines: LineTable,
+}
+
+impl Symbols {
+    /// Builds the table. When several symbols share an address, the
+    /// functions win over the other kinds, and then the first one wins.
+    pub fn new(mut symbols: Vec<Symbol>) -> Symbols {
+        symbols.retain(|s| !s.name.is_empty());
+        symbols.sort_by_key(|s| (s.addr, !s.func));
+        symbols.dedup_by_key(|s| s.addr);
+
+        Symbols {
+            symbols,
+            lines: LineTable::default(),
+        }
+    }
+
+    /// Sets the line table.
+    pub fn set_lines(&mut self, lines: LineTable) {
+        self.lines = lines;
+    }
+
+    /// The source line the code at `addr` comes from, if known.
+    pub fn line(&self, addr: u32) -> Option<SourceLine<'_>> {
+        self.lines.lookup(addr)
+    }
+
+    /// The symbol that starts exactly at `addr`, if any.
+    pub fn at(&self, addr: u32) -> Option<&Symbol> {
+        self.symbols
+            .binary_search_by_key(&addr, |s| s.addr)
+            .ok()
+            .map(|i| &self.symbols[i])
+    }
+
+    /// Finds the symbol that contains `addr`: the closest one at or
+    /// before it, as long as `addr` is not past its end. Symbols of
+    /// unknown size extend up to the next one.
+    pub fn locate(&self, addr: u32) -> Option<Location<'_>> {
+        let i = self.symbols.partition_point(|s| s.addr <= addr);
+        let symbol = self.symbols[..i].last()?;
+        let offset = addr - symbol.addr;
+
+        if symbol.size != 0 && offset >= symbol.size {
+            return None;
+        }
+
+        Some(Location { symbol, offset })
+    }
+
+    /// Formats `addr` as `0x00400010 <main+0x10> (pi.c:42)`, leaving out
+    /// what is not known about it.
+    pub fn describe(&self, addr: u32) -> String {
+        let mut desc = format!("{:#010x}", addr);
+        if let Some(loc) = self.locate(addr) {
+            desc += &format!(" <{}>", loc);
+        }
+        if let Some(line) = self.line(addr) {
+            desc += &format!(" ({})", line);
+        }
+        desc
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn symbol(name: &str, addr: u32, size: u32, func: bool) -> Symbol {
+        Symbol {
+            name: name.to_string(),
+            addr,
+            size,
+            func,
+        }
+    }
+
+    fn locate(symbols: &Symbols, addr: u32) -> Option<String> {
+        symbols.locate(addr).map(|loc| loc.to_string())
+    }
+
+    #[test]
+    fn lookup() {
+        let symbols = Symbols::new(vec![
+            symbol("loop", 0x00400010, 0, false),
+            symbol("main", 0x00400000, 0x20, true),
+            symbol("", 0x00400040, 0, false),
+            symbol("table", 0x10010000, 8, false),
+            // Functions win over labels at the same address.
+            symbol("start", 0x00400020, 0, false),
+            symbol("helper", 0x00400020, 0x10, true),
+        ]);
+
+        assert_eq!(locate(&symbols, 0x003FFFFC), None);
+        assert_eq!(locate(&symbols, 0x00400000).unwrap(), "main");
+        assert_eq!(locate(&symbols, 0x00400004).unwrap(), "main+0x4");
+        // A label inside a function is closer.
+        assert_eq!(locate(&symbols, 0x0040001c).unwrap(), "loop+0xc");
+        assert_eq!(locate(&symbols, 0x00400024).unwrap(), "helper+0x4");
+        // Past the end of a sized symbol, and the unnamed one is dropped.
+        assert_eq!(locate(&symbols, 0x00400040), None);
+        assert_eq!(locate(&symbols, 0x10010004).unwrap(), "table+0x4");
+        assert_eq!(locate(&symbols, 0x10010008), None);
+
+        assert_eq!(symbols.at(0x00400020).unwrap().name, "helper");
+        assert!(symbols.at(0x00400024).is_none());
+    }
+
+    #[test]
+    fn line_table() {
+        let files = vec![
+            SourceFile {
+                name: "pi.c".to_string(),
+                dir: None,
+            },
+            SourceFile {
+                name: "util.h".to_string(),
+                dir: None,
+            },
+        ];
+        let row = |addr, file, line| LineRow { addr, file, line };
+        let lines = LineTable::new(
+            files,
+            vec![
+                row(0x00400008, Some(1), 3),
+                row(0x00400000, Some(0), 10),
+                // One sequence ends where the next one starts.
+                row(0x00400010, None, 0),
+                row(0x00400010, Some(0), 20),
+                row(0x00400018, None, 0),
+            ],
+        );
+
+        let line = |addr| lines.lookup(addr).map(|l| l.to_string());
+        assert_eq!(line(0x003FFFFC), None);
+        assert_eq!(line(0x00400004).unwrap(), "pi.c:10");
+        assert_eq!(line(0x00400008).unwrap(), "util.h:3");
+        assert_eq!(line(0x00400010).unwrap(), "pi.c:20");
+        assert_eq!(line(0x00400018), None);
+
+        let mut symbols = Symbols::new(vec![symbol("main", 0x00400000, 0x18, true)]);
+        symbols.set_lines(lines);
+        assert_eq!(
+            symbols.describe(0x00400014),
+            "0x00400014 <main+0x14> (pi.c:20)"
+        );
+        assert_eq!(symbols.describe(0x00400018), "0x00400018");
+    }
+}
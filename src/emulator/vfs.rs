@@ -0,0 +1,544 @@
+//! The filesystem seen by the guest.
+//!
+//! Guest paths never reach the host as they are. They are resolved
+//! against a host directory mounted as the guest root: `/` and the guest
+//! working directory are both that directory, and `..` cannot climb above
+//! it. Symbolic links pointing outside of the root are refused as well.
+//!
+//! On top of that, the root can be mounted read-only, or with an
+//! in-memory overlay. With the overlay, files opened for writing are
+//! copied to memory and the host directory is never modified; the final
+//! contents of those files can be dumped to a directory once the program
+//! ends, so they can be checked.
+
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::convert::TryFrom;
+use std::fs::{self, File, Metadata};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::ffi::OsStr;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Component, Path, PathBuf};
+use std::rc::Rc;
+
+/// The errno values we build errors from. They are the same on MIPS and
+/// on the hosts we run on.
+const ENOENT: i32 = 2;
+const EBADF: i32 = 9;
+const EACCES: i32 = 13;
+const EEXIST: i32 = 17;
+const EINVAL: i32 = 22;
+const EFBIG: i32 = 27;
+const EROFS: i32 = 30;
+
+/// The largest file the overlay holds, since it lives in our memory.
+const MAX_OVERLAY_FILE: u64 = 64 << 20;
+
+/// How a file is opened, independently of where it lives.
+#[derive(Copy, Clone, Debug)]
+pub struct OpenOptions {
+    pub read: bool,
+    pub write: bool,
+    pub append: bool,
+    pub create: bool,
+    pub create_new: bool,
+    pub truncate: bool,
+    /// The permissions of a created file.
+    pub mode: u32,
+}
+
+impl Default for OpenOptions {
+    fn default() -> Self {
+        OpenOptions {
+            read: false,
+            write: false,
+            append: false,
+            create: false,
+            create_new: false,
+            truncate: false,
+            mode: 0o666,
+        }
+    }
+}
+
+impl OpenOptions {
+    /// Whether opening with these options may modify the filesystem.
+    fn writes(&self) -> bool {
+        self.write || self.append || self.create || self.create_new || self.truncate
+    }
+}
+
+/// The contents of a file in the overlay, shared by its descriptors.
+type Contents = Rc<RefCell<Vec<u8>>>;
+
+/// The filesystem seen by the guest.
+pub struct Vfs {
+    /// The host directory mounted as `/`.
+    root: PathBuf,
+    /// Refuses anything that would modify a file.
+    read_only: bool,
+    /// The files written by the guest, by their guest path, if the
+    /// overlay is on.
+    overlay: Option<BTreeMap<PathBuf, Contents>>,
+}
+
+impl Default for Vfs {
+    /// Mounts the current directory, writable and without an overlay.
+    fn default() -> Self {
+        Vfs::new(".")
+    }
+}
+
+impl Vfs {
+    /// Mounts the host directory `root` as the guest root.
+    pub fn new(root: impl Into<PathBuf>) -> Vfs {
+        Vfs {
+            root: root.into(),
+            read_only: false,
+            overlay: None,
+        }
+    }
+
+    /// Turns the read-only mode on or off.
+    pub fn set_read_only(&mut self, read_only: bool) {
+        self.read_only = read_only;
+    }
+
+    /// Turns the in-memory overlay on. Files already in it are kept.
+    pub fn enable_overlay(&mut self) {
+        self.overlay.get_or_insert_with(BTreeMap::new);
+    }
+
+    /// Opens the file at the guest `path`.
+    pub fn open(&mut self, path: &[u8], options: &OpenOptions) -> io::Result<VfsFile> {
+        let guest = guest_path(path)?;
+
+        if self.read_only && options.writes() {
+            return Err(io::Error::from_raw_os_error(EROFS));
+        }
+
+        let host = self.host_path(&guest)?;
+
+        if let Some(overlay) = self.overlay.as_mut() {
+            if let Some(contents) = overlay.get(&guest) {
+                if options.create_new {
+                    return Err(io::Error::from_raw_os_error(EEXIST));
+                }
+                if options.truncate {
+                    contents.borrow_mut().clear();
+                }
+                return Ok(VfsFile::memory(contents.clone(), options));
+            }
+
+            if options.writes() {
+                // Copy on write: the host file is never touched.
+                let data = match fs::read(&host) {
+                    Ok(_) if options.create_new => {
+                        return Err(io::Error::from_raw_os_error(EEXIST))
+                    }
+                    Ok(_) if options.truncate => Vec::new(),
+                    Ok(data) => data,
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => {
+                        if !options.create && !options.create_new {
+                            return Err(e);
+                        }
+                        Vec::new()
+                    }
+                    Err(e) => return Err(e),
+                };
+
+                let contents = Rc::new(RefCell::new(data));
+                overlay.insert(guest, contents.clone());
+                return Ok(VfsFile::memory(contents, options));
+            }
+        }
+
+        fs::OpenOptions::new()
+            .read(options.read)
+            .write(options.write)
+            .append(options.append)
+            .create(options.create)
+            .create_new(options.create_new)
+            .truncate(options.truncate)
+            .mode(options.mode)
+            .open(host)
+            .map(VfsFile::Host)
+    }
+
+    /// Removes the file at the guest `path`. With the overlay, only files
+    /// in it can be removed, since the host directory is never modified.
+    pub fn remove(&mut self, path: &[u8]) -> io::Result<()> {
+        let guest = guest_path(path)?;
+
+        if self.read_only {
+            return Err(io::Error::from_raw_os_error(EROFS));
+        }
+
+        let host = self.host_path(&guest)?;
+
+        match self.overlay.as_mut() {
+            Some(overlay) => match overlay.remove(&guest) {
+                Some(_) => Ok(()),
+                None if host.exists() => Err(io::Error::from_raw_os_error(EACCES)),
+                None => Err(io::Error::from_raw_os_error(ENOENT)),
+            },
+            None => fs::remove_file(host),
+        }
+    }
+
+    /// Writes the files in the overlay under the host directory `dir`,
+    /// keeping their guest paths.
+    pub fn dump(&self, dir: impl AsRef<Path>) -> io::Result<()> {
+        for (path, contents) in self.overlay.iter().flatten() {
+            let dest = dir.as_ref().join(path);
+            if let Some(parent) = dest.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            fs::write(dest, &*contents.borrow())?;
+        }
+
+        Ok(())
+    }
+
+    /// Maps a guest path to the host, making sure it stays inside the
+    /// root even if it goes through symbolic links.
+    fn host_path(&self, guest: &Path) -> io::Result<PathBuf> {
+        let root = self.root.canonicalize()?;
+        let host = root.join(guest);
+
+        // A file that does not exist yet is checked through its parent.
+        let resolved = match host.canonicalize() {
+            Ok(resolved) => resolved,
+            // A dangling link could still point outside.
+            Err(e) if e.kind() == io::ErrorKind::NotFound && host.symlink_metadata().is_ok() => {
+                return Err(io::Error::from_raw_os_error(EACCES))
+            }
+            Err(e) if e.kind() == io::ErrorKind::NotFound => match host.parent() {
+                Some(parent) => match parent.canonicalize() {
+                    Ok(parent) => parent,
+                    Err(_) => return Err(io::Error::from_raw_os_error(ENOENT)),
+                },
+                None => root.clone(),
+            },
+            Err(e) => return Err(e),
+        };
+
+        if resolved.starts_with(&root) {
+            Ok(host)
+        } else {
+            log::debug!("vfs: {} escapes the root", guest.display());
+            Err(io::Error::from_raw_os_error(EACCES))
+        }
+    }
+}
+
+/// Normalizes a guest path, relative to the guest root. The working
+/// directory is the root, and `..` at the root stays there.
+fn guest_path(path: &[u8]) -> io::Result<PathBuf> {
+    let mut guest = PathBuf::new();
+
+    for component in Path::new(OsStr::from_bytes(path)).components() {
+        match component {
+            Component::Normal(name) => guest.push(name),
+            Component::ParentDir => {
+                guest.pop();
+            }
+            _ => {}
+        }
+    }
+
+    if path.is_empty() {
+        Err(io::Error::from_raw_os_error(ENOENT))
+    } else {
+        Ok(guest)
+    }
+}
+
+/// What `fstat` reports about a file.
+pub enum FileMetadata {
+    Host(Metadata),
+    /// A file in the overlay, of the given size.
+    Memory(u64),
+}
+
+/// A file opened by the guest.
+pub enum VfsFile {
+    Host(File),
+    Memory(MemoryFile),
+}
+
+/// A descriptor for a file in the overlay.
+pub struct MemoryFile {
+    contents: Contents,
+    pos: u64,
+    read: bool,
+    write: bool,
+    append: bool,
+}
+
+impl VfsFile {
+    fn memory(contents: Contents, options: &OpenOptions) -> VfsFile {
+        VfsFile::Memory(MemoryFile {
+            contents,
+            pos: 0,
+            read: options.read,
+            write: options.write || options.append,
+            append: options.append,
+        })
+    }
+
+    /// Returns what `fstat` must report about the file.
+    pub fn metadata(&self) -> io::Result<FileMetadata> {
+        match self {
+            VfsFile::Host(file) => file.metadata().map(FileMetadata::Host),
+            VfsFile::Memory(file) => Ok(FileMetadata::Memory(
+                file.contents.borrow().len() as u64,
+            )),
+        }
+    }
+}
+
+impl Read for VfsFile {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            VfsFile::Host(file) => file.read(buf),
+            VfsFile::Memory(file) => {
+                if !file.read {
+                    return Err(io::Error::from_raw_os_error(EBADF));
+                }
+
+                let contents = file.contents.borrow();
+                let start = (file.pos as usize).min(contents.len());
+                let n = buf.len().min(contents.len() - start);
+
+                buf[..n].copy_from_slice(&contents[start..start + n]);
+                file.pos += n as u64;
+
+                Ok(n)
+            }
+        }
+    }
+}
+
+impl Write for VfsFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            VfsFile::Host(file) => file.write(buf),
+            VfsFile::Memory(file) => {
+                if !file.write {
+                    return Err(io::Error::from_raw_os_error(EBADF));
+                }
+
+                let mut contents = file.contents.borrow_mut();
+                if file.append {
+                    file.pos = contents.len() as u64;
+                }
+
+                let end = file.pos.saturating_add(buf.len() as u64);
+                if end > MAX_OVERLAY_FILE {
+                    return Err(io::Error::from_raw_os_error(EFBIG));
+                }
+
+                let (start, end) = (file.pos as usize, end as usize);
+                if contents.len() < end {
+                    contents.resize(end, 0);
+                }
+
+                contents[start..end].copy_from_slice(buf);
+                file.pos += buf.len() as u64;
+
+                Ok(buf.len())
+            }
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            VfsFile::Host(file) => file.flush(),
+            VfsFile::Memory(_) => Ok(()),
+        }
+    }
+}
+
+impl Seek for VfsFile {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        match self {
+            VfsFile::Host(file) => file.seek(pos),
+            VfsFile::Memory(file) => {
+                let len = file.contents.borrow().len() as i64;
+                let new = match pos {
+                    SeekFrom::Start(offset) => i64::try_from(offset).ok(),
+                    SeekFrom::Current(offset) => (file.pos as i64).checked_add(offset),
+                    SeekFrom::End(offset) => len.checked_add(offset),
+                };
+
+                match new {
+                    Some(new) if new >= 0 => file.pos = new as u64,
+                    _ => return Err(io::Error::from_raw_os_error(EINVAL)),
+                }
+
+                Ok(file.pos)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::os::unix::fs::symlink;
+
+    /// A scratch directory holding a root with `in.txt`, a `secret.txt`
+    /// outside of it, and links from the root to the outside.
+    struct Scratch(PathBuf);
+
+    impl Scratch {
+        fn new(name: &str) -> Scratch {
+            let dir =
+                std::env::temp_dir().join(format!("indy-vfs-{}-{}", name, std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            fs::create_dir_all(dir.join("root/sub")).unwrap();
+            fs::write(dir.join("root/in.txt"), b"input").unwrap();
+            fs::write(dir.join("secret.txt"), b"secret").unwrap();
+            symlink("../secret.txt", dir.join("root/link")).unwrap();
+            symlink("../nothing.txt", dir.join("root/dangling")).unwrap();
+            symlink("../..", dir.join("root/sub/up")).unwrap();
+            Scratch(dir)
+        }
+
+        fn vfs(&self) -> Vfs {
+            Vfs::new(self.0.join("root"))
+        }
+    }
+
+    impl Drop for Scratch {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn read() -> OpenOptions {
+        OpenOptions {
+            read: true,
+            ..Default::default()
+        }
+    }
+
+    fn create() -> OpenOptions {
+        OpenOptions {
+            read: true,
+            write: true,
+            create: true,
+            ..Default::default()
+        }
+    }
+
+    fn errno<T>(result: io::Result<T>) -> Option<i32> {
+        result.err().and_then(|e| e.raw_os_error())
+    }
+
+    fn contents(file: &mut VfsFile) -> Vec<u8> {
+        let mut data = Vec::new();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        file.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    #[test]
+    fn parent_dirs_stay_in_the_root() {
+        let scratch = Scratch::new("dotdot");
+        let mut vfs = scratch.vfs();
+
+        // `..` at the root is the root itself.
+        let mut file = vfs.open(b"../../in.txt", &read()).unwrap();
+        assert_eq!(contents(&mut file), b"input");
+        assert_eq!(errno(vfs.open(b"../secret.txt", &read())), Some(ENOENT));
+        assert_eq!(
+            errno(vfs.open(b"/sub/../../secret.txt", &read())),
+            Some(ENOENT)
+        );
+    }
+
+    #[test]
+    fn symlinks_stay_in_the_root() {
+        let scratch = Scratch::new("symlink");
+        let mut vfs = scratch.vfs();
+
+        assert_eq!(errno(vfs.open(b"link", &read())), Some(EACCES));
+        assert_eq!(errno(vfs.open(b"sub/up/secret.txt", &read())), Some(EACCES));
+        assert_eq!(errno(vfs.open(b"sub/up/new.txt", &create())), Some(EACCES));
+        assert_eq!(errno(vfs.open(b"dangling", &create())), Some(EACCES));
+        assert!(!scratch.0.join("nothing.txt").exists());
+        assert!(!scratch.0.join("new.txt").exists());
+
+        // Links that stay inside are fine.
+        let mut file = vfs.open(b"sub/up/root/in.txt", &read()).unwrap();
+        assert_eq!(contents(&mut file), b"input");
+        symlink("in.txt", scratch.0.join("root/alias")).unwrap();
+        let mut file = vfs.open(b"alias", &read()).unwrap();
+        assert_eq!(contents(&mut file), b"input");
+    }
+
+    #[test]
+    fn read_only() {
+        let scratch = Scratch::new("ro");
+        let mut vfs = scratch.vfs();
+        vfs.set_read_only(true);
+
+        let mut file = vfs.open(b"in.txt", &read()).unwrap();
+        assert_eq!(contents(&mut file), b"input");
+
+        let append = OpenOptions {
+            append: true,
+            ..Default::default()
+        };
+        assert_eq!(errno(vfs.open(b"in.txt", &append)), Some(EROFS));
+        assert_eq!(errno(vfs.open(b"new.txt", &create())), Some(EROFS));
+        assert_eq!(errno(vfs.remove(b"in.txt")), Some(EROFS));
+        assert!(!scratch.0.join("root/new.txt").exists());
+        assert_eq!(fs::read(scratch.0.join("root/in.txt")).unwrap(), b"input");
+    }
+
+    #[test]
+    fn overlay() {
+        let scratch = Scratch::new("overlay");
+        let mut vfs = scratch.vfs();
+        vfs.enable_overlay();
+
+        let mut file = vfs.open(b"in.txt", &create()).unwrap();
+        file.seek(SeekFrom::End(0)).unwrap();
+        file.write_all(b" and more").unwrap();
+        let mut file = vfs.open(b"/sub/new.txt", &create()).unwrap();
+        file.write_all(b"new").unwrap();
+
+        // Other descriptors see the changes, the host does not.
+        let mut file = vfs.open(b"in.txt", &read()).unwrap();
+        assert_eq!(contents(&mut file), b"input and more");
+        assert_eq!(fs::read(scratch.0.join("root/in.txt")).unwrap(), b"input");
+        assert!(!scratch.0.join("root/sub/new.txt").exists());
+
+        let dump = scratch.0.join("dump");
+        vfs.dump(&dump).unwrap();
+        assert_eq!(fs::read(dump.join("in.txt")).unwrap(), b"input and more");
+        assert_eq!(fs::read(dump.join("sub/new.txt")).unwrap(), b"new");
+    }
+
+    #[test]
+    fn overlay_files_are_capped() {
+        let scratch = Scratch::new("efbig");
+        let mut vfs = scratch.vfs();
+        vfs.enable_overlay();
+
+        let mut file = vfs.open(b"big.txt", &create()).unwrap();
+        file.seek(SeekFrom::Start(MAX_OVERLAY_FILE - 1)).unwrap();
+        assert_eq!(errno(file.write(b"ab")), Some(EFBIG));
+        file.seek(SeekFrom::Start(u64::MAX)).unwrap_err();
+        file.seek(SeekFrom::Start(i64::MAX as u64)).unwrap();
+        assert_eq!(errno(file.write(b"a")), Some(EFBIG));
+        assert_eq!(errno(file.seek(SeekFrom::Current(1))), Some(EINVAL));
+
+        assert!(matches!(file.metadata().unwrap(), FileMetadata::Memory(0)));
+    }
+}
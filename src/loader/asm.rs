@@ -0,0 +1,100 @@
+//! Assembly sources, assembled as they are loaded.
+
+use super::image::Image;
+use super::{Loader, Placement, Program};
+use crate::assembler::{self, Assembly};
+use crate::emulator::cop0::physical_address;
+use crate::emulator::symbols::{LineRow, LineTable, SourceFile, Symbol, Symbols};
+
+use std::fs;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Reads the assembly source at `path`. MARS takes its files in the
+/// platform's encoding, so anything that is not UTF-8 is read as Latin-1.
+pub fn read_source(path: &str) -> Result<String> {
+    let bytes = fs::read(path).map_err(|e| eyre!("{}: {}", path, e))?;
+    Ok(match String::from_utf8(bytes) {
+        Ok(source) => source,
+        Err(e) => e.into_bytes().iter().map(|&b| b as char).collect(),
+    })
+}
+
+/// Loads assembly sources.
+pub struct AsmLoader {
+    path: String,
+}
+
+impl AsmLoader {
+    /// Creates the loader of the source at `path`.
+    pub fn new(path: impl Into<String>) -> AsmLoader {
+        AsmLoader { path: path.into() }
+    }
+}
+
+impl Loader for AsmLoader {
+    /// Assembles the program. The labels become its symbols, and each
+    /// instruction knows the line it came from.
+    fn read(&self, placement: Placement) -> Result<Program> {
+        let asm = assembler::assemble(&read_source(&self.path)?, &self.path)?;
+        let mut image = Image::default();
+
+        for segment in asm.segments.iter().filter(|s| !s.bytes.is_empty()) {
+            let addr = match placement {
+                Placement::Virtual => segment.addr,
+                Placement::Physical => physical_address(segment.addr),
+            };
+            image.chunks.push((addr, segment.bytes.clone()));
+        }
+
+        let mut program = Program::new(image, asm.entry);
+        program.symbols = symbols(&asm, &self.path);
+
+        Ok(program)
+    }
+}
+
+/// The symbol table of the program: its labels, and the line of each
+/// instruction.
+fn symbols(asm: &Assembly, path: &str) -> Symbols {
+    let mut symbols = Symbols::new(
+        asm.labels
+            .iter()
+            .map(|label| Symbol {
+                name: label.name.clone(),
+                addr: label.addr,
+                size: 0,
+                func: asm.segments[label.segment].is_code(),
+            })
+            .collect(),
+    );
+
+    let mut rows: Vec<LineRow> = asm
+        .lines
+        .iter()
+        .map(|&(addr, line)| LineRow {
+            addr,
+            file: Some(0),
+            line,
+        })
+        .collect();
+
+    // The code ends with its segment.
+    rows.extend(
+        asm.segments
+            .iter()
+            .filter(|s| s.is_code())
+            .map(|s| LineRow {
+                addr: s.addr.wrapping_add(s.bytes.len() as u32),
+                file: None,
+                line: 0,
+            }),
+    );
+
+    let file = SourceFile {
+        name: path.to_string(),
+        dir: None,
+    };
+    symbols.set_lines(LineTable::new(vec![file], rows));
+    symbols
+}
@@ -0,0 +1,355 @@
+//! Reads the DWARF debugging information of programs built with `-g`.
+//!
+//! Only two things are used: the line table (`.debug_line`), which maps
+//! addresses to source lines, and the functions described in
+//! `.debug_info`, which name the ones missing from the symbol table, like
+//! the `static` functions of a stripped program.
+
+use super::elf::ElfFile;
+use crate::emulator::symbols::{LineRow, LineTable, SourceFile, SourceLine, Symbol};
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use gimli::{AttributeValue, Dwarf, EndianSlice, LittleEndian, SectionId, Unit};
+
+type Slice<'a> = EndianSlice<'a, LittleEndian>;
+
+/// What the debugging information says about the program.
+pub struct DebugInfo {
+    pub lines: LineTable,
+    pub functions: Vec<Symbol>,
+}
+
+/// Reads the debugging information of `elf`. A program without it gets
+/// an empty line table and no functions.
+pub fn read(elf: &ElfFile) -> gimli::Result<DebugInfo> {
+    let section = |id: SectionId| -> gimli::Result<Slice> {
+        let data = elf.section_by_name(id.name()).unwrap_or(&[]);
+        Ok(EndianSlice::new(data, LittleEndian))
+    };
+    let supplementary = |_| Ok(EndianSlice::new(&[], LittleEndian));
+    let dwarf = Dwarf::load(section, supplementary)?;
+
+    let mut files = Vec::new();
+    let mut rows = Vec::new();
+    let mut functions = Vec::new();
+
+    let mut units = dwarf.units();
+    while let Some(header) = units.next()? {
+        let unit = dwarf.unit(header)?;
+        read_lines(&dwarf, &unit, &mut files, &mut rows)?;
+        read_functions(&dwarf, &unit, &mut functions)?;
+    }
+
+    Ok(DebugInfo {
+        lines: LineTable::new(files, rows),
+        functions,
+    })
+}
+
+/// Reads a string attribute.
+fn string(dwarf: &Dwarf<Slice>, unit: &Unit<Slice>, attr: AttributeValue<Slice>) -> Option<String> {
+    let s = dwarf.attr_string(unit, attr).ok()?;
+    Some(String::from_utf8_lossy(s.slice()).into_owned())
+}
+
+/// Appends the rows of the line program of `unit` to `rows`, adding the
+/// files it mentions to `files`.
+fn read_lines(
+    dwarf: &Dwarf<Slice>,
+    unit: &Unit<Slice>,
+    files: &mut Vec<SourceFile>,
+    rows: &mut Vec<LineRow>,
+) -> gimli::Result<()> {
+    let program = match unit.line_program.clone() {
+        Some(program) => program,
+        None => return Ok(()),
+    };
+    let comp_dir = unit
+        .comp_dir
+        .map(|dir| String::from_utf8_lossy(dir.slice()).into_owned());
+
+    // The index of each file of this unit in `files`.
+    let mut indices: HashMap<u64, Option<usize>> = HashMap::new();
+
+    let mut program_rows = program.rows();
+    while let Some((header, row)) = program_rows.next_row()? {
+        let addr = row.address() as u32;
+
+        // Line 0 is code that does not come from any line.
+        let line = match row.line() {
+            Some(line) if !row.end_sequence() => line as u32,
+            _ => {
+                rows.push(LineRow {
+                    addr,
+                    file: None,
+                    line: 0,
+                });
+                continue;
+            }
+        };
+
+        let file = *indices.entry(row.file_index()).or_insert_with(|| {
+            let entry = row.file(header)?;
+            let mut name = string(dwarf, unit, entry.path_name())?;
+
+            // Directory 0 is the one the compiler ran in.
+            if entry.directory_index() != 0 && !name.starts_with('/') {
+                if let Some(dir) = entry.directory(header) {
+                    name = format!("{}/{}", string(dwarf, unit, dir)?, name);
+                }
+            }
+
+            let file = SourceFile {
+                name,
+                dir: comp_dir.clone(),
+            };
+            match files.iter().position(|f| *f == file) {
+                Some(i) => Some(i),
+                None => {
+                    files.push(file);
+                    Some(files.len() - 1)
+                }
+            }
+        });
+
+        rows.push(LineRow { addr, file, line });
+    }
+
+    Ok(())
+}
+
+/// Appends the functions of `unit` that have code to `functions`.
+fn read_functions(
+    dwarf: &Dwarf<Slice>,
+    unit: &Unit<Slice>,
+    functions: &mut Vec<Symbol>,
+) -> gimli::Result<()> {
+    let mut entries = unit.entries();
+    while let Some((_, entry)) = entries.next_dfs()? {
+        if entry.tag() != gimli::DW_TAG_subprogram {
+            continue;
+        }
+
+        let name = match entry.attr_value(gimli::DW_AT_name)? {
+            Some(attr) => string(dwarf, unit, attr),
+            None => None,
+        };
+        let low = match entry.attr_value(gimli::DW_AT_low_pc)? {
+            Some(attr) => dwarf.attr_address(unit, attr)?,
+            None => None,
+        };
+        let (name, low) = match (name, low) {
+            (Some(name), Some(low)) => (name, low),
+            // Declarations and inlined functions have no code of their own.
+            _ => continue,
+        };
+
+        let size = match entry.attr_value(gimli::DW_AT_high_pc)? {
+            Some(AttributeValue::Addr(high)) => high.saturating_sub(low),
+            Some(attr) => attr.udata_value().unwrap_or(0),
+            None => 0,
+        };
+
+        functions.push(Symbol {
+            name,
+            addr: low as u32,
+            size: size as u32,
+            func: true,
+        });
+    }
+
+    Ok(())
+}
+
+/// The source files of a program, read when first needed, to show them
+/// next to its code.
+pub struct Sources {
+    /// Where to look for the files the compiler did not find in its own
+    /// directory: usually, next to the program.
+    fallback: PathBuf,
+    files: HashMap<String, Option<Vec<String>>>,
+}
+
+impl Sources {
+    /// Creates the cache for the program at `program`.
+    pub fn new(program: &Path) -> Sources {
+        Sources {
+            fallback: program.parent().unwrap_or_else(|| Path::new("")).into(),
+            files: HashMap::new(),
+        }
+    }
+
+    /// The text of `line`, if its file can be found.
+    pub fn text(&mut self, line: SourceLine) -> Option<&str> {
+        let fallback = &self.fallback;
+        let lines = self
+            .files
+            .entry(line.file.name.clone())
+            .or_insert_with(|| Sources::read(fallback, line.file))
+            .as_ref()?;
+
+        lines
+            .get((line.line as usize).checked_sub(1)?)
+            .map(|s| s.as_str())
+    }
+
+    /// Reads `file` from where it was compiled, or from `fallback`.
+    fn read(fallback: &Path, file: &SourceFile) -> Option<Vec<String>> {
+        let name = Path::new(&file.name);
+        let mut candidates = Vec::new();
+        match &file.dir {
+            Some(dir) => candidates.push(Path::new(dir).join(name)),
+            None => candidates.push(name.into()),
+        }
+        candidates.push(fallback.join(name));
+        if let Some(base) = name.file_name() {
+            candidates.push(fallback.join(base));
+        }
+
+        let text = candidates.iter().find_map(|path| fs::read(path).ok())?;
+        Some(
+            String::from_utf8_lossy(&text)
+                .lines()
+                .map(String::from)
+                .collect(),
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::loader::elf;
+
+    use goblin::elf::header;
+    use goblin::elf::section_header::{SHT_PROGBITS, SHT_STRTAB};
+
+    /// An executable with nothing but the sections `sections`.
+    fn with_sections(sections: &[(&str, Vec<u8>)]) -> Vec<u8> {
+        let word = |bytes: &mut Vec<u8>, v: u32| bytes.extend_from_slice(&v.to_le_bytes());
+        let half = |bytes: &mut Vec<u8>, v: u16| bytes.extend_from_slice(&v.to_le_bytes());
+
+        let mut bytes = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        half(&mut bytes, header::ET_EXEC); // e_type
+        half(&mut bytes, header::EM_MIPS); // e_machine
+        word(&mut bytes, 1); // e_version
+        word(&mut bytes, 0x00400000); // e_entry
+        word(&mut bytes, 0); // e_phoff
+        word(&mut bytes, 0); // e_shoff, filled in below
+        word(&mut bytes, 0); // e_flags
+        half(&mut bytes, 52); // e_ehsize
+        half(&mut bytes, 32); // e_phentsize
+        half(&mut bytes, 0); // e_phnum
+        half(&mut bytes, 40); // e_shentsize
+        half(&mut bytes, sections.len() as u16 + 2); // e_shnum
+        half(&mut bytes, 1); // e_shstrndx
+
+        // The section names, and then the contents of each section.
+        let mut names = vec![0];
+        let mut headers = vec![[0; 10]];
+        let shstrtab = bytes.len() as u32;
+        headers.push([0, SHT_STRTAB, 0, 0, shstrtab, 0, 0, 0, 1, 0]);
+        for (name, _) in sections {
+            headers.push([names.len() as u32, SHT_PROGBITS, 0, 0, 0, 0, 0, 0, 1, 0]);
+            names.extend_from_slice(name.as_bytes());
+            names.push(0);
+        }
+        headers[1][5] = names.len() as u32;
+        bytes.extend_from_slice(&names);
+        for (i, (_, data)) in sections.iter().enumerate() {
+            headers[i + 2][4] = bytes.len() as u32;
+            headers[i + 2][5] = data.len() as u32;
+            bytes.extend_from_slice(data);
+        }
+
+        let shoff = bytes.len() as u32;
+        for field in headers.iter().flatten() {
+            word(&mut bytes, *field);
+        }
+        bytes[32..36].copy_from_slice(&shoff.to_le_bytes());
+        bytes
+    }
+
+    /// Prepends the 32-bit length of `contents`.
+    fn unit(contents: Vec<u8>) -> Vec<u8> {
+        let mut bytes = (contents.len() as u32).to_le_bytes().to_vec();
+        bytes.extend_from_slice(&contents);
+        bytes
+    }
+
+    /// The DWARF 2 of `pi.c`: `main` from 0x00400000 to 0x00400010,
+    /// whose code comes from lines 10 and 12, and `helper`, which the
+    /// symbol table may not have.
+    fn debug_sections() -> Vec<(&'static str, Vec<u8>)> {
+        // 1: a compile unit with a name and a line program, and 2: a
+        // function, with its name and code.
+        let mut abbrev = vec![1, 0x11, 1, 0x03, 0x08, 0x10, 0x06, 0, 0];
+        abbrev.extend_from_slice(&[2, 0x2e, 0, 0x03, 0x08, 0x11, 0x01, 0x12, 0x01, 0, 0]);
+        // The end of the table.
+        abbrev.push(0);
+
+        let mut info = vec![2, 0, 0, 0, 0, 0, 4];
+        info.push(1);
+        info.extend_from_slice(b"pi.c\0");
+        info.extend_from_slice(&0u32.to_le_bytes());
+        info.push(2);
+        info.extend_from_slice(b"helper\0");
+        info.extend_from_slice(&0x00400008u32.to_le_bytes());
+        info.extend_from_slice(&0x00400010u32.to_le_bytes());
+        info.push(0);
+
+        // The header: minimum instruction length 1, line base -5, line
+        // range 14, opcode base 13, no directories and a single file.
+        let mut header = vec![1, 1, 0xfb, 14, 13, 0, 1, 1, 1, 1, 0, 0, 0, 1, 0, 0, 1, 0];
+        header.extend_from_slice(b"pi.c\0\0\0\0\0");
+        let mut line = vec![2, 0];
+        line.extend_from_slice(&(header.len() as u32).to_le_bytes());
+        line.extend_from_slice(&header);
+        // set_address, advance_line 9, copy, advance_pc 8, advance_line 2,
+        // copy, advance_pc 8 and end_sequence.
+        line.extend_from_slice(&[0, 5, 2]);
+        line.extend_from_slice(&0x00400000u32.to_le_bytes());
+        line.extend_from_slice(&[3, 9, 1, 2, 8, 3, 2, 1, 2, 8, 0, 1, 1]);
+
+        vec![
+            (".debug_abbrev", abbrev),
+            (".debug_info", unit(info)),
+            (".debug_line", unit(line)),
+        ]
+    }
+
+    #[test]
+    fn maps_addresses_to_lines() {
+        let bytes = with_sections(&debug_sections());
+        let elf = elf::parse(&bytes).unwrap();
+        let debug = read(&elf).unwrap();
+
+        let line = |addr| debug.lines.lookup(addr).map(|l| l.to_string());
+        assert_eq!(line(0x003FFFFC), None);
+        assert_eq!(line(0x00400000).unwrap(), "pi.c:10");
+        assert_eq!(line(0x00400004).unwrap(), "pi.c:10");
+        assert_eq!(line(0x00400008).unwrap(), "pi.c:12");
+        assert_eq!(line(0x0040000c).unwrap(), "pi.c:12");
+        // The end of the sequence.
+        assert_eq!(line(0x00400010), None);
+
+        // The functions go in the symbol table.
+        let symbols = elf.symbols();
+        assert_eq!(
+            symbols.describe(0x0040000c),
+            "0x0040000c <helper+0x4> (pi.c:12)"
+        );
+        assert_eq!(symbols.describe(0x00400010), "0x00400010");
+    }
+
+    #[test]
+    fn programs_without_debugging_information() {
+        let bytes = with_sections(&[]);
+        let debug = read(&elf::parse(&bytes).unwrap()).unwrap();
+        assert!(debug.lines.lookup(0x00400000).is_none());
+        assert!(debug.functions.is_empty());
+    }
+}
@@ -0,0 +1,518 @@
+//! The ELF loader.
+//!
+//! Only what a statically linked, little-endian MIPS32 executable needs
+//! is supported, and anything else is refused with an error saying why,
+//! instead of being loaded into something that cannot run.
+//!
+//! The section headers are only needed for the symbol table, so a file
+//! whose section headers are damaged or cut short still loads: the
+//! symbols that can be read are used, and the rest are ignored.
+
+use super::image::Image;
+use super::{dwarf, Loader, Placement, Program, MAX_SEGMENT};
+use crate::emulator::abi::PAGE_SIZE;
+use crate::emulator::cop0::physical_address;
+use crate::emulator::symbols::{LineTable, Symbol, Symbols};
+
+use goblin::container::{Container, Ctx, Endian};
+use goblin::elf::header::{self, Header};
+use goblin::elf::program_header::{self, program_header32, ProgramHeader};
+use goblin::elf::section_header::{self, SectionHeader};
+use goblin::elf::sym::{self, Sym};
+use goblin::elf::{Elf, Symtab};
+use goblin::strtab::Strtab;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Checks the ELF header, refusing anything that is not for MIPS32.
+fn check_header(bytes: &[u8]) -> Result<Header> {
+    if bytes.len() < header::SIZEOF_IDENT || &bytes[..header::SELFMAG] != header::ELFMAG {
+        return Err(eyre!("not an ELF file"));
+    }
+
+    match bytes[header::EI_CLASS] {
+        header::ELFCLASS32 => {}
+        header::ELFCLASS64 => {
+            return Err(eyre!(
+                "64-bit ELF files are not supported, build it for MIPS32"
+            ))
+        }
+        class => return Err(eyre!("unknown ELF class {}", class)),
+    }
+
+    match bytes[header::EI_DATA] {
+        header::ELFDATA2LSB => {}
+        header::ELFDATA2MSB => {
+            return Err(eyre!(
+                "big-endian ELF files are not supported, build it for little-endian MIPS (mipsel)"
+            ))
+        }
+        data => return Err(eyre!("unknown ELF data encoding {}", data)),
+    }
+
+    let header = Elf::parse_header(bytes).map_err(|e| eyre!("bad ELF header: {}", e))?;
+
+    if header.e_machine != header::EM_MIPS {
+        return Err(eyre!(
+            "not a MIPS program, but a {} one",
+            header::machine_to_str(header.e_machine)
+        ));
+    }
+
+    Ok(header)
+}
+
+/// Refuses anything that is not an executable we can run.
+fn check_executable(header: &Header) -> Result<()> {
+    match header.e_type {
+        header::ET_EXEC => Ok(()),
+        header::ET_REL => Err(eyre!(
+            "this is an object file, link it into an executable first"
+        )),
+        header::ET_DYN => Err(eyre!(
+            "position-independent executables are not supported, link it with -static -no-pie"
+        )),
+        t => Err(eyre!(
+            "not an executable, but a {} file",
+            header::et_to_str(t)
+        )),
+    }
+}
+
+/// Reads the section headers that are actually in the file.
+fn section_headers(bytes: &[u8], header: &Header, ctx: Ctx) -> Vec<SectionHeader> {
+    let shoff = header.e_shoff as usize;
+    let entsize = header.e_shentsize as usize;
+    if shoff == 0 || entsize == 0 || shoff > bytes.len() {
+        return Vec::new();
+    }
+
+    let count = (header.e_shnum as usize).min((bytes.len() - shoff) / entsize);
+    if count < header.e_shnum as usize {
+        log::warn!(
+            "elf: only {} of {} section headers are in the file",
+            count,
+            header.e_shnum
+        );
+    }
+
+    SectionHeader::parse(bytes, shoff, count, ctx).unwrap_or_default()
+}
+
+/// A MIPS32 ELF file, with the headers that could be read.
+pub struct ElfFile<'a> {
+    bytes: &'a [u8],
+    ctx: Ctx,
+    pub header: Header,
+    pub program_headers: Vec<ProgramHeader>,
+    /// The section headers that are in the file.
+    pub sections: Vec<SectionHeader>,
+}
+
+/// Parses the headers of the ELF file in `bytes`. Object files are
+/// accepted too, since they can still be disassembled.
+pub fn parse(bytes: &[u8]) -> Result<ElfFile<'_>> {
+    let header = check_header(bytes)?;
+    let ctx = Ctx::new(Container::Little, Endian::Little);
+
+    let phoff = header.e_phoff as usize;
+    let phnum = header.e_phnum as usize;
+    if phoff + phnum * program_header32::SIZEOF_PHDR > bytes.len() {
+        return Err(eyre!("the program headers go past the end of the file"));
+    }
+    let program_headers = ProgramHeader::parse(bytes, phoff, phnum, ctx)
+        .map_err(|e| eyre!("bad program headers: {}", e))?;
+
+    Ok(ElfFile {
+        bytes,
+        ctx,
+        sections: section_headers(bytes, &header, ctx),
+        header,
+        program_headers,
+    })
+}
+
+impl<'a> ElfFile<'a> {
+    /// The contents of a section, if they are in the file.
+    pub fn section_data(&self, sh: &SectionHeader) -> Option<&'a [u8]> {
+        let start = sh.sh_offset as usize;
+        let end = start.checked_add(sh.sh_size as usize)?;
+        self.bytes.get(start..end)
+    }
+
+    /// The contents of a segment, if they are in the file.
+    pub fn segment_data(&self, ph: &ProgramHeader) -> Option<&'a [u8]> {
+        let start = ph.p_offset as usize;
+        let end = start.checked_add(ph.p_filesz as usize)?;
+        self.bytes.get(start..end)
+    }
+
+    /// The string table in section `index`.
+    fn strtab(&self, index: usize) -> Option<Strtab<'a>> {
+        let sh = self.sections.get(index)?;
+        self.section_data(sh)?;
+        Strtab::parse(self.bytes, sh.sh_offset as usize, sh.sh_size as usize, 0).ok()
+    }
+
+    /// The name of a section.
+    pub fn section_name(&self, sh: &SectionHeader) -> Option<&'a str> {
+        self.strtab(self.header.e_shstrndx as usize)?
+            .get(sh.sh_name)?
+            .ok()
+    }
+
+    /// The contents of the section called `name`.
+    pub fn section_by_name(&self, name: &str) -> Option<&'a [u8]> {
+        let sh = self
+            .sections
+            .iter()
+            .find(|sh| self.section_name(sh) == Some(name))?;
+        self.section_data(sh)
+    }
+
+    /// Every entry of `.symtab` and `.dynsym`, with its name.
+    fn raw_symbols(&self) -> Vec<(&'a str, Sym)> {
+        let mut all = Vec::new();
+
+        for sh in self.sections.iter() {
+            if sh.sh_type != section_header::SHT_SYMTAB && sh.sh_type != section_header::SHT_DYNSYM
+            {
+                continue;
+            }
+
+            let names = match self.strtab(sh.sh_link as usize) {
+                Some(names) => names,
+                None => continue,
+            };
+            let count = match (self.section_data(sh), sh.sh_entsize) {
+                (Some(_), size) if size != 0 => sh.sh_size / size,
+                _ => continue,
+            };
+            let offset = sh.sh_offset as usize;
+            let syms = match Symtab::parse(self.bytes, offset, count as usize, self.ctx) {
+                Ok(syms) => syms,
+                Err(_) => continue,
+            };
+
+            for sym in syms.iter() {
+                if let Some(Ok(name)) = names.get(sym.st_name) {
+                    all.push((name, sym));
+                }
+            }
+        }
+
+        all
+    }
+
+    /// The value of the symbol called `name`.
+    pub fn symbol_value(&self, name: &str) -> Option<u32> {
+        self.raw_symbols()
+            .into_iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, sym)| sym.st_value as u32)
+    }
+
+    /// The symbol table: the functions, objects and labels defined in the
+    /// program, and the functions and source lines from the debugging
+    /// information. Absolute symbols, like `_gp`, are not the address of
+    /// anything, so they are left out.
+    pub fn symbols(&self) -> Symbols {
+        let mut symbols: Vec<Symbol> = self
+            .raw_symbols()
+            .into_iter()
+            .filter(|(_, sym)| {
+                let shndx = sym.st_shndx as u32;
+                shndx != section_header::SHN_UNDEF && shndx != section_header::SHN_ABS
+            })
+            .filter(|(_, sym)| {
+                matches!(
+                    sym.st_type(),
+                    sym::STT_NOTYPE | sym::STT_OBJECT | sym::STT_FUNC
+                )
+            })
+            .map(|(name, sym)| Symbol {
+                name: name.to_string(),
+                addr: sym.st_value as u32,
+                size: sym.st_size as u32,
+                func: sym.st_type() == sym::STT_FUNC,
+            })
+            .collect();
+
+        // The symbol table goes first, so its names win.
+        let mut lines = LineTable::default();
+        match dwarf::read(self) {
+            Ok(debug) => {
+                symbols.extend(debug.functions);
+                lines = debug.lines;
+            }
+            Err(e) => log::warn!("elf: bad debugging information: {}", e),
+        }
+
+        let mut symbols = Symbols::new(symbols);
+        symbols.set_lines(lines);
+        symbols
+    }
+}
+
+/// Loads ELF executables.
+pub struct ElfLoader {
+    bytes: Vec<u8>,
+}
+
+impl ElfLoader {
+    /// Creates the loader of the ELF file in `bytes`.
+    pub fn new(bytes: Vec<u8>) -> ElfLoader {
+        ElfLoader { bytes }
+    }
+}
+
+impl Loader for ElfLoader {
+    /// Reads the segments of the executable. Each one is copied from the
+    /// file, and the rest of it, up to its size in memory (the `.bss`), is
+    /// zeroed.
+    fn read(&self, placement: Placement) -> Result<Program> {
+        let bytes = &self.bytes[..];
+        let elf = parse(bytes)?;
+        check_executable(&elf.header)?;
+
+        let header = &elf.header;
+
+        if elf
+            .program_headers
+            .iter()
+            .any(|ph| ph.p_type == program_header::PT_INTERP)
+        {
+            return Err(eyre!(
+                "dynamically linked programs are not supported, link it with -static"
+            ));
+        }
+
+        let mut image = Image {
+            label: Some("elf"),
+            ..Image::default()
+        };
+        let mut phdr = 0;
+        let mut brk = 0;
+
+        for ph in elf.program_headers.iter() {
+            match ph.p_type {
+                program_header::PT_LOAD => {}
+                program_header::PT_PHDR => {
+                    phdr = ph.p_vaddr as u32;
+                    continue;
+                }
+                _ => continue,
+            }
+
+            let offset = ph.p_offset as usize;
+            let filesz = ph.p_filesz as usize;
+            let memsz = ph.p_memsz as usize;
+
+            if filesz > memsz {
+                return Err(eyre!(
+                    "segment at {:#010x} is larger in the file than in memory",
+                    ph.p_vaddr
+                ));
+            }
+            if memsz > MAX_SEGMENT {
+                return Err(eyre!(
+                    "segment at {:#010x} takes {} bytes in memory, more than the {} MiB supported",
+                    ph.p_vaddr,
+                    memsz,
+                    MAX_SEGMENT >> 20
+                ));
+            }
+
+            let data = offset
+                .checked_add(filesz)
+                .and_then(|end| bytes.get(offset..end))
+                .ok_or_else(|| {
+                    eyre!(
+                        "segment at {:#010x} goes past the end of the file",
+                        ph.p_vaddr
+                    )
+                })?;
+
+            let addr = match placement {
+                Placement::Virtual => ph.p_vaddr as u32,
+                Placement::Physical => physical_address(ph.p_paddr as u32),
+            };
+
+            if (addr as u64) + (memsz as u64) > 1 << 32 {
+                return Err(eyre!("segment at {:#010x} does not fit in memory", addr));
+            }
+
+            let mut segment = data.to_vec();
+            segment.resize(memsz, 0);
+            image.chunks.push((addr, segment));
+
+            // The program break starts right after the image.
+            let end = (ph.p_vaddr + ph.p_memsz) as u32;
+            brk = brk.max(end.saturating_add(PAGE_SIZE - 1) & !(PAGE_SIZE - 1));
+
+            // Without a PT_PHDR, the program headers are found inside the
+            // segment that contains them, if any.
+            let has_phdrs =
+                ph.p_offset <= header.e_phoff && header.e_phoff < ph.p_offset + ph.p_filesz;
+            if phdr == 0 && has_phdrs {
+                phdr = (ph.p_vaddr + header.e_phoff - ph.p_offset) as u32;
+            }
+        }
+
+        if image.chunks.is_empty() {
+            return Err(eyre!("there is nothing to load"));
+        }
+
+        let mut program = Program::new(image, header.e_entry as u32);
+        program.phdr = phdr;
+        program.phnum = elf.program_headers.len() as u32;
+        program.brk = Some(brk);
+        program.gp = elf.symbol_value("_gp");
+        program.symbols = elf.symbols();
+
+        Ok(program)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// An executable with a single segment at `0x00400000`, holding 4
+    /// bytes of the file and taking `memsz` bytes in memory.
+    fn executable(memsz: u32) -> Vec<u8> {
+        let mut bytes = vec![0x7f, b'E', b'L', b'F', 1, 1, 1, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+        let half = |bytes: &mut Vec<u8>, v: u32| bytes.extend_from_slice(&(v as u16).to_le_bytes());
+        let word = |bytes: &mut Vec<u8>, v: u32| bytes.extend_from_slice(&v.to_le_bytes());
+
+        half(&mut bytes, header::ET_EXEC.into()); // e_type
+        half(&mut bytes, header::EM_MIPS.into()); // e_machine
+        word(&mut bytes, 1); // e_version
+        word(&mut bytes, 0x00400000); // e_entry
+        word(&mut bytes, 52); // e_phoff
+        word(&mut bytes, 0); // e_shoff
+        word(&mut bytes, 0); // e_flags
+        half(&mut bytes, 52); // e_ehsize
+        half(&mut bytes, 32); // e_phentsize
+        half(&mut bytes, 1); // e_phnum
+        half(&mut bytes, 40); // e_shentsize
+        half(&mut bytes, 0); // e_shnum
+        half(&mut bytes, 0); // e_shstrndx
+
+        word(&mut bytes, program_header::PT_LOAD); // p_type
+        word(&mut bytes, 84); // p_offset
+        word(&mut bytes, 0x00400000); // p_vaddr
+        word(&mut bytes, 0x00400000); // p_paddr
+        word(&mut bytes, 4); // p_filesz
+        word(&mut bytes, memsz); // p_memsz
+        word(&mut bytes, 5); // p_flags
+        word(&mut bytes, 4); // p_align
+
+        // The segment: a `syscall`.
+        word(&mut bytes, 0x0000000c);
+
+        bytes
+    }
+
+    /// Adds a symbol table to `bytes`, with `_gp` at `gp`.
+    fn with_gp(mut bytes: Vec<u8>, gp: u32) -> Vec<u8> {
+        let word = |bytes: &mut Vec<u8>, v: u32| bytes.extend_from_slice(&v.to_le_bytes());
+
+        let strtab = bytes.len() as u32;
+        bytes.extend_from_slice(b"\0_gp\0\0\0\0");
+        let symtab = bytes.len() as u32;
+        bytes.extend_from_slice(&[0; 16]);
+        word(&mut bytes, 1); // st_name
+        word(&mut bytes, gp); // st_value
+        word(&mut bytes, 0); // st_size
+        bytes.extend_from_slice(&[0, 0]); // st_info, st_other
+        bytes.extend_from_slice(&(section_header::SHN_ABS as u16).to_le_bytes());
+
+        // sh_name, sh_type, sh_flags, sh_addr, sh_offset, sh_size,
+        // sh_link, sh_info, sh_addralign and sh_entsize.
+        let shoff = bytes.len() as u32;
+        let sections = [
+            [0; 10],
+            [0, section_header::SHT_SYMTAB, 0, 0, symtab, 32, 2, 0, 4, 16],
+            [0, section_header::SHT_STRTAB, 0, 0, strtab, 8, 0, 0, 1, 0],
+        ];
+        for field in sections.iter().flatten() {
+            word(&mut bytes, *field);
+        }
+
+        bytes[32..36].copy_from_slice(&shoff.to_le_bytes()); // e_shoff
+        bytes[48..50].copy_from_slice(&3u16.to_le_bytes()); // e_shnum
+        bytes
+    }
+
+    /// The error reading `bytes` gives.
+    fn error(bytes: Vec<u8>) -> String {
+        match ElfLoader::new(bytes).read(Placement::Virtual) {
+            Ok(_) => panic!("the file was loaded"),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn rejects_64_bit_files() {
+        let mut bytes = executable(4);
+        bytes[header::EI_CLASS] = header::ELFCLASS64;
+        assert!(error(bytes).starts_with("64-bit ELF files are not supported"));
+    }
+
+    #[test]
+    fn rejects_big_endian_files() {
+        let mut bytes = executable(4);
+        bytes[header::EI_DATA] = header::ELFDATA2MSB;
+        assert!(error(bytes).starts_with("big-endian ELF files are not supported"));
+    }
+
+    #[test]
+    fn rejects_other_machines() {
+        let mut bytes = executable(4);
+        bytes[18..20].copy_from_slice(&header::EM_386.to_le_bytes());
+        assert_eq!(error(bytes), "not a MIPS program, but a 386 one");
+    }
+
+    #[test]
+    fn rejects_segments_larger_in_the_file() {
+        assert_eq!(
+            error(executable(2)),
+            "segment at 0x00400000 is larger in the file than in memory"
+        );
+    }
+
+    #[test]
+    fn picks_up_gp() {
+        let program = ElfLoader::new(executable(4))
+            .read(Placement::Virtual)
+            .unwrap();
+        assert_eq!(program.gp, None);
+
+        let program = ElfLoader::new(with_gp(executable(4), 0x10008000))
+            .read(Placement::Virtual)
+            .unwrap();
+        assert_eq!(program.gp, Some(0x10008000));
+        // It is absolute, so it is not the name of any address.
+        assert!(program.symbols.at(0x10008000).is_none());
+    }
+
+    #[test]
+    fn zeroes_the_rest_of_the_segment() {
+        let program = ElfLoader::new(executable(16))
+            .read(Placement::Virtual)
+            .unwrap();
+        let mut data = vec![0x0c, 0, 0, 0];
+        data.resize(16, 0);
+        assert_eq!(program.image.chunks, vec![(0x00400000, data)]);
+    }
+
+    #[test]
+    fn rejects_huge_segments() {
+        let memsz = MAX_SEGMENT as u32 + 1;
+        let err = ElfLoader::new(executable(memsz))
+            .read(Placement::Virtual)
+            .unwrap_err();
+        assert!(err.to_string().contains("more than the 256 MiB supported"));
+    }
+}
@@ -0,0 +1,145 @@
+//! Intel HEX files.
+//!
+//! Each line is a record, `:LLAAAATT<data>CC`: the data length, a 16-bit
+//! address, the record type, the data and a checksum. The upper bits of
+//! the addresses come from the extended address records, and the entry
+//! point from the start address records.
+
+use super::image::{hex_bytes, Image};
+
+use color_eyre::eyre::{eyre, Result};
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Reads an Intel HEX file.
+pub fn parse(text: &str) -> Result<Image> {
+    let mut image = Image::default();
+    // Added to the address of the data records.
+    let mut base = 0u32;
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let err = |msg: &str| eyre!("line {}: {}", n + 1, msg);
+
+        let record = line
+            .strip_prefix(':')
+            .ok_or_else(|| err("records must start with ':'"))?;
+        let bytes = hex_bytes(record).ok_or_else(|| err("bad hex digits"))?;
+
+        if bytes.len() < 5 || bytes.len() != bytes[0] as usize + 5 {
+            return Err(err("the record length is wrong"));
+        }
+        if bytes.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) != 0 {
+            return Err(err("bad checksum"));
+        }
+
+        let addr = u16::from_be_bytes([bytes[1], bytes[2]]) as u32;
+        let data = &bytes[4..bytes.len() - 1];
+
+        let word = |len: usize| -> Result<u32> {
+            if data.len() != len {
+                return Err(err("the record length is wrong"));
+            }
+            Ok(data.iter().fold(0, |val, b| (val << 8) | *b as u32))
+        };
+
+        match bytes[3] {
+            DATA => image.push(base.wrapping_add(addr), data),
+            END_OF_FILE => return Ok(image),
+            EXTENDED_SEGMENT_ADDRESS => base = word(2)? << 4,
+            EXTENDED_LINEAR_ADDRESS => base = word(2)? << 16,
+            START_SEGMENT_ADDRESS => {
+                let val = word(4)?;
+                image.entry = Some(((val >> 16) << 4) + (val & 0xFFFF));
+            }
+            START_LINEAR_ADDRESS => image.entry = Some(word(4)?),
+            t => return Err(err(&format!("unknown record type {:02x}", t))),
+        }
+    }
+
+    Err(eyre!(
+        "the end of file record is missing, is the file complete?"
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn linear_addresses() {
+        let image = parse(
+            ":020000040040BA\n\
+             :0400000001020304F2\n\
+             :0400040005060708DE\n\
+             :0400000500400010A7\n\
+             :00000001FF\n",
+        )
+        .unwrap();
+
+        // The two records are contiguous, so they make a single chunk.
+        assert_eq!(
+            image.chunks,
+            vec![(0x00400000, vec![1, 2, 3, 4, 5, 6, 7, 8])]
+        );
+        assert_eq!(image.entry, Some(0x00400010));
+    }
+
+    #[test]
+    fn segment_addresses() {
+        let image = parse(
+            "\n\
+             :020000021000EC\n\
+             :01001000AA45\n\
+             :0400000310000010D9\n\
+             :00000001FF\n\
+             what comes after the end is ignored\n",
+        )
+        .unwrap();
+
+        assert_eq!(image.chunks, vec![(0x00010010, vec![0xAA])]);
+        assert_eq!(image.entry, Some(0x00010010));
+    }
+
+    #[test]
+    fn bad_records() {
+        assert_eq!(
+            error(":0400000001020304F3\n:00000001FF"),
+            "line 1: bad checksum"
+        );
+        assert_eq!(
+            error(":020000040040BA\n:0100000600F9\n:00000001FF"),
+            "line 2: unknown record type 06"
+        );
+        assert_eq!(
+            error("0400000001020304F2"),
+            "line 1: records must start with ':'"
+        );
+
+        // Cut short, in the middle of a byte or at one.
+        assert_eq!(error(":04000000010203040"), "line 1: bad hex digits");
+        assert_eq!(
+            error(":0400000001020304"),
+            "line 1: the record length is wrong"
+        );
+        // An extended address takes two bytes.
+        assert_eq!(error(":0100000400FB"), "line 1: the record length is wrong");
+        assert_eq!(
+            error(":0400000001020304F2\n"),
+            "the end of file record is missing, is the file complete?"
+        );
+    }
+}
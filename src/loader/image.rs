@@ -0,0 +1,276 @@
+//! Memory images: the formats that are nothing but bytes to put at some
+//! addresses, and maybe where to start. These are the flat binaries and
+//! the text formats made by firmware tools, Intel HEX and Motorola
+//! S-records.
+
+use super::{ihex, srec, write_bytes, Loader, Placement, Program};
+use crate::emulator::cop0::physical_address;
+use crate::emulator::memory::Memory;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The formats we can read a program from.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Format {
+    Elf,
+    /// The segments dumped by MARS, in files that share a prefix.
+    Mars,
+    IntelHex,
+    Srec,
+    Binary,
+    /// Assembly source, in the syntax MARS reads.
+    Asm,
+}
+
+/// The names of the formats, as given on the command line.
+pub const FORMAT_NAMES: &[&str] = &["elf", "mars", "ihex", "srec", "bin", "asm"];
+
+impl Format {
+    /// Finds out the format of a file from its contents. Anything that
+    /// does not look like one of the others is a flat binary. (MARS split
+    /// files and assembly sources are found by their names instead.)
+    pub fn detect(bytes: &[u8]) -> Format {
+        if bytes.starts_with(b"\x7fELF") {
+            return Format::Elf;
+        }
+
+        let first_line = bytes
+            .split(|&b| b == b'\n')
+            .map(|line| line.trim_ascii())
+            .find(|line| !line.is_empty());
+
+        match first_line {
+            Some([b':', rest @ ..]) if is_hex(rest) => Format::IntelHex,
+            Some([b'S', b'0'..=b'9', rest @ ..]) if is_hex(rest) => Format::Srec,
+            _ => Format::Binary,
+        }
+    }
+
+    /// The format called `name`, one of `FORMAT_NAMES`.
+    pub fn from_name(name: &str) -> Result<Format> {
+        match name {
+            "elf" => Ok(Format::Elf),
+            "mars" => Ok(Format::Mars),
+            "ihex" => Ok(Format::IntelHex),
+            "srec" => Ok(Format::Srec),
+            "bin" => Ok(Format::Binary),
+            "asm" => Ok(Format::Asm),
+            _ => Err(eyre!("unknown format {}", name)),
+        }
+    }
+}
+
+/// Returns `true` if `s` is a non-empty run of hex digits.
+fn is_hex(s: &[u8]) -> bool {
+    !s.is_empty() && s.iter().all(u8::is_ascii_hexdigit)
+}
+
+/// Decodes a string of hex digit pairs.
+pub(super) fn hex_bytes(s: &str) -> Option<Vec<u8>> {
+    if !s.len().is_multiple_of(2) || !is_hex(s.as_bytes()) {
+        return None;
+    }
+
+    (0..s.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&s[i..i + 2], 16).ok())
+        .collect()
+}
+
+/// A memory image.
+#[derive(Debug, Default)]
+pub struct Image {
+    /// The contiguous runs of bytes, and where they go.
+    pub chunks: Vec<(u32, Vec<u8>)>,
+    /// Where execution starts, if the image says so.
+    pub entry: Option<u32>,
+    /// The name the chunks are announced with as they are loaded, if they
+    /// are.
+    pub label: Option<&'static str>,
+}
+
+impl Image {
+    /// Reads the image in `bytes`. Flat binaries are loaded at `base`,
+    /// and start there.
+    pub fn parse(bytes: &[u8], format: Format, base: u32) -> Result<Image> {
+        let text = || std::str::from_utf8(bytes).map_err(|_| eyre!("not a text file"));
+
+        let mut image = match format {
+            Format::IntelHex => ihex::parse(text()?)?,
+            Format::Srec => srec::parse(text()?)?,
+            Format::Binary => {
+                let mut image = Image {
+                    entry: Some(base),
+                    ..Image::default()
+                };
+                image.push(base, bytes);
+                image
+            }
+            Format::Elf | Format::Mars | Format::Asm => return Err(eyre!("not a memory image")),
+        };
+
+        image.label = Some("image");
+        Ok(image)
+    }
+
+    /// Adds `data` at `addr`, right after the previous bytes if it
+    /// continues them.
+    pub fn push(&mut self, addr: u32, data: &[u8]) {
+        match self.chunks.last_mut() {
+            Some((start, bytes)) if start.wrapping_add(bytes.len() as u32) == addr => {
+                bytes.extend_from_slice(data)
+            }
+            _ => self.chunks.push((addr, data.to_vec())),
+        }
+    }
+
+    /// Copies the image into `mem`.
+    pub fn load<M: Memory>(&self, mem: &mut M) -> Result<()> {
+        if self.chunks.is_empty() {
+            return Err(eyre!("there is nothing to load"));
+        }
+
+        for (addr, bytes) in self.chunks.iter() {
+            if (*addr as u64) + (bytes.len() as u64) > 1 << 32 {
+                return Err(eyre!("data at {:#010x} does not fit in memory", addr));
+            }
+
+            // On stderr, so it does not mix with the output of the program.
+            if let Some(label) = self.label {
+                eprintln!(
+                    "{}: loading {} bytes to {:#010x}...",
+                    label,
+                    bytes.len(),
+                    addr
+                );
+            }
+            write_bytes(mem, *addr, bytes)?;
+        }
+
+        Ok(())
+    }
+
+    /// Returns `true` if the image has something at `addr`.
+    pub fn covers(&self, addr: u32) -> bool {
+        self.chunks
+            .iter()
+            .any(|(start, bytes)| addr >= *start && ((addr - start) as usize) < bytes.len())
+    }
+}
+
+/// Loads memory images: flat binaries, Intel HEX and S-records.
+pub struct ImageLoader {
+    bytes: Vec<u8>,
+    format: Format,
+    base: u32,
+}
+
+impl ImageLoader {
+    /// Creates the loader of the image in `bytes`, in `format`. A flat
+    /// binary goes at `base`.
+    pub fn new(bytes: Vec<u8>, format: Format, base: u32) -> ImageLoader {
+        ImageLoader {
+            bytes,
+            format,
+            base,
+        }
+    }
+}
+
+impl Loader for ImageLoader {
+    fn read(&self, placement: Placement) -> Result<Program> {
+        let mut image = Image::parse(&self.bytes, self.format, self.base)?;
+
+        // Without a start address, the image starts where it begins.
+        let entry = match (image.entry, image.chunks.first()) {
+            (Some(entry), _) => entry,
+            (None, Some((addr, _))) => *addr,
+            (None, None) => return Err(eyre!("there is nothing to load")),
+        };
+
+        if placement == Placement::Physical {
+            for (addr, _) in image.chunks.iter_mut() {
+                *addr = physical_address(*addr);
+            }
+        }
+
+        Ok(Program::new(image, entry))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::emulator::memory::Ram;
+
+    #[test]
+    fn detects_the_format() {
+        assert_eq!(Format::detect(b"\x7fELF\x01\x01\x01"), Format::Elf);
+        assert_eq!(Format::detect(b"\r\n  :00000001FF\r\n"), Format::IntelHex);
+        assert_eq!(Format::detect(b"\nS9031000EC\n"), Format::Srec);
+
+        // Anything else is a flat binary, even if it looks a bit like text.
+        assert_eq!(Format::detect(b"\x00\x00\x40\x3c"), Format::Binary);
+        assert_eq!(Format::detect(b":not hex\n"), Format::Binary);
+        assert_eq!(Format::detect(b"Some text\n"), Format::Binary);
+        assert_eq!(Format::detect(b""), Format::Binary);
+
+        for name in FORMAT_NAMES {
+            assert!(Format::from_name(name).is_ok(), "{}", name);
+        }
+        assert!(Format::from_name("coff").is_err());
+    }
+
+    #[test]
+    fn loaders() {
+        // A flat binary goes at the base, and starts there.
+        let loader = ImageLoader::new(vec![1, 2, 3], Format::Binary, 0x80001000);
+        let program = loader.read(Placement::Virtual).unwrap();
+        assert_eq!(program.entry, 0x80001000);
+        assert_eq!(program.image.chunks, vec![(0x80001000, vec![1, 2, 3])]);
+
+        // Placed physically, as a boot loader would, but starting at the
+        // same address.
+        let program = loader.read(Placement::Physical).unwrap();
+        assert_eq!(program.entry, 0x80001000);
+        assert_eq!(program.image.chunks[0].0, 0x00001000);
+
+        // Without a start address, an image starts where it begins.
+        let hex = b":020000040040BA\n:0400080001020304EA\n:00000001FF\n".to_vec();
+        let program = ImageLoader::new(hex, Format::IntelHex, 0)
+            .read(Placement::Virtual)
+            .unwrap();
+        assert_eq!(program.entry, 0x00400008);
+
+        let empty = ImageLoader::new(b":00000001FF".to_vec(), Format::IntelHex, 0);
+        assert!(empty.read(Placement::Virtual).is_err());
+        let not_text = ImageLoader::new(vec![0xff, 0xfe], Format::Srec, 0);
+        assert!(not_text.read(Placement::Virtual).is_err());
+    }
+
+    #[test]
+    fn loads_unaligned_chunks() {
+        let mut mem = Ram::new(0);
+        mem.poke(0x1000, 0x44332211).unwrap();
+        mem.poke(0x1004, 0x88776655).unwrap();
+
+        let image = Image {
+            chunks: vec![(0x1001, vec![0xaa, 0xbb, 0xcc, 0xdd, 0xee])],
+            ..Image::default()
+        };
+        image.load(&mut mem).unwrap();
+
+        // The bytes around the chunk are kept.
+        assert_eq!(mem.inspect(0x1000).unwrap(), 0xccbbaa11);
+        assert_eq!(mem.inspect(0x1004).unwrap(), 0x8877eedd);
+        assert!(image.covers(0x1005));
+        assert!(!image.covers(0x1006));
+
+        let past_the_end = Image {
+            chunks: vec![(0xFFFFFFFE, vec![0; 4])],
+            ..Image::default()
+        };
+        assert!(past_the_end.load(&mut mem).is_err());
+        assert!(Image::default().load(&mut mem).is_err());
+    }
+}
@@ -0,0 +1,402 @@
+//! The memory dumps MARS writes with "File > Dump Memory", which is how
+//! the `.text` and `.data` files of `run` are made. Besides "Binary", the
+//! little-endian words `run` has always read, MARS has four text formats,
+//! told apart here by their first line:
+//!
+//! - "Hexadecimal Text": a word per line, as 8 hex digits;
+//! - "Binary Text": a word per line, as 32 binary digits;
+//! - "ASCII Text": a word per line, as its 4 bytes from the most
+//!   significant, each a character right-aligned in 3 columns. The control
+//!   characters with an escape (`\0`, `\n`, ...) take two columns, and the
+//!   other unprintable bytes are written as `.` (unaligned, for bytes past
+//!   127), so they come back as `.`;
+//! - "Segment Window": the lines of the Text or Data Segment window, each
+//!   an address followed by the words there. Code lines also have the
+//!   instruction and its source, which are ignored.
+//!
+//! A program is split into one of these files per segment, named after
+//! it: `prog.text`, `prog.data`, ...
+
+use super::image::Image;
+use super::{Loader, Placement, Program, MAX_SEGMENT};
+use crate::emulator::cop0::physical_address;
+
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Where MARS puts `.text`, `.data`, `.rodata`, `.ktext` and `.kdata`.
+const SEGMENT_ADDRESSES: [u32; 5] = [0x00400000, 0x10010000, 0x00800000, 0x80000180, 0x90000000];
+
+/// The names of the segments, in the same order.
+const SEGMENT_NAMES: [&str; 5] = [".text", ".data", ".rodata", ".ktext", ".kdata"];
+
+/// Where MARS starts a program: the beginning of `.text`.
+const ENTRY: u32 = 0x00400000;
+
+/// Reads the file at `path` into 32-bit words.
+fn u32_vec_from_file(path: &str) -> Result<Vec<u32>> {
+    let data = fs::read(path).map_err(|e| eyre!("{}: {}", path, e))?;
+    read_words(&data).map_err(|e| eyre!("{}: {}", path, e))
+}
+
+/// Like `u32_vec_from_file`, for the files that may be missing.
+fn optional_u32_vec_from_file(path: &str) -> Result<Option<Vec<u32>>> {
+    if Path::new(path).exists() {
+        u32_vec_from_file(path).map(Some)
+    } else {
+        Ok(None)
+    }
+}
+
+/// The segments of a program split in MARS dumps, as 32-bit words.
+#[derive(Debug)]
+pub struct Executable {
+    pub text: Vec<u32>,
+    pub data: Option<Vec<u32>>,
+    pub rodata: Option<Vec<u32>>,
+    pub ktext: Option<Vec<u32>>,
+    pub kdata: Option<Vec<u32>>,
+}
+
+impl Executable {
+    /// Reads `pfx.text` and, if they exist, `pfx.data`, `pfx.rodata`,
+    /// `pfx.ktext` and `pfx.kdata`.
+    pub fn from_naked_files(pfx: impl AsRef<str>) -> Result<Executable> {
+        let pfx = pfx.as_ref();
+        let file = |segment: &str| format!("{}.{}", pfx, segment);
+
+        Ok(Executable {
+            text: u32_vec_from_file(&file("text"))?,
+            data: optional_u32_vec_from_file(&file("data"))?,
+            rodata: optional_u32_vec_from_file(&file("rodata"))?,
+            ktext: optional_u32_vec_from_file(&file("ktext"))?,
+            kdata: optional_u32_vec_from_file(&file("kdata"))?,
+        })
+    }
+
+    /// The segments, in the order of `SEGMENT_ADDRESSES`.
+    fn segments(&self) -> [Option<&Vec<u32>>; 5] {
+        [
+            Some(&self.text),
+            self.data.as_ref(),
+            self.rodata.as_ref(),
+            self.ktext.as_ref(),
+            self.kdata.as_ref(),
+        ]
+    }
+
+    /// The segments that are present, with their names and addresses.
+    pub fn named_segments(&self) -> Vec<(&'static str, u32, &Vec<u32>)> {
+        SEGMENT_NAMES
+            .iter()
+            .zip(SEGMENT_ADDRESSES.iter())
+            .zip(self.segments().iter())
+            .filter_map(|((name, addr), words)| Some((*name, *addr, (*words)?)))
+            .collect()
+    }
+}
+
+/// Loads programs split in MARS dumps.
+pub struct SplitLoader {
+    prefix: String,
+}
+
+impl SplitLoader {
+    /// Creates the loader of the files named `prefix.text`, `prefix.data`,
+    /// ...
+    pub fn new(prefix: impl Into<String>) -> SplitLoader {
+        SplitLoader {
+            prefix: prefix.into(),
+        }
+    }
+}
+
+impl Loader for SplitLoader {
+    fn read(&self, placement: Placement) -> Result<Program> {
+        let executable = Executable::from_naked_files(&self.prefix)?;
+        let mut image = Image::default();
+
+        for (addr, words) in SEGMENT_ADDRESSES.iter().zip(executable.segments().iter()) {
+            let words = match words {
+                Some(words) => words,
+                None => continue,
+            };
+            let addr = match placement {
+                Placement::Virtual => *addr,
+                Placement::Physical => physical_address(*addr),
+            };
+            let bytes: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+            image.chunks.push((addr, bytes));
+        }
+
+        Ok(Program::new(image, ENTRY))
+    }
+}
+
+/// The formats of a dump.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum DumpFormat {
+    Binary,
+    HexText,
+    BinaryText,
+    AsciiText,
+    SegmentWindow,
+}
+
+/// Reads the words of a dump, in any of the formats.
+pub fn read_words(bytes: &[u8]) -> Result<Vec<u32>> {
+    let text = match std::str::from_utf8(bytes) {
+        Ok(text) => text,
+        Err(_) => return Ok(binary_words(bytes)),
+    };
+
+    match detect(text) {
+        DumpFormat::Binary => Ok(binary_words(bytes)),
+        DumpFormat::HexText => parse_lines(text, |line| radix_word(line, 16, 8)),
+        DumpFormat::BinaryText => parse_lines(text, |line| radix_word(line, 2, 32)),
+        DumpFormat::AsciiText => ascii_words(text),
+        DumpFormat::SegmentWindow => segment_words(text),
+    }
+}
+
+/// The lines of `text`, without the line breaks of either system.
+fn lines(text: &str) -> impl Iterator<Item = &str> {
+    text.lines()
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+}
+
+/// Finds out the format of a dump from its first line.
+fn detect(text: &str) -> DumpFormat {
+    let first = match lines(text).next() {
+        Some(line) => line,
+        None => return DumpFormat::Binary,
+    };
+
+    // A line of blanks is an ASCII dump of spaces.
+    if ascii_word(first).is_some() {
+        return DumpFormat::AsciiText;
+    }
+
+    let first = match lines(text).map(str::trim).find(|line| !line.is_empty()) {
+        Some(line) => line,
+        None => return DumpFormat::Binary,
+    };
+
+    if radix_word(first, 16, 8).is_some() {
+        DumpFormat::HexText
+    } else if radix_word(first, 2, 32).is_some() {
+        DumpFormat::BinaryText
+    } else if first.starts_with("Address") || segment_line(first).is_some() {
+        DumpFormat::SegmentWindow
+    } else {
+        DumpFormat::Binary
+    }
+}
+
+/// The little-endian words of `bytes`, padding the last one with zeros.
+fn binary_words(bytes: &[u8]) -> Vec<u32> {
+    bytes
+        .chunks(4)
+        .map(|b| {
+            let mut owned_b = [0u8; 4];
+            owned_b[..b.len()].copy_from_slice(b);
+            u32::from_le_bytes(owned_b)
+        })
+        .collect()
+}
+
+/// Reads a word per line, skipping the blank ones.
+fn parse_lines(text: &str, word: impl Fn(&str) -> Option<u32>) -> Result<Vec<u32>> {
+    lines(text)
+        .enumerate()
+        .map(|(n, line)| (n, line.trim()))
+        .filter(|(_, line)| !line.is_empty())
+        .map(|(n, line)| word(line).ok_or_else(|| eyre!("line {}: bad word {:?}", n + 1, line)))
+        .collect()
+}
+
+/// Reads a word written with exactly `digits` digits in `radix`.
+fn radix_word(s: &str, radix: u32, digits: usize) -> Option<u32> {
+    if s.len() != digits || !s.chars().all(|c| c.is_digit(radix)) {
+        return None;
+    }
+    u32::from_str_radix(s, radix).ok()
+}
+
+/// Reads a line of an ASCII dump.
+fn ascii_words(text: &str) -> Result<Vec<u32>> {
+    lines(text)
+        .enumerate()
+        .map(|(n, line)| {
+            ascii_word(line).ok_or_else(|| eyre!("line {}: bad ASCII word {:?}", n + 1, line))
+        })
+        .collect()
+}
+
+/// Reads a word written as 4 characters: 3 columns for each, or 1 for the
+/// `.` of a byte past 127.
+fn ascii_word(line: &str) -> Option<u32> {
+    let mut rest = line;
+    let mut word = 0u32;
+
+    for _ in 0..4 {
+        let field = if rest.starts_with(' ') {
+            rest.get(..3)?
+        } else {
+            rest.get(..1).filter(|&c| c == ".")?
+        };
+        rest = &rest[field.len()..];
+
+        let byte = match field.trim_start() {
+            "" => b' ',
+            "\\0" => 0,
+            "\\b" => 0x08,
+            "\\t" => b'\t',
+            "\\n" => b'\n',
+            "\\v" => 0x0b,
+            "\\f" => 0x0c,
+            "\\r" => b'\r',
+            c if c.len() == 1 && c.is_ascii() => c.as_bytes()[0],
+            _ => return None,
+        };
+
+        word = (word << 8) | byte as u32;
+    }
+
+    if rest.is_empty() {
+        Some(word)
+    } else {
+        None
+    }
+}
+
+/// Reads a number of a segment window: hexadecimal with `0x`, or decimal,
+/// signed for the values of the Data Segment.
+fn segment_number(s: &str) -> Option<u32> {
+    match s.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => s
+            .parse::<u32>()
+            .ok()
+            .or_else(|| s.parse::<i32>().ok().map(|v| v as u32)),
+    }
+}
+
+/// Reads a line of a segment window: its address, and the words that
+/// follow it, up to the first thing that is not a number.
+fn segment_line(line: &str) -> Option<(u32, Vec<u32>)> {
+    let mut tokens = line.split_whitespace();
+    let addr = segment_number(tokens.next()?)?;
+    let words: Vec<u32> = tokens.map_while(segment_number).collect();
+
+    if words.is_empty() {
+        None
+    } else {
+        Some((addr, words))
+    }
+}
+
+/// Reads a segment window dump. Words go at their addresses, counted from
+/// the first line, with anything skipped filled with zeros, as long as the
+/// segment stays within `MAX_SEGMENT`.
+fn segment_words(text: &str) -> Result<Vec<u32>> {
+    let mut origin = None;
+    let mut words = Vec::new();
+
+    for (n, line) in lines(text).enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with("Address") {
+            continue;
+        }
+
+        let (addr, line_words) =
+            segment_line(line).ok_or_else(|| eyre!("line {}: bad line {:?}", n + 1, line))?;
+        let origin = *origin.get_or_insert(addr);
+
+        if addr < origin || addr % 4 != 0 {
+            return Err(eyre!("line {}: bad address {:#010x}", n + 1, addr));
+        }
+
+        let start = ((addr - origin) / 4) as usize;
+        let end = start + line_words.len();
+        if end > MAX_SEGMENT / 4 {
+            return Err(eyre!(
+                "line {}: {:#010x} is too far from {:#010x}, the segment would take more than {} MiB",
+                n + 1,
+                addr,
+                origin,
+                MAX_SEGMENT >> 20
+            ));
+        }
+        if words.len() < end {
+            words.resize(end, 0);
+        }
+        words[start..end].copy_from_slice(&line_words);
+    }
+
+    Ok(words)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segment_window_gaps() {
+        let text = "0x10010000 0x00000001 0x00000002\n0x10010010 0x00000003\n";
+        assert_eq!(segment_words(text).unwrap(), vec![1, 2, 0, 0, 3]);
+
+        // A line near the top of memory would leave a gap of almost 4 GiB.
+        let text = "0x00000000 0x00000001\n0xfffffffc 0x00000002\n";
+        let err = segment_words(text).unwrap_err();
+        assert!(err.to_string().starts_with("line 2: 0xfffffffc is too far"));
+    }
+
+    #[test]
+    fn reads_every_format() {
+        let words: Vec<u32> = vec![0x3c011001, 0x6162630a];
+
+        let mut binary: Vec<u8> = words.iter().flat_map(|w| w.to_le_bytes()).collect();
+        assert_eq!(read_words(&binary).unwrap(), words);
+        // A word cut short is padded with zeros.
+        binary.push(0xab);
+        assert_eq!(
+            read_words(&binary).unwrap(),
+            vec![0x3c011001, 0x6162630a, 0xab]
+        );
+
+        let hex = "3c011001\r\n6162630a\r\n";
+        assert_eq!(read_words(hex.as_bytes()).unwrap(), words);
+
+        let binary_text = "00111100000000010001000000000001\n01100001011000100110001100001010\n";
+        assert_eq!(read_words(binary_text.as_bytes()).unwrap(), words);
+
+        // The escapes take two columns, and a byte past 127 is a `.`.
+        let ascii = "  <  . \\0  .\n  a  b  c \\n\n  a.  b  c\n            \n";
+        assert_eq!(
+            read_words(ascii.as_bytes()).unwrap(),
+            vec![0x3c2e002e, 0x6162630a, 0x612e6263, 0x20202020]
+        );
+
+        // The instruction and the source of the code lines are left out.
+        let window = "Address     Value (+0)  Value (+4)\n\
+                      0x00400000  0x3c011001  lui $1,0x00001001  4  la $t0, x\n\
+                      0x00400004  1633837834  -1\n";
+        assert_eq!(
+            read_words(window.as_bytes()).unwrap(),
+            vec![0x3c011001, 0x6162630a, 0xffffffff]
+        );
+    }
+
+    #[test]
+    fn bad_words() {
+        let err = read_words(b"3c011001\n3c01100g\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: bad word \"3c01100g\"");
+        let err = read_words(b"  a  b  c  d\n  a  b\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: bad ASCII word \"  a  b\"");
+        let err = read_words(b"0x10010004 0x00000001\n0x10010000 0x00000002\n").unwrap_err();
+        assert_eq!(err.to_string(), "line 2: bad address 0x10010000");
+    }
+}
@@ -0,0 +1,226 @@
+//! Loaders for the program formats indy can run.
+//!
+//! Each format has a `Loader`, which reads a file into a `Program`: the
+//! bytes to put in memory, where execution starts and the symbols. `open`
+//! finds out which one a file needs.
+
+pub mod asm;
+pub mod dwarf;
+pub mod elf;
+pub mod ihex;
+pub mod image;
+pub mod mars;
+pub mod srec;
+
+use crate::emulator::abi::Process;
+use crate::emulator::memory::Memory;
+use crate::emulator::symbols::Symbols;
+
+use asm::AsmLoader;
+use elf::ElfLoader;
+use image::{Format, Image, ImageLoader};
+use mars::SplitLoader;
+
+use std::fs;
+use std::path::Path;
+
+use color_eyre::eyre::{eyre, Result};
+
+/// The most a single segment can take in memory, in bytes. A program
+/// asking for more is most likely a damaged file, and the host would have
+/// to find that much memory to hold it.
+const MAX_SEGMENT: usize = 256 << 20;
+
+/// A program format.
+pub trait Loader {
+    /// Reads the program, with its contents at the addresses `placement`
+    /// asks for.
+    fn read(&self, placement: Placement) -> Result<Program>;
+}
+
+/// A program, read from a file and ready to be put in memory.
+#[derive(Debug)]
+pub struct Program {
+    /// What goes in memory.
+    pub image: Image,
+    /// The address of the first instruction.
+    pub entry: u32,
+    /// Where the ELF program headers are in memory, or 0 if they are not
+    /// loaded.
+    pub phdr: u32,
+    /// The number of ELF program headers.
+    pub phnum: u32,
+    /// The end of the image, rounded up to a page, where the heap starts.
+    pub brk: Option<u32>,
+    /// The value of `_gp`, if the symbol table has it.
+    pub gp: Option<u32>,
+    /// The symbol table, empty if the program has none.
+    pub symbols: Symbols,
+}
+
+impl Program {
+    /// A program with nothing but `image`, starting at `entry`.
+    pub fn new(image: Image, entry: u32) -> Program {
+        Program {
+            image,
+            entry,
+            phdr: 0,
+            phnum: 0,
+            brk: None,
+            gp: None,
+            symbols: Symbols::default(),
+        }
+    }
+
+    /// Puts the program in `mem`, returning the process that runs it,
+    /// named `name`.
+    pub fn load<M: Memory>(self, mem: &mut M, name: &str) -> Result<Process> {
+        self.image.load(mem)?;
+
+        let mut process = Process::new(self.entry, name);
+        process.phdr = self.phdr;
+        process.phnum = self.phnum;
+        process.brk = self.brk;
+        process.gp = self.gp;
+        process.symbols = self.symbols;
+
+        Ok(process)
+    }
+}
+
+/// Finds the loader for the program at `path`, in `format` or, if that is
+/// `None`, in the format its contents are in. MARS split files are named
+/// by their prefix (`prog` for `prog.text`, `prog.data`, ...) or by their
+/// `.text`, and assembly sources by their `.asm` or `.s`. Flat binaries go
+/// at `base`.
+pub fn open(path: &str, format: Option<Format>, base: u32) -> Result<Box<dyn Loader>> {
+    let split_prefix = match format {
+        Some(Format::Mars) => Some(path.strip_suffix(".text").unwrap_or(path)),
+        Some(_) => None,
+        None if path.ends_with(".text") => path.strip_suffix(".text"),
+        None if !Path::new(path).exists() && Path::new(&format!("{}.text", path)).exists() => {
+            Some(path)
+        }
+        None => None,
+    };
+    if let Some(prefix) = split_prefix {
+        return Ok(Box::new(SplitLoader::new(prefix)));
+    }
+
+    let is_source = path.ends_with(".asm") || path.ends_with(".s");
+    if format == Some(Format::Asm) || (format.is_none() && is_source) {
+        return Ok(Box::new(AsmLoader::new(path)));
+    }
+
+    let bytes = fs::read(path).map_err(|e| eyre!("{}: {}", path, e))?;
+
+    Ok(match format.unwrap_or_else(|| Format::detect(&bytes)) {
+        Format::Elf => Box::new(ElfLoader::new(bytes)),
+        format => Box::new(ImageLoader::new(bytes, format, base)),
+    })
+}
+
+/// Where the segments go.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Placement {
+    /// At their virtual addresses, as seen by a user-mode program.
+    Virtual,
+    /// At their physical addresses, as a boot loader (or a ROM
+    /// programmer) would put them.
+    Physical,
+}
+
+/// Writes `bytes` at `addr`, which does not need to be aligned. The bytes
+/// of the first and last words that are outside of the range are kept.
+fn write_bytes<M: Memory>(mem: &mut M, addr: u32, bytes: &[u8]) -> Result<()> {
+    if bytes.is_empty() {
+        return Ok(());
+    }
+
+    let start = addr & !3;
+    let lead = (addr - start) as usize;
+    let mut buf = vec![0u8; (lead + bytes.len() + 3) & !3];
+
+    if lead != 0 {
+        buf[..4].copy_from_slice(&mem.inspect(start)?.to_le_bytes());
+    }
+    let last = buf.len() - 4;
+    if (lead + bytes.len()) & 3 != 0 {
+        buf[last..].copy_from_slice(&mem.inspect(start + last as u32)?.to_le_bytes());
+    }
+    buf[lead..lead + bytes.len()].copy_from_slice(bytes);
+
+    let words: Vec<u32> = buf
+        .chunks(4)
+        .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect();
+
+    mem.poke_from_slice(start, &words)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The path of `name` in `res/`.
+    fn sample(name: &str) -> String {
+        format!("{}/res/{}", env!("CARGO_MANIFEST_DIR"), name)
+    }
+
+    /// Opens `path` in `format`, and reads it at the virtual addresses.
+    fn read(path: &str, format: Option<Format>) -> Program {
+        open(path, format, 0x80000000)
+            .unwrap()
+            .read(Placement::Virtual)
+            .unwrap()
+    }
+
+    #[test]
+    fn open_detects_the_format() {
+        let text = fs::read(sample("01.soma.text")).unwrap();
+
+        // MARS split files, by their prefix or their `.text`.
+        for path in [sample("01.soma"), sample("01.soma.text")] {
+            let program = read(&path, None);
+            assert_eq!(program.entry, 0x00400000);
+            assert_eq!(program.image.chunks[0], (0x00400000, text.clone()));
+        }
+
+        // The source of the same program.
+        let program = read(&sample("01.soma.asm"), None);
+        assert_eq!(program.image.chunks[0], (0x00400000, text.clone()));
+        assert_eq!(program.symbols.line(0x00400008).unwrap().line, 5);
+
+        // ELF, by its contents.
+        let program = read(&sample("15.pi.elf"), None);
+        assert_eq!(program.entry, 0x00400000);
+
+        // Given a format, the name does not matter: the `.text` is a flat
+        // binary too.
+        let program = read(&sample("01.soma.text"), Some(Format::Binary));
+        assert_eq!(program.entry, 0x80000000);
+        assert_eq!(program.image.chunks, vec![(0x80000000, text)]);
+
+        let dir = std::env::temp_dir().join(format!("indy-open-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str, contents: &str| {
+            let path = dir.join(name);
+            fs::write(&path, contents).unwrap();
+            path.to_str().unwrap().to_string()
+        };
+
+        // Intel HEX and S-records, whatever they are called.
+        let hex = file("prog.img", ":0400000001020304F2\n:00000001FF\n");
+        assert_eq!(read(&hex, None).image.chunks, vec![(0, vec![1, 2, 3, 4])]);
+        let srec = file("prog.hex", "S10510000102E7\nS9031000EC\n");
+        assert_eq!(read(&srec, None).entry, 0x1000);
+        // Anything else is a flat binary.
+        let binary = file("prog", "\x00\x00\x00\x00");
+        assert_eq!(read(&binary, None).entry, 0x80000000);
+
+        let missing = dir.join("missing");
+        assert!(open(missing.to_str().unwrap(), None, 0).is_err());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,122 @@
+//! Motorola S-record files.
+//!
+//! Each line is a record, `S<type><count><address><data><checksum>`. The
+//! type says how long the address is: S1, S2 and S3 hold data at 16, 24
+//! and 32-bit addresses, and S9, S8 and S7 end the file with the entry
+//! point. The header (S0) and the record counts (S5, S6) are ignored.
+
+use super::image::{hex_bytes, Image};
+
+use color_eyre::eyre::{eyre, Result};
+
+/// Reads an S-record file.
+pub fn parse(text: &str) -> Result<Image> {
+    let mut image = Image::default();
+
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+
+        let err = |msg: &str| eyre!("line {}: {}", n + 1, msg);
+
+        let record = line
+            .strip_prefix('S')
+            .ok_or_else(|| err("records must start with 'S'"))?;
+        let kind = record
+            .chars()
+            .next()
+            .and_then(|c| c.to_digit(10))
+            .ok_or_else(|| err("bad record type"))?;
+        let bytes = hex_bytes(&record[1..]).ok_or_else(|| err("bad hex digits"))?;
+
+        if bytes.len() < 2 || bytes.len() != bytes[0] as usize + 1 {
+            return Err(err("the record length is wrong"));
+        }
+        let (checksum, counted) = bytes.split_last().unwrap();
+        if counted.iter().fold(0u8, |sum, b| sum.wrapping_add(*b)) ^ 0xFF != *checksum {
+            return Err(err("bad checksum"));
+        }
+
+        let addr_len = match kind {
+            0 | 1 | 5 | 9 => 2,
+            2 | 6 | 8 => 3,
+            3 | 7 => 4,
+            _ => return Err(err(&format!("unknown record type S{}", kind))),
+        };
+        if counted.len() < 1 + addr_len {
+            return Err(err("the record length is wrong"));
+        }
+
+        let addr = counted[1..=addr_len]
+            .iter()
+            .fold(0, |val, b| (val << 8) | *b as u32);
+        let data = &counted[1 + addr_len..];
+
+        match kind {
+            1..=3 => image.push(addr, data),
+            7..=9 => image.entry = Some(addr),
+            _ => {}
+        }
+    }
+
+    Ok(image)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(text: &str) -> String {
+        parse(text).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn records() {
+        let image = parse(
+            "S0050000686929\n\
+             S10510000102E7\n\
+             S2064010000304A2\n\
+             S3091001000005060708CB\n\
+             S3061001000409DB\n\
+             S5030003F9\n\
+             S70500400010AA\n",
+        )
+        .unwrap();
+
+        assert_eq!(
+            image.chunks,
+            vec![
+                (0x00001000, vec![1, 2]),
+                (0x00401000, vec![3, 4]),
+                (0x10010000, vec![5, 6, 7, 8, 9]),
+            ]
+        );
+        assert_eq!(image.entry, Some(0x00400010));
+
+        // The entry point, with a 16 and a 24-bit address.
+        assert_eq!(parse("S9031000EC").unwrap().entry, Some(0x1000));
+        assert_eq!(parse("S804401000AB").unwrap().entry, Some(0x401000));
+    }
+
+    #[test]
+    fn bad_records() {
+        assert_eq!(error("S10510000102E8"), "line 1: bad checksum");
+        assert_eq!(
+            error("S10510000102E7\nS4030000FC"),
+            "line 2: unknown record type S4"
+        );
+        assert_eq!(
+            error("X10510000102E7"),
+            "line 1: records must start with 'S'"
+        );
+        assert_eq!(error("SX0510000102E7"), "line 1: bad record type");
+
+        // Cut short, in the middle of a byte or at one.
+        assert_eq!(error("S1051000010"), "line 1: bad hex digits");
+        assert_eq!(error("S10510000102"), "line 1: the record length is wrong");
+        // Too short for its address.
+        assert_eq!(error("S3030000FC"), "line 1: the record length is wrong");
+    }
+}
@@ -3,20 +3,40 @@
 //! O arquivo [main.rs](src/main.rs) toma conta apenas do CLI do emulador.
 //! Implementação do emulador em si está no módulo `emulator`.
 
-use clap::{crate_version, App, Arg, SubCommand};
-use color_eyre::eyre::{eyre, Result};
-use goblin::elf::Elf;
+use clap::{crate_version, App, Arg, ArgMatches, SubCommand};
+use color_eyre::eyre::{eyre, Report, Result};
 
 use std::cell::UnsafeCell;
 use std::fs::File;
-use std::io::Read;
+use std::io::{BufReader, BufWriter, Read};
+use std::path::Path;
 use std::sync::mpsc::SyncSender;
+use std::time::Duration;
 
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+pub(crate) mod assembler;
+pub(crate) mod disassembler;
 pub(crate) mod emulator;
+pub(crate) mod loader;
 
+use emulator::devices::{BitmapDisplay, KeyboardDisplay};
 use emulator::memory::{reporter::*, Cache, Memory, Ram, RepPolicy};
-use emulator::Cpu;
-use emulator::Instruction;
+use emulator::abi::{InitialState, Process};
+use emulator::board::{Board, BOARDS};
+use emulator::cop0::{physical_address, RESET_VECTOR};
+use emulator::console::StreamConsole;
+use emulator::vfs::Vfs;
+use emulator::fault::Fault;
+use emulator::cpu::Limits;
+use emulator::snapshot::{SnapshotPlan, SnapshotReader};
+use emulator::{Abi, Cpu};
+use disassembler::{disassemble, Object, Section, Style};
+use loader::dwarf::Sources;
+use loader::elf;
+use loader::image::{Format, FORMAT_NAMES};
+use loader::mars::Executable;
+use loader::Placement;
 
 /// Descrição e tabela das configurações de memória disponíveis.
 const CONFIG_HELP: &str = "As configurações de memória podem ser as seguintes:
@@ -42,94 +62,547 @@ const CONFIG_HELP: &str = "As configurações de memória podem ser as seguintes
 Se não informada, a configuração 1 é a padrão.
 ";
 
-/// Carrega o arquivo num vetor de palavras de 32 bits.
-fn u32_vec_from_file(mut file: File) -> Vec<u32> {
-    let mut data = Vec::new();
-    file.read_to_end(&mut data).unwrap();
+/// Carrega a imagem de uma ROM na memória física. A ROM pode estar em
+/// qualquer formato que o `run` aceita: um ELF tem seus segmentos
+/// carregados nos endereços físicos pedidos, assim como os registros de um
+/// arquivo Intel HEX ou S-record, e um arquivo binário é mapeado a partir
+/// do vetor de reset.
+///
+/// A execução começa no vetor de reset, a não ser que o programa não tenha
+/// nada lá (como um programa newlib ligado com `uhi32.ld`), caso em que ela
+/// começa no entry point dele.
+fn load_rom(ram: &mut Ram, file: &str) -> Result<Process> {
+    let program = loader::open(file, None, RESET_VECTOR)?
+        .read(Placement::Physical)
+        .map_err(|e| eyre!("{}: {}", file, e))?;
+
+    let has_reset = program.image.covers(physical_address(RESET_VECTOR));
+
+    let mut process = program.load(ram, file).map_err(|e| eyre!("{}: {}", file, e))?;
+    if has_reset {
+        process.entry = RESET_VECTOR;
+    }
+
+    Ok(process)
+}
+
+/// Carrega o programa `file` para ser executado sobre um perfil de ABI. O
+/// formato é o pedido com `--format`, ou o detectado pelo conteúdo.
+fn load_program(ram: &mut Ram, file: &str, matches: &ArgMatches) -> Result<Process> {
+    let format = matches.value_of("format").map(Format::from_name).transpose()?;
+    let base = parse_number(matches.value_of("base").unwrap())?;
+
+    let mut program = loader::open(file, format, base)?
+        .read(Placement::Virtual)
+        .map_err(|e| eyre!("{}: {}", file, e))?;
+
+    if let Some(entry) = matches.value_of("entry") {
+        program.entry = parse_number(entry)?;
+    }
+
+    program.load(ram, file).map_err(|e| eyre!("{}: {}", file, e))
+}
+
+/// Lê um endereço ou número, em hexadecimal (com o prefixo `0x`) ou decimal.
+fn parse_number(val: &str) -> Result<u32> {
+    let val = val.trim();
+
+    let parsed = match val.strip_prefix("0x").or_else(|| val.strip_prefix("0X")) {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => val.parse::<u32>(),
+    };
+
+    parsed.map_err(|e| eyre!("Número inválido {}: {}", val, e))
+}
+
+/// O subcomando que carrega um programa, em qualquer formato, e o executa.
+fn run_subcommand(name: &'static str, about: &'static str) -> App<'static, 'static> {
+    SubCommand::with_name(name)
+        .about(about)
+        .arg(
+            Arg::with_name("format")
+                .long("format")
+                .takes_value(true)
+                .possible_values(FORMAT_NAMES)
+                .help("O formato do programa; se omitido, é detectado pelo conteúdo"),
+        )
+        .arg(
+            Arg::with_name("entry")
+                .long("entry")
+                .short("e")
+                .takes_value(true)
+                .help("Endereço da primeira instrução; se omitido, vem do programa"),
+        )
+        .arg(
+            Arg::with_name("base")
+                .long("base")
+                .takes_value(true)
+                .default_value("0x00400000")
+                .help("Endereço onde carregar um programa binário"),
+        )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .help("Escreve os acessos de memória no arquivo de --outfile"),
+        )
+        .arg(
+            Arg::with_name("debug")
+                .long("debug")
+                .help("Como --trace, escrevendo também outras infos"),
+        )
+        .arg(
+            Arg::with_name("outfile")
+                .long("outfile")
+                .short("o")
+                .default_value("indy.trace")
+                .help("Arquivo onde escrever os acessos de memória"),
+        )
+        .args(&process_args())
+        .args(&run_args())
+        .arg(Arg::with_name("conf").required(true).index(1).help("Índice da configuração da memória").long_help(CONFIG_HELP))
+        .arg(Arg::with_name("file").required(false).index(2).help("O caminho do programa a ser executado"))
+        .arg(program_args())
+}
+
+/// Os argumentos dos subcomandos que executam um programa sobre um perfil
+/// de ABI, e não direto na máquina.
+fn process_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("abi")
+            .long("abi")
+            .takes_value(true)
+            .possible_values(&["mars", "spim", "linux"])
+            .default_value("mars")
+            .help("Perfil de ABI: tabela de syscalls, pilha inicial e código de partida"),
+        Arg::with_name("env")
+            .long("env")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("NOME=VALOR")
+            .help("Adiciona uma variável ao ambiente do programa (SPIM e Linux)"),
+    ]
+}
+
+/// Os argumentos comuns a todos os subcomandos que executam um programa.
+fn run_args() -> Vec<Arg<'static, 'static>> {
+    vec![
+        Arg::with_name("root")
+            .long("root")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Diretório usado como raiz do sistema de arquivos do programa (padrão: o atual)"),
+        Arg::with_name("read-only")
+            .long("read-only")
+            .help("Não deixa o programa modificar arquivos"),
+        Arg::with_name("overlay")
+            .long("overlay")
+            .help("Mantém em memória os arquivos escritos pelo programa, sem modificar a raiz"),
+        Arg::with_name("dump-files")
+            .long("dump-files")
+            .takes_value(true)
+            .value_name("DIR")
+            .help("Liga o overlay e salva os arquivos escritos pelo programa em DIR no final"),
+        Arg::with_name("deterministic")
+            .long("deterministic")
+            .help("Execução reproduzível: geradores aleatórios semeados e relógio simulado"),
+        Arg::with_name("seed")
+            .long("seed")
+            .takes_value(true)
+            .value_name("N")
+            .help("A semente do modo determinístico (padrão: 0); implica --deterministic"),
+        Arg::with_name("max-instructions")
+            .long("max-instructions")
+            .takes_value(true)
+            .value_name("N")
+            .help("Interrompe a execução depois de N instruções"),
+        Arg::with_name("max-cycles")
+            .long("max-cycles")
+            .takes_value(true)
+            .value_name("N")
+            .help("Interrompe a execução depois de N ciclos"),
+        Arg::with_name("timeout")
+            .long("timeout")
+            .takes_value(true)
+            .value_name("SEGUNDOS")
+            .help("Interrompe a execução depois de SEGUNDOS segundos"),
+        Arg::with_name("input")
+            .long("input")
+            .takes_value(true)
+            .value_name("ARQUIVO")
+            .help("Lê a entrada do programa de ARQUIVO, em vez do stdin"),
+        Arg::with_name("output")
+            .long("output")
+            .takes_value(true)
+            .value_name("ARQUIVO")
+            .help("Escreve a saída do programa em ARQUIVO, em vez do stdout"),
+        Arg::with_name("record")
+            .long("record")
+            .takes_value(true)
+            .value_name("ARQUIVO")
+            .help("Grava a entrada lida pelo programa em ARQUIVO, para ser repetida com --input"),
+        Arg::with_name("mmio")
+            .long("mmio")
+            .help("Liga o dispositivo Keyboard and Display MMIO do MARS em 0xffff0000"),
+        Arg::with_name("bitmap")
+            .long("bitmap")
+            .takes_value(true)
+            .value_name("ARQUIVO")
+            .help("Liga o Bitmap Display, salvando o framebuffer em ARQUIVO (.png ou .ppm)"),
+        Arg::with_name("bitmap-base")
+            .long("bitmap-base")
+            .takes_value(true)
+            .default_value("0x10010000")
+            .help("Endereço do framebuffer do Bitmap Display"),
+        Arg::with_name("bitmap-size")
+            .long("bitmap-size")
+            .takes_value(true)
+            .default_value("512x256")
+            .help("Tamanho do Bitmap Display, em unidades (LARGURAxALTURA)"),
+        Arg::with_name("bitmap-unit")
+            .long("bitmap-unit")
+            .takes_value(true)
+            .default_value("1")
+            .help("Lado de cada unidade do Bitmap Display, em pixels"),
+        Arg::with_name("bitmap-every")
+            .long("bitmap-every")
+            .takes_value(true)
+            .default_value("0")
+            .help("Salva um frame a cada N instruções (0 desliga)"),
+        Arg::with_name("core")
+            .long("core")
+            .takes_value(true)
+            .value_name("ARQUIVO")
+            .help("Escreve um core dump ELF em ARQUIVO se o programa falhar, para o gdb"),
+        Arg::with_name("snapshot-at")
+            .long("snapshot-at")
+            .takes_value(true)
+            .multiple(true)
+            .number_of_values(1)
+            .value_name("N")
+            .help("Salva um snapshot da máquina depois de N instruções, para continuar com `resume`"),
+        Arg::with_name("snapshot-file")
+            .long("snapshot-file")
+            .takes_value(true)
+            .default_value("indy.{}.snap")
+            .help("Arquivo dos snapshots; {} é trocado pelo número de instruções"),
+    ]
+}
+
+/// Os argumentos passados ao programa, depois do `--`.
+fn program_args() -> Arg<'static, 'static> {
+    Arg::with_name("args")
+        .index(3)
+        .multiple(true)
+        .last(true)
+        .help("Argumentos passados ao programa")
+}
+
+/// Opções de execução que independem da configuração de memória.
+#[derive(Default)]
+struct RunOptions {
+    /// O perfil de ABI (MARS, SPIM ou Linux).
+    abi: Abi,
+    /// Os argumentos do programa, sem o nome dele.
+    args: Vec<String>,
+    /// O ambiente do programa.
+    env: Vec<String>,
+    /// A semente do modo determinístico, se ele foi pedido.
+    seed: Option<u64>,
+    /// Os limites de execução.
+    limits: Limits,
+    /// O console usado pelas syscalls.
+    console: StreamConsole,
+    /// O sistema de arquivos visto pelo programa.
+    vfs: Vfs,
+    /// Onde salvar os arquivos do overlay no final.
+    dump_files: Option<String>,
+    /// Liga o dispositivo Keyboard and Display MMIO do MARS.
+    mmio: bool,
+    /// O Bitmap Display, se foi pedido.
+    bitmap: Option<BitmapDisplay>,
+    /// Liga a máquina a partir do vetor de reset, sem sistema operacional.
+    bare: bool,
+    /// A placa emulada, se alguma foi escolhida.
+    board: Option<&'static Board>,
+    /// Onde escrever o core dump se o programa falhar.
+    core: Option<String>,
+    /// Quando salvar snapshots da máquina.
+    snapshots: SnapshotPlan,
+    /// O snapshot de onde continuar a execução, se for o caso.
+    resume: Option<Vec<u8>>,
+}
+
+impl RunOptions {
+    /// Lê as opções de execução dos argumentos do subcomando.
+    fn from_matches(matches: &ArgMatches) -> Result<RunOptions> {
+        let bitmap = match matches.value_of("bitmap") {
+            Some(output) => {
+                let size = matches.value_of("bitmap-size").unwrap();
+                let (width, height) = size
+                    .split_once('x')
+                    .ok_or_else(|| eyre!("Tamanho inválido {}: use LARGURAxALTURA", size))?;
+
+                Some(BitmapDisplay::new(
+                    output,
+                    parse_number(matches.value_of("bitmap-base").unwrap())?,
+                    parse_number(width)?,
+                    parse_number(height)?,
+                    parse_number(matches.value_of("bitmap-unit").unwrap())?,
+                    parse_number(matches.value_of("bitmap-every").unwrap())? as usize,
+                )?)
+            }
+            None => None,
+        };
+
+        let seed = match matches.value_of("seed") {
+            Some(seed) => Some(
+                seed.parse::<u64>()
+                    .map_err(|e| eyre!("Semente inválida {}: {}", seed, e))?,
+            ),
+            None if matches.is_present("deterministic") => Some(0),
+            None => None,
+        };
+
+        let limits = Limits {
+            instructions: parse_limit(matches, "max-instructions")?,
+            cycles: parse_limit(matches, "max-cycles")?,
+            timeout: match matches.value_of("timeout") {
+                Some(secs) => {
+                    let timeout = secs
+                        .parse::<f64>()
+                        .map_err(Report::from)
+                        .and_then(|s| Ok(Duration::try_from_secs_f64(s)?))
+                        .map_err(|e| eyre!("Tempo inválido {}: {}", secs, e))?;
+                    Some(timeout)
+                }
+                None => None,
+            },
+        };
+
+        let mut console = StreamConsole::host();
+        if let Some(input) = matches.value_of("input") {
+            console.set_input(BufReader::new(File::open(input)?));
+        }
+        if let Some(output) = matches.value_of("output") {
+            console.set_output(BufWriter::new(File::create(output)?));
+        }
+        if let Some(record) = matches.value_of("record") {
+            console.record(File::create(record)?);
+        }
 
-    data.chunks(4)
-        .map(|b| {
-            let mut owned_b = [0u8; 4];
-            owned_b.copy_from_slice(b);
-            u32::from_le_bytes(owned_b)
+        let mut vfs = Vfs::new(matches.value_of("root").unwrap_or("."));
+        vfs.set_read_only(matches.is_present("read-only"));
+
+        let dump_files = matches.value_of("dump-files").map(String::from);
+        if matches.is_present("overlay") || dump_files.is_some() {
+            vfs.enable_overlay();
+        }
+
+        let snapshot_at = values(matches, "snapshot-at")
+            .iter()
+            .map(|n| n.parse().map_err(|e| eyre!("Número inválido {}: {}", n, e)))
+            .collect::<Result<Vec<usize>>>()?;
+        let snapshots = SnapshotPlan::new(snapshot_at, matches.value_of("snapshot-file").unwrap());
+
+        Ok(RunOptions {
+            abi: match matches.value_of("abi") {
+                Some(abi) => abi.parse()?,
+                None => Abi::default(),
+            },
+            args: values(matches, "args"),
+            env: values(matches, "env"),
+            seed,
+            limits,
+            console,
+            vfs,
+            dump_files,
+            mmio: matches.is_present("mmio"),
+            bitmap,
+            bare: false,
+            board: None,
+            core: matches.value_of("core").map(String::from),
+            snapshots,
+            resume: None,
         })
-        .collect()
+    }
 }
 
-/// Encapsula uma dupla .text/.data de palavras de 32 bits.
-#[derive(Debug)]
-pub struct Executable {
-    pub text: Vec<u32>,
-    pub data: Option<Vec<u32>>,
-    pub rodata: Option<Vec<u32>>,
+/// Lê um limite de execução, se foi passado.
+fn parse_limit(matches: &ArgMatches, name: &str) -> Result<Option<usize>> {
+    match matches.value_of(name) {
+        Some(val) => val
+            .parse()
+            .map(Some)
+            .map_err(|e| eyre!("Limite inválido {}: {}", val, e)),
+        None => Ok(None),
+    }
 }
 
-impl Executable {
-    /// Lê os arquivos `pfx.text` e `pfx.data` e retorna um Executable contendo os dados
-    pub fn from_naked_files(pfx: impl AsRef<str>) -> Result<Executable> {
-        let text = u32_vec_from_file(File::open(format!("{}.text", pfx.as_ref()))?);
-        let data = File::open(format!("{}.data", pfx.as_ref()))
-            .ok()
-            .map(u32_vec_from_file);
-        let rodata = File::open(format!("{}.rodata", pfx.as_ref()))
-            .ok()
-            .map(u32_vec_from_file);
-
-        Ok(Executable { text, data, rodata })
+/// Retorna todos os valores de um argumento que pode se repetir.
+fn values(matches: &ArgMatches, name: &str) -> Vec<String> {
+    matches
+        .values_of(name)
+        .map(|v| v.map(String::from).collect())
+        .unwrap_or_default()
+}
+
+/// Aplica as opções de execução na CPU e a executa.
+fn run_cpu<TD: Memory, TI: Memory>(
+    mut cpu: Cpu<TD, TI>,
+    opts: RunOptions,
+    init: &InitialState,
+    process: Process,
+) -> Result<i32> {
+    cpu.set_symbols(process.symbols);
+
+    if let Some(core) = opts.core {
+        cpu.set_core_file(core, process.args);
+    }
+
+    if opts.bare {
+        cpu.reset();
+        cpu.set_program_args(opts.args);
+    }
+
+    cpu.set_abi(opts.abi);
+    cpu.set_limits(opts.limits);
+
+    if let Some(seed) = opts.seed {
+        cpu.set_deterministic(seed);
+    }
+    cpu.set_console(Box::new(opts.console));
+    cpu.set_vfs(opts.vfs);
+
+    if let Some(brk) = init.brk {
+        cpu.set_program_break(brk);
+    }
+
+    if let Some((argc, argv)) = init.main_args {
+        cpu.set_main_args(argc, argv);
+    }
+
+    if let Some(board) = opts.board {
+        board.attach(&mut cpu);
+    }
+
+    if opts.mmio {
+        cpu.attach_device(Box::new(KeyboardDisplay::new()));
+    }
+
+    if let Some(bitmap) = opts.bitmap {
+        cpu.set_bitmap_display(bitmap);
+    }
+
+    cpu.set_snapshot_plan(opts.snapshots);
+    if let Some(snapshot) = opts.resume {
+        cpu.restore_snapshot(&snapshot)
+            .map_err(|e| eyre!("Não foi possível continuar do snapshot: {}", e))?;
+    }
+
+    let result = cpu.run();
+
+    // Os arquivos são salvos mesmo que o programa tenha falhado.
+    if let Some(dir) = opts.dump_files {
+        cpu.vfs().dump(&dir)?;
     }
+
+    result
 }
 
 /// Assume que o objeto `ram` recebido já contém o conteúdo do programa
 /// a ser executado, e o executa levando em consideração a configuração
 /// de memória e o reporter associado.
 fn run_from_ram(
-    ram: Ram,
-    entry: u32,
+    mut ram: Ram,
+    mut process: Process,
     mem_cfg: &str,
     tx: Option<SyncSender<MemoryEvent>>,
-) -> Result<()> {
-    match mem_cfg {
+    mut opts: RunOptions,
+) -> Result<i32> {
+    process.args.extend(opts.args.iter().cloned());
+    process.env.extend(opts.env.iter().cloned());
+    opts.snapshots.set_config(mem_cfg);
+
+    // No modo determinístico, tudo que é aleatório sai da semente.
+    let mut rng = match opts.seed {
+        Some(seed) => StdRng::seed_from_u64(seed),
+        None => StdRng::from_entropy(),
+    };
+
+    // O perfil de ABI decide onde a execução começa e os valores
+    // iniciais de $sp e $gp. Sem sistema operacional, nada é preparado, e
+    // ao continuar de um snapshot, tudo vem dele.
+    let init = if opts.bare || opts.resume.is_some() {
+        // O programa pede os argumentos, com o nome dele, ao host (UHI).
+        opts.args = process.args.clone();
+
+        InitialState {
+            pc: process.entry,
+            sp: 0,
+            gp: 0,
+            main_args: None,
+            brk: None,
+        }
+    } else {
+        opts.abi.setup(&mut ram, &process, &mut rng)?
+    };
+
+    // A CPU recebe a sua própria semente, tirada do mesmo gerador.
+    opts.seed = opts.seed.map(|_| rng.gen());
+    ram.reset_stats();
+
+    let (entry, sp, gp) = (init.pc, init.sp, init.gp);
+
+    let status = match mem_cfg {
         "1" => {
             let ram = UnsafeCell::new(ram);
-            let mut cpu = Cpu::new(&ram, &ram, entry, 0x7FFFEFFC, 0x10008000);
-            cpu.run()?;
+            let cpu = Cpu::new(&ram, &ram, entry, sp, gp);
+            run_cpu(cpu, opts, &init, process)?
         }
         "2" => {
             let ram = UnsafeCell::new(ram);
-            let cache: UnsafeCell<Cache<_, 8, 32, 1>> = UnsafeCell::new(Cache::new(
+            let mut cache: UnsafeCell<Cache<_, 8, 32, 1>> = UnsafeCell::new(Cache::new(
                 "L1",
                 &ram,
                 RepPolicy::Random,
                 1,
                 tx.as_ref().cloned(),
             ));
-            let mut cpu = Cpu::new(&cache, &cache, entry, 0x7FFFEFFC, 0x10008000);
-            cpu.run()?;
+            if opts.seed.is_some() {
+                cache.get_mut().set_seed(rng.gen());
+            }
+            let cpu = Cpu::new(&cache, &cache, entry, sp, gp);
+            run_cpu(cpu, opts, &init, process)?
         }
         "3" => {
             let ram = UnsafeCell::new(ram);
-            let l1d: UnsafeCell<Cache<_, 8, 16, 1>> = UnsafeCell::new(Cache::new(
+            let mut l1d: UnsafeCell<Cache<_, 8, 16, 1>> = UnsafeCell::new(Cache::new(
                 "L1d",
                 &ram,
                 RepPolicy::Random,
                 1,
                 tx.as_ref().cloned(),
             ));
-            let l1i: UnsafeCell<Cache<_, 8, 16, 1>> = UnsafeCell::new(Cache::new(
+            let mut l1i: UnsafeCell<Cache<_, 8, 16, 1>> = UnsafeCell::new(Cache::new(
                 "L1i",
                 &ram,
                 RepPolicy::Random,
                 1,
                 tx.as_ref().cloned(),
             ));
+            if opts.seed.is_some() {
+                l1d.get_mut().set_seed(rng.gen());
+                l1i.get_mut().set_seed(rng.gen());
+            }
 
             unsafe {
                 (&mut *l1d.get()).set_sister(&l1i, true);
                 (&mut *l1i.get()).set_sister(&l1d, true);
             }
 
-            let mut cpu = Cpu::new(&l1d, &l1i, entry, 0x7FFFEFFC, 0x10008000);
-            cpu.run()?;
+            let cpu = Cpu::new(&l1d, &l1i, entry, sp, gp);
+            run_cpu(cpu, opts, &init, process)?
         }
         "4" => {
             let ram = UnsafeCell::new(ram);
@@ -153,8 +626,8 @@ fn run_from_ram(
                 (&mut *l1i.get()).set_sister(&l1d, true);
             }
 
-            let mut cpu = Cpu::new(&l1d, &l1i, entry, 0x7FFFEFFC, 0x10008000);
-            cpu.run()?;
+            let cpu = Cpu::new(&l1d, &l1i, entry, sp, gp);
+            run_cpu(cpu, opts, &init, process)?
         }
         "5" => {
             let ram = UnsafeCell::new(ram);
@@ -178,8 +651,8 @@ fn run_from_ram(
                 (&mut *l1i.get()).set_sister(&l1d, true);
             }
 
-            let mut cpu = Cpu::new(&l1d, &l1i, entry, 0x7FFFEFFC, 0x10008000);
-            cpu.run()?;
+            let cpu = Cpu::new(&l1d, &l1i, entry, sp, gp);
+            run_cpu(cpu, opts, &init, process)?
         }
         "6" => {
             let ram = UnsafeCell::new(ram);
@@ -212,15 +685,74 @@ fn run_from_ram(
                 (&mut *l1i.get()).set_sister(&l1d, true);
             }
 
-            let mut cpu = Cpu::new(&l1d, &l1i, entry, 0x7FFFEFFC, 0x10008000);
-            cpu.run()?;
+            let cpu = Cpu::new(&l1d, &l1i, entry, sp, gp);
+            run_cpu(cpu, opts, &init, process)?
         }
         c => return Err(eyre!("Configuração de memória {} não conhecida!", c)),
     };
-    Ok(())
+    Ok(status)
 }
 
-fn main() -> Result<()> {
+/// O código de saída do indy quando o host falha (arquivo não encontrado,
+/// argumentos inválidos, ...).
+const EXIT_HOST_ERROR: i32 = 125;
+
+/// O código de saída quando um limite de execução é atingido, o mesmo do
+/// `timeout(1)`.
+const EXIT_LIMIT: i32 = 124;
+
+/// Os códigos de saída quando o programa faz algo errado. Seguem a
+/// convenção do shell para processos mortos por sinais: 128 + o sinal.
+const EXIT_SIGNAL: i32 = 128;
+
+/// Classifica o erro que interrompeu o indy, retornando o código de saída.
+fn exit_code(report: &Report) -> i32 {
+    match report.downcast_ref::<Fault>().map(Fault::signal) {
+        Some(Some(signal)) => EXIT_SIGNAL + signal as i32,
+        Some(None) => EXIT_LIMIT,
+        None => EXIT_HOST_ERROR,
+    }
+}
+
+/// Sai com o status pedido pelo programa, ou com um código que diz por
+/// que ele não terminou normalmente.
+fn main() {
+    let status = match cli() {
+        Ok(status) => status,
+        Err(report) => {
+            eprintln!("Error: {:?}", report);
+            exit_code(&report)
+        }
+    };
+
+    std::process::exit(status);
+}
+
+/// A opção dos comandos de disassemble que troca a saída pela do binutils.
+fn objdump_arg() -> Arg<'static, 'static> {
+    Arg::with_name("objdump")
+        .long("objdump")
+        .help("Mostra o código como o `objdump -d` do binutils, para comparar as saídas")
+}
+
+/// Mostra o disassemble de `object`, no estilo pedido na linha de comando.
+fn print_disassembly(object: &mut Object, matches: &ArgMatches) -> Result<()> {
+    let style = if matches.is_present("objdump") {
+        Style::Objdump
+    } else {
+        Style::Indy
+    };
+
+    // Quem lê a saída (um `head`, por exemplo) pode parar antes do fim.
+    match disassemble(object, style, &mut std::io::stdout().lock()) {
+        Err(e) if e
+            .downcast_ref::<std::io::Error>()
+            .is_some_and(|e| e.kind() == std::io::ErrorKind::BrokenPipe) => Ok(()),
+        result => result,
+    }
+}
+
+fn cli() -> Result<i32> {
     color_eyre::install()?;
 
     env_logger::init();
@@ -233,285 +765,288 @@ fn main() -> Result<()> {
         .subcommand(
             SubCommand::with_name("decode")
                 .about("Desconstrói o binário, mostrando o código Assembly equivalente")
+                .arg(objdump_arg())
                 .arg(Arg::with_name("file").index(1).required(true)),
         )
         .subcommand(
-            SubCommand::with_name("run")
-                .about("Carrega o binário e o executa")
-                .arg(
-                    Arg::with_name("entry")
-                        .long("entry")
-                        .short("e")
-                        .required(false)
-                        .default_value("0x00400000")
-                        .help("Endereço da primeira instrução"),
-                )
-                .arg(Arg::with_name("conf").required(true).index(1).help("Índice da configuração da memória").long_help(CONFIG_HELP))
-                .arg(Arg::with_name("file").required(false).index(2).help("O caminho do programa a ser executado")),
-        )
-        .subcommand(
-            SubCommand::with_name("trace")
-                .about("Carrega o binário e o executa, escrevendo os acessos de memória no arquivo.")
+            SubCommand::with_name("asm")
+                .about("Monta um programa em Assembly, na sintaxe do MARS")
                 .arg(
-                    Arg::with_name("entry")
-                        .long("entry")
-                        .short("e")
-                        .required(false)
-                        .default_value("0x00400000")
-                        .help("Endereço da primeira instrução"),
+                    Arg::with_name("format")
+                        .long("format")
+                        .takes_value(true)
+                        .possible_values(&["mars", "elf"])
+                        .default_value("mars")
+                        .help("O formato da saída: os arquivos .text, .data, ... do MARS ou um executável ELF"),
                 )
                 .arg(
-                    Arg::with_name("outfile")
-                        .long("outfile")
+                    Arg::with_name("output")
                         .short("o")
-                        .required(false)
-                        .default_value("indy.trace")
-                        .help("Arquivo onde escrever os acessos de memória"),
+                        .long("output")
+                        .takes_value(true)
+                        .help("O prefixo dos arquivos do MARS, ou o nome do ELF; se omitido, vem do nome do arquivo"),
                 )
-                .arg(Arg::with_name("conf").required(true).index(1).help("Índice da configuração da memória").long_help(CONFIG_HELP))
-                .arg(Arg::with_name("file").required(false).index(2).help("O caminho do programa a ser executado")),
+                .arg(Arg::with_name("file").required(true).index(1)),
         )
+        .subcommand(run_subcommand(
+            "run",
+            "Carrega o programa (ELF, arquivos do MARS, Intel HEX, S-record, binário ou código Assembly) e o executa",
+        ))
+        .subcommand(run_subcommand("trace", "O mesmo que `run --trace`"))
+        .subcommand(run_subcommand("debug", "O mesmo que `run --debug`"))
+        .subcommand(run_subcommand("runelf", "O mesmo que `run`, para arquivos ELF"))
+        .subcommand(run_subcommand("runimage", "O mesmo que `run`, para imagens de memória"))
         .subcommand(
-            SubCommand::with_name("debug")
-                .about("Carrega o binário e o executa, escrevendo os acessos de memória e outras infos no arquivo.")
-                .arg(
-                    Arg::with_name("entry")
-                        .long("entry")
-                        .short("e")
-                        .required(false)
-                        .default_value("0x00400000")
-                        .help("Endereço da primeira instrução"),
-                )
+            SubCommand::with_name("boot")
+                .about("Liga a máquina sem sistema operacional, executando a ROM a partir do vetor de reset")
                 .arg(
-                    Arg::with_name("outfile")
-                        .long("outfile")
-                        .short("o")
-                        .required(false)
-                        .default_value("indy.trace")
-                        .help("Arquivo onde escrever os acessos de memória"),
+                    Arg::with_name("board")
+                        .long("board")
+                        .takes_value(true)
+                        .possible_values(&BOARDS.iter().map(|b| b.name).collect::<Vec<_>>())
+                        .help("Emula uma placa: mapa de memória, UART, timer e controlador de interrupções"),
                 )
+                .args(&run_args())
                 .arg(Arg::with_name("conf").required(true).index(1).help("Índice da configuração da memória").long_help(CONFIG_HELP))
-                .arg(Arg::with_name("file").required(false).index(2).help("O caminho do programa a ser executado")),
+                .arg(Arg::with_name("file").required(false).index(2).help("A imagem da ROM: ELF, Intel HEX, S-record ou binária, carregada em 0xbfc00000"))
+                .arg(program_args()),
         )
         .subcommand(
-            SubCommand::with_name("runelf")
-                .about("Carrega um arquivo ELF e o executa (bonus!)")
-                .arg(Arg::with_name("conf").required(true).index(1).help("Índice da configuração da memória").long_help(CONFIG_HELP))
-                .arg(Arg::with_name("file").required(false).index(2).help("O caminho do programa a ser executado")),
+            SubCommand::with_name("resume")
+                .about("Continua a execução de onde um snapshot salvo com --snapshot-at parou")
+                .arg(
+                    Arg::with_name("board")
+                        .long("board")
+                        .takes_value(true)
+                        .possible_values(&BOARDS.iter().map(|b| b.name).collect::<Vec<_>>())
+                        .help("A placa emulada, se o snapshot foi tirado no `boot` com uma"),
+                )
+                .args(&run_args())
+                .arg(Arg::with_name("snapshot").required(true).index(1).help("O arquivo do snapshot")),
         )
         .subcommand(
             SubCommand::with_name("decodeelf")
                 .about("Carrega um arquivo ELF e o desconstrói, mostrando o código Assembly equivalente (bonus!)")
+                .arg(objdump_arg())
                 .arg(Arg::with_name("file").required(true)),
         )
         .get_matches();
 
     if let Some(matches) = matches.subcommand_matches("decode") {
-        // Desmonta o binário
-        let executable = Executable::from_naked_files(matches.value_of("file").unwrap())?;
-
-        let mut addr = 0x00400000;
+        // Desmonta o binário: .text e .ktext são código, o resto é dado
+        let file = matches.value_of("file").unwrap();
+        let executable = Executable::from_naked_files(file)?;
 
-        for word in executable.text {
-            println!(
-                "{:08x}:\t{:08x}\t{}",
+        let sections = executable
+            .named_segments()
+            .into_iter()
+            .map(|(name, addr, words)| Section {
+                name: name.to_string(),
                 addr,
-                word,
-                Instruction::decode(word)?
-            );
-            addr += 4;
+                bytes: words.iter().flat_map(|w| w.to_le_bytes()).collect(),
+                code: name.ends_with("text"),
+            })
+            .collect();
+
+        let mut object = Object {
+            file: file.to_string(),
+            format: "binary",
+            sections,
+            symbols: Default::default(),
+            entry: None,
+            sources: None,
+        };
+        print_disassembly(&mut object, matches)?;
+
+        Ok(0)
+    } else if let Some(matches) = matches.subcommand_matches("asm") {
+        // Monta o programa
+        let file = matches.value_of("file").unwrap();
+        let asm = assembler::assemble(&loader::asm::read_source(file)?, file)?;
+
+        let stem = file
+            .strip_suffix(".asm")
+            .or_else(|| file.strip_suffix(".s"))
+            .unwrap_or(file);
+
+        if matches.value_of("format") == Some("elf") {
+            let out = match matches.value_of("output") {
+                Some(out) => out.to_string(),
+                None => format!("{}.elf", stem),
+            };
+            std::fs::write(&out, assembler::output::elf(&asm)).map_err(|e| eyre!("{}: {}", out, e))?;
+        } else {
+            assembler::output::write_mars(&asm, matches.value_of("output").unwrap_or(stem))?;
         }
 
-        Ok(())
-    } else if let Some(matches) = matches.subcommand_matches("run") {
+        Ok(0)
+    } else if let (name @ ("run" | "trace" | "debug" | "runelf" | "runimage"), Some(matches)) =
+        matches.subcommand()
+    {
         let (file, mem_cfg) = match matches.value_of("file") {
             Some(file) => (file, matches.value_of("conf").unwrap()),
             None => (matches.value_of("conf").unwrap(), "1"),
         };
 
         let mut ram = Ram::new(100);
-
-        // Executa o binário
-        let entry = u32::from_str_radix(&matches.value_of("entry").unwrap()[2..], 16)?;
-        let executable = Executable::from_naked_files(file)?;
-
-        //let mut cpu = Cpu::new(0x00400000, 0x7FFFEFFC, 0x10008000);
-
-        ram.poke_from_slice(0x00400000, &executable.text[..])?;
-        if let Some(ref data) = executable.data {
-            ram.poke_from_slice(0x10010000, &data[..])?;
-        }
-        if let Some(ref data) = executable.rodata {
-            ram.poke_from_slice(0x00800000, &data[..])?;
-        }
-
+        let process = load_program(&mut ram, file, matches)?;
         ram.reset_stats();
 
-        run_from_ram(ram, entry, mem_cfg, None)?;
-
-        Ok(())
-    } else if let Some(matches) = matches.subcommand_matches("trace") {
-        let (file, mem_cfg) = match matches.value_of("file") {
-            Some(file) => (file, matches.value_of("conf").unwrap()),
-            None => (matches.value_of("conf").unwrap(), "1"),
+        let debug = name == "debug" || matches.is_present("debug");
+        let reporter = if debug || name == "trace" || matches.is_present("trace") {
+            let out_file = File::create(matches.value_of("outfile").unwrap())?;
+            Some(MemoryReporter::new(out_file, debug))
+        } else {
+            None
         };
 
-        let mut ram = Ram::new(100);
-
-        // Executa o binário
-        let entry = u32::from_str_radix(&matches.value_of("entry").unwrap()[2..], 16)?;
-        let executable = Executable::from_naked_files(file)?;
-
-        //let mut cpu = Cpu::new(0x00400000, 0x7FFFEFFC, 0x10008000);
-
-        ram.poke_from_slice(0x00400000, &executable.text[..])?;
-        if let Some(ref data) = executable.data {
-            ram.poke_from_slice(0x10010000, &data[..])?;
+        let status = run_from_ram(
+            ram,
+            process,
+            mem_cfg,
+            reporter.as_ref().map(|(_, tx)| tx.clone()),
+            RunOptions::from_matches(matches)?,
+        );
+
+        // O trace é terminado mesmo que o programa tenha falhado.
+        if let Some((rep_thread, tx)) = reporter {
+            tx.send(MemoryEvent::Finish).unwrap();
+            rep_thread.join().unwrap();
         }
-        if let Some(ref data) = executable.rodata {
-            ram.poke_from_slice(0x00800000, &data[..])?;
-        }
-
-        ram.reset_stats();
-
-        let out_file = matches.value_of("outfile").unwrap();
-        let out_file = File::create(out_file)?;
-        let (rep_thread, tx) = MemoryReporter::new(out_file, false);
-
-        run_from_ram(ram, entry, mem_cfg, Some(tx.clone()))?;
 
-        tx.send(MemoryEvent::Finish).unwrap();
-        rep_thread.join().unwrap();
-
-        Ok(())
-    } else if let Some(matches) = matches.subcommand_matches("debug") {
+        status
+    } else if let Some(matches) = matches.subcommand_matches("boot") {
         let (file, mem_cfg) = match matches.value_of("file") {
             Some(file) => (file, matches.value_of("conf").unwrap()),
             None => (matches.value_of("conf").unwrap(), "1"),
         };
 
         let mut ram = Ram::new(100);
-
-        // Executa o binário
-        let entry = u32::from_str_radix(&matches.value_of("entry").unwrap()[2..], 16)?;
-        let executable = Executable::from_naked_files(file)?;
-
-        //let mut cpu = Cpu::new(0x00400000, 0x7FFFEFFC, 0x10008000);
-
-        ram.poke_from_slice(0x00400000, &executable.text[..])?;
-        if let Some(ref data) = executable.data {
-            ram.poke_from_slice(0x10010000, &data[..])?;
-        }
-        if let Some(ref data) = executable.rodata {
-            ram.poke_from_slice(0x00800000, &data[..])?;
-        }
-
+        let process = load_rom(&mut ram, file)?;
         ram.reset_stats();
 
-        let out_file = matches.value_of("outfile").unwrap();
-        let out_file = File::create(out_file)?;
-        let (rep_thread, tx) = MemoryReporter::new(out_file, true);
-
-        run_from_ram(ram, entry, mem_cfg, Some(tx.clone()))?;
-
-        tx.send(MemoryEvent::Finish).unwrap();
-        rep_thread.join().unwrap();
+        let mut opts = RunOptions::from_matches(matches)?;
+        opts.bare = true;
+        opts.board = matches.value_of("board").map(Board::by_name).transpose()?;
 
-        Ok(())
-    } else if let Some(matches) = matches.subcommand_matches("runelf") {
-        let (file, mem_cfg) = match matches.value_of("file") {
-            Some(file) => (file, matches.value_of("conf").unwrap()),
-            None => (matches.value_of("conf").unwrap(), "1"),
-        };
+        let status = run_from_ram(ram, process, mem_cfg, None, opts)?;
 
-        let mut ram = Ram::new(100);
+        Ok(status)
+    } else if let Some(matches) = matches.subcommand_matches("resume") {
+        let file = matches.value_of("snapshot").unwrap();
+        let snapshot = std::fs::read(file).map_err(|e| eyre!("{}: {}", file, e))?;
 
-        // Executa o elf
-        let mut file = File::open(file)?;
-        let mut file_bytes = Vec::new();
-        file.read_to_end(&mut file_bytes)?;
-
-        let elf = Elf::parse(&file_bytes[..])?;
-
-        // Carrega cada seção carregável em seu respectivo endereço
-        for section in elf.program_headers {
-            if section.p_type == goblin::elf::program_header::PT_LOAD {
-                println!(
-                    "elf: loading {} bytes to {:#010x}...",
-                    section.p_memsz, section.p_paddr
-                );
-                let offset = section.p_offset as usize;
-                let size = section.p_filesz as usize;
-
-                let section_bytes: Vec<u32> = file_bytes[offset..offset + size]
-                    .chunks(4)
-                    .map(|b| {
-                        let mut owned_b = [0u8; 4];
-                        owned_b.copy_from_slice(b);
-                        u32::from_le_bytes(owned_b)
-                    })
-                    .collect();
-
-                ram.poke_from_slice(section.p_paddr as u32, &section_bytes[..])?;
-            }
-        }
+        // A configuração de memória é a da máquina que foi salva.
+        let mem_cfg = SnapshotReader::new(&snapshot)
+            .map_err(|e| eyre!("{}: {}", file, e))?
+            .config()
+            .to_string();
 
-        ram.reset_stats();
+        let mut opts = RunOptions::from_matches(matches)?;
+        opts.board = matches.value_of("board").map(Board::by_name).transpose()?;
+        opts.resume = Some(snapshot);
 
-        run_from_ram(ram, elf.entry as u32, mem_cfg, None)?;
+        // A memória e os registradores vêm todos do snapshot.
+        let status = run_from_ram(Ram::new(100), Process::new(0, file), &mem_cfg, None, opts)?;
 
-        Ok(())
+        Ok(status)
     } else if let Some(matches) = matches.subcommand_matches("decodeelf") {
         // Disassemble do arquivo ELF
-        let mut file = File::open(matches.value_of("file").unwrap())?;
+        let file = matches.value_of("file").unwrap();
         let mut file_bytes = Vec::new();
-        file.read_to_end(&mut file_bytes)?;
+        File::open(file)?.read_to_end(&mut file_bytes)?;
 
-        let elf = Elf::parse(&file_bytes[..])?;
+        let elf = elf::parse(&file_bytes).map_err(|e| eyre!("{}: {}", file, e))?;
 
         // Só desmonte as seções que vão ser carregadas na memória
-        for section in elf.section_headers {
+        let mut sections = Vec::new();
+        for section in elf.sections.iter() {
             if section.sh_type == goblin::elf::section_header::SHT_PROGBITS
                 && section.sh_flags & goblin::elf::section_header::SHF_ALLOC as u64 != 0
             {
-                println!(
-                    "Diassemble of section {}:",
-                    &elf.shdr_strtab[section.sh_name]
-                );
-                let offset = section.sh_offset as usize;
-                let size = section.sh_size as usize;
-
-                let section_bytes: Vec<u32> = file_bytes[offset..offset + size]
-                    .chunks(4)
-                    .map(|b| {
-                        let mut owned_b = [0u8; 4];
-                        owned_b.copy_from_slice(b);
-                        u32::from_le_bytes(owned_b)
-                    })
-                    .collect();
-
-                let offset = section.sh_addr;
-                let mut pos = 0;
-                for word in section_bytes {
-                    match Instruction::decode(word) {
-                        Ok(instr) => print!("{:08x}:\t{:08x}\t{}", offset + pos, word, instr),
-                        Err(_) => print!("{:08x}:\t{:08x}\t???", word, offset + pos),
-                    }
-                    if offset + pos == elf.entry {
-                        println!(" # <- entry");
-                    } else {
-                        println!();
-                    }
-                    pos += 4;
+                let bytes = elf
+                    .section_data(section)
+                    .ok_or_else(|| eyre!("Seção fora do arquivo {}", file))?;
+
+                sections.push(Section {
+                    name: elf.section_name(section).unwrap_or("?").to_string(),
+                    addr: section.sh_addr as u32,
+                    bytes: bytes.to_vec(),
+                    code: section.sh_flags & goblin::elf::section_header::SHF_EXECINSTR as u64 != 0,
+                });
+            }
+        }
+
+        // Sem as seções (há ELFs com os cabeçalhos de seção cortados), o
+        // código está nos segmentos PT_LOAD executáveis.
+        if sections.is_empty() {
+            for (i, ph) in elf.program_headers.iter().enumerate() {
+                if ph.p_type == goblin::elf::program_header::PT_LOAD
+                    && ph.p_flags & goblin::elf::program_header::PF_X != 0
+                {
+                    let bytes = elf
+                        .segment_data(ph)
+                        .ok_or_else(|| eyre!("Segmento fora do arquivo {}", file))?;
+
+                    sections.push(Section {
+                        name: format!("segment{}", i),
+                        addr: ph.p_vaddr as u32,
+                        bytes: bytes.to_vec(),
+                        code: true,
+                    });
                 }
-                println!();
             }
         }
+        if sections.is_empty() {
+            return Err(eyre!("{}: não há seções nem segmentos com código", file));
+        }
 
-        Ok(())
+        let mut object = Object {
+            file: file.to_string(),
+            format: "elf32-tradlittlemips",
+            sections,
+            symbols: elf.symbols(),
+            entry: Some(elf.header.e_entry as u32),
+            sources: Some(Sources::new(Path::new(file))),
+        };
+        print_disassembly(&mut object, matches)?;
+
+        Ok(0)
     } else {
         eprintln!("{}", matches.usage());
         eprintln!("Tente: indy --help");
-        Ok(())
+        Ok(0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use emulator::fault::Limit;
+
+    #[test]
+    fn codigos_de_saida() {
+        let code = |fault: Fault| exit_code(&Report::new(fault));
+        let limit = |limit: Limit| code(Fault::Limit { pc: 0, limit });
+
+        // 128 + o sinal quando o programa faz algo errado.
+        assert_eq!(code(Fault::Signal(6)), 134);
+        assert_eq!(code(Fault::AddressError(0x10010001)), 139);
+        assert_eq!(code(Fault::BusError(0)), 135);
+        assert_eq!(code(Fault::UnknownInstruction { pc: 0, word: 0 }), 132);
+        let trap = Fault::Trap {
+            pc: 0,
+            instr: "break",
+        };
+        assert_eq!(code(trap), 133);
+
+        // 124 nos limites, 125 nos erros do host.
+        assert_eq!(limit(Limit::Wait), 124);
+        assert_eq!(limit(Limit::Instructions(10)), 124);
+        assert_eq!(exit_code(&eyre!("arquivo não encontrado")), 125);
+
+        // O contexto adicionado ao erro não muda a causa.
+        let report = Report::new(Fault::BusError(0)).wrap_err("ao carregar");
+        assert_eq!(exit_code(&report), 135);
     }
 }
@@ -0,0 +1,107 @@
+//! Disassembles the sample programs in `res/` and compares the listings
+//! with the ones recorded in `tests/disassembly/`: `<name>.dis` in the
+//! default style, with the labels and pseudo-instructions put back, and
+//! `<name>.objdump` in the style of `objdump -d`.
+
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+/// The path of `name` in `res/`.
+fn sample(name: &str) -> PathBuf {
+    Path::new(env!("CARGO_MANIFEST_DIR")).join("res").join(name)
+}
+
+/// Runs `indy` with `args`, from the root of the crate.
+fn indy(args: &[&str]) -> Output {
+    Command::new(env!("CARGO_BIN_EXE_indy"))
+        .current_dir(env!("CARGO_MANIFEST_DIR"))
+        .args(args)
+        .output()
+        .unwrap()
+}
+
+/// Disassembles the sample `name` in both styles and checks the listings.
+fn check(name: &str) {
+    // `objdump` shows the path, so it is the same as in the listing.
+    let path = format!("res/{}", name);
+
+    for &(flags, ext) in &[(&[][..], "dis"), (&["--objdump"][..], "objdump")] {
+        let mut args = vec!["decode"];
+        args.extend_from_slice(flags);
+        args.push(&path);
+        let output = indy(&args);
+        assert!(output.status.success(), "{}.{}", name, ext);
+
+        let root = env!("CARGO_MANIFEST_DIR");
+        let golden = format!("{}/tests/disassembly/{}.{}", root, name, ext);
+        let expected = std::fs::read_to_string(golden).unwrap();
+        assert_eq!(
+            String::from_utf8_lossy(&output.stdout),
+            expected,
+            "{}.{}",
+            name,
+            ext
+        );
+    }
+}
+
+macro_rules! samples {
+    ($($test:ident: $name:literal;)*) => {
+        $(
+            #[test]
+            fn $test() {
+                check($name);
+            }
+        )*
+    };
+}
+
+samples! {
+    soma: "01.soma";
+    hello: "02.hello";
+    input: "03.input";
+    branches: "04.branches";
+    fibo: "05.fibo";
+    collatz: "06.collatz";
+    loadstore: "07.loadstore";
+    sort: "08.sort";
+    contador: "09.contador";
+    hello_plus_plus: "10.hello++";
+    surpresinha: "11.surpresinha";
+    branch_delay_slot: "12.branch_delay_slot";
+    flutuantes: "14.flutuantes";
+}
+
+#[test]
+fn decodeelf_falls_back_to_the_executable_segments() {
+    // The section headers of this one are cut off.
+    let path = sample("13.arit.elf");
+    let output = indy(&["decodeelf", path.to_str().unwrap()]);
+    assert!(output.status.success());
+
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let mut lines = stdout.lines();
+    assert_eq!(lines.next(), Some("Disassembly of section segment2:"));
+    assert_eq!(
+        lines.next(),
+        Some("00400000:\t27bdffe0\taddiu $sp, $sp, -32 # <- entry")
+    );
+}
+
+#[test]
+fn decodeelf_fails_without_code() {
+    // The same file, with no executable segment.
+    let mut bytes = std::fs::read(sample("13.arit.elf")).unwrap();
+    let phnum = u16::from_le_bytes([bytes[44], bytes[45]]) as usize;
+    for i in 0..phnum {
+        // p_flags
+        bytes[52 + i * 32 + 24] &= !1;
+    }
+    let path = std::env::temp_dir().join(format!("indy-no-code-{}.elf", std::process::id()));
+    std::fs::write(&path, bytes).unwrap();
+
+    let output = indy(&["decodeelf", path.to_str().unwrap()]);
+    std::fs::remove_file(&path).unwrap();
+    assert!(!output.status.success());
+    assert!(String::from_utf8_lossy(&output.stderr).contains("não há seções nem segmentos"));
+}
@@ -0,0 +1,90 @@
+//! Runs the sample programs in `res/` and compares what they print with
+//! the output recorded in `tests/samples/`.
+//!
+//! The larger dgemm samples are left out: they take several seconds each
+//! in a debug build.
+
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+
+/// The line printed when the program exits; what comes after it are the
+/// statistics, which include the host time.
+const FINISHED: &[u8] = b"Execution finished successfully!\n";
+
+/// Runs the sample `name` with `input` on its standard input, and checks
+/// its output.
+fn check(name: &str, input: &str) {
+    let root = Path::new(env!("CARGO_MANIFEST_DIR"));
+
+    let mut child = Command::new(env!("CARGO_BIN_EXE_indy"))
+        .arg("run")
+        .arg(root.join("res").join(name))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    child
+        .stdin
+        .take()
+        .unwrap()
+        .write_all(input.as_bytes())
+        .unwrap();
+    let output = child.wait_with_output().unwrap();
+
+    let stdout = output.stdout;
+    let end = stdout
+        .windows(FINISHED.len())
+        .position(|w| w == FINISHED)
+        .map(|i| i + FINISHED.len())
+        .unwrap_or_else(|| {
+            panic!(
+                "{} did not finish: {:?}",
+                name,
+                String::from_utf8_lossy(&stdout)
+            )
+        });
+
+    let expected = std::fs::read(root.join("tests/samples").join(format!("{}.out", name))).unwrap();
+    assert_eq!(
+        String::from_utf8_lossy(&stdout[..end]),
+        String::from_utf8_lossy(&expected),
+        "{}",
+        name
+    );
+}
+
+macro_rules! samples {
+    ($($test:ident: $name:literal $(, $input:literal)?;)*) => {
+        $(
+            #[test]
+            fn $test() {
+                check($name, concat!("" $(, $input)?));
+            }
+        )*
+    };
+}
+
+samples! {
+    soma: "01.soma";
+    hello: "02.hello";
+    input: "03.input", "7\n";
+    branches: "04.branches";
+    fibo: "05.fibo", "7\n";
+    collatz: "06.collatz", "17\n0\n";
+    loadstore: "07.loadstore";
+    sort: "08.sort";
+    contador: "09.contador";
+    hello_plus_plus: "10.hello++";
+    surpresinha: "11.surpresinha";
+    branch_delay_slot: "12.branch_delay_slot";
+    arit: "13.arit";
+    flutuantes: "14.flutuantes", "7\n2.5\n";
+    pi: "15.pi", "7\n";
+    automato: "16.automato", "30\n5\n";
+    rng: "17.rng";
+    mandelbrot: "21.mandelbrot";
+    smol_dgemm: "97.smol_dgemm";
+    arith: "99.arith";
+}